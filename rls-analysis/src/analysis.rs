@@ -48,6 +48,10 @@ pub struct PerCrateAnalysis {
     pub ref_spans: HashMap<Id, Vec<Span>>,
     pub globs: HashMap<Span, Glob>,
     pub impls: HashMap<Id, Vec<Span>>,
+    // Maps a type to the traits it implements.
+    pub supertypes: HashMap<Id, HashSet<Id>>,
+    // Maps a trait to the types implementing it.
+    pub subtypes: HashMap<Id, HashSet<Id>>,
     pub idents: HashMap<PathBuf, IdentsByLine>,
 
     pub root_id: Option<Id>,
@@ -172,6 +176,8 @@ impl PerCrateAnalysis {
             ref_spans: HashMap::new(),
             globs: HashMap::new(),
             impls: HashMap::new(),
+            supertypes: HashMap::new(),
+            subtypes: HashMap::new(),
             idents: HashMap::new(),
             root_id: None,
             timestamp,
@@ -405,6 +411,33 @@ impl Analysis {
         })
     }
 
+    /// See `AnalysisHost::items_in_module`.
+    pub fn items_in_module(&self, module_path: &str) -> Vec<Def> {
+        // Qualified names are relative to the crate, e.g. `::io::Read`.
+        let prefix = format!("::{}::", module_path.trim_matches(':'));
+        let mut result = vec![];
+        for (crate_id, c) in &self.per_crate {
+            let crate_prefix = format!("::{}::", crate_id.name);
+            let relative_prefix = if prefix.starts_with(&crate_prefix) {
+                Some(&prefix[crate_prefix.len() - 2..])
+            } else {
+                None
+            };
+            result.extend(
+                c.defs
+                    .values()
+                    .filter(|def| {
+                        let in_module = |prefix: &str| {
+                            def.qualname.len() > prefix.len() && def.qualname.starts_with(prefix)
+                        };
+                        in_module(&prefix) || relative_prefix.map_or(false, in_module)
+                    })
+                    .cloned(),
+            );
+        }
+        result
+    }
+
     pub fn with_def_names<F, T>(&self, name: &str, f: F) -> Vec<T>
     where
         F: Fn(&Vec<Id>) -> Vec<T>,
@@ -24,7 +24,7 @@ pub use loader::{AnalysisLoader, CargoAnalysisLoader, SearchDirectory, Target};
 pub use raw::{name_space_for_def_kind, read_analysis_from_files, Crate, CrateId, DefKind};
 pub use symbol_query::SymbolQuery;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
@@ -45,6 +45,14 @@ pub enum AError {
     Unclassified,
 }
 
+/// The amount of analysis data held in memory, see `AnalysisHost::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AnalysisStats {
+    pub crates: usize,
+    pub defs: usize,
+    pub refs: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct SymbolResult {
     pub id: Id,
@@ -105,6 +113,18 @@ impl AnalysisHost<CargoAnalysisLoader> {
             loader: Mutex::new(CargoAnalysisLoader::new(target)),
         }
     }
+
+    /// Sets the `library` directory of the Rust source the spans of the std
+    /// crates point to. Changing it forces the next reload to be a hard one.
+    pub fn set_sysroot_src(&self, sysroot_src: Option<PathBuf>) -> AResult<()> {
+        let mut loader = self.loader.lock()?;
+        if loader.sysroot_src != sysroot_src {
+            loader.sysroot_src = sysroot_src;
+            // See `needs_hard_reload`.
+            loader.path_prefix = None;
+        }
+        Ok(())
+    }
 }
 
 impl<L: AnalysisLoader> AnalysisHost<L> {
@@ -218,6 +238,29 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         Ok(())
     }
 
+    /// Drops all the analysis data held in memory, e.g., to reclaim the memory
+    /// used by crates which are no longer part of the build. Until the next
+    /// reload, which re-reads all the data, queries return no results.
+    pub fn clear_caches(&self) -> AResult<()> {
+        let mut analysis = self.analysis.lock()?;
+        let mut master_crate_map = self.master_crate_map.lock()?;
+        *analysis = Some(Analysis::new());
+        master_crate_map.clear();
+        Ok(())
+    }
+
+    /// Returns the number of crates, definitions and references held in memory.
+    pub fn stats(&self) -> AResult<AnalysisStats> {
+        self.with_analysis(|a| {
+            let mut stats = AnalysisStats { crates: a.per_crate.len(), ..AnalysisStats::default() };
+            for per_crate in a.per_crate.values() {
+                stats.defs += per_crate.defs.len();
+                stats.refs += per_crate.ref_spans.values().map(Vec::len).sum::<usize>();
+            }
+            Some(stats)
+        })
+    }
+
     /// Note that `self.has_def()` =/> `self.goto_def().is_ok()`, since if the
     /// Def is in an api crate, there is no reasonable Span to jump to.
     pub fn has_def(&self, id: Id) -> bool {
@@ -365,6 +408,14 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         result
     }
 
+    /// Returns the definitions of the items in the module at `module_path` and
+    /// its submodules, i.e., the definitions whose qualified name starts with
+    /// it. The path may start with the name of the crate, e.g., `std::io` or
+    /// `io` both match `std::io::Read`.
+    pub fn items_in_module(&self, module_path: &str) -> AResult<Vec<Def>> {
+        self.with_analysis(|a| Some(a.items_in_module(module_path)))
+    }
+
     /// Search for a symbol name, returns a list of spans matching defs and refs
     /// for that name.
     pub fn search(&self, name: &str) -> AResult<Vec<Span>> {
@@ -417,8 +468,36 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         result
     }
 
+    /// Returns the spans of the impls for the trait or type with the given id.
+    /// For a trait these are the impls of all its implementors, for a type
+    /// these are its inherent impls and the impls of all traits it implements.
     pub fn find_impls(&self, id: Id) -> AResult<Vec<Span>> {
-        self.with_analysis(|a| Some(a.for_all_crates(|c| c.impls.get(&id).cloned())))
+        self.with_analysis(|a| {
+            // The same impl is recorded once per crate it is compiled into
+            // (e.g., once for the library and once for its unit tests).
+            let mut seen = HashSet::new();
+            let mut impls = a.for_all_crates(|c| c.impls.get(&id).cloned());
+            impls.retain(|span| seen.insert(span.clone()));
+            Some(impls)
+        })
+    }
+
+    /// Returns the ids of the traits implemented by the type with the given id.
+    pub fn find_supertypes(&self, id: Id) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| {
+            Some(
+                a.for_all_crates(|c| {
+                    c.supertypes.get(&id).map(|ids| ids.iter().cloned().collect())
+                }),
+            )
+        })
+    }
+
+    /// Returns the ids of the types implementing the trait with the given id.
+    pub fn find_subtypes(&self, id: Id) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| {
+            Some(a.for_all_crates(|c| c.subtypes.get(&id).map(|ids| ids.iter().cloned().collect())))
+        })
     }
 
     /// Search for a symbol name, returning a list of def_ids for that name.
@@ -15,6 +15,9 @@ use crate::AnalysisHost;
 pub struct CargoAnalysisLoader {
     pub path_prefix: Option<PathBuf>,
     pub target: Target,
+    /// The `library` directory of the Rust source the spans of the std crates
+    /// point to, instead of the `rust-src` component of the sysroot.
+    pub sysroot_src: Option<PathBuf>,
 }
 
 #[derive(Debug, new)]
@@ -29,7 +32,7 @@ pub struct SearchDirectory {
 
 impl CargoAnalysisLoader {
     pub fn new(target: Target) -> CargoAnalysisLoader {
-        CargoAnalysisLoader { path_prefix: None, target }
+        CargoAnalysisLoader { path_prefix: None, target, sysroot_src: None }
     }
 }
 
@@ -52,6 +55,7 @@ impl AnalysisLoader for CargoAnalysisLoader {
     fn fresh_host(&self) -> AnalysisHost<Self> {
         AnalysisHost::new_with_loader(CargoAnalysisLoader {
             path_prefix: self.path_prefix.clone(),
+            sysroot_src: self.sysroot_src.clone(),
             ..CargoAnalysisLoader::new(self.target)
         })
     }
@@ -77,7 +81,12 @@ impl AnalysisLoader for CargoAnalysisLoader {
         let libs_path =
             sys_root_path.join("lib").join("rustlib").join(&target_triple).join("analysis");
 
-        let src_path = sys_root_path.join("lib").join("rustlib").join("src").join("rust");
+        // The spans of the std crates are relative to the root of the Rust
+        // source, which contains the `library` directory.
+        let src_path = match self.sysroot_src {
+            Some(ref library) => library.parent().unwrap_or(library).to_owned(),
+            None => sys_root_path.join("lib").join("rustlib").join("src").join("rust"),
+        };
 
         vec![SearchDirectory::new(libs_path, Some(src_path)), SearchDirectory::new(deps_path, None)]
     }
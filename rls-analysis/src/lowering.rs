@@ -455,18 +455,26 @@ impl<'a> CrateReader<'a> {
             let self_id = self.id_from_compiler_id(r.from);
             let trait_id = self.id_from_compiler_id(r.to);
             let span = lower_span(&r.span, &self.base_dir, &self.path_rewrite);
+            let mut abs_self_id = None;
             if self_id != NULL {
                 if let Some(self_id) = abs_ref_id(self_id, analysis, project_analysis) {
                     trace!("record impl for self type {:?} {}", span, self_id);
                     analysis.impls.entry(self_id).or_insert_with(Vec::new).push(span.clone());
+                    abs_self_id = Some(self_id);
                 }
             }
+            let mut abs_trait_id = None;
             if trait_id != NULL {
                 if let Some(trait_id) = abs_ref_id(trait_id, analysis, project_analysis) {
                     trace!("record impl for trait {:?} {}", span, trait_id);
                     analysis.impls.entry(trait_id).or_insert_with(Vec::new).push(span);
+                    abs_trait_id = Some(trait_id);
                 }
             }
+            if let (Some(self_id), Some(trait_id)) = (abs_self_id, abs_trait_id) {
+                analysis.supertypes.entry(self_id).or_insert_with(HashSet::new).insert(trait_id);
+                analysis.subtypes.entry(trait_id).or_insert_with(HashSet::new).insert(self_id);
+            }
         }
     }
 
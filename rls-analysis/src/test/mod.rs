@@ -1,9 +1,12 @@
+use crate::analysis::{Analysis, PerCrateAnalysis, Ref};
 use crate::loader::SearchDirectory;
-use crate::raw::DefKind;
-use crate::{AnalysisHost, AnalysisLoader};
+use crate::raw::{CrateId, DefKind};
+use crate::{AnalysisHost, AnalysisLoader, AnalysisStats, Id, Span};
 
+use span::{Column, Row};
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 #[derive(Clone, new)]
 struct TestAnalysisLoader {
@@ -141,6 +144,59 @@ fn smoke() {
     host.reload(Path::new("test_data/rls-analysis"), Path::new("test_data/rls-analysis")).unwrap();
 }
 
+#[test]
+fn clear_caches_drops_analysis() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/rust-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/rust-analysis"), Path::new("test_data/rust-analysis"))
+        .unwrap();
+    assert!(!host.search_for_id("VarError").unwrap().is_empty());
+
+    host.clear_caches().unwrap();
+    assert!(host.search_for_id("VarError").unwrap().is_empty());
+
+    host.reload(Path::new("test_data/rust-analysis"), Path::new("test_data/rust-analysis"))
+        .unwrap();
+    assert!(!host.search_for_id("VarError").unwrap().is_empty());
+}
+
+#[test]
+fn stats_count_loaded_data() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/rls-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/rls-analysis"), Path::new("test_data/rls-analysis")).unwrap();
+    let stats = host.stats().unwrap();
+    assert!(stats.crates > 0);
+    assert!(stats.defs > 0);
+    assert!(stats.refs > 0);
+
+    host.clear_caches().unwrap();
+    assert_eq!(host.stats().unwrap(), AnalysisStats::default());
+}
+
+#[test]
+fn items_in_module() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/rls-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/rls-analysis"), Path::new("test_data/rls-analysis")).unwrap();
+
+    let io_items = host.items_in_module("byteorder::io").unwrap();
+    assert!(!io_items.is_empty());
+    assert!(io_items.iter().all(|def| def.qualname.starts_with("::io::")));
+    assert!(io_items.iter().any(|def| def.qualname == "::io::ReadBytesExt"));
+
+    let adaptors = host.items_in_module("itertools::adaptors::multi_product").unwrap();
+    assert!(!adaptors.is_empty());
+    assert!(adaptors.iter().all(|def| def.qualname.starts_with("::adaptors::multi_product::")));
+    // Paths relative to the crate match as well.
+    assert_eq!(host.items_in_module("adaptors::multi_product").unwrap().len(), adaptors.len());
+
+    assert!(host.items_in_module("byteorder::no_such_module").unwrap().is_empty());
+}
+
 #[test]
 fn test_hello() {
     // Simple program, a somewhat thorough test that we have all the defs and refs we expect.
@@ -373,3 +429,63 @@ fn test_all_ref_unique() {
     let refs = host.find_all_refs(&spans[2], true, true);
     assert_eq!(refs.unwrap().len(), 3);
 }
+
+#[test]
+fn test_find_impls() {
+    // trait Shape {}
+    // struct Circle;
+    // struct Square;
+    // impl Shape for Circle {}
+    // impl Shape for Square {}
+    // impl Circle {}
+    let span = |row: u32, col_start: u32, col_end: u32| {
+        Span::new(
+            Row::new_zero_indexed(row),
+            Row::new_zero_indexed(row),
+            Column::new_zero_indexed(col_start),
+            Column::new_zero_indexed(col_end),
+            PathBuf::from("src/lib.rs"),
+        )
+    };
+    let (shape, circle, square) = (Id(1), Id(2), Id(3));
+    let circle_impl = span(3, 15, 21);
+    let square_impl = span(4, 15, 21);
+    let inherent_impl = span(5, 5, 11);
+
+    let mut per_crate = PerCrateAnalysis::new(SystemTime::now(), None);
+    per_crate.def_id_for_span.insert(span(0, 6, 11), Ref::Id(shape));
+    per_crate.def_id_for_span.insert(span(1, 7, 13), Ref::Id(circle));
+    per_crate.def_id_for_span.insert(span(2, 7, 13), Ref::Id(square));
+    per_crate.impls.insert(shape, vec![circle_impl.clone(), square_impl.clone()]);
+    per_crate.impls.insert(circle, vec![circle_impl.clone(), inherent_impl.clone()]);
+    per_crate.impls.insert(square, vec![square_impl.clone()]);
+    per_crate.subtypes.insert(shape, vec![circle, square].into_iter().collect());
+    per_crate.supertypes.insert(circle, vec![shape].into_iter().collect());
+    per_crate.supertypes.insert(square, vec![shape].into_iter().collect());
+
+    let mut analysis = Analysis::new();
+    let crate_id = |name: &str| CrateId { name: name.to_owned(), disambiguator: (0, 0) };
+    // The same impls are recorded for the library and for its unit tests.
+    let mut test_crate = PerCrateAnalysis::new(SystemTime::now(), None);
+    test_crate.impls = per_crate.impls.clone();
+    analysis.update(crate_id("shapes"), per_crate);
+    analysis.update(crate_id("shapes_test"), test_crate);
+
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(PathBuf::new()));
+    *host.analysis.lock().unwrap() = Some(analysis);
+
+    // All implementations of a trait.
+    let trait_id = host.id(&span(0, 6, 11)).unwrap();
+    let mut impls = host.find_impls(trait_id).unwrap();
+    impls.sort_by_key(|span| span.range.row_start);
+    assert_eq!(impls, vec![circle_impl.clone(), square_impl.clone()]);
+    let subtypes: HashSet<_> = host.find_subtypes(trait_id).unwrap().into_iter().collect();
+    assert_eq!(subtypes, vec![circle, square].into_iter().collect());
+
+    // All traits implemented by a type.
+    let type_id = host.id(&span(1, 7, 13)).unwrap();
+    let mut impls = host.find_impls(type_id).unwrap();
+    impls.sort_by_key(|span| span.range.row_start);
+    assert_eq!(impls, vec![circle_impl, inherent_impl]);
+    assert_eq!(host.find_supertypes(type_id).unwrap(), vec![shape]);
+}
@@ -4,18 +4,23 @@ extern crate rls_span as span;
 #[macro_use]
 extern crate log;
 
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs;
+use std::hash::Hasher;
 use std::io::Read;
 use std::marker::PhantomData;
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use std::thread::{self, Thread};
+use std::time::Instant;
 
 #[cfg(test)]
 mod test;
+#[cfg(feature = "watch")]
+mod watch;
 
 macro_rules! try_opt_loc {
     ($e:expr) => {
@@ -84,6 +89,28 @@ impl VfsSpan {
     }
 }
 
+/// Unit of the column offsets of positions within a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PositionEncoding {
+    /// UTF-8 bytes.
+    Utf8,
+    /// UTF-16 code units, as used by the Language Server Protocol.
+    Utf16,
+    /// Unicode scalar values, i.e. `char`s, as used by VFS spans.
+    Char,
+}
+
+impl PositionEncoding {
+    /// Returns the number of units `c` takes up in this encoding.
+    fn char_len(self, c: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => c.len_utf8(),
+            PositionEncoding::Utf16 => c.len_utf16(),
+            PositionEncoding::Char => 1,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Change {
     /// Create an in-memory image of the file.
@@ -123,6 +150,10 @@ pub enum Error {
     NoUserDataForFile,
     /// Wrong kind of file.
     BadFileKind,
+    /// The context or removed lines of a diff do not match the file content.
+    ContextMismatch(PathBuf),
+    /// The diff could not be parsed, e.g. because of an invalid hunk header.
+    MalformedDiff,
     /// An internal error - a bug in the VFS.
     InternalError(&'static str),
 }
@@ -141,6 +172,8 @@ impl Error {
             Error::BadFileKind => {
                 "file is not the correct kind for the operation (e.g., text op on binary file)"
             }
+            Error::ContextMismatch(ref _path_buf) => "diff does not match the file content",
+            Error::MalformedDiff => "malformed unified diff",
             Error::InternalError(_) => "internal error",
         }
     }
@@ -161,8 +194,12 @@ impl fmt::Display for Error {
             Error::UncommittedChanges(ref path_buf) => {
                 write!(f, "{} has uncommitted changes", path_buf.display())
             }
+            Error::ContextMismatch(ref path_buf) => {
+                write!(f, "diff does not match the content of {}", path_buf.display())
+            }
             Error::InternalError(e) => write!(f, "internal error: {}", e),
-            Error::BadLocation
+            Error::MalformedDiff
+            | Error::BadLocation
             | Error::FileNotCached
             | Error::NoUserDataForFile
             | Error::Io(..)
@@ -199,11 +236,69 @@ impl<U> Vfs<U> {
         self.0.file_is_synced(path)
     }
 
+    /// Returns a hash of the in-memory content of a file, or `None` if the
+    /// file is not cached. Compare with `content_hash` of the on-disk content
+    /// to cheaply detect whether these differ.
+    pub fn file_hash(&self, path: &Path) -> Option<u64> {
+        self.0.file_hash(path)
+    }
+
+    /// Returns the in-memory content of a file if it was loaded or modified
+    /// after `since`, or `None` if it wasn't (or the file is not cached). Lets
+    /// callers polling for changes skip reading and hashing unchanged files.
+    pub fn content_bytes_if_modified(&self, path: &Path, since: Instant) -> Option<Vec<u8>> {
+        self.0.content_bytes_if_modified(path, since)
+    }
+
     /// Record a set of changes to the VFS.
     pub fn on_changes(&self, changes: &[Change]) -> Result<(), Error> {
         self.0.on_changes(changes)
     }
 
+    /// Runs `f`, which may modify multiple files through the given writer, while
+    /// holding an exclusive lock on the VFS. Nobody else observes the VFS until
+    /// `f` returns, so they either see all of its modifications or none.
+    ///
+    /// Note that modifications made before an error are not rolled back.
+    pub fn transaction<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut VfsWriter<'_, U>) -> R,
+    {
+        self.0.transaction(f)
+    }
+
+    /// Applies the hunks of a unified diff (as produced by e.g. `diff -u`) to
+    /// the file. The diff must describe a single file; context and removed
+    /// lines are checked against the current content before anything is
+    /// changed. Returns `Error::MalformedDiff` if a hunk doesn't have as many
+    /// lines as its header says.
+    pub fn apply_unified_diff(&self, path: &Path, diff: &str) -> Result<(), Error> {
+        self.0.apply_unified_diff(path, diff)
+    }
+
+    /// Returns a unified diff from the on-disk content of a file to its
+    /// in-memory content, or `None` if these don't differ (or the file is not a
+    /// cached text file).
+    #[cfg(feature = "diff")]
+    pub fn diff_against_disk(&self, path: &Path) -> Option<String> {
+        self.0.diff_against_disk(path)
+    }
+
+    /// Calls `callback` whenever the file at `path` is changed on disk, e.g.
+    /// by another program. The callback is called on a separate thread, which
+    /// polls the watched files every 100 ms.
+    ///
+    /// The RLS doesn't enable this, it's notified of changes on disk by the
+    /// client (`workspace/didChangeWatchedFiles`).
+    #[cfg(feature = "watch")]
+    pub fn watch_file(
+        &self,
+        path: &Path,
+        callback: Box<dyn Fn() + Send + 'static>,
+    ) -> Result<(), Error> {
+        self.0.watcher.lock().unwrap().watch(path, callback)
+    }
+
     /// Return all files in the VFS.
     pub fn get_cached_files(&self) -> HashMap<PathBuf, String> {
         self.0.get_cached_files()
@@ -247,6 +342,20 @@ impl<U> Vfs<U> {
         self.0.load_span(span)
     }
 
+    /// Returns the span and the text of the word found at the given position,
+    /// whose column is given in `encoding`. The columns of the returned span
+    /// are `char` offsets, like the ones of every other VFS span.
+    /// The line is loaded and searched while holding the VFS lock, so the
+    /// returned span always matches the returned text.
+    pub fn word_at_position(
+        &self,
+        path: &Path,
+        pos: &span::Position<span::ZeroIndexed>,
+        encoding: PositionEncoding,
+    ) -> Option<(span::Span<span::ZeroIndexed>, String)> {
+        self.0.word_at_position(path, pos, encoding)
+    }
+
     pub fn for_each_line<F>(&self, path: &Path, f: F) -> Result<(), Error>
     where
         F: FnMut(&str, usize) -> Result<(), Error>,
@@ -289,6 +398,8 @@ impl<U> Vfs<U> {
 struct VfsInternal<T, U> {
     files: Mutex<HashMap<PathBuf, File<U>>>,
     pending_files: Mutex<HashMap<PathBuf, Vec<Thread>>>,
+    #[cfg(feature = "watch")]
+    watcher: Mutex<watch::FileWatcher>,
     loader: PhantomData<T>,
 }
 
@@ -297,6 +408,8 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         VfsInternal {
             files: Mutex::new(HashMap::new()),
             pending_files: Mutex::new(HashMap::new()),
+            #[cfg(feature = "watch")]
+            watcher: Mutex::default(),
             loader: PhantomData,
         }
     }
@@ -339,6 +452,58 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         }
     }
 
+    fn file_hash(&self, path: &Path) -> Option<u64> {
+        let files = self.files.lock().unwrap();
+        files.get(path).map(|f| content_hash(f.kind.as_bytes()))
+    }
+
+    fn apply_unified_diff(&self, path: &Path, diff: &str) -> Result<(), Error> {
+        self.ensure_file(path, |_| Ok(()))?;
+
+        let mut files = self.files.lock().unwrap();
+        let file = files.get_mut(path).ok_or(Error::FileNotCached)?;
+        match file.kind {
+            FileKind::Text(ref mut t) => {
+                t.text = apply_diff_hunks(path, &t.text, diff)?;
+                t.line_indices = make_line_indices(&t.text);
+                t.changed = true;
+                file.user_data = None;
+                file.last_modified = Instant::now();
+                Ok(())
+            }
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        }
+    }
+
+    #[cfg(feature = "diff")]
+    fn diff_against_disk(&self, path: &Path) -> Option<String> {
+        let files = self.files.lock().unwrap();
+        let text = match files.get(path)?.kind {
+            FileKind::Text(ref t) => &t.text,
+            FileKind::Binary(_) => return None,
+        };
+        let disk_text = match T::read::<U>(path).ok()?.kind {
+            FileKind::Text(t) => t.text,
+            FileKind::Binary(_) => return None,
+        };
+        if disk_text == *text {
+            return None;
+        }
+
+        let name = path.display().to_string();
+        Some(unified_diff(&name, &disk_text, text))
+    }
+
+    fn content_bytes_if_modified(&self, path: &Path, since: Instant) -> Option<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        let file = files.get(path)?;
+        if file.last_modified > since {
+            Some(file.kind.as_bytes().to_owned())
+        } else {
+            None
+        }
+    }
+
     fn file_is_synced(&self, path: &Path) -> Result<bool, Error> {
         let files = self.files.lock().unwrap();
         match files.get(path) {
@@ -347,6 +512,15 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         }
     }
 
+    fn transaction<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut VfsWriter<'_, U>) -> R,
+    {
+        let mut files = self.files.lock().unwrap();
+        let mut writer = VfsWriter { files: &mut files, read: T::read::<U> };
+        f(&mut writer)
+    }
+
     fn on_changes(&self, changes: &[Change]) -> Result<(), Error> {
         trace!("on_changes: {:?}", changes);
         for (file_name, changes) in coalesce_changes(changes) {
@@ -376,14 +550,11 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
     }
 
     fn set_file(&self, path: &Path, text: &str) {
-        let file = File {
-            kind: FileKind::Text(TextFile {
-                text: text.to_owned(),
-                line_indices: make_line_indices(text),
-                changed: true,
-            }),
-            user_data: None,
-        };
+        let file = File::new(FileKind::Text(TextFile {
+            text: text.to_owned(),
+            line_indices: make_line_indices(text),
+            changed: true,
+        }));
 
         loop {
             let mut pending_files = self.pending_files.lock().unwrap();
@@ -442,6 +613,36 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
         self.ensure_file(&span.file, |f| f.load_range(span.range).map(|s| s.to_owned()))
     }
 
+    fn word_at_position(
+        &self,
+        path: &Path,
+        pos: &span::Position<span::ZeroIndexed>,
+        encoding: PositionEncoding,
+    ) -> Option<(span::Span<span::ZeroIndexed>, String)> {
+        self.ensure_file(path, |f| {
+            let line = f.load_line(pos.row)?;
+            trace!("word_at_position: line: `{}`", line);
+
+            let col = char_column(line, pos.col, encoding);
+            let (start, end) = match find_word_at_pos(line, col, PositionEncoding::Char) {
+                // E.g., the position of the `'` of a lifetime.
+                (start, end) if start == end => {
+                    find_lifetime_at_pos(line, &col).unwrap_or((start, end))
+                }
+                range => range,
+            };
+            let word =
+                line.chars().skip(start.0 as usize).take((end.0 - start.0) as usize).collect();
+            let span = span::Span::from_positions(
+                span::Position::new(pos.row, start),
+                span::Position::new(pos.row, end),
+                path,
+            );
+            Ok((span, word))
+        })
+        .ok()
+    }
+
     fn for_each_line<F>(&self, path: &Path, f: F) -> Result<(), Error>
     where
         F: FnMut(&str, usize) -> Result<(), Error>,
@@ -590,6 +791,47 @@ impl<T: FileLoader, U> VfsInternal<T, U> {
     }
 }
 
+/// Modifies files of the VFS within a transaction, see `Vfs::transaction`.
+pub struct VfsWriter<'a, U> {
+    files: &'a mut HashMap<PathBuf, File<U>>,
+    // Reads files which are not yet cached, from the VFS's `FileLoader`.
+    read: fn(&Path) -> Result<File<U>, Error>,
+}
+
+impl<'a, U> VfsWriter<'a, U> {
+    /// Sets the content of a file, replacing any previous content.
+    pub fn open(&mut self, path: &Path, text: &str) {
+        let file = File::new(FileKind::Text(TextFile {
+            text: text.to_owned(),
+            line_indices: make_line_indices(text),
+            changed: true,
+        }));
+        self.files.insert(path.to_owned(), file);
+    }
+
+    /// Records a set of changes, like `Vfs::on_changes`.
+    pub fn change(&mut self, changes: &[Change]) -> Result<(), Error> {
+        trace!("VfsWriter::change: {:?}", changes);
+        for (file_name, changes) in coalesce_changes(changes) {
+            if let Some(file) = self.files.get_mut(file_name) {
+                file.make_change(&changes)?;
+                continue;
+            }
+
+            let mut file = (self.read)(file_name)?;
+            file.make_change(&changes)?;
+            self.files.insert(file_name.to_path_buf(), file);
+        }
+
+        Ok(())
+    }
+
+    /// Removes a file, like `Vfs::flush_file`.
+    pub fn close(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+}
+
 fn coalesce_changes<'a>(changes: &'a [Change]) -> HashMap<&'a Path, Vec<&'a Change>> {
     // Note that for any given file, we preserve the order of the changes.
     let mut result = HashMap::new();
@@ -610,6 +852,229 @@ fn make_line_indices(text: &str) -> Vec<u32> {
     result
 }
 
+/// Applies the hunks of a single-file unified diff to `text`. Any lines
+/// preceding the first hunk header or following a hunk (e.g. `---`/`+++` file
+/// headers) are ignored, but the diff mustn't describe another file. Unchanged
+/// lines keep their terminators, added ones get that of the first line.
+fn apply_diff_hunks(path: &Path, text: &str, diff: &str) -> Result<String, Error> {
+    let old_lines = lines_with_terminators(text);
+    let eol = match old_lines.first() {
+        Some((_, "\r\n")) => "\r\n",
+        _ => "\n",
+    };
+    let mut new_lines: Vec<(&str, &str)> = Vec::with_capacity(old_lines.len());
+    // Index of the first line in `old_lines` which has not yet been copied.
+    let mut cursor = 0;
+    let mut seen_hunk = false;
+
+    let mut diff_lines = diff.lines().peekable();
+    while let Some(header) = diff_lines.next() {
+        if !header.starts_with("@@") {
+            // A file header following a hunk starts the diff of another file.
+            if seen_hunk && header.starts_with("+++ ") {
+                return Err(Error::MalformedDiff);
+            }
+            continue;
+        }
+        let ((old_start, old_len), (_, new_len)) =
+            parse_hunk_header(header).ok_or(Error::MalformedDiff)?;
+        seen_hunk = true;
+        // Hunk positions are 1-based, unless the hunk removes no lines, in
+        // which case the position is that of the line after which to insert.
+        let start = if old_len == 0 { old_start } else { old_start.saturating_sub(1) };
+        if start < cursor || start > old_lines.len() {
+            return Err(Error::ContextMismatch(path.to_owned()));
+        }
+        new_lines.extend_from_slice(&old_lines[cursor..start]);
+        cursor = start;
+
+        // The number of lines of the hunk in the original and the new text.
+        let (mut old_seen, mut new_seen) = (0, 0);
+        while old_seen < old_len || new_seen < new_len {
+            let line = diff_lines.next().ok_or(Error::MalformedDiff)?;
+            // The hunk is shorter than its header claims and runs into the
+            // file header of the next diff.
+            if line.starts_with("--- ")
+                && diff_lines.peek().map_or(false, |l| l.starts_with("+++ "))
+            {
+                return Err(Error::MalformedDiff);
+            }
+            // Some tools strip the trailing whitespace of empty context lines.
+            let kind = line.chars().next().unwrap_or(' ');
+            let content = line.get(1..).unwrap_or("");
+            match kind {
+                ' ' | '-' => {
+                    match old_lines.get(cursor) {
+                        Some(&(old, _)) if old == content => {}
+                        _ => return Err(Error::ContextMismatch(path.to_owned())),
+                    }
+                    if kind == ' ' {
+                        new_lines.push(old_lines[cursor]);
+                        new_seen += 1;
+                    }
+                    old_seen += 1;
+                    cursor += 1;
+                }
+                '+' => {
+                    new_lines.push((content, eol));
+                    new_seen += 1;
+                }
+                _ => return Err(Error::MalformedDiff),
+            }
+            if old_seen > old_len || new_seen > new_len {
+                return Err(Error::MalformedDiff);
+            }
+
+            // `\ No newline at end of file` follows the last line of the
+            // original or new text (or of both, if it's unchanged).
+            if diff_lines.peek().map_or(false, |l| l.starts_with('\\')) {
+                diff_lines.next();
+                match kind {
+                    '+' => new_lines.last_mut().unwrap().1 = "",
+                    _ if cursor != old_lines.len() || !old_lines[cursor - 1].1.is_empty() => {
+                        return Err(Error::ContextMismatch(path.to_owned()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    new_lines.extend_from_slice(&old_lines[cursor..]);
+
+    let mut result = String::with_capacity(text.len());
+    for (line, eol) in new_lines {
+        result.push_str(line);
+        result.push_str(eol);
+    }
+    Ok(result)
+}
+
+/// Splits `text` into its lines, each with its terminator: `"\n"`, `"\r\n"`,
+/// or `""` for a last line without one.
+fn lines_with_terminators(text: &str) -> Vec<(&str, &str)> {
+    let mut lines = vec![];
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (line, eol, next) = match rest.find('\n') {
+            Some(i) if rest[..i].ends_with('\r') => (&rest[..i - 1], "\r\n", &rest[i + 1..]),
+            Some(i) => (&rest[..i], "\n", &rest[i + 1..]),
+            None => (rest, "", ""),
+        };
+        lines.push((line, eol));
+        rest = next;
+    }
+    lines
+}
+
+/// The number of unchanged lines around the changes in a hunk of a unified diff.
+#[cfg(feature = "diff")]
+const DIFF_CONTEXT_LINES: usize = 3;
+
+/// Returns the unified diff (as produced by `diff -u`) turning `old` into
+/// `new`, both named `name` in the header.
+#[cfg(feature = "diff")]
+fn unified_diff(name: &str, old: &str, new: &str) -> String {
+    let lines = diff::lines(old, new);
+    let is_removed = |line: &&diff::Result<&str>| match line {
+        diff::Result::Left(_) => true,
+        _ => false,
+    };
+    let is_added = |line: &&diff::Result<&str>| match line {
+        diff::Result::Right(_) => true,
+        _ => false,
+    };
+    let is_change = |line: &&diff::Result<&str>| is_removed(line) || is_added(line);
+
+    // The ranges of `lines` in a hunk, i.e. the changes and their context.
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for (i, _) in lines.iter().enumerate().filter(|(_, line)| is_change(line)) {
+        let start = i.saturating_sub(DIFF_CONTEXT_LINES);
+        let end = (i + 1 + DIFF_CONTEXT_LINES).min(lines.len());
+        match hunks.last_mut() {
+            Some(hunk) if hunk.1 >= start => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", name, name);
+    // The number of lines of `old` and `new` before the current hunk.
+    let (mut old_line, mut new_line) = (0, 0);
+    let mut prev_end = 0;
+    for (start, end) in hunks {
+        for line in &lines[prev_end..start] {
+            match line {
+                diff::Result::Left(_) => old_line += 1,
+                diff::Result::Right(_) => new_line += 1,
+                diff::Result::Both(..) => {
+                    old_line += 1;
+                    new_line += 1;
+                }
+            }
+        }
+        prev_end = end;
+
+        let hunk = &lines[start..end];
+        let old_len = hunk.iter().filter(|line| !is_added(line)).count();
+        let new_len = hunk.iter().filter(|line| !is_removed(line)).count();
+        // Empty ranges start at the line before them.
+        let range_start = |line: usize, len: usize| if len == 0 { line } else { line + 1 };
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            range_start(old_line, old_len),
+            old_len,
+            range_start(new_line, new_len),
+            new_len
+        ));
+        old_line += old_len;
+        new_line += new_len;
+
+        // Removed lines come before the added ones they're replaced with.
+        let mut added = vec![];
+        for line in hunk {
+            match line {
+                diff::Result::Left(text) => out.push_str(&format!("-{}\n", text)),
+                diff::Result::Right(text) => added.push(*text),
+                diff::Result::Both(text, _) => {
+                    added.drain(..).for_each(|text| out.push_str(&format!("+{}\n", text)));
+                    out.push_str(&format!(" {}\n", text));
+                }
+            }
+        }
+        added.drain(..).for_each(|text| out.push_str(&format!("+{}\n", text)));
+    }
+    out
+}
+
+/// Parses a `@@ -a,b +c,d @@` hunk header, returning the start and length of
+/// the original and of the new range. A length may be omitted, in which case
+/// it is 1.
+fn parse_hunk_header(header: &str) -> Option<((usize, usize), (usize, usize))> {
+    let parse_range = |range: &str| -> Option<(usize, usize)> {
+        let mut parts = range.splitn(2, ',');
+        let start = parts.next()?.parse().ok()?;
+        let len = match parts.next() {
+            Some(len) => len.parse().ok()?,
+            None => 1,
+        };
+        Some((start, len))
+    };
+
+    if !header.starts_with("@@ -") {
+        return None;
+    }
+    let mut ranges = header[4..].split(' ');
+    let old_range = parse_range(ranges.next()?)?;
+    let new_range = ranges.next().filter(|range| range.starts_with('+'))?;
+    Some((old_range, parse_range(&new_range[1..])?))
+}
+
+/// Hashes file content, as used by `Vfs::file_hash`.
+pub fn content_hash(content: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(content);
+    hasher.finish()
+}
+
 #[derive(Clone)]
 enum FileKind {
     Text(TextFile),
@@ -642,9 +1107,15 @@ struct TextFile {
 struct File<U> {
     kind: FileKind,
     user_data: Option<U>,
+    // When the content was last loaded or modified.
+    last_modified: Instant,
 }
 
 impl<U> File<U> {
+    fn new(kind: FileKind) -> File<U> {
+        File { kind, user_data: None, last_modified: Instant::now() }
+    }
+
     fn contents(&self) -> FileContents {
         match self.kind {
             FileKind::Text(ref t) => FileContents::Text(t.text.clone()),
@@ -656,6 +1127,7 @@ impl<U> File<U> {
         match self.kind {
             FileKind::Text(ref mut t) => {
                 self.user_data = None;
+                self.last_modified = Instant::now();
                 t.make_change(changes)
             }
             FileKind::Binary(_) => Err(Error::BadFileKind),
@@ -820,6 +1292,87 @@ impl TextFile {
     }
 }
 
+/// Represents a text cursor between characters, pointing at the next character
+/// in the buffer.
+type Column = span::Column<span::ZeroIndexed>;
+
+/// Returns a text cursor range for a found word inside `line` at which `pos`
+/// text cursor points to. Resulting type represents a (`start`, `end`) range
+/// between `start` and `end` cursors, with `pos` and the cursors counted in
+/// `encoding`.
+/// For example (4, 4) means an empty selection starting after first 4 characters.
+fn find_word_at_pos(line: &str, pos: Column, encoding: PositionEncoding) -> (Column, Column) {
+    let col = pos.0 as usize;
+    let is_ident_char = |c: char| c.is_alphanumeric() || c == '_';
+    let chars = char_offsets(line, encoding);
+
+    let start = chars
+        .iter()
+        .take_while(|&&(offset, _)| offset < col)
+        .filter(|&&(_, c)| !is_ident_char(c))
+        .last()
+        .map(|&(offset, c)| offset + encoding.char_len(c))
+        .unwrap_or(0) as u32;
+
+    let end = chars
+        .iter()
+        .skip_while(|&&(offset, _)| offset < col)
+        .find(|&&(_, c)| !is_ident_char(c))
+        .map(|&(offset, _)| offset)
+        .unwrap_or(col) as u32;
+
+    (span::Column::new_zero_indexed(start), span::Column::new_zero_indexed(end))
+}
+
+/// Returns the characters of `line` with their offsets in `encoding`.
+fn char_offsets(line: &str, encoding: PositionEncoding) -> Vec<(usize, char)> {
+    let mut offset = 0;
+    line.chars()
+        .map(|c| {
+            let char_offset = offset;
+            offset += encoding.char_len(c);
+            (char_offset, c)
+        })
+        .collect()
+}
+
+/// Converts `col`, counted in `encoding`, to a `char` offset in `line`. A
+/// column in the middle of a character is rounded down to its start.
+fn char_column(line: &str, col: Column, encoding: PositionEncoding) -> Column {
+    let col = col.0 as usize;
+    let chars = char_offsets(line, encoding);
+    let char_col = match chars.iter().position(|&(offset, c)| offset + encoding.char_len(c) > col) {
+        Some(char_col) => char_col,
+        // Past the end of the line.
+        None => {
+            chars.len() + col - chars.last().map_or(0, |&(offset, c)| offset + encoding.char_len(c))
+        }
+    };
+    span::Column::new_zero_indexed(char_col as u32)
+}
+
+/// Returns a text cursor range for a lifetime, e.g. `'a`, `'static` or `'_`,
+/// inside `line` at which `pos` points to, including the leading `'`. `pos`
+/// may also point to the `'` itself. Returns `None` if there is no lifetime at
+/// `pos`.
+fn find_lifetime_at_pos(line: &str, pos: &Column) -> Option<(Column, Column)> {
+    let chars: Vec<char> = line.chars().collect();
+    let col = pos.0 as usize;
+    let word_pos = if chars.get(col) == Some(&'\'') { col + 1 } else { col };
+
+    let (start, end) =
+        find_word_at_pos(line, Column::new_zero_indexed(word_pos as u32), PositionEncoding::Char);
+    if start == end || start.0 == 0 || chars[start.0 as usize - 1] != '\'' {
+        return None;
+    }
+    // A character literal, e.g. `'a'`.
+    if chars.get(end.0 as usize) == Some(&'\'') {
+        return None;
+    }
+
+    Some((span::Column::new_zero_indexed(start.0 - 1), end))
+}
+
 /// Return a UTF-8 byte offset in `s` for a given UTF-8 unicode scalar value offset.
 fn byte_in_str(s: &str, c: span::Column<span::ZeroIndexed>) -> Result<usize, Error> {
     // We simulate a null-terminated string here because spans are exclusive at
@@ -879,15 +1432,12 @@ impl FileLoader for RealFileLoader {
         }
 
         match String::from_utf8(buf) {
-            Ok(s) => Ok(File {
-                kind: FileKind::Text(TextFile {
-                    line_indices: make_line_indices(&s),
-                    text: s,
-                    changed: false,
-                }),
-                user_data: None,
-            }),
-            Err(e) => Ok(File { kind: FileKind::Binary(e.into_bytes()), user_data: None }),
+            Ok(s) => Ok(File::new(FileKind::Text(TextFile {
+                line_indices: make_line_indices(&s),
+                text: s,
+                changed: false,
+            }))),
+            Err(e) => Ok(File::new(FileKind::Binary(e.into_bytes()))),
         }
     }
 
@@ -913,6 +1463,7 @@ impl FileLoader for RealFileLoader {
 
 #[cfg(test)]
 mod tests {
+    use super::PositionEncoding;
     use span::Column;
 
     #[test]
@@ -927,4 +1478,106 @@ mod tests {
         // 😢 is represented by 2 u16s - we can't index in the middle of a character
         assert!(byte_in_str_utf16("😢", Column::new_zero_indexed(1)).is_err());
     }
+
+    #[test]
+    fn find_word_at_pos() {
+        fn encoded_len(s: &str, encoding: PositionEncoding) -> u32 {
+            s.chars().map(|c| encoding.char_len(c) as u32).sum()
+        }
+
+        fn assert_encoded_range(
+            test_str: &'static str,
+            encoding: PositionEncoding,
+            range: (u32, u32),
+        ) {
+            assert!(test_str.chars().filter(|c| *c == '|').count() == 1);
+            let col = encoded_len(&test_str[..test_str.find('|').unwrap()], encoding);
+            let line = test_str.replace('|', "");
+            let (start, end) =
+                super::find_word_at_pos(&line, Column::new_zero_indexed(col), encoding);
+            let actual = (start.0, end.0);
+            assert_eq!(range, actual, "Assertion failed for {:?} ({:?})", test_str, encoding);
+        }
+
+        fn assert_range(test_str: &'static str, range: (u32, u32)) {
+            assert_encoded_range(test_str, PositionEncoding::Char, range);
+        }
+
+        assert_range("|struct Def {", (0, 6));
+        assert_range("stru|ct Def {", (0, 6));
+        assert_range("struct| Def {", (0, 6));
+
+        assert_range("struct |Def {", (7, 10));
+        assert_range("struct De|f {", (7, 10));
+        assert_range("struct Def| {", (7, 10));
+
+        assert_range("struct Def |{", (11, 11));
+
+        assert_range("|span::Position<T>", (0, 4));
+        assert_range(" |span::Position<T>", (1, 5));
+        assert_range("sp|an::Position<T>", (0, 4));
+        assert_range("span|::Position<T>", (0, 4));
+        assert_range("span::|Position<T>", (6, 14));
+        assert_range("span::Position|<T>", (6, 14));
+        assert_range("span::Position<|T>", (15, 16));
+        assert_range("span::Position<T|>", (15, 16));
+
+        // The word `x` after an emoji (4 bytes, 2 UTF-16 code units).
+        let emoji = "// 😢 let |x = 1;";
+        assert_encoded_range(emoji, PositionEncoding::Utf8, (12, 13));
+        assert_encoded_range(emoji, PositionEncoding::Utf16, (10, 11));
+        assert_encoded_range(emoji, PositionEncoding::Char, (9, 10));
+
+        // CJK characters (3 bytes, 1 UTF-16 code unit) are alphanumeric.
+        let cjk = "let 名前| = 1;";
+        assert_encoded_range(cjk, PositionEncoding::Utf8, (4, 10));
+        assert_encoded_range(cjk, PositionEncoding::Utf16, (4, 6));
+        assert_encoded_range(cjk, PositionEncoding::Char, (4, 6));
+        let cjk = "// 日本語 let |x;";
+        assert_encoded_range(cjk, PositionEncoding::Utf8, (17, 18));
+        assert_encoded_range(cjk, PositionEncoding::Utf16, (11, 12));
+
+        // A combining acute accent (2 bytes, 1 UTF-16 code unit) isn't.
+        let diacritic = "// cafe\u{301} |let x";
+        assert_encoded_range(diacritic, PositionEncoding::Utf8, (10, 13));
+        assert_encoded_range(diacritic, PositionEncoding::Utf16, (9, 12));
+        assert_encoded_range(diacritic, PositionEncoding::Char, (9, 12));
+    }
+
+    #[test]
+    fn char_column() {
+        let col = |line, col, encoding| {
+            super::char_column(line, Column::new_zero_indexed(col), encoding).0
+        };
+
+        assert_eq!(col("a😢b", 3, PositionEncoding::Utf16), 2);
+        assert_eq!(col("a😢b", 5, PositionEncoding::Utf8), 2);
+        // In the middle of the emoji.
+        assert_eq!(col("a😢b", 2, PositionEncoding::Utf16), 1);
+        assert_eq!(col("a😢b", 3, PositionEncoding::Char), 3);
+        // Past the end of the line.
+        assert_eq!(col("a😢b", 6, PositionEncoding::Utf16), 5);
+    }
+
+    #[test]
+    fn find_lifetime_at_pos() {
+        fn lifetime_range(test_str: &'static str) -> Option<(u32, u32)> {
+            let col = test_str.find('|').unwrap() as u32;
+            let line = test_str.replace('|', "");
+            super::find_lifetime_at_pos(&line, &Column::new_zero_indexed(col))
+                .map(|(start, end)| (start.0, end.0))
+        }
+
+        assert_eq!(lifetime_range("fn foo<|'a>(x: &'a str)"), Some((7, 9)));
+        assert_eq!(lifetime_range("fn foo<'|a>(x: &'a str)"), Some((7, 9)));
+        assert_eq!(lifetime_range("fn foo<'a>(x: &'a| str)"), Some((15, 17)));
+        assert_eq!(lifetime_range("const S: &|'static str"), Some((10, 17)));
+        assert_eq!(lifetime_range("const S: &'sta|tic str"), Some((10, 17)));
+        assert_eq!(lifetime_range("impl Foo<|'_> {"), Some((9, 11)));
+        assert_eq!(lifetime_range("impl Foo<'|_> {"), Some((9, 11)));
+
+        assert_eq!(lifetime_range("fn |foo<'a>()"), None);
+        assert_eq!(lifetime_range("let c = |'a';"), None);
+        assert_eq!(lifetime_range("let c = |' ';"), None);
+    }
 }
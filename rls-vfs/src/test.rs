@@ -1,10 +1,11 @@
 use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use span::{self, Column, Position, Row};
 
 use super::{
-    make_line_indices, Change, Error, File, FileContents, FileKind, FileLoader, TextFile,
-    VfsInternal, VfsSpan,
+    content_hash, make_line_indices, Change, Error, File, FileContents, FileKind, FileLoader,
+    PositionEncoding, TextFile, VfsInternal, VfsSpan,
 };
 
 type Span = span::Span<span::ZeroIndexed>;
@@ -15,7 +16,7 @@ impl FileLoader for MockFileLoader {
     fn read<U>(file_name: &Path) -> Result<File<U>, Error> {
         let text = format!("{}\nHello\nWorld\nHello, World!\n", file_name.display());
         let text_file = TextFile { line_indices: make_line_indices(&text), text, changed: false };
-        Ok(File { kind: FileKind::Text(text_file), user_data: None })
+        Ok(File::new(FileKind::Text(text_file)))
     }
 
     fn write(file_name: &Path, file: &FileKind) -> Result<(), Error> {
@@ -354,3 +355,273 @@ fn test_wide_utf16() {
 
     assert_eq!(vfs.load_file(&Path::new("foo")).unwrap(), FileContents::Text("".to_owned()),);
 }
+
+#[test]
+fn test_word_at_position() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let pos = Position::new(Row::new_zero_indexed(3), Column::new_zero_indexed(9));
+
+    let (span, word) =
+        vfs.word_at_position(Path::new("foo"), &pos, PositionEncoding::Utf16).unwrap();
+    assert_eq!(word, "World");
+    assert_eq!(span.range.col_start, Column::new_zero_indexed(7));
+    assert_eq!(span.range.col_end, Column::new_zero_indexed(12));
+    assert_eq!(vfs.load_span(span).unwrap(), word);
+
+    let out_of_bounds = Position::new(Row::new_zero_indexed(10), Column::new_zero_indexed(0));
+    assert!(vfs
+        .word_at_position(Path::new("foo"), &out_of_bounds, PositionEncoding::Char)
+        .is_none());
+}
+
+#[test]
+fn test_file_hash() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.file_hash(Path::new("foo")), None);
+
+    vfs.load_file(&Path::new("foo")).unwrap();
+    let hash = vfs.file_hash(Path::new("foo")).unwrap();
+    assert_eq!(hash, content_hash(b"foo\nHello\nWorld\nHello, World!\n"));
+
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    assert_ne!(vfs.file_hash(Path::new("foo")), Some(hash));
+}
+
+#[test]
+fn test_content_bytes_if_modified() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let path = Path::new("foo");
+    assert_eq!(vfs.content_bytes_if_modified(path, Instant::now()), None);
+
+    vfs.set_file(path, "foo\nHello\n");
+    let first_write = Instant::now();
+    assert_eq!(vfs.content_bytes_if_modified(path, first_write), None);
+
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    assert_eq!(
+        vfs.content_bytes_if_modified(path, first_write).as_deref(),
+        Some(&b"foo\nHfooo\n"[..])
+    );
+    assert_eq!(vfs.content_bytes_if_modified(path, Instant::now()), None);
+}
+
+#[test]
+fn test_apply_unified_diff() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let diff = "--- a/foo
++++ b/foo
+@@ -1,3 +1,3 @@
+ foo
+-Hello
++Goodbye
+ World
+@@ -4,0 +5,1 @@
++Bye, World!
+";
+    vfs.apply_unified_diff(Path::new("foo"), diff).unwrap();
+
+    let files = vfs.get_changes();
+    assert_eq!(files[Path::new("foo")], "foo\nGoodbye\nWorld\nHello, World!\nBye, World!\n");
+}
+
+#[test]
+fn test_apply_unified_diff_context_mismatch() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let diff = "@@ -2,2 +2,2 @@
+-Goodbye
++Hello
+ World
+";
+    match vfs.apply_unified_diff(Path::new("foo"), diff) {
+        Err(Error::ContextMismatch(path)) => assert_eq!(path, Path::new("foo")),
+        _ => panic!("expected a context mismatch"),
+    }
+    // The file is left untouched.
+    assert!(vfs.get_changes().is_empty());
+}
+
+#[test]
+fn test_apply_unified_diff_crlf() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let path = Path::new("foo");
+    vfs.set_file(path, "foo\r\nHello\r\nWorld\r\n");
+    let diff = "@@ -2,2 +2,3 @@\n-Hello\n+Goodbye\n World\n+Bye\n";
+    vfs.apply_unified_diff(path, diff).unwrap();
+
+    let files = vfs.get_cached_files();
+    assert_eq!(files[path], "foo\r\nGoodbye\r\nWorld\r\nBye\r\n");
+}
+
+#[test]
+fn test_apply_unified_diff_trailing_newline() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let path = Path::new("foo");
+    let diff = "@@ -4 +4 @@
+-Hello, World!
++Hello, World!
+\\ No newline at end of file
+";
+    vfs.apply_unified_diff(path, diff).unwrap();
+    assert_eq!(vfs.get_changes()[path], "foo\nHello\nWorld\nHello, World!");
+
+    let diff = "@@ -4 +4 @@
+-Hello, World!
+\\ No newline at end of file
++Goodbye, World!
+";
+    vfs.apply_unified_diff(path, diff).unwrap();
+    assert_eq!(vfs.get_changes()[path], "foo\nHello\nWorld\nGoodbye, World!\n");
+}
+
+#[test]
+fn test_apply_unified_diff_wrong_hunk_length() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let path = Path::new("foo");
+    // The hunk claims one more line than it has, so it runs into the file
+    // header of the next diff, which isn't the removal of a `-- a/foo` line.
+    let diff = "@@ -1,3 +1,2 @@
+ foo
+-Hello
+--- a/foo
++++ b/foo
+";
+    match vfs.apply_unified_diff(path, diff) {
+        Err(Error::MalformedDiff) => {}
+        _ => panic!("expected a malformed diff"),
+    }
+
+    let diff = "@@ -1,3 +1,3 @@
+ foo
+-Hello
++Goodbye
+";
+    match vfs.apply_unified_diff(path, diff) {
+        Err(Error::MalformedDiff) => {}
+        _ => panic!("expected a malformed diff"),
+    }
+    assert!(vfs.get_changes().is_empty());
+}
+
+#[test]
+fn test_transaction_is_atomic() {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let vfs = Arc::new(VfsInternal::<MockFileLoader, ()>::new());
+    let (a, b) = (Path::new("a"), Path::new("b"));
+
+    let writers: Vec<_> = (0..2)
+        .map(|i| {
+            let vfs = Arc::clone(&vfs);
+            thread::spawn(move || {
+                for j in 0..10 {
+                    let text = format!("{}-{}", i, j);
+                    vfs.transaction(|writer| {
+                        writer.open(a, &text);
+                        thread::sleep(Duration::from_millis(1));
+                        writer.open(b, &text);
+                    });
+                }
+            })
+        })
+        .collect();
+
+    // The writers hold the other references until they are done.
+    while Arc::strong_count(&vfs) > 1 {
+        let files = vfs.get_cached_files();
+        assert_eq!(files.get(a), files.get(b));
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let files = vfs.get_cached_files();
+    assert_eq!(files.get(a), files.get(b));
+    assert!(files.get(a).is_some());
+}
+
+#[test]
+fn test_transaction_change_and_close() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+
+    vfs.transaction(|writer| {
+        writer.change(&[make_change(false)]).unwrap();
+        writer.open(Path::new("bar"), "bar");
+        writer.close(Path::new("bar"));
+    });
+
+    let files = vfs.get_cached_files();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[Path::new("foo")], "foo\nHfooo\nWorld\nHello, World!\n");
+}
+
+#[cfg(feature = "diff")]
+#[test]
+fn test_diff_against_disk() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.diff_against_disk(Path::new("foo")), None);
+
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    let diff = vfs.diff_against_disk(Path::new("foo")).unwrap();
+    assert!(diff.starts_with("--- foo\n+++ foo\n"), "{}", diff);
+    assert!(diff.contains("@@ -1,4 +1,4 @@\n foo\n-Hello\n+Hfooo\n World\n"), "{}", diff);
+
+    vfs.set_file(Path::new("foo"), "foo\nHello\nWorld\nHello, World!\n");
+    assert_eq!(vfs.diff_against_disk(Path::new("foo")), None);
+}
+
+#[cfg(feature = "watch")]
+#[test]
+fn test_watch_file() {
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    let path = std::env::temp_dir().join(format!("rls-vfs-watch-{}.rs", std::process::id()));
+    std::fs::write(&path, "fn foo() {}\n").unwrap();
+
+    let vfs = super::Vfs::<()>::new();
+    let (sender, receiver) = channel();
+    vfs.watch_file(&path, Box::new(move || sender.send(()).unwrap())).unwrap();
+
+    std::fs::write(&path, "fn bar() {}\n").unwrap();
+    let changed = receiver.recv_timeout(Duration::from_secs(10));
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(changed, Ok(()));
+}
+
+#[cfg(feature = "watch")]
+#[test]
+fn test_watch_file_from_callback() {
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let path = std::env::temp_dir().join(format!("rls-vfs-rewatch-{}.rs", std::process::id()));
+    std::fs::write(&path, "fn foo() {}\n").unwrap();
+
+    let vfs = Arc::new(super::Vfs::<()>::new());
+    let (sender, receiver) = channel();
+    let (weak_vfs, watched) = (Arc::downgrade(&vfs), path.clone());
+    let callback = move || {
+        let second = sender.clone();
+        let vfs = weak_vfs.upgrade().unwrap();
+        vfs.watch_file(&watched, Box::new(move || second.send("second").unwrap())).unwrap();
+        sender.send("first").unwrap();
+    };
+    vfs.watch_file(&path, Box::new(callback)).unwrap();
+
+    std::fs::write(&path, "fn bar() {}\n\n").unwrap();
+    let first = receiver.recv_timeout(Duration::from_secs(10));
+    std::fs::write(&path, "fn baz() {}\n\n\n").unwrap();
+    let changed = vec![
+        first,
+        receiver.recv_timeout(Duration::from_secs(10)),
+        receiver.recv_timeout(Duration::from_secs(10)),
+    ];
+    std::fs::remove_file(&path).unwrap();
+
+    // The callback is called on the first change, and on the second one along
+    // with the callback it registered.
+    assert_eq!(changed, vec![Ok("first"), Ok("first"), Ok("second")]);
+}
@@ -0,0 +1,87 @@
+//! Watching files on disk for changes, see `Vfs::watch_file`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::Error;
+
+pub(crate) type Callback = Box<dyn Fn() + Send + 'static>;
+
+/// How often the watched files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The state of a watched file on disk, `None` if it doesn't exist.
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Calls the callbacks registered for a file when it changes on disk, judging
+/// by its modification time and size. This polls the files rather than using
+/// inotify or kqueue, since it mustn't pull in any dependencies. The thread
+/// polling the files is only started once the first file is watched, and stops
+/// once the watcher is dropped.
+#[derive(Default)]
+pub(crate) struct FileWatcher {
+    files: Arc<Mutex<HashMap<PathBuf, (Stamp, Vec<Callback>)>>>,
+    polling: bool,
+}
+
+impl FileWatcher {
+    pub(crate) fn watch(&mut self, path: &Path, callback: Callback) -> Result<(), Error> {
+        let io_error = |e: std::io::Error| Error::Io(Some(path.to_owned()), Some(e.to_string()));
+        // Fails for files which don't exist.
+        fs::metadata(path).and_then(|m| m.modified()).map_err(io_error)?;
+
+        if !self.polling {
+            self.spawn().map_err(io_error)?;
+            self.polling = true;
+        }
+        let mut files = self.files.lock().unwrap();
+        files.entry(path.to_owned()).or_insert_with(|| (stamp(path), vec![])).1.push(callback);
+        Ok(())
+    }
+
+    fn spawn(&self) -> std::io::Result<()> {
+        let files: Weak<_> = Arc::downgrade(&self.files);
+        thread::Builder::new().name("vfs-watcher".to_owned()).spawn(move || {
+            while let Some(watched) = files.upgrade() {
+                // The callbacks are called without holding the lock, so that
+                // they can watch files themselves.
+                let changed: Vec<_> = watched
+                    .lock()
+                    .unwrap()
+                    .iter_mut()
+                    .filter_map(|(path, (old_stamp, callbacks))| {
+                        let new_stamp = stamp(path);
+                        if new_stamp == *old_stamp {
+                            return None;
+                        }
+                        trace!("watched file changed: {}", path.display());
+                        *old_stamp = new_stamp;
+                        Some((path.clone(), mem::take(callbacks)))
+                    })
+                    .collect();
+
+                for (path, mut callbacks) in changed {
+                    callbacks.iter().for_each(|callback| callback());
+                    let mut files = watched.lock().unwrap();
+                    let registered = &mut files.get_mut(&path).unwrap().1;
+                    // Keep the callbacks registered by the callbacks we called.
+                    callbacks.append(registered);
+                    *registered = callbacks;
+                }
+                drop(watched);
+                thread::sleep(POLL_INTERVAL);
+            }
+        })?;
+        Ok(())
+    }
+}
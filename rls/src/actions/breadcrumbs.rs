@@ -0,0 +1,269 @@
+//! Breadcrumbs, i.e., the chain of items enclosing a position in a file.
+//!
+//! Save-analysis only records the span of an item's name, so the extent of an
+//! item is recovered from the source text by matching the braces of its body.
+//! Impl blocks aren't recorded as definitions at all and are found in the
+//! source text.
+
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use log::error;
+use regex::Regex;
+use rls_analysis::DefKind;
+use rls_vfs::FileContents;
+
+use crate::actions::run::LineIndex;
+use crate::actions::InitActionContext;
+use crate::lsp_data::{
+    ls_util, source_kind_from_def_kind, BreadcrumbItem, Position, Range, SymbolKind,
+};
+
+/// Returns the items enclosing `pos` in `file`, from the file itself to the
+/// innermost item.
+pub fn collect_breadcrumbs(
+    ctx: &InitActionContext,
+    file: &Path,
+    pos: Position,
+) -> Vec<BreadcrumbItem> {
+    let text = match ctx.vfs.load_file(file) {
+        Ok(FileContents::Text(text)) => text,
+        Ok(FileContents::Binary(_)) => return vec![],
+        Err(e) => {
+            error!("failed to collect breadcrumbs: {}", e);
+            return vec![];
+        }
+    };
+
+    let defs = ctx.analysis.symbols(file).unwrap_or_else(|_| vec![]);
+    let defs = defs
+        .into_iter()
+        .filter(|def| is_item(def.kind))
+        .map(|def| {
+            (
+                def.name,
+                source_kind_from_def_kind(def.kind),
+                ls_util::rls_to_range(def.span.range).start,
+            )
+        })
+        .collect();
+    let file_name = file.file_name().map(|name| name.to_string_lossy().into_owned());
+
+    enclosing_items(&text, file_name.unwrap_or_default(), defs, pos)
+}
+
+fn is_item(kind: DefKind) -> bool {
+    match kind {
+        DefKind::Mod
+        | DefKind::Struct
+        | DefKind::Enum
+        | DefKind::Union
+        | DefKind::Trait
+        | DefKind::Function
+        | DefKind::Method => true,
+        _ => false,
+    }
+}
+
+/// Returns the items enclosing `pos`, sorted from the outermost to the
+/// innermost one. `defs` are the names, kinds and name positions of the items
+/// defined in `text`.
+fn enclosing_items(
+    text: &str,
+    file_name: String,
+    defs: Vec<(String, SymbolKind, Position)>,
+    pos: Position,
+) -> Vec<BreadcrumbItem> {
+    lazy_static! {
+        static ref IMPL_RE: Regex =
+            Regex::new(r"(?m)^[ \t]*(?:(?:pub(?:\([^)]*\))?|default|unsafe)\s+)*impl\b").unwrap();
+    }
+
+    let lines = LineIndex::new(text);
+    let offset = match lines.lsp_position_to_offset(pos) {
+        Some(offset) => offset,
+        None => return vec![],
+    };
+
+    let mut items = vec![];
+    for (name, kind, name_pos) in defs {
+        let start = match lines.lsp_position_to_offset(name_pos) {
+            // The root module of a file is not spelled out at its position.
+            Some(start) if text[start..].starts_with(name.as_str()) => start,
+            _ => continue,
+        };
+        if let Some(end) = item_end(text, start) {
+            items.push((start, end, name, kind));
+        }
+    }
+    for impl_match in IMPL_RE.find_iter(text) {
+        let start = impl_match.end() - "impl".len();
+        if let Some(end) = item_end(text, start) {
+            let header = text[start..end].split('{').next().unwrap_or_default();
+            let name = header.split_whitespace().collect::<Vec<_>>().join(" ");
+            items.push((start, end, name, SymbolKind::Object));
+        }
+    }
+
+    items.retain(|&(start, end, ..)| start <= offset && offset < end);
+    // Outer items start earlier, or at the same position but end later.
+    items.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+    let file_range =
+        Range { start: Position::new(0, 0), end: lines.offset_to_lsp_position(text.len()) };
+    let file = BreadcrumbItem { name: file_name, kind: SymbolKind::File, range: file_range };
+    let items = items.into_iter().map(|(start, end, name, kind)| BreadcrumbItem {
+        name,
+        kind,
+        range: Range {
+            start: lines.offset_to_lsp_position(start),
+            end: lines.offset_to_lsp_position(end),
+        },
+    });
+    std::iter::once(file).chain(items).collect()
+}
+
+/// Returns the byte offset just past the end of the item starting at `start`,
+/// i.e., past its body's closing brace. Returns `None` for items without a
+/// body, e.g. `mod foo;` or `fn foo();`.
+pub(crate) fn item_end(text: &str, start: usize) -> Option<usize> {
+    let (mut parens, mut braces) = (0usize, 0usize);
+
+    for (i, c) in find_chars(&text[start..], &['(', '[', ')', ']', ';', '{', '}']) {
+        match c {
+            '(' | '[' => parens += 1,
+            ')' | ']' => parens = parens.saturating_sub(1),
+            ';' if parens == 0 && braces == 0 => return None,
+            '{' => braces += 1,
+            '}' => {
+                braces = braces.checked_sub(1)?;
+                if braces == 0 {
+                    return Some(start + i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns the offsets of the occurrences of `wanted` in `text`, skipping the
+/// ones in comments, strings and character literals.
+pub(crate) fn find_chars(text: &str, wanted: &[char]) -> Vec<(usize, char)> {
+    let mut found = vec![];
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '/' if chars.peek().map(|&(_, c)| c) == Some('/') => {
+                chars.find(|&(_, c)| c == '\n');
+            }
+            '/' if chars.peek().map(|&(_, c)| c) == Some('*') => {
+                chars.next();
+                let mut prev = ' ';
+                chars.find(|&(_, c)| {
+                    let end = prev == '*' && c == '/';
+                    prev = c;
+                    end
+                });
+            }
+            '"' => {
+                let mut escaped = false;
+                chars.find(|&(_, c)| {
+                    let end = !escaped && c == '"';
+                    escaped = !escaped && c == '\\';
+                    end
+                });
+            }
+            // Character literals, as opposed to lifetimes.
+            '\'' => {
+                let rest = &text[i + 1..];
+                let literal_len = match rest.chars().next() {
+                    Some('\\') => rest.get(2..).and_then(|s| s.find('\'')).map(|end| end + 3),
+                    Some(c) if rest[c.len_utf8()..].starts_with('\'') => Some(c.len_utf8() + 1),
+                    _ => None,
+                };
+                if let Some(len) = literal_len {
+                    while chars.peek().map_or(false, |&(j, _)| j <= i + len) {
+                        chars.next();
+                    }
+                }
+            }
+            c if wanted.contains(&c) => found.push((i, c)),
+            _ => {}
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "\
+use std::fmt;
+
+mod shapes {
+    pub struct Circle(f64);
+
+    impl Circle {
+        // A `}` in a comment.
+        pub fn area(&self) -> f64 {
+            let brace = '}';
+            self.0 * self.0 * 3.14
+        }
+    }
+}
+
+fn main() {}
+";
+
+    fn defs() -> Vec<(String, SymbolKind, Position)> {
+        vec![
+            ("shapes".to_owned(), SymbolKind::Module, Position::new(2, 4)),
+            ("Circle".to_owned(), SymbolKind::Struct, Position::new(3, 15)),
+            ("area".to_owned(), SymbolKind::Method, Position::new(7, 15)),
+            ("main".to_owned(), SymbolKind::Function, Position::new(14, 3)),
+        ]
+    }
+
+    #[test]
+    fn breadcrumbs_in_nested_method() {
+        let items = enclosing_items(TEXT, "lib.rs".to_owned(), defs(), Position::new(9, 12));
+        let names: Vec<_> = items.iter().map(|item| (item.name.as_str(), item.kind)).collect();
+        assert_eq!(
+            names,
+            vec![
+                ("lib.rs", SymbolKind::File),
+                ("shapes", SymbolKind::Module),
+                ("impl Circle", SymbolKind::Object),
+                ("area", SymbolKind::Method),
+            ]
+        );
+        assert_eq!(items[0].range.end, Position::new(15, 0));
+        assert_eq!(items[2].range.start, Position::new(5, 4));
+        assert_eq!(items[2].range.end, Position::new(11, 5));
+        assert_eq!(items[3].range.start, Position::new(7, 15));
+        assert_eq!(items[3].range.end, Position::new(10, 9));
+    }
+
+    #[test]
+    fn breadcrumbs_outside_items() {
+        let items = enclosing_items(TEXT, "lib.rs".to_owned(), defs(), Position::new(0, 5));
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].kind, SymbolKind::File);
+
+        // `Circle` has no body.
+        let items = enclosing_items(TEXT, "lib.rs".to_owned(), defs(), Position::new(3, 20));
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].name, "shapes");
+    }
+
+    #[test]
+    fn find_chars_skips_comments_strings_and_char_literals() {
+        let text = "f('\"', '{', '\\'', \"(\\\"\"); // (\n/* ) */ g::<'a>(x)";
+        let found: Vec<_> =
+            find_chars(text, &['(', ')', '{']).into_iter().map(|(_, c)| c).collect();
+        assert_eq!(found, vec!['(', ')', '(', ')']);
+    }
+}
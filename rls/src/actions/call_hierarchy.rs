@@ -0,0 +1,228 @@
+//! Call hierarchies, see `CallHierarchyPrepare`.
+//!
+//! Save-analysis records the references to a function, but not the functions
+//! they're made from. The caller of a reference is the innermost function whose
+//! definition encloses it, whose extent is recovered from the source text by
+//! matching the braces of its body (as for `breadcrumbs`). The calls made by a
+//! function are found in the source text of its body (as for `inlay_hints`).
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use log::error;
+use rls_analysis::{DefKind, Id};
+use rls_vfs::{FileContents, PositionEncoding};
+use url::Url;
+
+use crate::actions::breadcrumbs::item_end;
+use crate::actions::inlay_hints::calls;
+use crate::actions::run::LineIndex;
+use crate::actions::InitActionContext;
+use crate::lsp_data::{
+    ls_util, parse_file_path, source_kind_from_def_kind, CallHierarchyIncomingCall,
+    CallHierarchyItem, CallHierarchyOutgoingCall, Position, Range,
+};
+use crate::Span;
+
+/// A function defined in a file.
+struct Function {
+    id: Id,
+    item: CallHierarchyItem,
+}
+
+/// Returns the call hierarchy item of the function named at `pos` in `file`,
+/// or else of the innermost function enclosing `pos`.
+pub fn prepare_call_hierarchy(
+    ctx: &InitActionContext,
+    file: &Path,
+    pos: Position,
+) -> Option<CallHierarchyItem> {
+    let word =
+        ctx.vfs.word_at_position(file, &ls_util::position_to_rls(pos), PositionEncoding::Utf16);
+    if let Some(function) = word.and_then(|(span, _)| function(ctx, ctx.analysis.id(&span).ok()?)) {
+        return Some(function.item);
+    }
+
+    functions(ctx, file)
+        .into_iter()
+        .filter(|function| function.item.range.start <= pos && pos <= function.item.range.end)
+        .max_by_key(|function| function.item.range.start)
+        .map(|function| function.item)
+}
+
+/// Returns the functions referring to the function of `item`, i.e., calling it
+/// (in any crate of the analysis data), grouped by caller.
+pub fn incoming_calls(
+    ctx: &InitActionContext,
+    item: &CallHierarchyItem,
+) -> Option<Vec<CallHierarchyIncomingCall>> {
+    let id = item_id(ctx, item)?;
+    let def_span = ctx.analysis.get_def(id).ok()?.span;
+    let refs = ctx.analysis.find_all_refs_by_id(id).ok()?;
+
+    let mut functions_per_file: HashMap<PathBuf, Vec<Function>> = HashMap::new();
+    let mut calls: Vec<(Id, CallHierarchyIncomingCall)> = vec![];
+    for span in refs.into_iter().filter(|span| *span != def_span) {
+        let file_functions = functions_per_file
+            .entry(span.file.clone())
+            .or_insert_with(|| functions(ctx, &span.file));
+        let range = ls_util::rls_to_range(span.range);
+        let caller = file_functions
+            .iter()
+            .filter(|function| {
+                function.item.range.start <= range.start && range.end <= function.item.range.end
+            })
+            .max_by_key(|function| function.item.range.start);
+        let caller = match caller {
+            Some(caller) => caller,
+            // E.g. in the initializer of a constant.
+            None => continue,
+        };
+        match calls.iter_mut().find(|(id, _)| *id == caller.id) {
+            Some((_, call)) => call.from_ranges.push(range),
+            None => calls.push((
+                caller.id,
+                CallHierarchyIncomingCall { from: caller.item.clone(), from_ranges: vec![range] },
+            )),
+        }
+    }
+    Some(calls.into_iter().map(|(_, call)| call).collect())
+}
+
+/// Returns the functions called in the body of the function of `item`,
+/// grouped by callee.
+pub fn outgoing_calls(
+    ctx: &InitActionContext,
+    item: &CallHierarchyItem,
+) -> Option<Vec<CallHierarchyOutgoingCall>> {
+    item_id(ctx, item)?;
+    let file = parse_file_path(&item.uri).ok()?;
+    let text = load_text(ctx, &file)?;
+
+    let mut callees: Vec<(Id, Vec<Range>)> = vec![];
+    for call in calls(&text, item.range) {
+        let span = Span::from_range(ls_util::range_to_rls(call.callee), file.clone());
+        let id = match ctx.analysis.id(&span) {
+            Ok(id) => id,
+            Err(_) => continue,
+        };
+        match callees.iter_mut().find(|(callee, _)| *callee == id) {
+            Some((_, ranges)) => ranges.push(call.callee),
+            None => callees.push((id, vec![call.callee])),
+        }
+    }
+    Some(
+        callees
+            .into_iter()
+            .filter_map(|(id, from_ranges)| {
+                let to = function(ctx, id)?.item;
+                Some(CallHierarchyOutgoingCall { to, from_ranges })
+            })
+            .collect(),
+    )
+}
+
+/// Finds the definition of a call hierarchy item, which was previously
+/// returned by one of the call hierarchy requests.
+fn item_id(ctx: &InitActionContext, item: &CallHierarchyItem) -> Option<Id> {
+    let file = parse_file_path(&item.uri).ok()?;
+    let span = Span::from_range(ls_util::range_to_rls(item.selection_range), file);
+    ctx.analysis.id(&span).ok()
+}
+
+/// Returns the function defined as `id`, if any.
+fn function(ctx: &InitActionContext, id: Id) -> Option<Function> {
+    let def = ctx.analysis.get_def(id).ok()?;
+    if !is_function(def.kind) {
+        return None;
+    }
+    functions(ctx, &def.span.file).into_iter().find(|function| function.id == id)
+}
+
+fn is_function(kind: DefKind) -> bool {
+    kind == DefKind::Function || kind == DefKind::Method
+}
+
+/// Returns the functions (with a body) defined in `file`.
+fn functions(ctx: &InitActionContext, file: &Path) -> Vec<Function> {
+    let text = match load_text(ctx, file) {
+        Some(text) => text,
+        None => return vec![],
+    };
+    let uri = match Url::from_file_path(file) {
+        Ok(uri) => uri,
+        Err(_) => return vec![],
+    };
+
+    let defs = ctx.analysis.symbols(file).unwrap_or_else(|_| vec![]);
+    let defs: Vec<_> = defs.into_iter().filter(|def| is_function(def.kind)).collect();
+    let names = defs.iter().map(|def| ls_util::rls_to_range(def.span.range)).collect();
+    defs.iter()
+        .zip(function_extents(&text, names))
+        .filter_map(|(def, extent)| {
+            let def_details = ctx.analysis.get_def(def.id).ok()?;
+            Some(Function {
+                id: def.id,
+                item: CallHierarchyItem {
+                    name: def.name.clone(),
+                    kind: source_kind_from_def_kind(def.kind),
+                    detail: Some(def_details.qualname),
+                    uri: uri.clone(),
+                    range: extent?,
+                    selection_range: ls_util::rls_to_range(def.span.range),
+                },
+            })
+        })
+        .collect()
+}
+
+/// Returns the extents of the functions named at `names` in `text`, i.e., from
+/// their names to the end of their bodies, or `None` for functions without a
+/// body.
+fn function_extents(text: &str, names: Vec<Range>) -> Vec<Option<Range>> {
+    let lines = LineIndex::new(text);
+    names
+        .into_iter()
+        .map(|name| {
+            let end = item_end(text, lines.lsp_position_to_offset(name.start)?)?;
+            Some(Range { start: name.start, end: lines.offset_to_lsp_position(end) })
+        })
+        .collect()
+}
+
+fn load_text(ctx: &InitActionContext, file: &Path) -> Option<String> {
+    match ctx.vfs.load_file(file) {
+        Ok(FileContents::Text(text)) => Some(text),
+        Ok(FileContents::Binary(_)) => None,
+        Err(e) => {
+            error!("failed to load {:?} for the call hierarchy: {}", file, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: (u64, u64), end: (u64, u64)) -> Range {
+        Range { start: Position::new(start.0, start.1), end: Position::new(end.0, end.1) }
+    }
+
+    #[test]
+    fn extents_of_functions() {
+        let text = "\
+fn main() {
+    first(\"}\");
+}
+
+trait Second {
+    fn second();
+}
+";
+        assert_eq!(
+            function_extents(text, vec![range((0, 3), (0, 7)), range((5, 7), (5, 13))]),
+            vec![Some(range((0, 3), (2, 1))), None]
+        );
+    }
+}
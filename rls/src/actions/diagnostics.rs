@@ -8,10 +8,12 @@ use std::collections::HashMap;
 use std::iter;
 use std::path::{Path, PathBuf};
 
+use crate::config::AutoImportStyle;
 use crate::lsp_data::ls_util;
 use log::debug;
 use lsp_types::{
-    DiagnosticRelatedInformation, DiagnosticSeverity, Location, NumberOrString, Range,
+    DiagnosticRelatedInformation, DiagnosticSeverity, Location, NumberOrString, Position, Range,
+    TextEdit,
 };
 use rls_span::compiler::DiagnosticSpan;
 use serde_derive::Deserialize;
@@ -39,6 +41,15 @@ struct CompilerMessage {
     level: String,
     spans: Vec<DiagnosticSpan>,
     children: Vec<AssociatedMessage>,
+    /// Set for lints about code which will be rejected by future versions of
+    /// the compiler.
+    #[serde(default)]
+    future_incompatibility: Option<FutureIncompatibility>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FutureIncompatibility {
+    future_breakage_date: Option<String>,
 }
 
 /// Represents an emitted subdiagnostic for a certain message. Rustc also emits
@@ -56,6 +67,46 @@ struct CompilerMessageCode {
     code: String,
 }
 
+/// Deserialized JSON diagnostic, as far as needed for extracting the
+/// machine-applicable suggestions attached to it.
+#[derive(Debug, Deserialize)]
+struct SuggestionMessage {
+    spans: Vec<SuggestionSpan>,
+    children: Vec<SuggestionMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SuggestionSpan {
+    file_name: String,
+    /// 1-based.
+    line_start: u64,
+    line_end: u64,
+    /// 1-based, character offset.
+    column_start: u64,
+    column_end: u64,
+    is_primary: bool,
+    suggested_replacement: Option<String>,
+    suggestion_applicability: Option<String>,
+}
+
+impl SuggestionSpan {
+    fn range(&self) -> Range {
+        Range {
+            start: Position::new(
+                self.line_start.saturating_sub(1),
+                self.column_start.saturating_sub(1),
+            ),
+            end: Position::new(self.line_end.saturating_sub(1), self.column_end.saturating_sub(1)),
+        }
+    }
+
+    fn is_machine_applicable(&self) -> bool {
+        self.suggested_replacement.is_some()
+            && self.suggestion_applicability.as_ref().map(String::as_str)
+                == Some("MachineApplicable")
+    }
+}
+
 pub fn parse_diagnostics(
     message: &str,
     cwd: &Path,
@@ -112,12 +163,24 @@ pub fn parse_diagnostics(
             if let Some(notes) = format_notes(&message.children, span) {
                 diagnostic_message.push_str(&format!("\n\n{}", notes));
             }
+
+            if let Some(ref future_incompat) = message.future_incompatibility {
+                diagnostic_message
+                    .push_str(&format_future_incompatibility(future_incompat, &message.children));
+            }
             diagnostic_message
         };
 
         // A diagnostic source is quite likely to be clippy if it contains
-        // the further information link to the rust-clippy project.
-        let source = if diagnostic_message.contains("rust-clippy") { "clippy" } else { "rustc" };
+        // the further information link to the rust-clippy project, or if it's
+        // a `clippy::*` lint.
+        let is_clippy_lint =
+            message.code.as_ref().map_or(false, |c| c.code.starts_with("clippy::"));
+        let source = if is_clippy_lint || diagnostic_message.contains("rust-clippy") {
+            "clippy"
+        } else {
+            "rustc"
+        };
 
         let rls_span = {
             let mut span = span;
@@ -151,6 +214,68 @@ pub fn parse_diagnostics(
     Some(ParsedDiagnostics { diagnostics })
 }
 
+/// Extracts the machine-applicable suggestions of a rustc JSON message, as the
+/// text edits fixing the diagnostic at a given primary span.
+///
+/// Only the first such suggestion is returned per primary span, since rustc may
+/// emit mutually exclusive alternatives.
+pub fn parse_suggestion_edits(message: &str, cwd: &Path) -> Vec<(Url, Range, Vec<TextEdit>)> {
+    let message = match serde_json::from_str::<SuggestionMessage>(message) {
+        Ok(m) => m,
+        Err(_) => return vec![],
+    };
+
+    let primaries = message.spans.iter().filter(|span| span.is_primary);
+    primaries
+        .filter_map(|primary| {
+            let edits = message.children.iter().find_map(|child| {
+                let suggested: Vec<_> =
+                    child.spans.iter().filter(|s| s.suggested_replacement.is_some()).collect();
+                // Edits spanning other files can't be applied as a fix for this one.
+                let applicable = !suggested.is_empty()
+                    && suggested
+                        .iter()
+                        .all(|s| s.is_machine_applicable() && s.file_name == primary.file_name);
+                if !applicable {
+                    return None;
+                }
+
+                let edits = suggested.iter().map(|s| TextEdit {
+                    range: s.range(),
+                    new_text: s.suggested_replacement.clone().unwrap(),
+                });
+                Some(edits.collect::<Vec<_>>())
+            })?;
+
+            let uri = Url::from_file_path(cwd.join(&primary.file_name)).ok()?;
+            Some((uri, primary.range(), edits))
+        })
+        .collect()
+}
+
+/// Describes when the code will stop compiling, linking to the tracking issue of
+/// the change if rustc mentions one.
+fn format_future_incompatibility(
+    future_incompat: &FutureIncompatibility,
+    children: &[AssociatedMessage],
+) -> String {
+    let mut text = String::from("\n\nfuture incompatibility: ");
+    match future_incompat.future_breakage_date {
+        Some(ref date) => text.push_str(&format!("this will become a hard error on {}", date)),
+        None => text.push_str("this will become a hard error in a future release"),
+    }
+
+    let tracking_issue = children.iter().find_map(|child| {
+        let start = child.message.find("<https://github.com/rust-lang/rust/issues/")?;
+        let url = &child.message[start + 1..];
+        Some(&url[..url.find('>')?])
+    });
+    if let Some(url) = tracking_issue {
+        text.push_str(&format!(" (see {})", url));
+    }
+    text
+}
+
 fn format_notes(children: &[AssociatedMessage], primary: &DiagnosticSpan) -> Option<String> {
     let mut notes = String::new();
 
@@ -246,6 +371,54 @@ fn make_suggestions<'a>(
     suggestions
 }
 
+/// Rewrites the suggestions to import an unresolved name (e.g. `use
+/// std::collections::HashMap;`) of a diagnostic at `range` according to `style`.
+pub fn restyle_import_suggestions(
+    range: Range,
+    suggestions: &mut [Suggestion],
+    style: AutoImportStyle,
+) {
+    for suggestion in suggestions {
+        let path = match imported_path(&suggestion.new_text) {
+            Some(path) => path,
+            None => continue,
+        };
+        let new_text = match style {
+            AutoImportStyle::UseStatement => continue,
+            AutoImportStyle::PathOnly => path.to_owned(),
+            AutoImportStyle::AbsolutePath => {
+                let is_relative = ["crate", "self", "super"]
+                    .iter()
+                    .any(|kw| path == *kw || path.starts_with(&format!("{}::", kw)));
+                if is_relative || path.starts_with("::") {
+                    path.to_owned()
+                } else {
+                    format!("::{}", path)
+                }
+            }
+        };
+        *suggestion = Suggestion { label: format!("Change to `{}`", new_text), range, new_text };
+    }
+}
+
+/// Returns the path imported by a single `use` item, e.g. `std::fmt::Write` for
+/// `use std::fmt::Write;\n`.
+fn imported_path(text: &str) -> Option<&str> {
+    let text = text.trim();
+    if !text.starts_with("use ") || !text.ends_with(';') {
+        return None;
+    }
+    let path = text["use ".len()..text.len() - 1].trim();
+    let is_simple_path = !path.is_empty()
+        && path.chars().all(|c| c.is_alphanumeric() || c == '_' || c == ':')
+        && !path.ends_with(':');
+    if is_simple_path {
+        Some(path)
+    } else {
+        None
+    }
+}
+
 fn span_suggestion(span: &DiagnosticSpan, suggested: &str) -> Suggestion {
     let rls_span = span.rls_span().zero_indexed();
     let range = ls_util::rls_to_range(rls_span.range);
@@ -548,6 +721,20 @@ help: consider borrowing here: `&string`"#,
         }
     }
 
+    #[test]
+    fn message_unused_use_denied() {
+        let diag =
+            parse_compiler_message(&read_fixture("compiler_message/unused-use-denied.json"), true);
+        let (diagnostic, _) = &diag.single_file_results()[0];
+
+        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::Error));
+        assert_eq!(
+            diagnostic.message,
+            "unused import: `std::fmt`\n\n\
+             note: requested on the command line with `-D unused-imports`"
+        );
+    }
+
     #[test]
     fn message_cannot_find_type() {
         let messages =
@@ -562,6 +749,16 @@ help: consider borrowing here: `&string`"#,
         assert_eq!(messages[0].1, vec!["not found in this scope"]);
     }
 
+    #[test]
+    fn message_clippy_lint_code() {
+        let message = read_fixture("compiler_message/unused-mut.json")
+            .replace("\"unused_mut\"", "\"clippy::redundant_clone\"");
+        let diag = parse_compiler_message(&message, true);
+
+        let diagnostic = &diag.diagnostics.values().nth(0).unwrap()[0];
+        assert_eq!(diagnostic.0.source, Some("clippy".into()));
+    }
+
     /// ```
     /// let _s = 1 / 1;
     /// ```
@@ -656,6 +853,22 @@ help: consider borrowing here: `&string`"#,
 
         assert!(messages[0].1.is_empty(), "{:?}", messages[0].1);
     }
+
+    #[test]
+    fn future_incompatibility() {
+        let diag =
+            parse_compiler_message(&read_fixture("compiler_message/future-incompat.json"), true);
+        let (diagnostic, _) = &diag.single_file_results()[0];
+        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::Warning));
+        assert!(diagnostic.message.ends_with(
+            "\n\nfuture incompatibility: this will become a hard error on 2021-06-01 \
+             (see https://github.com/rust-lang/rust/issues/46043)"
+        ));
+
+        let diag = parse_compiler_message(&read_fixture("compiler_message/unused-mut.json"), true);
+        let (diagnostic, _) = &diag.single_file_results()[0];
+        assert!(!diagnostic.message.contains("future incompatibility"));
+    }
 }
 
 /// Tests for creating suggestions from the compilers JSON output.
@@ -761,4 +974,78 @@ mod diagnostic_suggestion_test {
             Range { start: Position::new(0, 0), end: Position::new(0, 0) }
         );
     }
+
+    #[test]
+    fn machine_applicable_suggestion_edits() {
+        let cwd = ::std::env::current_dir().unwrap();
+        let message = read_fixture("compiler_message/unused-mut.json");
+        let edits = parse_suggestion_edits(&message, &cwd);
+
+        assert_eq!(edits.len(), 1);
+        let (uri, range, edits) = &edits[0];
+        assert_eq!(*uri, Url::from_file_path(cwd.join("src/main.rs")).unwrap());
+        assert_eq!(*range, Range { start: Position::new(1, 8), end: Position::new(1, 13) });
+        assert_eq!(
+            *edits,
+            vec![TextEdit {
+                range: Range { start: Position::new(1, 8), end: Position::new(1, 12) },
+                new_text: String::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn no_edits_without_machine_applicable_suggestion() {
+        let cwd = ::std::env::current_dir().unwrap();
+        let message = read_fixture("compiler_message/not-mut.json");
+        assert!(parse_suggestion_edits(&message, &cwd).is_empty());
+    }
+
+    #[test]
+    fn import_suggestion_styles() {
+        let diag =
+            parse_compiler_message(&read_fixture("compiler_message/cannot-find-type.json"), true);
+        let restyled = |style| {
+            let (diagnostic, suggestions) = &diag.single_file_results()[0];
+            let mut suggestions: Vec<_> = suggestions
+                .iter()
+                .map(|s| Suggestion {
+                    range: s.range,
+                    new_text: s.new_text.clone(),
+                    label: s.label.clone(),
+                })
+                .collect();
+            restyle_import_suggestions(diagnostic.range, &mut suggestions, style);
+            suggestions
+                .into_iter()
+                .find(|s| s.new_text.contains("std::collections::HashSet"))
+                .expect("`std::collections::HashSet` not found")
+        };
+        let name_range = Range { start: Position::new(157, 31), end: Position::new(157, 38) };
+
+        let use_statement = restyled(AutoImportStyle::UseStatement);
+        assert_eq!(use_statement.new_text, "use std::collections::HashSet;\n");
+        assert_eq!(
+            use_statement.range,
+            Range { start: Position::new(14, 0), end: Position::new(14, 0) }
+        );
+
+        let path_only = restyled(AutoImportStyle::PathOnly);
+        assert_eq!(path_only.new_text, "std::collections::HashSet");
+        assert_eq!(path_only.label, "Change to `std::collections::HashSet`");
+        assert_eq!(path_only.range, name_range);
+
+        let absolute_path = restyled(AutoImportStyle::AbsolutePath);
+        assert_eq!(absolute_path.new_text, "::std::collections::HashSet");
+        assert_eq!(absolute_path.range, name_range);
+    }
+
+    #[test]
+    fn imported_paths() {
+        assert_eq!(imported_path("use std::fmt::Write;\n\n"), Some("std::fmt::Write"));
+        assert_eq!(imported_path("use crate::foo::Bar;"), Some("crate::foo::Bar"));
+        assert_eq!(imported_path("use std::io::{Read, Write};"), None);
+        assert_eq!(imported_path("use std::fmt::Write as _;"), None);
+        assert_eq!(imported_path("mut x"), None);
+    }
 }
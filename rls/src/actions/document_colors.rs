@@ -0,0 +1,249 @@
+//! Colors of `const` and `static` items, see `DocumentColor`.
+//!
+//! Save-analysis doesn't tell whether an item represents a color, so this is
+//! guessed from the item's name and value: integer items are colors if their
+//! name mentions a color, e.g. `BG_COLOR` or `TEXT_RGBA`, or if their value is
+//! written as a 6-digit hex literal, e.g. `0xFF5733`. A color picked by the
+//! user is written back as a hex literal, see `ColorPresentationRequest`.
+
+use std::convert::TryFrom;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use log::error;
+use regex::Regex;
+use rls_analysis::DefKind;
+use rls_vfs::FileContents;
+
+use crate::actions::inline_values::{fold_constant, initializer};
+use crate::actions::InitActionContext;
+use crate::lsp_data::{
+    ls_util, Color, ColorInformation, ColorPresentation, Position, Range, TextEdit,
+};
+
+lazy_static! {
+    static ref COLOR_NAME_RE: Regex = Regex::new(r"(?i)colou?r|rgb").unwrap();
+    static ref HEX_RE: Regex =
+        Regex::new(r"^0x(?P<digits>[0-9a-fA-F_]+)(?P<suffix>[iu](?:32|64|128|size))?$").unwrap();
+}
+
+/// Returns the colors of the constant items defined in `file`.
+pub fn collect_document_colors(ctx: &InitActionContext, file: &Path) -> Vec<ColorInformation> {
+    let text = match ctx.vfs.load_file(file) {
+        Ok(FileContents::Text(text)) => text,
+        Ok(FileContents::Binary(_)) => return vec![],
+        Err(e) => {
+            error!("failed to collect document colors: {}", e);
+            return vec![];
+        }
+    };
+
+    let defs = ctx.analysis.symbols(file).unwrap_or_else(|_| vec![]);
+    let defs = defs
+        .into_iter()
+        .filter(|def| def.kind == DefKind::Const || def.kind == DefKind::Static)
+        .map(|def| (def.name, ls_util::rls_to_range(def.span.range)))
+        .collect();
+
+    const_colors(&text, defs)
+}
+
+/// Returns the colors of the items named at `defs`.
+fn const_colors(text: &str, defs: Vec<(String, Range)>) -> Vec<ColorInformation> {
+    let lines: Vec<_> = text.lines().collect();
+    defs.into_iter()
+        .filter_map(|(name, range)| {
+            let color = parse_color(&name, initializer(&lines, &name, range)?)?;
+            Some(ColorInformation { range, color })
+        })
+        .collect()
+}
+
+/// Returns the ways to write `color` as the initializer of the color item whose
+/// name is at `range` of `file`, i.e., a hex literal with the channels of the
+/// current initializer.
+pub fn color_presentations(
+    ctx: &InitActionContext,
+    file: &Path,
+    color: Color,
+    range: Range,
+) -> Vec<ColorPresentation> {
+    let text = match ctx.vfs.load_file(file) {
+        Ok(FileContents::Text(text)) => text,
+        Ok(FileContents::Binary(_)) => return vec![],
+        Err(e) => {
+            error!("failed to present color: {}", e);
+            return vec![];
+        }
+    };
+
+    presentation(&text, color, range).into_iter().collect()
+}
+
+fn presentation(text: &str, color: Color, range: Range) -> Option<ColorPresentation> {
+    if range.start.line != range.end.line {
+        return None;
+    }
+    let lines: Vec<_> = text.lines().collect();
+    let line = *lines.get(range.start.line as usize)?;
+    let name: String = line
+        .chars()
+        .skip(range.start.character as usize)
+        .take(range.end.character.saturating_sub(range.start.character) as usize)
+        .collect();
+    let value = initializer(&lines, &name, range)?.trim_end();
+    let has_alpha = has_alpha(&name, value)?;
+
+    let channel = |value: f64| (value.max(0.0).min(1.0) * 255.0).round() as u32;
+    let rgb = (channel(color.red) << 16) | (channel(color.green) << 8) | channel(color.blue);
+    let suffix = HEX_RE.captures(value).and_then(|caps| caps.name("suffix"));
+    let suffix = suffix.map_or("", |suffix| suffix.as_str());
+    let new_text = if has_alpha {
+        format!("0x{:08X}{}", (rgb << 8) | channel(color.alpha), suffix)
+    } else {
+        format!("0x{:06X}{}", rgb, suffix)
+    };
+
+    // `value` is a slice of `line`.
+    let start = line[..value.as_ptr() as usize - line.as_ptr() as usize].chars().count() as u64;
+    let end = start + value.chars().count() as u64;
+    let range = Range {
+        start: Position::new(range.start.line, start),
+        end: Position::new(range.start.line, end),
+    };
+    Some(ColorPresentation {
+        label: new_text.clone(),
+        text_edit: Some(TextEdit { range, new_text }),
+        additional_text_edits: None,
+    })
+}
+
+/// Returns whether the item named `name` with the initializer `value` is a
+/// color with an alpha channel, i.e., whether its value is `0xRRGGBBAA` rather
+/// than `0xRRGGBB`, which is the case for an 8-digit hex literal or a name
+/// mentioning RGBA. Returns `None` if the item isn't a color.
+fn has_alpha(name: &str, value: &str) -> Option<bool> {
+    let named = COLOR_NAME_RE.is_match(name);
+    let hex_digits =
+        HEX_RE.captures(value.trim()).map(|caps| caps["digits"].replace('_', "").len());
+    match hex_digits {
+        Some(6) => Some(false),
+        Some(8) if named => Some(true),
+        _ if named => Some(name.to_lowercase().contains("rgba")),
+        _ => None,
+    }
+}
+
+/// Returns the color represented by the item named `name` with the initializer
+/// `value`, see `has_alpha`.
+fn parse_color(name: &str, value: &str) -> Option<Color> {
+    let has_alpha = has_alpha(name, value)?;
+
+    let value = u32::try_from(fold_constant(value)?.parse::<i128>().ok()?).ok()?;
+    let (rgb, alpha) = if has_alpha {
+        (value >> 8, value & 0xFF)
+    } else if value <= 0xFF_FFFF {
+        (value, 0xFF)
+    } else {
+        return None;
+    };
+
+    let channel = |value: u32| f64::from(value & 0xFF) / 255.0;
+    Some(Color {
+        red: channel(rgb >> 16),
+        green: channel(rgb >> 8),
+        blue: channel(rgb),
+        alpha: channel(alpha),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name_range(line: u64, start: u64, name: &str) -> Range {
+        Range {
+            start: Position::new(line, start),
+            end: Position::new(line, start + name.len() as u64),
+        }
+    }
+
+    #[test]
+    fn const_colors_by_name_and_value() {
+        let text = "const RED_COLOR: u32 = 0xFF0000;\n\
+                    pub static HIGHLIGHT: u32 = 0x33_66_ffu32;\n\
+                    const SHADOW_RGBA: u32 = 0x00000080;\n\
+                    const MASK: u32 = 0xFFFFFFFF;\n\
+                    const TEXT_COLOR: u32 = 16_777_215;\n\
+                    const BORDER_COLOR: &str = \"red\";\n";
+        let defs = vec![
+            ("RED_COLOR".to_owned(), name_range(0, 6, "RED_COLOR")),
+            ("HIGHLIGHT".to_owned(), name_range(1, 11, "HIGHLIGHT")),
+            ("SHADOW_RGBA".to_owned(), name_range(2, 6, "SHADOW_RGBA")),
+            ("MASK".to_owned(), name_range(3, 6, "MASK")),
+            ("TEXT_COLOR".to_owned(), name_range(4, 6, "TEXT_COLOR")),
+            ("BORDER_COLOR".to_owned(), name_range(5, 6, "BORDER_COLOR")),
+        ];
+
+        let colors = const_colors(text, defs);
+        let rgba = |red, green, blue, alpha| Color { red, green, blue, alpha };
+        assert_eq!(
+            colors,
+            vec![
+                ColorInformation {
+                    range: name_range(0, 6, "RED_COLOR"),
+                    color: rgba(1.0, 0.0, 0.0, 1.0),
+                },
+                ColorInformation {
+                    range: name_range(1, 11, "HIGHLIGHT"),
+                    color: rgba(0.2, 0.4, 1.0, 1.0),
+                },
+                ColorInformation {
+                    range: name_range(2, 6, "SHADOW_RGBA"),
+                    color: rgba(0.0, 0.0, 0.0, 128.0 / 255.0),
+                },
+                ColorInformation {
+                    range: name_range(4, 6, "TEXT_COLOR"),
+                    color: rgba(1.0, 1.0, 1.0, 1.0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn color_presentation_keeps_format() {
+        let text = "const RED_COLOR: u32 = 0xFF0000;\n\
+                    pub static HIGHLIGHT: u32 = 0x33_66_ffu32 ;\n\
+                    const SHADOW_RGBA: u32 = 0x00000080;\n\
+                    const BORDER_COLOR: &str = \"red\";\n";
+        let edit = |line, start, name| {
+            let color = Color { red: 0.2, green: 0.4, blue: 1.0, alpha: 0.5 };
+            let presented = presentation(text, color, name_range(line, start, name))?;
+            assert_eq!(presented.label, presented.text_edit.as_ref()?.new_text);
+            presented.text_edit
+        };
+
+        assert_eq!(
+            edit(0, 6, "RED_COLOR"),
+            Some(TextEdit {
+                range: name_range(0, 23, "0xFF0000"),
+                new_text: "0x3366FF".to_owned()
+            })
+        );
+        assert_eq!(
+            edit(1, 11, "HIGHLIGHT"),
+            Some(TextEdit {
+                range: name_range(1, 28, "0x33_66_ffu32"),
+                new_text: "0x3366FFu32".to_owned(),
+            })
+        );
+        assert_eq!(
+            edit(2, 6, "SHADOW_RGBA"),
+            Some(TextEdit {
+                range: name_range(2, 25, "0x00000080"),
+                new_text: "0x3366FF80".to_owned(),
+            })
+        );
+        assert_eq!(edit(3, 6, "BORDER_COLOR"), None);
+    }
+}
@@ -0,0 +1,343 @@
+//! "Extract to function" refactoring.
+//!
+//! The extraction is mostly textual: the enclosing function is found by
+//! scanning the source upwards from the selection, while the free variables of
+//! the selection (and their types) come from the save-analysis data. Free
+//! variables are passed by value, so the generated code may need some manual
+//! touch-ups (e.g., borrowing) to compile.
+
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use log::{error, trace};
+use regex::Regex;
+use rls_analysis::DefKind;
+use rls_span::{Column, Position, Row, Span, ZeroIndexed};
+use rls_vfs::FileContents;
+use url::Url;
+
+use crate::actions::InitActionContext;
+use crate::lsp_data::{self, ls_util, Range, TextEdit, WorkspaceEdit};
+
+lazy_static! {
+    /// Matches a line starting a function item, e.g. `pub(crate) async fn foo`.
+    static ref FN_ITEM_RE: Regex = Regex::new(
+        r#"^\s*(pub(\([^)]*\))?\s+)?((const|async|unsafe|extern(\s+"[^"]*")?)\s+)*fn\s+\w+"#
+    )
+    .unwrap();
+    static ref IDENT_RE: Regex = Regex::new(r"\b[A-Za-z_][A-Za-z0-9_]*\b").unwrap();
+}
+
+/// A variable defined outside of the extracted selection, which becomes a
+/// parameter of the extracted function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FreeVariable {
+    pub name: String,
+    pub ty: String,
+}
+
+/// Computes the edit extracting `selection` in `file` into a new function,
+/// inserted above the enclosing function. Returns `None` if the selection
+/// can't be extracted (e.g., it's not inside of a function body or some of
+/// the types involved are not known).
+pub fn extract_fn_edit(
+    ctx: &InitActionContext,
+    file: &Path,
+    uri: &Url,
+    selection: Range,
+) -> Option<WorkspaceEdit> {
+    if selection.start == selection.end {
+        return None;
+    }
+
+    let text = match ctx.vfs.load_file(file) {
+        Ok(FileContents::Text(text)) => text,
+        Ok(FileContents::Binary(_)) => return None,
+        Err(e) => {
+            error!("failed to extract function: {}", e);
+            return None;
+        }
+    };
+    let lines: Vec<&str> = text.lines().collect();
+
+    let range = ls_util::range_to_rls(selection);
+    let selected = text_in_range(&lines, range.start(), range.end())?;
+    if selected.trim().is_empty() {
+        return None;
+    }
+    let fn_row = enclosing_fn_row(&lines, range.row_start.0 as usize)?;
+
+    let idents = idents_in_range(&lines, range.start(), range.end());
+    let mut params: Vec<FreeVariable> = vec![];
+    for (name, pos) in &idents {
+        if params.iter().any(|p| p.name == *name) {
+            continue;
+        }
+        let span = ident_span(file, name, *pos);
+        let def = match ctx.analysis.id(&span).and_then(|id| ctx.analysis.get_def(id)) {
+            Ok(def) => def,
+            Err(_) => continue,
+        };
+        let is_free = def.kind == DefKind::Local
+            && def.span.file == file
+            && def.span.range.row_start.0 as usize >= fn_row
+            && def.span.range.start() < range.start();
+        if is_free {
+            if def.value.is_empty() {
+                trace!("extract_fn: unknown type of `{}`", name);
+                return None;
+            }
+            params.push(FreeVariable { name: name.clone(), ty: def.value });
+        }
+    }
+
+    let is_expr = !selected.trim_end().ends_with(';') && !selected.trim_end().ends_with('}');
+    let ret_ty = if !is_expr {
+        None
+    } else if let [(name, pos)] = &idents[..] {
+        if selected.trim() != name.as_str() {
+            return None;
+        }
+        Some(ctx.analysis.show_type(&ident_span(file, name, *pos)).ok()?)
+    } else {
+        // The type of a call expression is the return type of the callee.
+        let (name, pos) = &idents[callee_index(&selected)?];
+        let sig = ctx.analysis.show_type(&ident_span(file, name, *pos)).ok()?;
+        return_type_from_signature(&sig)
+    };
+
+    let fn_name = extracted_fn_name(&text);
+    let insert_row = item_start_row(&lines, fn_row);
+    let indent: String = lines[fn_row].chars().take_while(|c| c.is_whitespace()).collect();
+
+    let new_fn =
+        make_fn_text(&fn_name, &params, ret_ty.as_ref().map(String::as_str), &selected, &indent);
+    let mut call = format!(
+        "{}({})",
+        fn_name,
+        params.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(", ")
+    );
+    if !is_expr {
+        call.push(';');
+    }
+
+    let insert_pos = lsp_data::Position::new(insert_row as u64, 0);
+    let edits = vec![
+        TextEdit { range: Range { start: insert_pos, end: insert_pos }, new_text: new_fn },
+        TextEdit { range: selection, new_text: call },
+    ];
+    let changes = vec![(uri.clone(), edits)].into_iter().collect();
+
+    Some(WorkspaceEdit { changes: Some(changes), document_changes: None })
+}
+
+fn ident_span(file: &Path, name: &str, pos: Position<ZeroIndexed>) -> Span<ZeroIndexed> {
+    Span::new(
+        pos.row,
+        pos.row,
+        pos.col,
+        Column::new_zero_indexed(pos.col.0 + name.chars().count() as u32),
+        file.to_path_buf(),
+    )
+}
+
+/// Returns the text between `start` and `end` (character-based positions).
+fn text_in_range(
+    lines: &[&str],
+    start: Position<ZeroIndexed>,
+    end: Position<ZeroIndexed>,
+) -> Option<String> {
+    let (start_row, end_row) = (start.row.0 as usize, end.row.0 as usize);
+    if end_row >= lines.len() {
+        return None;
+    }
+
+    let mut result = String::new();
+    for (row, line) in lines.iter().enumerate().take(end_row + 1).skip(start_row) {
+        let from = if row == start_row { start.col.0 as usize } else { 0 };
+        let to = if row == end_row { end.col.0 as usize } else { line.chars().count() };
+        result.extend(line.chars().skip(from).take(to.saturating_sub(from)));
+        if row != end_row {
+            result.push('\n');
+        }
+    }
+    Some(result)
+}
+
+/// Returns all identifiers (with their positions) between `start` and `end`.
+fn idents_in_range(
+    lines: &[&str],
+    start: Position<ZeroIndexed>,
+    end: Position<ZeroIndexed>,
+) -> Vec<(String, Position<ZeroIndexed>)> {
+    let mut idents = vec![];
+    for (row, line) in lines.iter().enumerate() {
+        for m in IDENT_RE.find_iter(line) {
+            let col = line[..m.start()].chars().count() as u32;
+            let pos =
+                Position::new(Row::new_zero_indexed(row as u32), Column::new_zero_indexed(col));
+            let end_col = col + m.as_str().chars().count() as u32;
+            let end_pos =
+                Position::new(Row::new_zero_indexed(row as u32), Column::new_zero_indexed(end_col));
+            if pos >= start && end_pos <= end {
+                idents.push((m.as_str().to_owned(), pos));
+            }
+        }
+    }
+    idents
+}
+
+/// If `expr` is a call expression, returns the index of the callee among all
+/// of the identifiers in `expr`.
+fn callee_index(expr: &str) -> Option<usize> {
+    let expr = expr.trim_end();
+    if !expr.ends_with(')') {
+        return None;
+    }
+
+    let mut depth = 0;
+    for (idx, c) in expr.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return IDENT_RE.find_iter(&expr[..idx]).count().checked_sub(1);
+        }
+    }
+    None
+}
+
+/// Returns the row of the function item enclosing `row`.
+fn enclosing_fn_row(lines: &[&str], row: usize) -> Option<usize> {
+    (0..row.min(lines.len())).rev().find(|&row| FN_ITEM_RE.is_match(lines[row]))
+}
+
+/// Returns the first row of the item starting at `fn_row`, including any
+/// preceding attributes and doc comments.
+fn item_start_row(lines: &[&str], fn_row: usize) -> usize {
+    let mut row = fn_row;
+    while row > 0 {
+        let prev = lines[row - 1].trim_start();
+        if !prev.starts_with("#[") && !prev.starts_with("///") {
+            break;
+        }
+        row -= 1;
+    }
+    row
+}
+
+/// Returns a `extracted_N` function name that is not yet used in `text`.
+fn extracted_fn_name(text: &str) -> String {
+    (1..)
+        .map(|n| format!("extracted_{}", n))
+        .find(|name| !text.contains(&format!("fn {}(", name)))
+        .unwrap()
+}
+
+/// Extracts the return type out of a function signature, e.g. `u32` out of
+/// `fn (u32) -> u32`. Returns `None` for functions returning `()`.
+fn return_type_from_signature(sig: &str) -> Option<String> {
+    sig.rfind("->").map(|idx| sig[idx + 2..].trim().to_owned()).filter(|ty| !ty.is_empty())
+}
+
+fn make_fn_text(
+    name: &str,
+    params: &[FreeVariable],
+    ret_ty: Option<&str>,
+    body: &str,
+    indent: &str,
+) -> String {
+    let params =
+        params.iter().map(|p| format!("{}: {}", p.name, p.ty)).collect::<Vec<_>>().join(", ");
+    let ret_ty = ret_ty.map(|ty| format!(" -> {}", ty)).unwrap_or_default();
+
+    // The first line starts at the selection, so its indentation is not
+    // representative of the whole body.
+    let body_lines: Vec<&str> = body.lines().collect();
+    let common_indent = body_lines
+        .iter()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.chars().take_while(|c| c.is_whitespace()).count())
+        .min()
+        .unwrap_or(0);
+
+    let mut text = format!("{}fn {}({}){} {{\n", indent, name, params, ret_ty);
+    for (i, line) in body_lines.iter().enumerate() {
+        let line = if i == 0 {
+            line.trim_start()
+        } else {
+            let skip = line.char_indices().nth(common_indent).map(|(i, _)| i);
+            skip.map(|i| &line[i..]).unwrap_or_else(|| line.trim_start())
+        };
+        if line.is_empty() {
+            text.push('\n');
+        } else {
+            text.push_str(&format!("{}    {}\n", indent, line));
+        }
+    }
+    text.push_str(&format!("{}}}\n\n", indent));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(row: u32, col: u32) -> Position<ZeroIndexed> {
+        Position::new(Row::new_zero_indexed(row), Column::new_zero_indexed(col))
+    }
+
+    #[test]
+    fn finds_enclosing_fn() {
+        let lines =
+            vec!["struct Foo;", "", "#[inline]", "pub(crate) fn foo() {", "    bar();", "}"];
+        assert_eq!(enclosing_fn_row(&lines, 4), Some(3));
+        assert_eq!(item_start_row(&lines, 3), 2);
+        assert_eq!(enclosing_fn_row(&lines, 1), None);
+    }
+
+    #[test]
+    fn finds_unused_fn_name() {
+        assert_eq!(extracted_fn_name("fn main() {}"), "extracted_1");
+        assert_eq!(extracted_fn_name("fn extracted_1() {}\nfn extracted_2() {}"), "extracted_3");
+    }
+
+    #[test]
+    fn finds_callee() {
+        assert_eq!(callee_index("foo(a, bar(b))"), Some(0));
+        assert_eq!(callee_index("x.len()"), Some(1));
+        assert_eq!(callee_index("a + b"), None);
+    }
+
+    #[test]
+    fn parses_return_type() {
+        assert_eq!(return_type_from_signature("fn (u32) -> Vec<u8>"), Some("Vec<u8>".to_owned()));
+        assert_eq!(return_type_from_signature("fn (&self)"), None);
+    }
+
+    #[test]
+    fn selects_text_and_idents() {
+        let lines = vec!["fn foo() {", "    let x = a + b;", "    x * 2", "}"];
+        let (start, end) = (pos(1, 12), pos(2, 9));
+        assert_eq!(text_in_range(&lines, start, end).unwrap(), "a + b;\n    x * 2");
+        let idents: Vec<_> =
+            idents_in_range(&lines, start, end).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(idents, ["a", "b", "x"]);
+    }
+
+    #[test]
+    fn makes_fn_text() {
+        let params = vec![
+            FreeVariable { name: "a".to_owned(), ty: "u32".to_owned() },
+            FreeVariable { name: "b".to_owned(), ty: "u32".to_owned() },
+        ];
+        let text = make_fn_text("extracted_1", &params, Some("u32"), "a +\n        b", "    ");
+        assert_eq!(
+            text,
+            "    fn extracted_1(a: u32, b: u32) -> u32 {\n        a +\n        b\n    }\n\n"
+        );
+    }
+}
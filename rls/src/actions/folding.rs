@@ -0,0 +1,200 @@
+//! Folding ranges, see `FoldingRangeRequest`.
+//!
+//! Without a full parse, the ranges are found in the source text: blocks (and
+//! other groups) by matching their delimiters, and comments and imports as
+//! runs of consecutive lines.
+
+use std::cmp::Reverse;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use log::error;
+use regex::Regex;
+use rls_vfs::FileContents;
+
+use crate::actions::breadcrumbs::find_chars;
+use crate::actions::run::LineIndex;
+use crate::actions::InitActionContext;
+use crate::lsp_data::{FoldingRange, FoldingRangeKind};
+
+/// Returns the folding ranges of `file`.
+pub fn collect_folding_ranges(ctx: &InitActionContext, file: &Path) -> Vec<FoldingRange> {
+    match ctx.vfs.load_file(file) {
+        Ok(FileContents::Text(text)) => folding_ranges(&text),
+        Ok(FileContents::Binary(_)) => vec![],
+        Err(e) => {
+            error!("failed to collect folding ranges: {}", e);
+            vec![]
+        }
+    }
+}
+
+/// Returns the folding ranges of `text`, sorted by their start line, from the
+/// outermost to the innermost one.
+fn folding_ranges(text: &str) -> Vec<FoldingRange> {
+    let mut ranges = delimited_folds(text);
+    ranges.extend(comment_folds(text));
+    ranges.extend(import_folds(text));
+    ranges.sort_by_key(|range| (range.start_line, Reverse(range.end_line)));
+    // E.g. the parentheses and the braces of `foo(|| { .. })`.
+    ranges.dedup();
+    ranges
+}
+
+fn fold(start_line: usize, end_line: usize, kind: Option<FoldingRangeKind>) -> FoldingRange {
+    FoldingRange {
+        start_line: start_line as u64,
+        start_character: None,
+        end_line: end_line as u64,
+        end_character: None,
+        kind,
+    }
+}
+
+/// Folds the groups delimited by braces, brackets or parentheses, keeping the
+/// line of the closing delimiter visible.
+fn delimited_folds(text: &str) -> Vec<FoldingRange> {
+    let index = LineIndex::new(text);
+    let mut folds = vec![];
+    let mut open = vec![];
+    for (i, c) in find_chars(text, &['{', '}', '(', ')', '[', ']']) {
+        let expected = match c {
+            '{' | '(' | '[' => {
+                open.push((i, c));
+                continue;
+            }
+            '}' => '{',
+            ')' => '(',
+            _ => '[',
+        };
+        match open.pop() {
+            Some((start, opening)) if opening == expected => {
+                let start_line = index.offset_to_lsp_position(start).line as usize;
+                let end_line = index.offset_to_lsp_position(i).line as usize;
+                if end_line > start_line + 1 {
+                    folds.push(fold(start_line, end_line - 1, None));
+                }
+            }
+            _ => {}
+        }
+    }
+    folds
+}
+
+/// Folds the runs of consecutive comment lines, including block comments
+/// spanning multiple lines.
+fn comment_folds(text: &str) -> Vec<FoldingRange> {
+    let mut folds = vec![];
+    let mut run: Option<(usize, usize)> = None;
+    let mut in_block = false;
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim_start();
+        let opens_block = !in_block && line.starts_with("/*");
+        let is_comment = in_block || opens_block || line.starts_with("//");
+        if opens_block || in_block {
+            let rest = if opens_block { &line[2..] } else { line };
+            in_block = !rest.contains("*/");
+        }
+
+        if is_comment {
+            run = Some((run.map_or(line_no, |(first, _)| first), line_no));
+        } else if let Some((first, last)) = run.take() {
+            if last > first {
+                folds.push(fold(first, last, Some(FoldingRangeKind::Comment)));
+            }
+        }
+    }
+    if let Some((first, last)) = run {
+        if last > first {
+            folds.push(fold(first, last, Some(FoldingRangeKind::Comment)));
+        }
+    }
+    folds
+}
+
+/// Folds the runs of (at least two) `use` statements on consecutive lines. A
+/// blank line or any other line between the statements starts a new run.
+fn import_folds(text: &str) -> Vec<FoldingRange> {
+    lazy_static! {
+        static ref USE_RE: Regex = Regex::new(r"^\s*(pub(\([^)]*\))?\s+)?use\s").unwrap();
+    }
+
+    // The first and last lines of every `use` statement.
+    let lines: Vec<_> = text.lines().collect();
+    let mut statements = vec![];
+    let mut line_no = 0;
+    while line_no < lines.len() {
+        if USE_RE.is_match(lines[line_no]) {
+            let start = line_no;
+            while !lines[line_no].contains(';') && line_no + 1 < lines.len() {
+                line_no += 1;
+            }
+            statements.push((start, line_no));
+        }
+        line_no += 1;
+    }
+
+    let mut folds = vec![];
+    let mut statements = statements.into_iter().peekable();
+    while let Some((first, mut last)) = statements.next() {
+        let mut count = 1;
+        while let Some(&(start, end)) = statements.peek() {
+            if start != last + 1 {
+                break;
+            }
+            last = end;
+            count += 1;
+            statements.next();
+        }
+        if count > 1 {
+            folds.push(fold(first, last, Some(FoldingRangeKind::Imports)));
+        }
+    }
+    folds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "\
+use std::fmt;
+use std::io::{
+    self,
+    Write,
+};
+
+use crate::a;
+use crate::b;
+
+// A comment
+// spanning lines.
+fn main() {
+    let s = \"{
+not a block\";
+    if true {
+        println!(\"{}\", s);
+    }
+}
+/*
+ * A block comment.
+ */
+";
+
+    #[test]
+    fn folding_ranges_of_blocks_comments_and_imports() {
+        assert_eq!(
+            folding_ranges(TEXT),
+            vec![
+                fold(0, 4, Some(FoldingRangeKind::Imports)),
+                fold(1, 3, None),
+                fold(6, 7, Some(FoldingRangeKind::Imports)),
+                fold(9, 10, Some(FoldingRangeKind::Comment)),
+                // The `{` in the string doesn't count.
+                fold(11, 16, None),
+                fold(14, 15, None),
+                fold(18, 20, Some(FoldingRangeKind::Comment)),
+            ]
+        );
+    }
+}
@@ -1,14 +1,17 @@
 //! Code formatting using Rustfmt -- by default using statically-linked one or
 //! possibly running Rustfmt binary specified by the user.
 
+use std::collections::HashMap;
 use std::env::temp_dir;
 use std::fmt;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::string::FromUtf8Error;
+use std::sync::Mutex;
 
+use lazy_static::lazy_static;
 use log::debug;
 use lsp_types::{Position, Range, TextEdit};
 use rand::{distributions, thread_rng, Rng};
@@ -32,6 +35,8 @@ pub enum Error {
     Io(std::io::Error),
     ConfigTomlOutput(String),
     OutputNotUtf8(FromUtf8Error),
+    /// The external `rustfmt` exited unsuccessfully, with the given stderr.
+    ExternalFailed(String),
 }
 
 impl std::error::Error for Error {}
@@ -48,6 +53,7 @@ impl fmt::Display for Error {
             Error::OutputNotUtf8(err) => {
                 write!(f, "Formatted output is not valid UTF-8 source: {}", err)
             }
+            Error::ExternalFailed(stderr) => write!(f, "Rustfmt failed: {}", stderr.trim()),
         }
     }
 }
@@ -81,6 +87,16 @@ impl Rustfmt {
         }
     }
 
+    /// Returns `true` if Rustfmt can format selected lines only (i.e., supports
+    /// `--file-lines`), which requires its unstable features. Otherwise the
+    /// `file_lines` of the config are ignored.
+    pub fn supports_file_lines(&self) -> bool {
+        match self {
+            Rustfmt::Internal => true,
+            Rustfmt::External { path, cwd } => supports_unstable_features(path, cwd),
+        }
+    }
+
     pub fn calc_text_edits(&self, input: String, mut cfg: Config) -> Result<Vec<TextEdit>, Error> {
         cfg.set().emit_mode(rustfmt_nightly::EmitMode::ModifiedLines);
 
@@ -128,6 +144,23 @@ impl Rustfmt {
     }
 }
 
+/// Returns the `edits` (of whole lines, see `Rustfmt::calc_text_edits`) which
+/// touch the lines of `range`.
+pub fn edits_within(edits: Vec<TextEdit>, range: Range) -> Vec<TextEdit> {
+    edits
+        .into_iter()
+        .filter(|edit| {
+            let (start, end) = (edit.range.start.line, edit.range.end.line);
+            if start == end {
+                // An inserted line.
+                range.start.line <= start && start <= range.end.line
+            } else {
+                start <= range.end.line && range.start.line < end
+            }
+        })
+        .collect()
+}
+
 fn format_external(
     path: &PathBuf,
     cwd: &PathBuf,
@@ -135,25 +168,59 @@ fn format_external(
     cfg: Config,
 ) -> Result<String, Error> {
     let (_file_handle, config_path) = gen_config_file(&cfg)?;
-    let args = rustfmt_args(&cfg, &config_path);
+    let args = rustfmt_args(&cfg, &config_path, supports_unstable_features(path, cwd));
 
     let mut rustfmt = Command::new(path)
         .args(args)
         .current_dir(cwd)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
         .map_err(Error::Io)?;
 
-    {
+    // Rustfmt may exit before reading all of its input (e.g., on invalid
+    // arguments), which is reported as a failure below.
+    let stdin_closed = {
         let stdin = rustfmt.stdin.as_mut().unwrap(); // Safe because stdin is piped
-        stdin.write_all(input.as_bytes())?;
-    }
+        match stdin.write_all(input.as_bytes()) {
+            Ok(()) => false,
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => true,
+            Err(e) => return Err(e.into()),
+        }
+    };
 
     let output = rustfmt.wait_with_output()?;
+    if !output.status.success() || stdin_closed {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        debug!("Reformat failed: rustfmt exited with {}: {}", output.status, stderr);
+        return Err(Error::ExternalFailed(stderr));
+    }
     Ok(String::from_utf8(output.stdout)?)
 }
 
+/// Returns `true` if the external Rustfmt at `path` accepts
+/// `--unstable-features`, e.g. is a nightly one. Every Rustfmt is only asked
+/// once.
+fn supports_unstable_features(path: &Path, cwd: &Path) -> bool {
+    lazy_static! {
+        static ref SUPPORTED: Mutex<HashMap<PathBuf, bool>> = Mutex::default();
+    }
+
+    *SUPPORTED.lock().unwrap().entry(path.to_owned()).or_insert_with(|| {
+        let supported = Command::new(path)
+            .args(&["--unstable-features", "--help=file-lines"])
+            .current_dir(cwd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map_or(false, |status| status.success());
+        debug!("`{}` supports unstable features: {}", path.display(), supported);
+        supported
+    })
+}
+
 fn format_internal(input: String, config: Config) -> Result<String, Error> {
     let mut buf = Vec::<u8>::new();
 
@@ -199,17 +266,15 @@ fn gen_config_file(config: &Config) -> Result<(File, PathBuf), Error> {
     Ok((file, path))
 }
 
-fn rustfmt_args(config: &Config, config_path: &Path) -> Vec<String> {
-    let mut args = vec![
-        "--unstable-features".into(),
-        "--skip-children".into(),
-        "--emit".into(),
-        "stdout".into(),
-        "--quiet".into(),
-    ];
+fn rustfmt_args(config: &Config, config_path: &Path, unstable_features: bool) -> Vec<String> {
+    let mut args = vec!["--emit".into(), "stdout".into(), "--quiet".into()];
+    if unstable_features {
+        args.push("--unstable-features".into());
+        args.push("--skip-children".into());
+    }
 
     // Otherwise --file-lines [] are treated as no lines rather than FileLines::all()
-    if config.file_lines().files().count() > 0 {
+    if unstable_features && config.file_lines().files().count() > 0 {
         args.push("--file-lines".into());
         let file_lines_json = config.file_lines().to_json_spans();
         let lines = serde_json::to_string(&file_lines_json).unwrap();
@@ -222,6 +287,18 @@ fn rustfmt_args(config: &Config, config_path: &Path) -> Vec<String> {
     args
 }
 
+/// Writes an executable shell script with the given body to `dir`, to be run
+/// in place of Rustfmt.
+#[cfg(all(test, unix))]
+pub(crate) fn stub_rustfmt(dir: &Path, body: &str) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = dir.join("rustfmt");
+    std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -261,6 +338,55 @@ mod tests {
             "  struct Upper ;\n\nstruct Lower ;",
             vec![(0, 0, 1, 0, "struct Upper;\n"), (2, 0, 3, 0, "struct Lower;\n")],
         );
+        // Check that only the misindented line is edited
+        test_case(
+            "fn main() {\n    let a = 1;\n  let b = 2;\n    let c = 3;\n}\n",
+            vec![(2, 0, 3, 0, "    let b = 2;\n")],
+        );
+    }
+
+    #[test]
+    fn format_selected_lines_only() {
+        let input = "fn first() {\n  let a = 1;\n}\n\nfn second() {\n  let b = 2;\n}\n";
+        let mut config = FmtConfig::default().get_rustfmt_config().clone();
+        config
+            .set()
+            .file_lines(FileLines::from_str(r#"[{ "file": "stdin", "range": [5, 7] }]"#).unwrap());
+        let edits = Rustfmt::Internal.calc_text_edits(input.to_owned(), config).unwrap();
+        let second = TextEdit {
+            range: Range { start: Position::new(5, 0), end: Position::new(6, 0) },
+            new_text: "    let b = 2;\n".to_owned(),
+        };
+        assert_eq!(edits, vec![second.clone()]);
+
+        // The same, if Rustfmt doesn't support `--file-lines`.
+        let config = FmtConfig::default().get_rustfmt_config().clone();
+        let edits = Rustfmt::Internal.calc_text_edits(input.to_owned(), config).unwrap();
+        assert_eq!(edits.len(), 2);
+        let selection = Range { start: Position::new(4, 0), end: Position::new(6, 1) };
+        assert_eq!(edits_within(edits, selection), vec![second]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn external_rustfmt_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FmtConfig::default().get_rustfmt_config().clone();
+        let input = "fn main() {}\n".to_owned();
+
+        let path = stub_rustfmt(dir.path(), "cat >/dev/null; echo 'error: oops' >&2; exit 1");
+        let rustfmt = Rustfmt::External { path, cwd: dir.path().to_owned() };
+        match rustfmt.calc_text_edits(input.clone(), config.clone()) {
+            Err(Error::ExternalFailed(stderr)) => assert_eq!(stderr, "error: oops\n"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // Exiting without reading the input is a failure too.
+        let rustfmt = Rustfmt::External { path: PathBuf::from("false"), cwd: temp_dir() };
+        match rustfmt.calc_text_edits(input, config) {
+            Err(Error::ExternalFailed(_)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
     }
 
     #[test]
@@ -272,10 +398,14 @@ mod tests {
             );
             config
         };
-        let args = rustfmt_args(&config_with_lines, Path::new("dummy"));
+        let args = rustfmt_args(&config_with_lines, Path::new("dummy"), true);
         assert!(args.join(" ").find("--file-lines").is_some());
 
-        let args = rustfmt_args(&Config::default(), Path::new("dummy"));
+        let args = rustfmt_args(&Config::default(), Path::new("dummy"), true);
+        assert_eq!(args.join(" ").find("--file-lines"), None);
+
+        let args = rustfmt_args(&config_with_lines, Path::new("dummy"), false);
         assert_eq!(args.join(" ").find("--file-lines"), None);
+        assert_eq!(args.join(" ").find("--unstable-features"), None);
     }
 }
@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::path::{Path, PathBuf};
 
 use log::*;
@@ -20,6 +21,83 @@ pub struct Tooltip {
     pub range: Range<ZeroIndexed>,
 }
 
+/// Commands attached to a hover response (as its `data` payload) when the
+/// `hover_actions` option is enabled.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct HoverActions {
+    pub commands: Vec<Command>,
+}
+
+/// The most recent hover responses, keyed by file and position (see the
+/// `hover_cache_size` config option). The entries of a file are dropped when
+/// it's modified, and all entries when new analysis data is loaded.
+#[derive(Debug, Default)]
+pub struct HoverCache {
+    entries: VecDeque<(PathBuf, Position, requests::HoverResponse)>,
+}
+
+impl HoverCache {
+    pub fn get(&self, file: &Path, pos: Position) -> Option<requests::HoverResponse> {
+        self.entries
+            .iter()
+            .find(|(entry_file, entry_pos, _)| entry_file == file && *entry_pos == pos)
+            .map(|(.., response)| response.clone())
+    }
+
+    /// Adds a response, evicting the oldest ones beyond `capacity`.
+    pub fn insert(
+        &mut self,
+        file: PathBuf,
+        pos: Position,
+        response: requests::HoverResponse,
+        capacity: usize,
+    ) {
+        self.entries.retain(|(entry_file, entry_pos, _)| *entry_file != file || *entry_pos != pos);
+        self.entries.push_back((file, pos, response));
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn invalidate_file(&mut self, file: &Path) {
+        self.entries.retain(|(entry_file, ..)| entry_file != file);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Builds the commands offered alongside the tooltip for the symbol at the
+/// given position. "Open Documentation" is only offered if `doc_url` is known.
+pub fn hover_commands(
+    params: &TextDocumentPositionParams,
+    doc_url: Option<String>,
+) -> Vec<Command> {
+    let position = serde_json::to_value(params).unwrap();
+
+    let mut commands = vec![
+        Command {
+            title: "Go to Definition".to_owned(),
+            command: "textDocument/definition".to_owned(),
+            arguments: Some(vec![position.clone()]),
+        },
+        Command {
+            title: "Find References".to_owned(),
+            command: "textDocument/references".to_owned(),
+            arguments: Some(vec![position]),
+        },
+    ];
+    if let Some(doc_url) = doc_url {
+        commands.push(Command {
+            title: "Open Documentation".to_owned(),
+            command: "rls/openDocs".to_owned(),
+            arguments: Some(vec![serde_json::Value::String(doc_url)]),
+        });
+    }
+    commands
+}
+
 /// Cleanup documentation code blocks. The `docs` are expected to have
 /// the preceding `///` or `//!` prefixes already trimmed away. Rust code
 /// blocks will ignore lines beginning with `#`. Code block annotations
@@ -805,8 +883,7 @@ pub fn tooltip(
 ) -> Result<Tooltip, ResponseError> {
     let analysis = &ctx.analysis;
 
-    let hover_file_path = parse_file_path!(&params.text_document.uri, "hover")?;
-    let hover_span = ctx.convert_pos_to_span(hover_file_path, params.position);
+    let (_, hover_span) = ctx.get_identifier_at_position(params)?;
     let hover_span_doc = analysis.docs(&hover_span).unwrap_or_else(|_| String::new());
     let hover_span_typ = analysis.show_type(&hover_span).unwrap_or_else(|_| String::new());
     let hover_span_def = analysis.id(&hover_span).and_then(|id| analysis.get_def(id));
@@ -949,6 +1026,29 @@ pub mod test {
         assert_eq!("Hello, world ! ! !\nThe next line\n    Indented line\nLast line", &lines);
     }
 
+    #[test]
+    fn test_hover_commands() {
+        let params = TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier::new(
+                Url::from_file_path(fixtures_dir().join("hover/src/lib.rs")).unwrap(),
+            ),
+            position: Position::new(10, 4),
+        };
+
+        let commands = hover_commands(&params, None);
+        let ids: Vec<_> = commands.iter().map(|c| &*c.command).collect();
+        assert_eq!(ids, ["textDocument/definition", "textDocument/references"]);
+
+        let doc_url = "https://doc.rust-lang.org/nightly/std/string/struct.String.html";
+        let commands = hover_commands(&params, Some(doc_url.to_owned()));
+        let ids: Vec<_> = commands.iter().map(|c| &*c.command).collect();
+        assert_eq!(ids, ["textDocument/definition", "textDocument/references", "rls/openDocs"]);
+        assert_eq!(commands[2].arguments, Some(vec![serde_json::Value::String(doc_url.into())]));
+
+        let data = serde_json::to_value(HoverActions { commands }).unwrap();
+        assert_eq!(data["commands"][1]["command"], "textDocument/references");
+    }
+
     #[test]
     fn test_process_docs_rust_blocks() {
         let docs = &noindent("
@@ -1607,4 +1707,34 @@ pub mod test {
 
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn test_hover_cache() {
+        let response = |text: &str| requests::HoverResponse {
+            hover: Hover {
+                contents: HoverContents::Array(vec![MarkedString::from_markdown(text.to_owned())]),
+                range: None,
+            },
+            data: None,
+        };
+        let (lib, main) = (PathBuf::from("/src/lib.rs"), PathBuf::from("/src/main.rs"));
+
+        let mut cache = HoverCache::default();
+        cache.insert(lib.clone(), Position::new(0, 0), response("a"), 2);
+        cache.insert(main.clone(), Position::new(0, 0), response("b"), 2);
+        assert!(cache.get(&lib, Position::new(0, 0)).is_some());
+        assert!(cache.get(&lib, Position::new(0, 1)).is_none());
+
+        // Evicts the oldest entry.
+        cache.insert(lib.clone(), Position::new(1, 0), response("c"), 2);
+        assert!(cache.get(&lib, Position::new(0, 0)).is_none());
+        assert_eq!(
+            cache.get(&lib, Position::new(1, 0)).unwrap().hover.contents,
+            HoverContents::Array(vec![MarkedString::from_markdown("c".to_owned())])
+        );
+
+        cache.invalidate_file(&lib);
+        assert!(cache.get(&lib, Position::new(1, 0)).is_none());
+        assert!(cache.get(&main, Position::new(0, 0)).is_some());
+    }
 }
@@ -0,0 +1,285 @@
+//! Inlay hints, see `InlayHintRequest`.
+//!
+//! The types of bindings come from the save-analysis data, which records the
+//! type of every local variable. The arguments of calls are found in the
+//! source text by matching parentheses, and are named after the parameters in
+//! the signature of the callee.
+
+use std::path::Path;
+
+use log::error;
+use rls_analysis::DefKind;
+use rls_vfs::FileContents;
+
+use crate::actions::breadcrumbs::find_chars;
+use crate::actions::requests::{is_self_param, signature_params};
+use crate::actions::run::LineIndex;
+use crate::actions::InitActionContext;
+use crate::lsp_data::{ls_util, InlayHint, InlayHintKind, Position, Range, RangeExt};
+use crate::Span;
+
+/// Returns the inlay hints in `range` of `file`.
+pub fn collect_inlay_hints(ctx: &InitActionContext, file: &Path, range: Range) -> Vec<InlayHint> {
+    let text = match ctx.vfs.load_file(file) {
+        Ok(FileContents::Text(text)) => text,
+        Ok(FileContents::Binary(_)) => return vec![],
+        Err(e) => {
+            error!("failed to collect inlay hints: {}", e);
+            return vec![];
+        }
+    };
+    let (show_types, show_params) = {
+        let config = ctx.config.lock().unwrap();
+        (config.inlay_type_hints, config.inlay_parameter_hints)
+    };
+
+    let mut hints = vec![];
+    if show_types {
+        let defs = ctx.analysis.symbols(file).unwrap_or_else(|_| vec![]);
+        let bindings = defs
+            .into_iter()
+            .filter(|def| def.kind == DefKind::Local)
+            .filter_map(|def| {
+                let name_range = ls_util::rls_to_range(def.span.range);
+                if !range.overlaps(&name_range) {
+                    return None;
+                }
+                let value = ctx.analysis.get_def(def.id).ok()?.value;
+                Some((def.name, name_range, value))
+            })
+            .collect();
+        hints.extend(type_hints(&text, bindings));
+    }
+    if show_params {
+        for call in calls(&text, range) {
+            let span = Span::from_range(ls_util::range_to_rls(call.callee), file.to_owned());
+            let def = match ctx.analysis.id(&span).and_then(|id| ctx.analysis.get_def(id)) {
+                Ok(def) => def,
+                Err(_) => continue,
+            };
+            if def.kind == DefKind::Function || def.kind == DefKind::Method {
+                hints.extend(parameter_hints(&call, &def.value));
+            }
+        }
+    }
+    hints.sort_by_key(|hint| (hint.position.line, hint.position.character));
+    hints
+}
+
+/// Returns the type hints of `bindings`, i.e., their names, name ranges and
+/// types, skipping the ones with an explicit type annotation.
+fn type_hints(text: &str, bindings: Vec<(String, Range, String)>) -> Vec<InlayHint> {
+    let lines: Vec<_> = text.lines().collect();
+    bindings
+        .into_iter()
+        .filter_map(|(name, range, ty)| {
+            let ty = ty.trim();
+            // Closures have no type which could be written down.
+            if name == "self" || ty.is_empty() || ty.contains('@') {
+                return None;
+            }
+            let line = lines.get(range.end.line as usize)?;
+            let rest: String = line.chars().skip(range.end.character as usize).collect();
+            if rest.trim_start().starts_with(':') {
+                return None;
+            }
+            Some(InlayHint {
+                position: range.end,
+                label: format!(": {}", ty),
+                kind: Some(InlayHintKind::TYPE),
+            })
+        })
+        .collect()
+}
+
+/// A call of a function or method in the source text.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Call {
+    /// The name of the callee.
+    pub(crate) callee: Range,
+    /// Whether it's a method call, i.e., `receiver.callee(..)`.
+    method: bool,
+    /// The start of every argument.
+    args: Vec<Position>,
+}
+
+/// Returns the calls whose callee is named in `range` of `text`.
+pub(crate) fn calls(text: &str, range: Range) -> Vec<Call> {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let index = LineIndex::new(text);
+    let tokens = find_chars(text, &['(', ')', '[', ']', '{', '}', ',']);
+
+    let mut calls = vec![];
+    for (t, &(open, c)) in tokens.iter().enumerate() {
+        if c != '(' {
+            continue;
+        }
+        let before = &text[..open];
+        let name_start = before.trim_end_matches(is_ident).len();
+        let name = &before[name_start..];
+        if name.is_empty() || name.starts_with(|c: char| c.is_numeric()) {
+            continue;
+        }
+        // Not a call, but the definition of a function.
+        if before[..name_start].trim_end().rsplit(|c: char| !is_ident(c)).next() == Some("fn") {
+            continue;
+        }
+        let callee = Range {
+            start: index.offset_to_lsp_position(name_start),
+            end: index.offset_to_lsp_position(open),
+        };
+        if !range.overlaps(&callee) {
+            continue;
+        }
+
+        let mut args = vec![];
+        let mut arg_start = open + 1;
+        let mut depth = 0;
+        let mut closed = false;
+        for &(i, c) in &tokens[t + 1..] {
+            match c {
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' if depth > 0 => depth -= 1,
+                ',' if depth == 0 => {
+                    args.push((arg_start, i));
+                    arg_start = i + 1;
+                }
+                ',' => {}
+                _ => {
+                    args.push((arg_start, i));
+                    closed = c == ')';
+                    break;
+                }
+            }
+        }
+        if !closed {
+            continue;
+        }
+
+        let args = args
+            .into_iter()
+            .filter_map(|(start, end)| {
+                let arg = &text[start..end];
+                let trimmed = arg.trim_start();
+                if trimmed.trim_end().is_empty() {
+                    return None;
+                }
+                Some(index.offset_to_lsp_position(start + arg.len() - trimmed.len()))
+            })
+            .collect();
+        calls.push(Call { callee, method: before[..name_start].ends_with('.'), args });
+    }
+    calls
+}
+
+/// Returns the hints naming the arguments of `call` after the parameters in
+/// `signature`, the signature of the callee. Calls with less than two
+/// arguments don't need them.
+fn parameter_hints(call: &Call, signature: &str) -> Vec<InlayHint> {
+    if call.args.len() < 2 {
+        return vec![];
+    }
+    let mut params = match signature_params(signature) {
+        Some(params) => params,
+        None => return vec![],
+    };
+    if call.method && params.first().map_or(false, |param| is_self_param(param)) {
+        params.remove(0);
+    }
+    // E.g. a closure taking several arguments, whose commas were mistaken for
+    // the ones between the arguments of the call.
+    if params.len() != call.args.len() {
+        return vec![];
+    }
+
+    params
+        .into_iter()
+        .zip(&call.args)
+        .filter_map(|(param, &position)| {
+            let name = param.trim_start_matches("mut ").trim();
+            // Patterns, e.g. `(x, y)`, and unused parameters aren't worth a hint.
+            if name.starts_with('_') || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                return None;
+            }
+            Some(InlayHint {
+                position,
+                label: format!("{}:", name),
+                kind: Some(InlayHintKind::PARAMETER),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: (u64, u64), end: (u64, u64)) -> Range {
+        Range { start: Position::new(start.0, start.1), end: Position::new(end.0, end.1) }
+    }
+
+    #[test]
+    fn type_hints_of_bindings() {
+        let text = "fn main() {\n    let x = 1;\n    let y: u8 = 2;\n}\n";
+        let bindings = vec![
+            ("x".to_owned(), range((1, 8), (1, 9)), "i32".to_owned()),
+            ("y".to_owned(), range((2, 8), (2, 9)), "u8".to_owned()),
+        ];
+        assert_eq!(
+            type_hints(text, bindings),
+            vec![InlayHint {
+                position: Position::new(1, 9),
+                label: ": i32".to_owned(),
+                kind: Some(InlayHintKind::TYPE),
+            }]
+        );
+    }
+
+    #[test]
+    fn parameter_hints_of_calls() {
+        let text = "\
+fn foo(a: u32, b: u32) {}
+fn main() {
+    foo(1, (2));
+    bar(3);
+    x.baz(4, 5);
+}
+";
+        let calls = calls(text, range((0, 0), (6, 0)));
+        assert_eq!(
+            calls,
+            vec![
+                Call {
+                    callee: range((2, 4), (2, 7)),
+                    method: false,
+                    args: vec![Position::new(2, 8), Position::new(2, 11)],
+                },
+                Call {
+                    callee: range((3, 4), (3, 7)),
+                    method: false,
+                    args: vec![Position::new(3, 8)]
+                },
+                Call {
+                    callee: range((4, 6), (4, 9)),
+                    method: true,
+                    args: vec![Position::new(4, 10), Position::new(4, 13)],
+                },
+            ]
+        );
+
+        let hint = |line, character, label: &str| InlayHint {
+            position: Position::new(line, character),
+            label: label.to_owned(),
+            kind: Some(InlayHintKind::PARAMETER),
+        };
+        assert_eq!(
+            parameter_hints(&calls[0], "fn (a: u32, b: u32)"),
+            vec![hint(2, 8, "a:"), hint(2, 11, "b:")]
+        );
+        assert_eq!(parameter_hints(&calls[1], "fn (c: u32)"), vec![]);
+        assert_eq!(
+            parameter_hints(&calls[2], "fn (&self, mut d: u8, _e: u8)"),
+            vec![hint(4, 10, "d:")]
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! Inline values of `const` and `static` items.
+//!
+//! `textDocument/inlineValue` is meant for debuggers, to show the current
+//! values of variables. The RLS only analyses the code statically, so it
+//! provides the values of constant items whose initializer is a literal or an
+//! integer expression which can be folded.
+
+use std::convert::TryFrom;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use log::error;
+use regex::Regex;
+use rls_analysis::DefKind;
+use rls_vfs::FileContents;
+
+use crate::actions::InitActionContext;
+use crate::lsp_data::{ls_util, InlineValueText, Range, RangeExt};
+
+/// Returns the values of the constant items defined in `range` of `file`.
+pub fn collect_inline_values(
+    ctx: &InitActionContext,
+    file: &Path,
+    range: Range,
+) -> Vec<InlineValueText> {
+    let text = match ctx.vfs.load_file(file) {
+        Ok(FileContents::Text(text)) => text,
+        Ok(FileContents::Binary(_)) => return vec![],
+        Err(e) => {
+            error!("failed to collect inline values: {}", e);
+            return vec![];
+        }
+    };
+
+    let defs = ctx.analysis.symbols(file).unwrap_or_else(|_| vec![]);
+    let defs = defs
+        .into_iter()
+        .filter(|def| def.kind == DefKind::Const || def.kind == DefKind::Static)
+        .map(|def| (def.name, ls_util::rls_to_range(def.span.range)))
+        .filter(|(_, name_range)| range.overlaps(name_range))
+        .collect();
+
+    const_values(&text, defs)
+}
+
+/// Returns the values of the items named at `defs`, shown after their names.
+fn const_values(text: &str, defs: Vec<(String, Range)>) -> Vec<InlineValueText> {
+    let lines: Vec<_> = text.lines().collect();
+    defs.into_iter()
+        .filter_map(|(name, range)| {
+            let value = initializer(&lines, &name, range)?;
+            Some(InlineValueText { range, text: fold_constant(value)? })
+        })
+        .collect()
+}
+
+/// Returns the initializer of the item named `name` at `range` of `lines`,
+/// e.g. `1 << 4` for `const X: u32 = 1 << 4;`. Only initializers on the same
+/// line as the name are found.
+pub(super) fn initializer<'a>(lines: &[&'a str], name: &str, range: Range) -> Option<&'a str> {
+    lazy_static! {
+        static ref INITIALIZER_RE: Regex = Regex::new(r"^\s*:[^=;]*=\s*(?P<value>[^;]*);").unwrap();
+    }
+
+    let line = *lines.get(range.start.line as usize)?;
+    let start = line
+        .char_indices()
+        .nth(range.start.character as usize)
+        .map(|(i, _)| i)
+        .unwrap_or_else(|| line.len());
+    if !line[start..].starts_with(name) {
+        return None;
+    }
+    let value = INITIALIZER_RE.captures(&line[start + name.len()..])?.name("value")?;
+    Some(value.as_str())
+}
+
+/// Returns the value of a constant expression, if it's a literal or an
+/// integer expression of literals, e.g., `(1 << 4) - 1`.
+pub(super) fn fold_constant(expr: &str) -> Option<String> {
+    lazy_static! {
+        static ref LITERAL_RE: Regex = Regex::new(
+            r#"^(true|false|'([^'\\]|\\.[^']*)'|"([^"\\]|\\.)*"|\d[\d_]*\.\d[\d_]*(e[+-]?\d+)?(f32|f64)?)$"#
+        )
+        .unwrap();
+    }
+
+    let expr = expr.trim();
+    if LITERAL_RE.is_match(expr) {
+        return Some(expr.to_owned());
+    }
+
+    let tokens = tokenize(expr)?;
+    let mut parser = IntParser { tokens: &tokens, pos: 0 };
+    let value = parser.expr()?;
+    if parser.pos == tokens.len() {
+        Some(value.to_string())
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Int(i128),
+    Op(char),
+}
+
+fn tokenize(expr: &str) -> Option<Vec<Token>> {
+    lazy_static! {
+        static ref INT_RE: Regex = Regex::new(
+            r"^(?:0x(?P<hex>[0-9a-fA-F_]+)|0o(?P<oct>[0-7_]+)|0b(?P<bin>[01_]+)|(?P<dec>\d[\d_]*))(?:[iu](?:8|16|32|64|128|size))?"
+        )
+        .unwrap();
+    }
+
+    let mut tokens = vec![];
+    let mut rest = expr.trim_start();
+    while !rest.is_empty() {
+        if rest.starts_with("<<") || rest.starts_with(">>") {
+            tokens.push(Token::Op(rest.as_bytes()[0] as char));
+            rest = &rest[2..];
+        } else if rest.starts_with(|c: char| "+-*/%()".contains(c)) {
+            tokens.push(Token::Op(rest.as_bytes()[0] as char));
+            rest = &rest[1..];
+        } else {
+            let caps = INT_RE.captures(rest)?;
+            let (digits, radix) = match (caps.name("hex"), caps.name("oct"), caps.name("bin")) {
+                (Some(hex), ..) => (hex, 16),
+                (_, Some(oct), _) => (oct, 8),
+                (.., Some(bin)) => (bin, 2),
+                _ => (caps.name("dec")?, 10),
+            };
+            let value = i128::from_str_radix(&digits.as_str().replace('_', ""), radix).ok()?;
+            tokens.push(Token::Int(value));
+            rest = &rest[caps.get(0)?.end()..];
+            // E.g., `1.5` or `x`, which aren't integer expressions.
+            if rest.starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '.') {
+                return None;
+            }
+        }
+        rest = rest.trim_start();
+    }
+    Some(tokens)
+}
+
+/// Evaluates integer expressions with Rust's operator precedence.
+struct IntParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl IntParser<'_> {
+    fn expr(&mut self) -> Option<i128> {
+        // `<<` and `>>` bind less tightly than `+` and `-`.
+        let mut lhs = self.sum()?;
+        while let Some(op @ '<') | Some(op @ '>') = self.peek_op() {
+            self.pos += 1;
+            let rhs = self.sum()?;
+            let rhs = u32::try_from(rhs).ok()?;
+            lhs = if op == '<' { lhs.checked_shl(rhs)? } else { lhs.checked_shr(rhs)? };
+        }
+        Some(lhs)
+    }
+
+    fn sum(&mut self) -> Option<i128> {
+        let mut lhs = self.product()?;
+        while let Some(op @ '+') | Some(op @ '-') = self.peek_op() {
+            self.pos += 1;
+            let rhs = self.product()?;
+            lhs = if op == '+' { lhs.checked_add(rhs)? } else { lhs.checked_sub(rhs)? };
+        }
+        Some(lhs)
+    }
+
+    fn product(&mut self) -> Option<i128> {
+        let mut lhs = self.unary()?;
+        while let Some(op @ '*') | Some(op @ '/') | Some(op @ '%') = self.peek_op() {
+            self.pos += 1;
+            let rhs = self.unary()?;
+            lhs = match op {
+                '*' => lhs.checked_mul(rhs)?,
+                '/' => lhs.checked_div(rhs)?,
+                _ => lhs.checked_rem(rhs)?,
+            };
+        }
+        Some(lhs)
+    }
+
+    fn unary(&mut self) -> Option<i128> {
+        match *self.tokens.get(self.pos)? {
+            Token::Op('-') => {
+                self.pos += 1;
+                self.unary()?.checked_neg()
+            }
+            Token::Op('(') => {
+                self.pos += 1;
+                let value = self.expr()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Op(')')) => {
+                        self.pos += 1;
+                        Some(value)
+                    }
+                    _ => None,
+                }
+            }
+            Token::Int(value) => {
+                self.pos += 1;
+                Some(value)
+            }
+            Token::Op(_) => None,
+        }
+    }
+
+    fn peek_op(&self) -> Option<char> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(*op),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsp_data::Position;
+
+    fn name_range(line: u64, start: u64, name: &str) -> Range {
+        Range {
+            start: Position::new(line, start),
+            end: Position::new(line, start + name.len() as u64),
+        }
+    }
+
+    #[test]
+    fn inline_const_values() {
+        let text = "const X: i32 = 42;\n\
+                    pub static MASK: u32 = (1 << 4) - 0x1;\n\
+                    const NAME: &str = \"rls\";\n\
+                    const LEN: usize = NAME.len();\n";
+        let defs = vec![
+            ("X".to_owned(), name_range(0, 6, "X")),
+            ("MASK".to_owned(), name_range(1, 11, "MASK")),
+            ("NAME".to_owned(), name_range(2, 6, "NAME")),
+            ("LEN".to_owned(), name_range(3, 6, "LEN")),
+        ];
+
+        let values = const_values(text, defs);
+        assert_eq!(
+            values,
+            vec![
+                InlineValueText { range: name_range(0, 6, "X"), text: "42".to_owned() },
+                InlineValueText { range: name_range(1, 11, "MASK"), text: "15".to_owned() },
+                InlineValueText { range: name_range(2, 6, "NAME"), text: "\"rls\"".to_owned() },
+            ]
+        );
+    }
+
+    #[test]
+    fn fold_constants() {
+        assert_eq!(fold_constant("1_000 * 60 % 7").as_deref(), Some("1"));
+        assert_eq!(fold_constant("-(2 + 3) * 4u8").as_deref(), Some("-20"));
+        assert_eq!(fold_constant("0b1010 >> 1").as_deref(), Some("5"));
+        assert_eq!(fold_constant("1.5e3").as_deref(), Some("1.5e3"));
+        assert_eq!(fold_constant("'\\n'").as_deref(), Some("'\\n'"));
+        assert_eq!(fold_constant("1 / 0"), None);
+        assert_eq!(fold_constant("1 +"), None);
+        assert_eq!(fold_constant("u32::MAX"), None);
+        assert_eq!(fold_constant("2.max(3)"), None);
+    }
+}
@@ -0,0 +1,144 @@
+//! Code lenses, see `CodeLensRequest` and `CodeLensResolve`.
+//!
+//! The lenses are returned without a command, as counting the references to
+//! every function of a file would block the response. The command is filled in
+//! when the client resolves a lens, using the `CodeLensData` it carries.
+
+use std::path::Path;
+
+use rls_analysis::DefKind;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use url::Url;
+
+use crate::actions::run::{collect_run_actions, Cmd};
+use crate::actions::InitActionContext;
+use crate::lsp_data::{ls_util, parse_file_path, CodeLens, Command, Location, Range};
+
+/// What a code lens shows, stored in its `data` until it's resolved.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum CodeLensData {
+    /// Runs (or debugs) the test function below the lens.
+    RunTest { cmd: Cmd, debug: bool },
+    /// The references to the function named at the lens' range.
+    References { uri: Url },
+    /// The implementations of the trait named at the lens' range.
+    Implementations { uri: Url },
+}
+
+/// Returns the unresolved code lenses of `file`, identified by `uri`.
+pub fn collect_code_lenses(ctx: &InitActionContext, file: &Path, uri: &Url) -> Vec<CodeLens> {
+    let lens = |range, data: CodeLensData| CodeLens {
+        range,
+        command: None,
+        data: Some(serde_json::to_value(data).unwrap()),
+    };
+
+    let mut lenses = vec![];
+    // Running tests is up to the client.
+    if ctx.client_supports_cmd_run {
+        for action in collect_run_actions(ctx, file) {
+            let range = ls_util::rls_to_range(action.target_element);
+            for &debug in &[false, true] {
+                lenses.push(lens(range, CodeLensData::RunTest { cmd: action.cmd.clone(), debug }));
+            }
+        }
+    }
+
+    for symbol in ctx.analysis.symbols(file).unwrap_or_else(|_| vec![]) {
+        let data = match symbol.kind {
+            DefKind::Function | DefKind::Method => CodeLensData::References { uri: uri.clone() },
+            DefKind::Trait => CodeLensData::Implementations { uri: uri.clone() },
+            _ => continue,
+        };
+        lenses.push(lens(ls_util::rls_to_range(symbol.span.range), data));
+    }
+    lenses
+}
+
+/// Fills in the command of `lens`.
+pub fn resolve_code_lens(ctx: &InitActionContext, mut lens: CodeLens) -> Result<CodeLens, String> {
+    let data = lens.data.clone().and_then(|data| serde_json::from_value(data).ok());
+    let command = match data {
+        Some(CodeLensData::RunTest { cmd, debug }) => test_command(cmd, debug),
+        Some(CodeLensData::References { uri }) => locations_command(ctx, uri, lens.range, false)?,
+        Some(CodeLensData::Implementations { uri }) => {
+            locations_command(ctx, uri, lens.range, true)?
+        }
+        None => return Err("unknown code lens".to_owned()),
+    };
+    lens.command = Some(command);
+    Ok(lens)
+}
+
+fn test_command(cmd: Cmd, debug: bool) -> Command {
+    Command {
+        title: if debug { "Debug test" } else { "Run test" }.to_owned(),
+        command: "rls.runTest".to_owned(),
+        arguments: Some(vec![serde_json::to_value(cmd).unwrap(), json!(debug)]),
+    }
+}
+
+/// Returns a command showing the references to (or the implementations of)
+/// the item named at `range` in the file identified by `uri`.
+fn locations_command(
+    ctx: &InitActionContext,
+    uri: Url,
+    range: Range,
+    implementations: bool,
+) -> Result<Command, String> {
+    let file = parse_file_path(&uri).map_err(|e| format!("invalid code lens: {}", e))?;
+    let range = ls_util::range_to_rls(range);
+    let symbol = ctx
+        .analysis
+        .symbols(&file)
+        .ok()
+        .and_then(|symbols| symbols.into_iter().find(|symbol| symbol.span.range == range))
+        .ok_or_else(|| "the item of the code lens no longer exists".to_owned())?;
+
+    let (title, spans) = if implementations {
+        ("Implementations", ctx.analysis.find_impls(symbol.id).unwrap_or_else(|_| vec![]))
+    } else {
+        let mut spans = ctx.analysis.find_all_refs_by_id(symbol.id).unwrap_or_else(|_| vec![]);
+        spans.retain(|span| *span != symbol.span);
+        ("References", spans)
+    };
+    let locations: Vec<Location> = spans.iter().map(ls_util::rls_to_location).collect();
+
+    Ok(Command {
+        title: format!("{}: {}", title, locations.len()),
+        command: "editor.action.showReferences".to_owned(),
+        arguments: Some(vec![
+            json!(uri),
+            json!(ls_util::rls_to_range(range).start),
+            json!(locations),
+        ]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_test_lens_data() {
+        let cmd = Cmd {
+            binary: "cargo".to_owned(),
+            args: vec!["test".to_owned(), "--".to_owned(), "test_foo".to_owned()],
+            env: Default::default(),
+        };
+        let data = serde_json::to_value(CodeLensData::RunTest { cmd: cmd.clone(), debug: true });
+        let data = data.unwrap();
+        assert_eq!(data["kind"], "runTest");
+        assert_eq!(
+            serde_json::from_value::<CodeLensData>(data).unwrap(),
+            CodeLensData::RunTest { cmd: cmd.clone(), debug: true }
+        );
+
+        let command = test_command(cmd, true);
+        assert_eq!(command.title, "Debug test");
+        assert_eq!(command.command, "rls.runTest");
+        assert_eq!(command.arguments.unwrap()[1], json!(true));
+    }
+}
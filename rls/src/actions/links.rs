@@ -0,0 +1,185 @@
+//! Document links, pointing `extern crate` items to the documentation of the
+//! crate on docs.rs.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use log::{error, trace};
+use regex::Regex;
+use rls_vfs::FileContents;
+use semver::Version;
+use serde_derive::Deserialize;
+use url::Url;
+
+use crate::actions::InitActionContext;
+use crate::lsp_data::{DocumentLink, Position, Range};
+
+/// Versions of the packages recorded in a `Cargo.lock` file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CargoLock {
+    versions: HashMap<String, Version>,
+}
+
+impl CargoLock {
+    /// Parses the contents of a `Cargo.lock` file. If there are multiple
+    /// versions of a package, the newest one is recorded.
+    pub fn parse(text: &str) -> Option<CargoLock> {
+        #[derive(Debug, Deserialize)]
+        struct Lockfile {
+            #[serde(default)]
+            package: Vec<LockedPackage>,
+        }
+        #[derive(Debug, Deserialize)]
+        struct LockedPackage {
+            name: String,
+            version: String,
+        }
+
+        let lockfile: Lockfile = match toml::from_str(text) {
+            Ok(lockfile) => lockfile,
+            Err(e) => {
+                error!("failed to parse Cargo.lock: {}", e);
+                return None;
+            }
+        };
+
+        let mut versions: HashMap<String, Version> = HashMap::new();
+        for package in lockfile.package {
+            let version = match Version::parse(&package.version) {
+                Ok(version) => version,
+                Err(_) => continue,
+            };
+            let newest = versions.entry(package.name).or_insert_with(|| version.clone());
+            if *newest < version {
+                *newest = version;
+            }
+        }
+
+        Some(CargoLock { versions })
+    }
+
+    pub fn load(path: &Path) -> Option<CargoLock> {
+        CargoLock::parse(&std::fs::read_to_string(path).ok()?)
+    }
+
+    /// Returns the locked version of the package providing the crate `name`.
+    /// Package names may use dashes where the crate names use underscores.
+    pub fn version(&self, crate_name: &str) -> Option<&Version> {
+        self.versions.get(crate_name).or_else(|| self.versions.get(&crate_name.replace('_', "-")))
+    }
+}
+
+/// Returns links to docs.rs for every `extern crate` item in `file`.
+pub fn collect_document_links(ctx: &InitActionContext, file: &Path) -> Vec<DocumentLink> {
+    let text = match ctx.vfs.load_file(file) {
+        Ok(FileContents::Text(text)) => text,
+        Ok(FileContents::Binary(_)) => return vec![],
+        Err(e) => {
+            error!("failed to collect document links: {}", e);
+            return vec![];
+        }
+    };
+
+    let mut cargo_lock = ctx.cargo_lock.lock().unwrap();
+    if cargo_lock.is_none() {
+        *cargo_lock = CargoLock::load(&ctx.current_project.join("Cargo.lock"));
+    }
+    match *cargo_lock {
+        Some(ref cargo_lock) => extern_crate_links(&text, cargo_lock),
+        None => {
+            trace!("collect_document_links: no Cargo.lock in {:?}", ctx.current_project);
+            vec![]
+        }
+    }
+}
+
+fn extern_crate_links(text: &str, cargo_lock: &CargoLock) -> Vec<DocumentLink> {
+    lazy_static! {
+        static ref EXTERN_CRATE_RE: Regex =
+            Regex::new(r"^\s*(pub(\([^)]*\))?\s+)?extern\s+crate\s+(?P<name>\w+)").unwrap();
+    }
+
+    text.lines()
+        .enumerate()
+        .filter_map(|(row, line)| {
+            let name = EXTERN_CRATE_RE.captures(line)?.name("name")?;
+            // Crates without a locked version (e.g., `std`) are not on docs.rs.
+            let version = cargo_lock.version(name.as_str())?;
+            let target =
+                Url::parse(&format!("https://docs.rs/{}/{}", name.as_str(), version)).ok()?;
+
+            let col = |byte_idx: usize| line[..byte_idx].chars().count() as u64;
+            let range = Range {
+                start: Position::new(row as u64, col(name.start())),
+                end: Position::new(row as u64, col(name.end())),
+            };
+            Some(DocumentLink { range, target })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARGO_LOCK: &str = r#"
+[[package]]
+name = "serde_json"
+version = "1.0.44"
+dependencies = [
+ "serde",
+]
+
+[[package]]
+name = "serde"
+version = "1.0.104"
+
+[[package]]
+name = "rand"
+version = "0.6.5"
+
+[[package]]
+name = "rand"
+version = "0.7.3"
+
+[[package]]
+name = "lazy-static"
+version = "1.4.0"
+"#;
+
+    #[test]
+    fn parse_cargo_lock() {
+        let cargo_lock = CargoLock::parse(CARGO_LOCK).unwrap();
+        assert_eq!(cargo_lock.version("serde"), Some(&Version::new(1, 0, 104)));
+        assert_eq!(cargo_lock.version("rand"), Some(&Version::new(0, 7, 3)));
+        assert_eq!(cargo_lock.version("lazy_static"), Some(&Version::new(1, 4, 0)));
+        assert_eq!(cargo_lock.version("std"), None);
+
+        assert!(CargoLock::parse("[[package]\n").is_none());
+    }
+
+    #[test]
+    fn links_to_extern_crates() {
+        let cargo_lock = CargoLock::parse(CARGO_LOCK).unwrap();
+        let text = "extern crate std;\n\
+                    extern crate serde_json;\n\
+                    \n\
+                    pub(crate) extern crate lazy_static;\n\
+                    // extern crate rand;\n";
+
+        let links = extern_crate_links(text, &cargo_lock);
+        assert_eq!(links.len(), 2);
+
+        assert_eq!(links[0].target.as_str(), "https://docs.rs/serde_json/1.0.44");
+        assert_eq!(
+            links[0].range,
+            Range { start: Position::new(1, 13), end: Position::new(1, 23) }
+        );
+        assert_eq!(links[1].target.as_str(), "https://docs.rs/lazy_static/1.4.0");
+        assert_eq!(
+            links[1].range,
+            Range { start: Position::new(3, 24), end: Position::new(3, 35) }
+        );
+    }
+}
@@ -1,34 +1,42 @@
 //! Actions that the RLS can perform: responding to requests, watching files,
 //! etc.
 
-use crate::config::Config;
 use crate::config::FmtConfig;
+use crate::config::{Config, StaleAnalysis};
 use crate::Span;
+use jsonrpc_core::types::ErrorCode;
 use log::{debug, error, info, trace};
 use rls_analysis::AnalysisHost;
-use rls_span as span;
-use rls_vfs::{FileContents, Vfs};
+use rls_vfs::{FileContents, PositionEncoding, Vfs};
 use serde_json::{self, json};
 use url::Url;
 use walkdir::WalkDir;
 
 use crate::actions::format::Rustfmt;
-use crate::actions::post_build::{AnalysisQueue, BuildResults, PostBuildHandler};
-use crate::actions::progress::{BuildDiagnosticsNotifier, BuildProgressNotifier};
+use crate::actions::links::CargoLock;
+use crate::actions::post_build::{
+    AnalysisQueue, BuildCommand, BuildResults, PendingSuggestions, PostBuildHandler,
+};
+use crate::actions::progress::{
+    report_build_queue_status, BuildDiagnosticsNotifier, BuildProgressNotifier,
+};
+use crate::actions::requests::PendingCodeAction;
 use crate::build::*;
 use crate::concurrency::{ConcurrentJob, Jobs};
 use crate::lsp_data;
+use crate::lsp_data::notification::{LogMessage, ShowMessage};
 use crate::lsp_data::*;
 use crate::project_model::{ProjectModel, RacerFallbackModel, RacerProjectModel};
-use crate::server::Output;
+use crate::server::{Notification, Output, ResponseError};
 
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 // TODO: Support non-`file` URI schemes in VFS. We're currently ignoring them because
 // we don't want to crash the RLS in case a client opens a file under different URI scheme
@@ -48,14 +56,27 @@ macro_rules! parse_file_path {
     };
 }
 
+pub mod breadcrumbs;
+pub mod call_hierarchy;
 pub mod diagnostics;
+pub mod document_colors;
+pub mod extract;
+pub mod folding;
 pub mod format;
 pub mod hover;
+pub mod inlay_hints;
+pub mod inline_values;
+pub mod lens;
+pub mod links;
 pub mod notifications;
+pub mod on_type_formatting;
+pub mod organize_imports;
 pub mod post_build;
 pub mod progress;
 pub mod requests;
 pub mod run;
+pub mod selection_range;
+pub mod signature_help;
 pub mod work_pool;
 
 /// Persistent context shared across all requests and notifications.
@@ -90,6 +111,7 @@ impl ActionContext {
                     Arc::clone(&uninit.analysis),
                     Arc::clone(&uninit.vfs),
                     Arc::clone(&uninit.config),
+                    Arc::clone(&uninit.build_scheduler),
                     client_capabilities,
                     current_project,
                     uninit.pid,
@@ -120,6 +142,19 @@ impl ActionContext {
     }
 }
 
+/// How requests relying on the analysis data are served while a build is
+/// running, i.e., while the data may not reflect the latest edits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StalenessPolicy {
+    /// Serve the request from the data of the last build.
+    ReturnStaleData,
+    /// Wait for the build to finish, for at most the given duration, and serve
+    /// the request from the stale data if it doesn't.
+    WaitForBuild(Duration),
+    /// Respond with a `ContentModified` error, so the client may retry later.
+    ReturnError,
+}
+
 /// Persistent context shared across all requests and actions after the RLS has
 /// been initialized.
 #[derive(Clone)]
@@ -131,16 +166,26 @@ pub struct InitActionContext {
 
     current_project: PathBuf,
     project_model: Arc<Mutex<Option<Arc<ProjectModel>>>>,
+    // Parsed `Cargo.lock` of the current project, loaded on first use.
+    cargo_lock: Arc<Mutex<Option<CargoLock>>>,
 
     previous_build_results: Arc<Mutex<BuildResults>>,
+    pending_suggestions: Arc<Mutex<PendingSuggestions>>,
+    // Code actions sent without an edit, waiting to be resolved by the client.
+    pending_code_actions: Arc<Mutex<HashMap<u64, PendingCodeAction>>>,
+    next_code_action_id: Arc<AtomicUsize>,
     build_queue: BuildQueue,
     file_to_crates: Arc<Mutex<HashMap<PathBuf, HashSet<Crate>>>>,
+    hover_cache: Arc<Mutex<hover::HoverCache>>,
     // Keep a record of builds/post-build tasks currently in flight so that
     // mutating actions can block until the data is ready.
     active_build_count: Arc<AtomicUsize>,
     // Whether we've shown an error message from Cargo since the last successful
     // build.
     shown_cargo_error: Arc<AtomicBool>,
+    // Memory use when the analysis caches were last cleared for exceeding
+    // `max_analysis_memory_mb`, see `PostBuildHandler::enforce_memory_limit`.
+    memory_cleared_at_mb: Arc<AtomicU64>,
     // Set to true when a potentially mutating request is received. Set to false
     // if a change arrives. We can thus tell if the RLS has been quiescent while
     // waiting to mutate the client state.
@@ -161,12 +206,18 @@ pub struct InitActionContext {
     pub pid: u32,
 }
 
+/// The LSP `ContentModified` error code.
+const CONTENT_MODIFIED_CODE: ErrorCode = ErrorCode::ServerError(-32801);
+
 /// Persistent context shared across all requests and actions before the RLS has
 /// been initialized.
 pub struct UninitActionContext {
     analysis: Arc<AnalysisHost>,
     vfs: Arc<Vfs>,
     config: Arc<Mutex<Config>>,
+    // Runs the builds of the project once initialized, shared so that the builds
+    // of several projects don't run concurrently.
+    build_scheduler: Arc<SharedBuildScheduler>,
     pid: u32,
 }
 
@@ -176,7 +227,13 @@ impl UninitActionContext {
         vfs: Arc<Vfs>,
         config: Arc<Mutex<Config>>,
     ) -> UninitActionContext {
-        UninitActionContext { analysis, vfs, config, pid: ::std::process::id() }
+        UninitActionContext {
+            analysis,
+            vfs,
+            config,
+            build_scheduler: Arc::default(),
+            pid: ::std::process::id(),
+        }
     }
 }
 
@@ -185,12 +242,14 @@ impl InitActionContext {
         analysis: Arc<AnalysisHost>,
         vfs: Arc<Vfs>,
         config: Arc<Mutex<Config>>,
+        build_scheduler: Arc<SharedBuildScheduler>,
         client_capabilities: lsp_data::ClientCapabilities,
         current_project: PathBuf,
         pid: u32,
         client_supports_cmd_run: bool,
     ) -> InitActionContext {
-        let build_queue = BuildQueue::new(Arc::clone(&vfs), Arc::clone(&config));
+        let build_queue =
+            BuildQueue::with_scheduler(Arc::clone(&vfs), Arc::clone(&config), build_scheduler);
         let analysis_queue = Arc::new(AnalysisQueue::init());
         InitActionContext {
             analysis,
@@ -200,11 +259,17 @@ impl InitActionContext {
             jobs: Arc::default(),
             current_project,
             project_model: Arc::default(),
+            cargo_lock: Arc::default(),
             previous_build_results: Arc::default(),
+            pending_suggestions: Arc::default(),
+            pending_code_actions: Arc::default(),
+            next_code_action_id: Arc::default(),
             build_queue,
             file_to_crates: Arc::default(),
+            hover_cache: Arc::default(),
             active_build_count: Arc::new(AtomicUsize::new(0)),
             shown_cargo_error: Arc::new(AtomicBool::new(false)),
+            memory_cleared_at_mb: Arc::default(),
             quiescent: Arc::new(AtomicBool::new(false)),
             prev_changes: Arc::default(),
             client_capabilities: Arc::new(client_capabilities),
@@ -215,8 +280,18 @@ impl InitActionContext {
         }
     }
 
+    /// Stores that the server is shutting down and waits for the build in
+    /// progress, if any, discarding pending ones.
+    pub fn shutdown(&self) {
+        self.shut_down.store(true, Ordering::SeqCst);
+        self.build_queue.drain();
+    }
+
+    /// Drops the project model after the manifests changed, and starts
+    /// fetching the metadata the next Cargo build needs meanwhile.
     pub fn invalidate_project_model(&self) {
         *self.project_model.lock().unwrap() = None;
+        self.build_queue.pre_warm_metadata(&self.current_project);
     }
 
     pub fn project_model(&self) -> Result<Arc<ProjectModel>, anyhow::Error> {
@@ -314,17 +389,36 @@ impl InitActionContext {
 
         if needs_inference {
             let config = Arc::clone(&self.config);
+            let out = out.clone();
             // Spawn another thread since we're shelling out to Cargo and this can
             // cause a non-trivial amount of time due to disk access
-            thread::spawn(move || {
-                let mut config = config.lock().unwrap();
-                if let Err(e) = config.infer_defaults(&current_project) {
-                    debug!("Encountered an error while trying to infer config defaults: {:?}", e);
+            thread::spawn(move || infer_config_defaults(&config, &current_project, &out));
+        }
+
+        // Incompatible Cargo versions lead to rather opaque build errors, so
+        // warn about these upfront.
+        thread::spawn({
+            let out = out.clone();
+            move || {
+                if let Err(message) = check_cargo_version() {
+                    out.notify(Notification::<ShowMessage>::new(ShowMessageParams {
+                        typ: MessageType::Warning,
+                        message,
+                    }));
                 }
-            });
+            }
+        });
+
+        if self.client_capabilities.work_done_progress_support {
+            let statuses = self.build_queue.status_receiver();
+            let out = out.clone();
+            thread::spawn(move || report_build_queue_status(statuses, &out));
         }
 
         if !init_options.omit_init_build {
+            // Cargo needs the metadata only after loading the workspace, so
+            // fetch it meanwhile.
+            self.build_queue.pre_warm_metadata(&self.current_project);
             self.build_current_project(BuildPriority::Cargo, out);
         }
     }
@@ -333,26 +427,59 @@ impl InitActionContext {
         let (job, token) = ConcurrentJob::new();
         self.add_job(job);
 
-        let pbh = {
+        let (pbh, project_name) = {
             let config = self.config.lock().unwrap();
-            PostBuildHandler {
+            let project_name = config.project_name.clone().unwrap_or_else(|| {
+                project_path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            });
+            let pbh = PostBuildHandler {
                 analysis: Arc::clone(&self.analysis),
                 analysis_queue: Arc::clone(&self.analysis_queue),
                 previous_build_results: Arc::clone(&self.previous_build_results),
+                pending_suggestions: Arc::clone(&self.pending_suggestions),
                 file_to_crates: Arc::clone(&self.file_to_crates),
                 project_path: project_path.to_owned(),
                 show_warnings: config.show_warnings,
                 related_information_support: self.client_capabilities.related_information_support,
+                semantic_tokens_refresh_support: self
+                    .client_capabilities
+                    .semantic_tokens_refresh_support,
                 shown_cargo_error: Arc::clone(&self.shown_cargo_error),
                 active_build_count: Arc::clone(&self.active_build_count),
+                build_id: 0,
+                priority,
+                diagnostics_end: None,
                 crate_blacklist: config.crate_blacklist.as_ref().clone(),
+                sysroot_src: config.sysroot_src.clone(),
+                max_diagnostics_per_file: config.max_diagnostics_per_file,
+                auto_import_style: config.auto_import_style,
+                max_analysis_memory_mb: config.max_analysis_memory_mb,
+                memory_cleared_at_mb: Arc::clone(&self.memory_cleared_at_mb),
+                diagnostic_deduplicate_across_files: config.diagnostic_deduplicate_across_files,
+                hover_cache: Arc::clone(&self.hover_cache),
+                on_build_complete: config.on_build_complete.clone().map(|command| {
+                    let out = out.clone();
+                    BuildCommand {
+                        command,
+                        log: Box::new(move |message| {
+                            out.notify(Notification::<LogMessage>::new(LogMessageParams {
+                                typ: MessageType::Log,
+                                message,
+                            }))
+                        }),
+                    }
+                }),
                 notifier: Box::new(BuildDiagnosticsNotifier::new(out.clone())),
                 blocked_threads: vec![],
                 _token: token,
-            }
+            };
+            (pbh, project_name)
         };
 
-        let notifier = Box::new(BuildProgressNotifier::new(out.clone()));
+        let notifier = Box::new(BuildProgressNotifier::new(out.clone(), &project_name));
 
         self.active_build_count.fetch_add(1, Ordering::SeqCst);
         self.build_queue.request_build(project_path, priority, notifier, pbh);
@@ -362,6 +489,77 @@ impl InitActionContext {
         self.build(&self.current_project, priority, out);
     }
 
+    /// Runs Cargo for the current project, without reusing the results of the
+    /// last Cargo build. Used when the user or the client asks for a rebuild.
+    fn rebuild_current_project<O: Output>(&self, out: &O) {
+        self.build_queue.forget_last_cargo_build();
+        self.build_current_project(BuildPriority::Cargo, out);
+    }
+
+    /// Re-reads every file tracked by the VFS from disk and rebuilds the
+    /// project. Used after an external event (e.g. `git checkout`) changed
+    /// many files at once. Files with unsaved changes belong to the client
+    /// and are kept as they are.
+    pub fn on_vfs_reload<O: Output>(&self, out: &O) {
+        let paths: Vec<PathBuf> = self.vfs.get_cached_files().into_iter().map(|(p, _)| p).collect();
+        trace!("on_vfs_reload: {} files", paths.len());
+        self.hover_cache.lock().unwrap().clear();
+
+        for path in paths {
+            if !self.vfs.file_is_synced(&path).unwrap_or(false) {
+                trace!("on_vfs_reload: keeping unsaved changes of {:?}", path);
+                continue;
+            }
+            if let Err(e) = self.vfs.flush_file(&path).and_then(|()| self.vfs.load_file(&path)) {
+                debug!("on_vfs_reload: could not reload {:?}: {:?}", path, e);
+                continue;
+            }
+
+            let version = self.prev_changes.lock().unwrap().get(&path).cloned().unwrap_or(0);
+            self.build_queue.mark_file_dirty(path, version);
+        }
+
+        self.build_current_project(BuildPriority::Immediate, out);
+    }
+
+    /// Re-reads the configuration from the `rls.toml` file at the root of the
+    /// project, which overrides the one sent by the client.
+    pub fn reload_config_file(&self) -> Result<(), String> {
+        let path = self.current_project.join("rls.toml");
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        let value: toml::Value =
+            toml::from_str(&text).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+        let value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+
+        let mut unknowns = vec![];
+        let new_config =
+            Config::try_deserialize(&value, &mut HashMap::new(), &mut unknowns, &mut vec![])
+                .map_err(|()| format!("Invalid configuration in {}", path.display()))?;
+        if !unknowns.is_empty() {
+            debug!("Ignoring unknown options in {}: {:?}", path.display(), unknowns);
+        }
+
+        let mut config = self.config.lock().unwrap();
+        let needs_inference = new_config.needs_inference();
+        config.update(new_config);
+        if needs_inference {
+            if let Err(e) = config.infer_defaults(&self.current_project) {
+                debug!("Encountered an error while trying to infer config defaults: {:?}", e);
+            }
+        }
+        trace!("Reloaded config: {:?}", *config);
+        Ok(())
+    }
+
+    /// Forgets the diagnostics of the last build, returning the files they
+    /// were published for.
+    pub fn clear_diagnostics(&self) -> Vec<Url> {
+        self.pending_suggestions.lock().unwrap().clear();
+        let mut results = self.previous_build_results.lock().unwrap();
+        results.drain().filter_map(|(path, _)| Url::from_file_path(path).ok()).collect()
+    }
+
     pub fn add_job(&self, job: ConcurrentJob) {
         self.jobs.lock().unwrap().add(job);
     }
@@ -375,6 +573,40 @@ impl InitActionContext {
         self.build_queue.block_on_build();
     }
 
+    /// Applies the `staleness_policy` if a build is running, for requests which
+    /// are about to query the analysis data. Can be called from any thread.
+    pub fn wait_for_fresh_analysis(&self) -> Result<(), ResponseError> {
+        if self.build_ready() {
+            return Ok(());
+        }
+        match self.staleness_policy() {
+            StalenessPolicy::ReturnStaleData => Ok(()),
+            StalenessPolicy::WaitForBuild(timeout) => {
+                if !self.build_queue.wait_for_build(timeout) {
+                    debug!("build still running after {:?}, using stale data", timeout);
+                }
+                Ok(())
+            }
+            StalenessPolicy::ReturnError => Err(ResponseError::Message(
+                CONTENT_MODIFIED_CODE,
+                "The analysis data is being rebuilt".to_owned(),
+            )),
+        }
+    }
+
+    /// How requests are served while the analysis data may be stale, as set by
+    /// the `stale_analysis` options.
+    fn staleness_policy(&self) -> StalenessPolicy {
+        let config = self.config.lock().unwrap();
+        match config.stale_analysis {
+            StaleAnalysis::ReturnStaleData => StalenessPolicy::ReturnStaleData,
+            StaleAnalysis::WaitForBuild => {
+                StalenessPolicy::WaitForBuild(Duration::from_millis(config.stale_analysis_timeout))
+            }
+            StaleAnalysis::ReturnError => StalenessPolicy::ReturnError,
+        }
+    }
+
     /// Returns `true` if there are no builds pending or in progress.
     fn build_ready(&self) -> bool {
         self.build_queue.build_ready()
@@ -417,21 +649,67 @@ impl InitActionContext {
         prev_changes.remove(&file_path);
     }
 
+    /// Returns the commands to attach to a hover response at the given position,
+    /// or `None` if `hover_actions` are disabled.
+    fn hover_actions(&self, params: &TextDocumentPositionParams) -> Option<hover::HoverActions> {
+        if !self.config.lock().unwrap().hover_actions {
+            return None;
+        }
+
+        let file_path = parse_file_path!(&params.text_document.uri, "hover_actions").ok()?;
+        let span = self.convert_pos_to_span(file_path, params.position);
+        let doc_url = self.analysis.doc_url(&span).ok();
+
+        Some(hover::HoverActions { commands: hover::hover_commands(params, doc_url) })
+    }
+
+    /// Returns the file and the span of the identifier at the position of
+    /// `params`, for the requests which operate on the identifier under the
+    /// cursor.
+    pub fn get_identifier_at_position(
+        &self,
+        params: &TextDocumentPositionParams,
+    ) -> Result<(PathBuf, Span), ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "identifier_at_position")?;
+        let pos = ls_util::position_to_rls(params.position);
+        let (span, word) = self
+            .vfs
+            .word_at_position(&file_path, &pos, PositionEncoding::Utf16)
+            .ok_or_else(|| {
+                debug!("no identifier at {:?} {:?}", file_path, pos);
+                ResponseError::Empty
+            })?;
+        trace!("word: `{}`, span: {:?}", word, span);
+
+        Ok((file_path, span))
+    }
+
     fn convert_pos_to_span(&self, file_path: PathBuf, pos: Position) -> Span {
         trace!("convert_pos_to_span: {:?} {:?}", file_path, pos);
 
         let pos = ls_util::position_to_rls(pos);
-        let line = self.vfs.load_line(&file_path, pos.row).unwrap();
-        trace!("line: `{}`", line);
+        let (span, word) =
+            self.vfs.word_at_position(&file_path, &pos, PositionEncoding::Utf16).unwrap();
+        trace!("word: `{}`, span: {:?}", word, span);
 
-        let (start, end) = find_word_at_pos(&line, pos.col);
-        trace!("start: {}, end: {}", start.0, end.0);
+        span
+    }
+}
 
-        Span::from_positions(
-            span::Position::new(pos.row, start),
-            span::Position::new(pos.row, end),
-            file_path,
-        )
+/// Infers unspecified config values for the given project and shows any
+/// resulting config warnings to the user.
+fn infer_config_defaults<O: Output>(config: &Mutex<Config>, project_dir: &Path, out: &O) {
+    let mut config = config.lock().unwrap();
+    match config.infer_defaults(project_dir) {
+        Ok(warnings) => {
+            for message in warnings {
+                out.notify(Notification::<ShowMessage>::new(ShowMessageParams {
+                    typ: MessageType::Warning,
+                    message,
+                }));
+            }
+        }
+        Err(e) => debug!("Encountered an error while trying to infer config defaults: {:?}", e),
     }
 }
 
@@ -470,40 +748,6 @@ pub enum VersionOrdering {
     OutOfOrder,
 }
 
-/// Represents a text cursor between characters, pointing at the next character
-/// in the buffer.
-type Column = span::Column<span::ZeroIndexed>;
-
-/// Returns a text cursor range for a found word inside `line` at which `pos`
-/// text cursor points to. Resulting type represents a (`start`, `end`) range
-/// between `start` and `end` cursors.
-/// For example (4, 4) means an empty selection starting after first 4 characters.
-fn find_word_at_pos(line: &str, pos: Column) -> (Column, Column) {
-    let col = pos.0 as usize;
-    let is_ident_char = |c: char| c.is_alphanumeric() || c == '_';
-
-    let start = line
-        .chars()
-        .enumerate()
-        .take(col)
-        .filter(|&(_, c)| !is_ident_char(c))
-        .last()
-        .map(|(i, _)| i + 1)
-        .unwrap_or(0) as u32;
-
-    #[allow(clippy::filter_next)]
-    let end = line
-        .chars()
-        .enumerate()
-        .skip(col)
-        .filter(|&(_, c)| !is_ident_char(c))
-        .next()
-        .map(|(i, _)| i)
-        .unwrap_or(col) as u32;
-
-    (span::Column::new_zero_indexed(start), span::Column::new_zero_indexed(end))
-}
-
 /// Client file-watching request / filtering logic
 /// We want to watch workspace 'Cargo.toml', root 'Cargo.lock' & the root 'target' dir
 pub struct FileWatch {
@@ -597,37 +841,6 @@ impl FileWatch {
 mod test {
     use super::*;
 
-    #[test]
-    fn test_find_word_at_pos() {
-        fn assert_range(test_str: &'static str, range: (u32, u32)) {
-            assert!(test_str.chars().filter(|c| *c == '|').count() == 1);
-            let col = test_str.find('|').unwrap() as u32;
-            let line = test_str.replace('|', "");
-            let (start, end) = find_word_at_pos(&line, Column::new_zero_indexed(col));
-            let actual = (start.0, end.0);
-            assert_eq!(range, actual, "Assertion failed for {:?}", test_str);
-        }
-
-        assert_range("|struct Def {", (0, 6));
-        assert_range("stru|ct Def {", (0, 6));
-        assert_range("struct| Def {", (0, 6));
-
-        assert_range("struct |Def {", (7, 10));
-        assert_range("struct De|f {", (7, 10));
-        assert_range("struct Def| {", (7, 10));
-
-        assert_range("struct Def |{", (11, 11));
-
-        assert_range("|span::Position<T>", (0, 4));
-        assert_range(" |span::Position<T>", (1, 5));
-        assert_range("sp|an::Position<T>", (0, 4));
-        assert_range("span|::Position<T>", (0, 4));
-        assert_range("span::|Position<T>", (6, 14));
-        assert_range("span::Position|<T>", (6, 14));
-        assert_range("span::Position<|T>", (15, 16));
-        assert_range("span::Position<T|>", (15, 16));
-    }
-
     fn change(url: &str) -> FileEvent {
         FileEvent::new(Url::parse(url).unwrap(), FileChangeType::Changed)
     }
@@ -692,6 +905,67 @@ mod test {
         assert!(!watch.is_relevant_save_doc(&did_save("file:///c:/Cargo.toml")));
     }
 
+    fn building_context() -> InitActionContext {
+        let ctx = InitActionContext::new(
+            Arc::new(AnalysisHost::new(rls_analysis::Target::Debug)),
+            Arc::new(Vfs::new()),
+            <_>::default(),
+            <_>::default(),
+            <_>::default(),
+            "/some/dir".into(),
+            123,
+            false,
+        );
+        ctx.build_queue.set_building(true);
+        ctx
+    }
+
+    #[test]
+    fn staleness_policy_return_stale_data() {
+        let ctx = building_context();
+        assert!(ctx.wait_for_fresh_analysis().is_ok());
+        assert!(!ctx.build_ready());
+    }
+
+    #[test]
+    fn staleness_policy_wait_for_build() {
+        let ctx = building_context();
+        {
+            let mut config = ctx.config.lock().unwrap();
+            config.stale_analysis = StaleAnalysis::WaitForBuild;
+            config.stale_analysis_timeout = 10_000;
+        }
+        assert_eq!(ctx.staleness_policy(), StalenessPolicy::WaitForBuild(Duration::from_secs(10)));
+
+        let build_queue = ctx.build_queue.clone();
+        let finisher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            build_queue.set_building(false);
+        });
+        assert!(ctx.wait_for_fresh_analysis().is_ok());
+        assert!(ctx.build_ready());
+        finisher.join().unwrap();
+
+        // Stale data is served if the build takes too long.
+        ctx.build_queue.set_building(true);
+        ctx.config.lock().unwrap().stale_analysis_timeout = 10;
+        assert!(ctx.wait_for_fresh_analysis().is_ok());
+        assert!(!ctx.build_ready());
+    }
+
+    #[test]
+    fn staleness_policy_return_error() {
+        let ctx = building_context();
+        ctx.config.lock().unwrap().stale_analysis = StaleAnalysis::ReturnError;
+        match ctx.wait_for_fresh_analysis() {
+            Err(ResponseError::Message(code, _)) => assert_eq!(code, CONTENT_MODIFIED_CODE),
+            _ => panic!("expected a `ContentModified` error"),
+        }
+
+        ctx.build_queue.set_building(false);
+        assert!(ctx.wait_for_fresh_analysis().is_ok());
+    }
+
     #[test]
     fn explicit_edition_from_manifest() -> Result<(), std::io::Error> {
         use std::{fs::File, io::Write};
@@ -1,22 +1,25 @@
 //! One-way notifications that the RLS receives from the client.
 
-use crate::actions::{FileWatch, InitActionContext, VersionOrdering};
+use crate::actions::{infer_config_defaults, FileWatch, InitActionContext, VersionOrdering};
 use crate::Span;
-use log::{debug, trace, warn};
-use rls_vfs::{Change, VfsSpan};
+use log::{trace, warn};
+use rls_vfs::{Change, Vfs, VfsSpan};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use crate::build::*;
-use crate::lsp_data::request::{RangeFormatting, RegisterCapability, UnregisterCapability};
+use crate::lsp_data::request::{
+    DocumentLinkRequest, RangeFormatting, RegisterCapability, UnregisterCapability,
+};
 use crate::lsp_data::*;
 use crate::server::Request;
 use lsp_types::notification::ShowMessage;
 
 pub use crate::lsp_data::notification::{
     Cancel, DidChangeConfiguration, DidChangeTextDocument, DidChangeWatchedFiles,
-    DidOpenTextDocument, DidSaveTextDocument, Initialized,
+    DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument, Initialized,
 };
+pub use crate::lsp_data::{DidChangeWindowFocus, ReloadVfs};
 
 use crate::server::{BlockingNotificationAction, Notification, Output};
 
@@ -31,16 +34,41 @@ impl BlockingNotificationAction for Initialized {
         out: O,
     ) -> Result<(), ()> {
         const WATCH_ID: &str = "rls-watch";
+        const DOCUMENT_LINK_ID: &str = "rls-document-link";
+        const INLAY_HINT_ID: &str = "rls-inlay-hint";
+        const CALL_HIERARCHY_ID: &str = "rls-call-hierarchy";
+
+        let mut registrations = vec![Registration {
+            id: WATCH_ID.to_owned(),
+            method: <DidChangeWatchedFiles as LSPNotification>::METHOD.to_owned(),
+            register_options: Some(FileWatch::new(&ctx).watchers_config()),
+        }];
+        // Not part of the static `ServerCapabilities` in `lsp_types` yet, so
+        // these are advertised in the `initialize` response otherwise.
+        if ctx.client_capabilities.document_link_dynamic_registration {
+            registrations.push(Registration {
+                id: DOCUMENT_LINK_ID.to_owned(),
+                method: <DocumentLinkRequest as LSPRequest>::METHOD.to_owned(),
+                register_options: None,
+            });
+        }
+        if ctx.client_capabilities.inlay_hint_dynamic_registration {
+            registrations.push(Registration {
+                id: INLAY_HINT_ID.to_owned(),
+                method: <InlayHintRequest as LSPRequest>::METHOD.to_owned(),
+                register_options: None,
+            });
+        }
+        if ctx.client_capabilities.call_hierarchy_dynamic_registration {
+            registrations.push(Registration {
+                id: CALL_HIERARCHY_ID.to_owned(),
+                method: <CallHierarchyPrepare as LSPRequest>::METHOD.to_owned(),
+                register_options: None,
+            });
+        }
 
         let id = out.provide_id();
-        let params = RegistrationParams {
-            registrations: vec![Registration {
-                id: WATCH_ID.to_owned(),
-                method: <DidChangeWatchedFiles as LSPNotification>::METHOD.to_owned(),
-                register_options: Some(FileWatch::new(&ctx).watchers_config()),
-            }],
-        };
-
+        let params = RegistrationParams { registrations };
         let request = Request::<RegisterCapability>::new(id, params);
         out.request(request);
         Ok(())
@@ -61,6 +89,18 @@ impl BlockingNotificationAction for DidOpenTextDocument {
     }
 }
 
+impl BlockingNotificationAction for DidCloseTextDocument {
+    fn handle<O: Output>(
+        params: Self::Params,
+        ctx: &mut InitActionContext,
+        out: O,
+    ) -> Result<(), ()> {
+        trace!("on_close: {:?}", params.text_document.uri);
+        ctx.build_current_project(BuildPriority::Idle, &out);
+        Ok(())
+    }
+}
+
 impl BlockingNotificationAction for DidChangeTextDocument {
     fn handle<O: Output>(
         params: Self::Params,
@@ -110,6 +150,7 @@ impl BlockingNotificationAction for DidChangeTextDocument {
             .collect();
         ctx.vfs.on_changes(&changes).expect("error committing to VFS");
 
+        ctx.hover_cache.lock().unwrap().invalidate_file(&file_path);
         ctx.build_queue.mark_file_dirty(file_path, version_num);
 
         if !ctx.config.lock().unwrap().build_on_save {
@@ -178,17 +219,9 @@ impl BlockingNotificationAction for DidChangeConfiguration {
             if needs_inference {
                 let project_dir = ctx.current_project.clone();
                 let config = Arc::clone(&ctx.config);
+                let out = out.clone();
                 // Will lock and access Config just outside the current scope
-                thread::spawn(move || {
-                    let mut config = config.lock().unwrap();
-                    if let Err(e) = config.infer_defaults(&project_dir) {
-                        debug!(
-                            "Encountered an error while trying to infer config \
-                             defaults: {:?}",
-                            e
-                        );
-                    }
-                });
+                thread::spawn(move || infer_config_defaults(&config, &project_dir, &out));
             }
         }
         // We do a clean build so that if we've changed any relevant options
@@ -236,11 +269,15 @@ impl BlockingNotificationAction for DidSaveTextDocument {
 
         ctx.vfs.file_saved(&file_path).unwrap();
 
-        if !ctx.client_use_change_watched && FileWatch::new(&ctx).is_relevant_save_doc(&params) {
+        // The corresponding workspace/didChangeWatchedFiles notification is
+        // ignored if the saved content matches the VFS, so handle it here.
+        let watch_ignores_save =
+            !ctx.client_use_change_watched || ctx.vfs.file_hash(&file_path).is_some();
+        if watch_ignores_save && FileWatch::new(&ctx).is_relevant_save_doc(&params) {
             // support manifest change rebuilding for client's that don't send
             // workspace/didChangeWatchedFiles notifications
-            ctx.build_current_project(BuildPriority::Cargo, &out);
             ctx.invalidate_project_model();
+            ctx.rebuild_current_project(&out);
         } else if ctx.config.lock().unwrap().build_on_save {
             ctx.build_current_project(BuildPriority::Normal, &out);
         }
@@ -260,21 +297,59 @@ impl BlockingNotificationAction for DidChangeWatchedFiles {
         ctx.client_use_change_watched = true;
         let file_watch = FileWatch::new(&ctx);
 
-        if params.changes.iter().any(|c| file_watch.is_relevant(c)) {
-            ctx.build_current_project(BuildPriority::Cargo, &out);
+        let is_relevant =
+            |c: &FileEvent| file_watch.is_relevant(c) && is_content_change(&ctx.vfs, c);
+        if params.changes.iter().any(is_relevant) {
             ctx.invalidate_project_model();
+            ctx.rebuild_current_project(&out);
         }
 
         Ok(())
     }
 }
 
+impl BlockingNotificationAction for DidChangeWindowFocus {
+    fn handle<O: Output>(
+        params: Self::Params,
+        ctx: &mut InitActionContext,
+        out: O,
+    ) -> Result<(), ()> {
+        trace!("on_window_focus: {}", params.focused);
+        ctx.build_current_project(BuildPriority::Idle, &out);
+        Ok(())
+    }
+}
+
+impl BlockingNotificationAction for ReloadVfs {
+    fn handle<O: Output>(_params: (), ctx: &mut InitActionContext, out: O) -> Result<(), ()> {
+        ctx.on_vfs_reload(&out);
+        Ok(())
+    }
+}
+
+/// Returns `false` if a file was changed on disk but its new content matches
+/// the one in the VFS, e.g. because it was just saved by the client.
+fn is_content_change(vfs: &Vfs, change: &FileEvent) -> bool {
+    if change.typ != FileChangeType::Changed {
+        return true;
+    }
+    let path = match change.uri.to_file_path() {
+        Ok(path) => path,
+        Err(_) => return true,
+    };
+
+    match (vfs.file_hash(&path), std::fs::read(&path)) {
+        (Some(hash), Ok(content)) => hash != rls_vfs::content_hash(&content),
+        _ => true,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::server::{Output, RequestId};
     use rls_analysis::{AnalysisHost, Target};
-    use rls_vfs::Vfs;
+    use rls_vfs::{FileContents, Vfs};
     use std::sync::Arc;
     use url::Url;
 
@@ -301,6 +376,7 @@ mod test {
             Arc::new(Vfs::new()),
             <_>::default(),
             <_>::default(),
+            <_>::default(),
             project_root.into(),
             123,
             false,
@@ -322,4 +398,110 @@ mod test {
 
         ctx.wait_for_concurrent_jobs();
     }
+
+    #[test]
+    fn ignore_watched_change_matching_vfs() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("Cargo.toml");
+        std::fs::write(&manifest, "[package]\n").unwrap();
+        let change =
+            FileEvent::new(Url::from_file_path(&manifest).unwrap(), FileChangeType::Changed);
+
+        let vfs = Vfs::new();
+        // Not cached in the VFS, so we can't tell.
+        assert!(is_content_change(&vfs, &change));
+
+        vfs.set_file(&manifest, "[package]\n");
+        assert!(!is_content_change(&vfs, &change));
+
+        std::fs::write(&manifest, "[workspace]\n").unwrap();
+        assert!(is_content_change(&vfs, &change));
+    }
+
+    #[test]
+    fn reload_vfs_rereads_files_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        std::fs::write(&file, "fn old() {}\n").unwrap();
+
+        let vfs = Arc::new(Vfs::new());
+        let mut ctx = InitActionContext::new(
+            Arc::new(AnalysisHost::new(Target::Debug)),
+            Arc::clone(&vfs),
+            <_>::default(),
+            <_>::default(),
+            <_>::default(),
+            dir.path().to_owned(),
+            123,
+            false,
+        );
+
+        vfs.load_file(&file).unwrap();
+        std::fs::write(&file, "fn new() {}\n").unwrap();
+
+        ReloadVfs::handle((), &mut ctx, NoOutput).unwrap();
+        assert_eq!(vfs.load_file(&file).unwrap(), FileContents::Text("fn new() {}\n".to_owned()));
+
+        ctx.wait_for_concurrent_jobs();
+    }
+
+    #[test]
+    fn hover_cache_invalidated_on_change() {
+        use crate::actions::requests::{Hover, HoverResponse};
+        use crate::config::Config;
+        use crate::server::RequestAction;
+        use std::sync::Mutex;
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        std::fs::write(&file, "fn foo() {}\n").unwrap();
+        let uri = Url::from_file_path(&file).unwrap();
+
+        // Don't start a build for the change.
+        let config = Config { build_on_save: true, ..Config::default() };
+        let mut ctx = InitActionContext::new(
+            Arc::new(AnalysisHost::new(Target::Debug)),
+            Arc::new(Vfs::new()),
+            Arc::new(Mutex::new(config)),
+            <_>::default(),
+            <_>::default(),
+            dir.path().to_owned(),
+            123,
+            false,
+        );
+
+        let pos = Position::new(0, 3);
+        let params = TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier::new(uri.clone()),
+            position: pos,
+        };
+        let response = HoverResponse {
+            hover: crate::lsp_data::Hover { contents: HoverContents::Array(vec![]), range: None },
+            data: None,
+        };
+        ctx.hover_cache.lock().unwrap().insert(file.clone(), pos, response, 10);
+
+        // The analysis is empty, so only a cache hit can answer the request.
+        assert!(Hover::handle(ctx.clone(), params.clone()).is_ok());
+        assert!(Hover::handle(ctx.clone(), params.clone()).is_ok());
+
+        DidChangeTextDocument::handle(
+            DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier { uri, version: Some(1) },
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: "fn bar() {}\n".to_owned(),
+                }],
+            },
+            &mut ctx,
+            NoOutput,
+        )
+        .unwrap();
+
+        assert!(ctx.hover_cache.lock().unwrap().get(&file, pos).is_none());
+        assert!(Hover::handle(ctx.clone(), params).is_err());
+
+        ctx.wait_for_concurrent_jobs();
+    }
 }
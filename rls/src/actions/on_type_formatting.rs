@@ -0,0 +1,162 @@
+//! Formatting as the user types, see `OnTypeFormatting`.
+//!
+//! Typing `}` formats the block it closes with rustfmt. Typing `{` or `;` is
+//! far more frequent, so it only re-indents the current line to match the
+//! enclosing block, which is determined by matching the braces in the source
+//! text.
+
+use crate::actions::breadcrumbs::find_chars;
+use crate::actions::run::LineIndex;
+use crate::lsp_data::{FormattingOptions, Position, Range, TextEdit};
+
+/// Returns the lines of the block closed by the last `}` before `pos` on its
+/// line, i.e., from the line of the matching `{` to the line of the `}`.
+pub fn closed_block(text: &str, pos: Position) -> Option<Range> {
+    let index = LineIndex::new(text);
+    let line_start = index.lsp_position_to_offset(Position::new(pos.line, 0))?;
+    let offset = index.lsp_position_to_offset(pos)?;
+
+    let braces = braces(text);
+    let close = braces.iter().rposition(|&(i, c)| c == '}' && line_start <= i && i < offset)?;
+
+    let mut depth = 0;
+    for &(i, c) in braces[..close].iter().rev() {
+        match c {
+            '}' => depth += 1,
+            '{' if depth == 0 => {
+                return Some(Range {
+                    start: Position::new(index.offset_to_lsp_position(i).line, 0),
+                    end: Position::new(pos.line, 0),
+                });
+            }
+            '{' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns an edit re-indenting the line at `pos` one level deeper than the
+/// line opening the enclosing block (or to the level of that line, if the line
+/// closes the block). Lines continuing a statement, e.g. method chains or
+/// arguments, are left alone.
+pub fn reindent_line(text: &str, pos: Position, opts: &FormattingOptions) -> Option<TextEdit> {
+    let index = LineIndex::new(text);
+    let line_start = index.lsp_position_to_offset(Position::new(pos.line, 0))?;
+    let line = text[line_start..].lines().next().unwrap_or_default();
+    let content = line.trim_start();
+    if content.is_empty() {
+        return None;
+    }
+
+    // The previous line has to finish a statement or an item.
+    let previous = text[..line_start].trim_end();
+    if !previous.is_empty() && !previous.ends_with(|c: char| c == ';' || c == '{' || c == '}') {
+        return None;
+    }
+
+    let mut open = vec![];
+    for (i, c) in braces(text).into_iter().take_while(|&(i, _)| i < line_start) {
+        match c {
+            '{' | '(' | '[' => open.push((i, c)),
+            _ => {
+                open.pop();
+            }
+        }
+    }
+    let indent = match open.last() {
+        Some(&(_, c)) if c != '{' => return None,
+        Some(&(i, _)) => {
+            let open_line = &text[i - index.offset_to_position(i).col.0 as usize..];
+            let open_indent = &open_line[..open_line.len() - open_line.trim_start().len()];
+            if content.starts_with('}') {
+                open_indent.to_owned()
+            } else if opts.insert_spaces {
+                format!("{}{}", open_indent, " ".repeat(opts.tab_size as usize))
+            } else {
+                format!("{}\t", open_indent)
+            }
+        }
+        None => String::new(),
+    };
+
+    let current_indent = &line[..line.len() - content.len()];
+    if current_indent == indent {
+        return None;
+    }
+    Some(TextEdit {
+        range: Range {
+            start: Position::new(pos.line, 0),
+            end: Position::new(pos.line, current_indent.chars().count() as u64),
+        },
+        new_text: indent,
+    })
+}
+
+/// Returns the offsets of the braces, brackets and parentheses in `text`,
+/// skipping the ones in comments, strings and character literals.
+fn braces(text: &str) -> Vec<(usize, char)> {
+    find_chars(text, &['{', '}', '(', ')', '[', ']'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "\
+fn main() {
+    let x = 1;
+    if x > 0 {
+  println!(\"{}\", x);
+        }
+let y = vec![
+1];
+        }
+";
+
+    fn opts(insert_spaces: bool) -> FormattingOptions {
+        FormattingOptions { tab_size: 4, insert_spaces, properties: Default::default() }
+    }
+
+    #[test]
+    fn closed_blocks() {
+        assert_eq!(
+            closed_block(TEXT, Position::new(4, 9)),
+            Some(Range { start: Position::new(2, 0), end: Position::new(4, 0) })
+        );
+        // The `{}` in the string doesn't count.
+        assert_eq!(
+            closed_block(TEXT, Position::new(7, 9)),
+            Some(Range { start: Position::new(0, 0), end: Position::new(7, 0) })
+        );
+        assert_eq!(closed_block(TEXT, Position::new(4, 8)), None);
+        assert_eq!(closed_block("}\n", Position::new(0, 1)), None);
+    }
+
+    #[test]
+    fn reindent_lines() {
+        let edit =
+            |line, insert_spaces| reindent_line(TEXT, Position::new(line, 0), &opts(insert_spaces));
+
+        assert_eq!(edit(1, true), None);
+        assert_eq!(
+            edit(3, true),
+            Some(TextEdit {
+                range: Range { start: Position::new(3, 0), end: Position::new(3, 2) },
+                new_text: "        ".to_owned(),
+            })
+        );
+        assert_eq!(edit(3, false).unwrap().new_text, "    \t");
+        assert_eq!(
+            edit(4, true),
+            Some(TextEdit {
+                range: Range { start: Position::new(4, 0), end: Position::new(4, 8) },
+                new_text: "    ".to_owned(),
+            })
+        );
+        assert_eq!(edit(5, true).unwrap().new_text, "    ");
+        // Continues the previous line.
+        assert_eq!(edit(6, true), None);
+        assert_eq!(edit(7, true).unwrap().new_text, "");
+    }
+}
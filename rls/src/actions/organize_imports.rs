@@ -0,0 +1,178 @@
+//! Organizing imports, i.e., sorting and deduplicating the `use` items at the
+//! top of a file.
+//!
+//! Save-analysis doesn't record the extent of `use` items, so these are found
+//! in the source text. Only the first block of consecutive top-level `use`
+//! items is organized; blocks with comments or attributes in between are left
+//! alone, since it's unclear which items these belong to.
+
+use crate::lsp_data::{Position, Range, TextEdit};
+
+/// The groups `use` items are sorted into, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ImportGroup {
+    /// `std`, `core`, `alloc` and the other crates shipped with the compiler.
+    Std,
+    /// Other crates.
+    External,
+    /// Paths starting with `crate`, `self` or `super`.
+    Internal,
+}
+
+/// Returns an edit replacing the first block of `use` items in `text` with
+/// the same items grouped (std, external and internal ones, separated by an
+/// empty line), sorted and deduplicated. Returns `None` if there is nothing
+/// to organize or the items are organized already.
+pub fn organize_imports(text: &str) -> Option<TextEdit> {
+    let lines: Vec<_> = text.lines().collect();
+    let first = lines.iter().position(|line| is_use_item(line))?;
+
+    let mut items = vec![];
+    let mut row = first;
+    let mut last = first;
+    while row < lines.len() {
+        let line = lines[row];
+        if line.trim().is_empty() {
+            row += 1;
+            continue;
+        }
+        if !is_use_item(line) {
+            break;
+        }
+        // Items may span multiple lines, e.g. `use foo::{\n    bar,\n};`.
+        let start = row;
+        while !lines[row].trim_end().ends_with(';') {
+            row += 1;
+            if row == lines.len() {
+                return None;
+            }
+        }
+        let item = lines[start..=row].join("\n");
+        if item.contains("//") || item.contains("/*") || item.matches(';').count() > 1 {
+            return None;
+        }
+        items.push(item);
+        last = row;
+        row += 1;
+    }
+
+    let mut sorted: Vec<_> =
+        items.iter().map(|item| (import_group(item), sort_key(item), item)).collect();
+    sorted.sort();
+    sorted.dedup_by(|a, b| a.1 == b.1 && normalize(a.2) == normalize(b.2));
+
+    let mut new_text = String::new();
+    for (idx, (group, _, item)) in sorted.iter().enumerate() {
+        if idx > 0 {
+            new_text.push('\n');
+            if sorted[idx - 1].0 != *group {
+                new_text.push('\n');
+            }
+        }
+        new_text.push_str(item);
+    }
+
+    let range = Range {
+        start: Position::new(first as u64, 0),
+        end: Position::new(last as u64, lines[last].chars().count() as u64),
+    };
+    if new_text == lines[first..=last].join("\n") {
+        return None;
+    }
+    Some(TextEdit { range, new_text })
+}
+
+/// Returns `true` if `line` starts a top-level `use` item.
+fn is_use_item(line: &str) -> bool {
+    let rest = line.trim_end();
+    let rest = if rest.starts_with("pub ") || rest.starts_with("pub(") {
+        match rest.find(|c: char| c == ' ' || c == ')') {
+            Some(idx) if rest.as_bytes()[idx] == b')' => rest[idx + 1..].trim_start(),
+            Some(idx) => rest[idx..].trim_start(),
+            None => return false,
+        }
+    } else {
+        rest
+    };
+    rest.starts_with("use ")
+}
+
+/// Returns the path imported by a `use` item, e.g., `std::fmt` for
+/// `pub use ::std::fmt;`.
+fn import_path(item: &str) -> &str {
+    let path = &item[item.find("use ").map_or(0, |idx| idx + "use ".len())..];
+    let path = path.trim_start();
+    if path.starts_with("::") {
+        &path[2..]
+    } else {
+        path
+    }
+}
+
+fn import_group(item: &str) -> ImportGroup {
+    let path = import_path(item);
+    let root = path.split(|c: char| !(c.is_alphanumeric() || c == '_')).next().unwrap_or_default();
+    match root {
+        "std" | "core" | "alloc" | "proc_macro" | "test" => ImportGroup::Std,
+        "crate" | "self" | "super" => ImportGroup::Internal,
+        _ => ImportGroup::External,
+    }
+}
+
+/// Items are sorted by their paths, ignoring whitespace, e.g. `use foo::bar;`
+/// sorts before `pub use foo::baz;`.
+fn sort_key(item: &str) -> String {
+    normalize(import_path(item))
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn organize_unsorted_imports() {
+        let text = "//! Crate docs.\n\
+                    \n\
+                    use crate::config::Config;\n\
+                    use std::sync::Arc;\n\
+                    use serde_json::json;\n\
+                    use super::helpers;\n\
+                    \n\
+                    use std::collections::{\n    HashMap,\n    HashSet,\n};\n\
+                    use log::trace;\n\
+                    use std::sync::Arc;\n\
+                    pub use ::core::fmt;\n\
+                    \n\
+                    fn main() {}\n";
+
+        let edit = organize_imports(text).unwrap();
+        assert_eq!(edit.range, Range { start: Position::new(2, 0), end: Position::new(13, 20) });
+        assert_eq!(
+            edit.new_text,
+            "pub use ::core::fmt;\n\
+             use std::collections::{\n    HashMap,\n    HashSet,\n};\n\
+             use std::sync::Arc;\n\
+             \n\
+             use log::trace;\n\
+             use serde_json::json;\n\
+             \n\
+             use crate::config::Config;\n\
+             use super::helpers;"
+        );
+
+        // Organizing is idempotent.
+        let organized = format!("{}\n\nfn main() {{}}\n", edit.new_text);
+        assert_eq!(organize_imports(&organized), None);
+    }
+
+    #[test]
+    fn dont_organize_commented_imports() {
+        assert_eq!(organize_imports("fn main() {}\n"), None);
+        assert_eq!(organize_imports("use std::fmt;\n// Used by macros.\nuse log::trace;\n"), None);
+        assert_eq!(organize_imports("use std::fmt; // Debugging.\nuse log::trace;\n"), None);
+    }
+}
@@ -5,54 +5,117 @@
 use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::io::Read;
 use std::ops::Deref;
 use std::panic::RefUnwindSafe;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
 
-use crate::actions::diagnostics::{parse_diagnostics, Diagnostic, ParsedDiagnostics, Suggestion};
+use crate::actions::diagnostics::{
+    parse_diagnostics, parse_suggestion_edits, restyle_import_suggestions, Diagnostic,
+    ParsedDiagnostics, Suggestion,
+};
+use crate::actions::hover::HoverCache;
 use crate::actions::progress::DiagnosticsNotifier;
-use crate::build::{BuildResult, Crate};
+use crate::build::{BuildPriority, BuildResult, Crate};
 use crate::concurrency::JobToken;
-use crate::config::CrateBlacklist;
-use crate::lsp_data::{PublishDiagnosticsParams, Range};
+use crate::config::{AutoImportStyle, CrateBlacklist};
+use crate::lsp_data::{
+    BuildStatsParams, DiagnosticsBeginParams, DiagnosticsEndParams, PublishDiagnosticsParams,
+    Range, TextEdit,
+};
 
 use itertools::Itertools;
 use log::{trace, warn};
-use lsp_types::DiagnosticSeverity;
+use lsp_types::{DiagnosticSeverity, NumberOrString};
 use rls_analysis::AnalysisHost;
 use rls_data::Analysis;
 use url::Url;
 
 pub type BuildResults = HashMap<PathBuf, Vec<(Diagnostic, Vec<Suggestion>)>>;
+/// Edits of machine-applicable compiler suggestions, keyed by the file and the
+/// range of the diagnostic they fix. Served as quick fixes on code action requests.
+pub type PendingSuggestions = HashMap<(Url, Range), Vec<TextEdit>>;
 
 pub struct PostBuildHandler {
     pub analysis: Arc<AnalysisHost>,
     pub analysis_queue: Arc<AnalysisQueue>,
     pub previous_build_results: Arc<Mutex<BuildResults>>,
+    pub pending_suggestions: Arc<Mutex<PendingSuggestions>>,
     pub file_to_crates: Arc<Mutex<HashMap<PathBuf, HashSet<Crate>>>>,
     pub project_path: PathBuf,
     pub show_warnings: bool,
     pub crate_blacklist: CrateBlacklist,
+    pub sysroot_src: Option<PathBuf>,
+    pub max_diagnostics_per_file: usize,
+    pub auto_import_style: AutoImportStyle,
+    pub max_analysis_memory_mb: Option<u64>,
+    /// Resident memory (in MiB) when `enforce_memory_limit` last cleared the
+    /// analysis caches, or 0 if it didn't yet.
+    pub memory_cleared_at_mb: Arc<AtomicU64>,
+    pub diagnostic_deduplicate_across_files: bool,
+    pub hover_cache: Arc<Mutex<HoverCache>>,
+    pub on_build_complete: Option<BuildCommand>,
     pub related_information_support: bool,
+    pub semantic_tokens_refresh_support: bool,
     pub shown_cargo_error: Arc<AtomicBool>,
     pub active_build_count: Arc<AtomicUsize>,
+    /// Identifies the build in the `rls/diagnosticsBegin` and
+    /// `rls/diagnosticsEnd` notifications. Set by the build queue, along with
+    /// `priority`, when the build is run.
+    pub build_id: u64,
+    pub priority: BuildPriority,
+    /// Sent by `finalize` once the analysis data of the build is indexed.
+    pub diagnostics_end: Option<DiagnosticsEndParams>,
     pub notifier: Box<dyn DiagnosticsNotifier>,
     pub blocked_threads: Vec<thread::Thread>,
     pub _token: JobToken,
 }
 
+/// A shell command run after each successful build, see the
+/// `on_build_complete` config option.
+pub struct BuildCommand {
+    pub command: String,
+    /// Receives the output of the command.
+    pub log: Box<dyn Fn(String) + Send>,
+}
+
+/// How long the `on_build_complete` command may run before it's killed.
+const BUILD_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
 impl PostBuildHandler {
-    pub fn handle(self, result: BuildResult) {
+    pub fn handle(mut self, result: BuildResult) {
+        let succeeded = match result {
+            BuildResult::Success(..) => true,
+            _ => false,
+        };
+        let compiled = match result {
+            BuildResult::Success(.., true, _) => true,
+            _ => false,
+        };
         match result {
-            BuildResult::Success(cwd, messages, new_analysis, input_files, _) => {
+            BuildResult::Success(cwd, messages, new_analysis, input_files, _, elapsed)
+            | BuildResult::PartialSuccess(cwd, messages, new_analysis, input_files, _, elapsed) => {
                 trace!("build - Success");
-                self.notifier.notify_begin_diagnostics();
+                // Replayed results weren't timed, see `BuildResult::Success`.
+                if elapsed > Duration::default() {
+                    self.notifier.notify_build_stats(BuildStatsParams {
+                        duration_ms: elapsed.as_millis() as u64,
+                        success: compiled,
+                    });
+                }
+                self.notifier.notify_begin_diagnostics(self.diagnostics_begin_params());
 
                 // Emit appropriate diagnostics using the ones from build.
-                self.handle_messages(&cwd, &messages);
+                let counts = self.handle_messages(&cwd, &messages);
+                self.diagnostics_end = Some(self.diagnostics_end_params(compiled, counts, elapsed));
+                if self.semantic_tokens_refresh_support {
+                    self.notifier.request_semantic_tokens_refresh();
+                }
                 let analysis_queue = Arc::clone(&self.analysis_queue);
 
                 {
@@ -61,6 +124,18 @@ impl PostBuildHandler {
                     trace!("Files to crates: {:#?}", files_to_crates.deref());
                 }
 
+                if let (true, Some(command)) = (succeeded, self.on_build_complete.take()) {
+                    let project_path = self.project_path.clone();
+                    thread::spawn(move || {
+                        let output = run_build_command(
+                            &command.command,
+                            &project_path,
+                            BUILD_COMMAND_TIMEOUT,
+                        );
+                        (command.log)(output);
+                    });
+                }
+
                 let job = Job::new(self, new_analysis, cwd);
                 analysis_queue.enqueue(job);
             }
@@ -68,9 +143,13 @@ impl PostBuildHandler {
                 trace!("build - Squashed");
                 self.active_build_count.fetch_sub(1, Ordering::SeqCst);
             }
+            BuildResult::Cancelled => {
+                trace!("build - Cancelled");
+                self.active_build_count.fetch_sub(1, Ordering::SeqCst);
+            }
             BuildResult::Err(cause, cmd) => {
                 trace!("build - Error {} when running {:?}", cause, cmd);
-                self.notifier.notify_begin_diagnostics();
+                self.notifier.notify_begin_diagnostics(self.diagnostics_begin_params());
                 if self.shown_cargo_error.swap(true, Ordering::SeqCst) {
                     warn!("Not reporting: {}", cause);
                 } else {
@@ -79,12 +158,13 @@ impl PostBuildHandler {
                     // root cause.
                     self.notifier.notify_error_diagnostics(cause);
                 }
-                self.notifier.notify_end_diagnostics();
+                let params = self.diagnostics_end_params(false, (0, 1), Duration::default());
+                self.notifier.notify_end_diagnostics(params);
                 self.active_build_count.fetch_sub(1, Ordering::SeqCst);
             }
             BuildResult::CargoError { error, stdout, manifest_path, manifest_error_range } => {
                 trace!("build - CargoError: {}, stdout: {:?}", error, stdout);
-                self.notifier.notify_begin_diagnostics();
+                self.notifier.notify_begin_diagnostics(self.diagnostics_begin_params());
 
                 if let Some(manifest) = manifest_path {
                     // if possible generate manifest diagnostics instead of showMessage
@@ -97,7 +177,8 @@ impl PostBuildHandler {
                     self.notifier.notify_error_diagnostics(format!("{}{}", error, stdout_msg));
                 }
 
-                self.notifier.notify_end_diagnostics();
+                let params = self.diagnostics_end_params(false, (0, 1), Duration::default());
+                self.notifier.notify_end_diagnostics(params);
                 self.active_build_count.fetch_sub(1, Ordering::SeqCst);
             }
         }
@@ -118,6 +199,7 @@ impl PostBuildHandler {
         // errors for those files.
         let mut results = self.previous_build_results.lock().unwrap();
         results.values_mut().for_each(Vec::clear);
+        self.pending_suggestions.lock().unwrap().clear();
 
         // cover whole manifest if we haven't any better idea.
         let range = manifest_error_range
@@ -147,7 +229,29 @@ impl PostBuildHandler {
         self.emit_notifications(&results);
     }
 
-    fn handle_messages(&self, cwd: &Path, messages: &[String]) {
+    fn diagnostics_begin_params(&self) -> DiagnosticsBeginParams {
+        let priority = format!("{:?}", self.priority).to_lowercase();
+        DiagnosticsBeginParams { build_id: self.build_id, priority }
+    }
+
+    fn diagnostics_end_params(
+        &self,
+        success: bool,
+        (warnings, errors): (u32, u32),
+        duration: Duration,
+    ) -> DiagnosticsEndParams {
+        DiagnosticsEndParams {
+            build_id: self.build_id,
+            success,
+            warnings,
+            errors,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+
+    /// Publishes the diagnostics of the compiler `messages` and returns the
+    /// number of warnings and errors among them.
+    fn handle_messages(&self, cwd: &Path, messages: &[String]) -> (u32, u32) {
         // These notifications will include empty sets of errors for files
         // which had errors, but now don't. This instructs the IDE to clear
         // errors for those files.
@@ -164,30 +268,92 @@ impl PostBuildHandler {
             .filter_map(|msg| parse_diagnostics(msg, cwd, self.related_information_support))
             .flat_map(|ParsedDiagnostics { diagnostics }| diagnostics);
 
-        for (file_path, diagnostics) in file_diagnostics {
+        for (file_path, mut diagnostics) in file_diagnostics {
+            for (diagnostic, suggestions) in &mut diagnostics {
+                restyle_import_suggestions(diagnostic.range, suggestions, self.auto_import_style);
+            }
             results.entry(file_path).or_insert_with(Vec::new).extend(diagnostics);
         }
+        dedup_diagnostics(&mut results);
+
+        // Store the quick fixes before publishing the diagnostics, since clients
+        // may ask for code actions as soon as they receive them.
+        {
+            let mut pending_suggestions = self.pending_suggestions.lock().unwrap();
+            pending_suggestions.clear();
+            let suggestions =
+                messages.iter().unique().flat_map(|msg| parse_suggestion_edits(msg, cwd));
+            for (uri, range, edits) in suggestions {
+                pending_suggestions.entry((uri, range)).or_insert(edits);
+            }
+        }
 
         self.emit_notifications(&results);
+        diagnostic_counts(&results)
     }
 
     fn reload_analysis_from_disk(&self, cwd: &Path) {
+        self.analysis.set_sysroot_src(self.sysroot_src.clone()).unwrap();
         self.analysis
             .reload_with_blacklist(&self.project_path, cwd, &self.crate_blacklist.0[..])
             .unwrap();
     }
 
     fn reload_analysis_from_memory(&self, cwd: &Path, analysis: Vec<Analysis>) {
+        self.analysis.set_sysroot_src(self.sysroot_src.clone()).unwrap();
         self.analysis
             .reload_from_analysis(analysis, &self.project_path, cwd, &self.crate_blacklist.0[..])
             .unwrap();
     }
 
+    /// Drops the analysis data held in memory if the RLS uses more memory than
+    /// `max_analysis_memory_mb`, so that the following reload only reads the
+    /// data of the crates which are still part of the build. Returns `true` if
+    /// the data was dropped.
+    ///
+    /// The freed memory isn't necessarily returned to the OS, so once the data
+    /// was dropped, it's only dropped again after the memory use grew by a
+    /// quarter since. The user is only warned the first time.
+    fn enforce_memory_limit(&self) -> bool {
+        let limit = match self.max_analysis_memory_mb {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let used = match resident_memory_mb() {
+            Some(used) if used > limit => used,
+            _ => return false,
+        };
+        let cleared_at = self.memory_cleared_at_mb.load(Ordering::SeqCst);
+        if cleared_at > 0 && used < cleared_at + cleared_at / 4 {
+            trace!("using {} MiB of memory, caches cleared at {} MiB", used, cleared_at);
+            return false;
+        }
+
+        warn!("using {} MiB of memory, clearing analysis caches", used);
+        if let Err(e) = self.analysis.clear_caches() {
+            warn!("failed to clear analysis caches: {:?}", e);
+            return false;
+        }
+        self.memory_cleared_at_mb.store(used, Ordering::SeqCst);
+        if cleared_at == 0 {
+            self.notifier.notify_warning(format!(
+                "The RLS uses {} MiB of memory, more than `max_analysis_memory_mb` ({} MiB); \
+                 reloading the analysis data",
+                used, limit
+            ));
+        }
+        true
+    }
+
     fn finalize(mut self) {
         // the end message must be dispatched before waking up
         // the blocked threads, or we might see "done":true message
         // first in the next action invocation.
-        self.notifier.notify_end_diagnostics();
+        let params = match self.diagnostics_end.take() {
+            Some(params) => params,
+            None => self.diagnostics_end_params(false, (0, 0), Duration::default()),
+        };
+        self.notifier.notify_end_diagnostics(params);
 
         // Wake up any threads blocked on this analysis.
         for t in self.blocked_threads.drain(..) {
@@ -199,24 +365,83 @@ impl PostBuildHandler {
     }
 
     fn emit_notifications(&self, build_results: &BuildResults) {
-        for (path, diagnostics) in build_results {
-            let params = PublishDiagnosticsParams {
-                uri: Url::from_file_path(path).unwrap(),
-                diagnostics: diagnostics
-                    .iter()
-                    .map(|(diag, _)| diag)
-                    .filter(|diag| {
-                        self.show_warnings || diag.severity != Some(DiagnosticSeverity::Warning)
-                    })
-                    .cloned()
-                    .collect(),
-            };
+        // The messages (and codes) published so far in this build, with the
+        // file they were published for.
+        let mut seen_messages: HashMap<(String, Option<String>), &Path> = HashMap::new();
+
+        // Sorted, so that deduplicated diagnostics consistently stay with the
+        // same file.
+        for (path, diagnostics) in build_results.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+            let mut diagnostics: Vec<_> = diagnostics
+                .iter()
+                .map(|(diag, _)| diag)
+                .filter(|diag| {
+                    self.show_warnings || diag.severity != Some(DiagnosticSeverity::Warning)
+                })
+                .filter(|diag| {
+                    if !self.diagnostic_deduplicate_across_files {
+                        return true;
+                    }
+                    let code = diag.code.as_ref().map(|code| match code {
+                        NumberOrString::Number(n) => n.to_string(),
+                        NumberOrString::String(s) => s.clone(),
+                    });
+                    let first_path =
+                        seen_messages.entry((diag.message.clone(), code)).or_insert(path.as_path());
+                    *first_path == path.as_path()
+                })
+                .cloned()
+                .collect();
+
+            if diagnostics.len() > self.max_diagnostics_per_file {
+                let suppressed = diagnostics.len() - self.max_diagnostics_per_file;
+                diagnostics.truncate(self.max_diagnostics_per_file);
+                diagnostics.push(Diagnostic {
+                    range: diagnostics.last().map(|diag| diag.range).unwrap_or_default(),
+                    message: format!("{} more diagnostics suppressed", suppressed),
+                    severity: Some(DiagnosticSeverity::Information),
+                    source: Some("rls".to_owned()),
+                    ..Diagnostic::default()
+                });
+            }
+
+            let params =
+                PublishDiagnosticsParams { uri: Url::from_file_path(path).unwrap(), diagnostics };
 
             self.notifier.notify_publish_diagnostics(params);
         }
     }
 }
 
+/// Returns the number of warnings and errors in `results`.
+fn diagnostic_counts(results: &BuildResults) -> (u32, u32) {
+    let count = |severity| {
+        results.values().flatten().filter(|(diag, _)| diag.severity == Some(severity)).count()
+            as u32
+    };
+    (count(DiagnosticSeverity::Warning), count(DiagnosticSeverity::Error))
+}
+
+/// Removes the diagnostics of each file which have the same range, message and
+/// severity as a previous one, e.g., if a compiler message was emitted for
+/// several targets of a package.
+fn dedup_diagnostics(results: &mut BuildResults) {
+    for diagnostics in results.values_mut() {
+        let mut unique: Vec<(Diagnostic, Vec<Suggestion>)> = Vec::with_capacity(diagnostics.len());
+        for (diagnostic, suggestions) in diagnostics.drain(..) {
+            let is_duplicate = unique.iter().any(|(other, _)| {
+                other.range == diagnostic.range
+                    && other.message == diagnostic.message
+                    && other.severity == diagnostic.severity
+            });
+            if !is_duplicate {
+                unique.push((diagnostic, suggestions));
+            }
+        }
+        *diagnostics = unique;
+    }
+}
+
 // Queue up analysis tasks and execute them on the same thread (this is slower
 // than executing in parallel, but allows us to skip indexing tasks).
 pub struct AnalysisQueue {
@@ -344,6 +569,7 @@ impl Job {
             self.cwd,
             self.analysis.len(),
         );
+        self.handler.enforce_memory_limit();
         if self.analysis.is_empty() {
             trace!("reloading from disk: {:?}", self.cwd);
             self.handler.reload_analysis_from_disk(&self.cwd);
@@ -351,7 +577,682 @@ impl Job {
             trace!("reloading from memory: {:?}", self.cwd);
             self.handler.reload_analysis_from_memory(&self.cwd, self.analysis);
         }
+        self.handler.hover_cache.lock().unwrap().clear();
 
         self.handler.finalize();
     }
 }
+
+/// Runs `command` in a shell in `cwd`, killing it after `timeout`. Returns a
+/// description of its exit status, followed by its output.
+fn run_build_command(command: &str, cwd: &Path, timeout: Duration) -> String {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    cmd.arg(command)
+        .current_dir(cwd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => return format!("`{}` failed to start: {}", command, e),
+    };
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                let stdout = stdout.join().unwrap_or_default();
+                let stderr = stderr.join().unwrap_or_default();
+                return format!("`{}` finished ({}):\n{}{}", command, status, stdout, stderr);
+            }
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+            Ok(None) => {
+                // Don't wait for the output, processes started by the command
+                // might still hold on to the pipes.
+                let _ = child.kill();
+                let _ = child.wait();
+                return format!("`{}` was killed after {:?}", command, timeout);
+            }
+            Err(e) => return format!("`{}` failed: {}", command, e),
+        }
+    }
+}
+
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = String::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_string(&mut output);
+        }
+        output
+    })
+}
+
+/// Returns the resident set size of the current process, in MiB.
+#[cfg(target_os = "linux")]
+fn resident_memory_mb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    // E.g., `VmRSS:\t   12345 kB`.
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb = line["VmRSS:".len()..].trim().trim_end_matches("kB").trim();
+    kb.parse::<u64>().ok().map(|kb| kb / 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory_mb() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::actions::progress::{DiagnosticsNotifier, ProgressNotifier, ProgressUpdate};
+    use crate::build::environment::EnvironmentLock;
+    use crate::build::{
+        dependency_build_script_message, BuildPriority, BuildQueue, BuildQueueStatus,
+        SharedBuildScheduler,
+    };
+    use crate::concurrency::{ConcurrentJob, Jobs};
+    use crate::lsp_data::Position;
+    use rls_analysis::Target;
+    use rls_vfs::Vfs;
+
+    /// Records every `textDocument/publishDiagnostics` notification, every
+    /// warning shown, the number of semantic token refreshes requested and
+    /// every `rls/buildStats` notification, as well as every
+    /// `rls/diagnosticsBegin` and `rls/diagnosticsEnd` notification.
+    #[derive(Clone, Default)]
+    struct MockNotifier(
+        Arc<Mutex<Vec<PublishDiagnosticsParams>>>,
+        Arc<Mutex<Vec<String>>>,
+        Arc<AtomicUsize>,
+        Arc<Mutex<Vec<BuildStatsParams>>>,
+        Arc<Mutex<Vec<DiagnosticsBeginParams>>>,
+        Arc<Mutex<Vec<DiagnosticsEndParams>>>,
+    );
+
+    impl DiagnosticsNotifier for MockNotifier {
+        fn notify_begin_diagnostics(&self, params: DiagnosticsBeginParams) {
+            self.4.lock().unwrap().push(params);
+        }
+        fn notify_publish_diagnostics(&self, params: PublishDiagnosticsParams) {
+            self.0.lock().unwrap().push(params);
+        }
+        fn notify_error_diagnostics(&self, _: String) {}
+        fn notify_warning(&self, msg: String) {
+            self.1.lock().unwrap().push(msg);
+        }
+        fn request_semantic_tokens_refresh(&self) {
+            self.2.fetch_add(1, Ordering::SeqCst);
+        }
+        fn notify_build_stats(&self, params: BuildStatsParams) {
+            self.3.lock().unwrap().push(params);
+        }
+        fn notify_end_diagnostics(&self, params: DiagnosticsEndParams) {
+            self.5.lock().unwrap().push(params);
+        }
+    }
+
+    struct NoProgress;
+
+    impl ProgressNotifier for NoProgress {
+        fn notify_begin_progress(&self) {}
+        fn notify_progress(&self, _: ProgressUpdate) {}
+        fn notify_end_progress(&self) {}
+    }
+
+    fn fixtures_dir() -> &'static Path {
+        Path::new(env!("FIXTURES_DIR"))
+    }
+
+    fn read_fixture(path: impl AsRef<Path>) -> String {
+        std::fs::read_to_string(fixtures_dir().join(path.as_ref())).unwrap()
+    }
+
+    fn post_build_handler(notifier: &MockNotifier, jobs: &mut Jobs) -> PostBuildHandler {
+        let (job, token) = ConcurrentJob::new();
+        jobs.add(job);
+
+        PostBuildHandler {
+            analysis: Arc::new(AnalysisHost::new(Target::Debug)),
+            analysis_queue: Arc::new(AnalysisQueue::init()),
+            previous_build_results: Arc::default(),
+            pending_suggestions: Arc::default(),
+            file_to_crates: Arc::default(),
+            project_path: fixtures_dir().to_owned(),
+            show_warnings: true,
+            crate_blacklist: CrateBlacklist::default(),
+            sysroot_src: None,
+            max_diagnostics_per_file: usize::max_value(),
+            auto_import_style: AutoImportStyle::default(),
+            max_analysis_memory_mb: None,
+            memory_cleared_at_mb: Arc::default(),
+            diagnostic_deduplicate_across_files: false,
+            hover_cache: Arc::default(),
+            on_build_complete: None,
+            related_information_support: true,
+            semantic_tokens_refresh_support: false,
+            shown_cargo_error: Arc::default(),
+            active_build_count: Arc::new(AtomicUsize::new(1)),
+            build_id: 0,
+            priority: BuildPriority::Normal,
+            diagnostics_end: None,
+            notifier: Box::new(notifier.clone()),
+            blocked_threads: vec![],
+            _token: token,
+        }
+    }
+
+    fn success(messages: Vec<String>) -> BuildResult {
+        BuildResult::Success(
+            fixtures_dir().to_owned(),
+            messages,
+            vec![],
+            HashMap::new(),
+            false,
+            Duration::default(),
+        )
+    }
+
+    #[test]
+    fn replay_build_publishes_diagnostics() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let pbh = post_build_handler(&notifier, &mut jobs);
+
+        let build_queue = BuildQueue::new(Arc::new(Vfs::new()), Arc::default());
+        let message = read_fixture("compiler_message/use-after-move.json");
+        build_queue.replay_build(success(vec![message]), move |result| pbh.handle(result));
+        jobs.wait_for_all();
+
+        let published = notifier.0.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        assert!(published[0].uri.path().ends_with("src/lib.rs"));
+        assert!(!published[0].diagnostics.is_empty());
+        assert!(published[0].diagnostics[0].message.starts_with("use of moved value: `s`"));
+    }
+
+    #[test]
+    fn build_reports_compilation_metrics() {
+        let project = tempfile::tempdir().unwrap();
+        let manifest = "[package]\nname = \"metrics\"\nversion = \"0.1.0\"\n";
+        std::fs::write(project.path().join("Cargo.toml"), manifest).unwrap();
+        std::fs::create_dir(project.path().join("src")).unwrap();
+        std::fs::write(project.path().join("src/lib.rs"), "pub fn f() {\n    let x = 1;\n}\n")
+            .unwrap();
+
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let mut pbh = post_build_handler(&notifier, &mut jobs);
+        pbh.project_path = project.path().to_owned();
+        let build_queue = BuildQueue::new(Arc::new(Vfs::new()), Arc::default());
+        let metrics = build_queue.metrics_channel();
+        build_queue.request_build(project.path(), BuildPriority::Cargo, Box::new(NoProgress), pbh);
+        build_queue.block_on_build();
+        jobs.wait_for_all();
+
+        let metric = metrics.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(metric.crate_name, "metrics");
+        // The unused variable, and possibly a summary of the warnings.
+        assert!(metric.warnings >= 1, "{:?}", metric);
+        assert_eq!(metric.errors, 0);
+        assert!(metrics.try_recv().is_err());
+    }
+
+    #[test]
+    fn dependency_build_script_failure_is_reported_on_manifest() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let pbh = post_build_handler(&notifier, &mut jobs);
+        let manifest = fixtures_dir().join("Cargo.toml");
+        let stderr = "error: could not find system library 'openssl'\n";
+        let message = dependency_build_script_message(&manifest, "openssl-sys", stderr);
+        pbh.handle(success(vec![message]));
+        jobs.wait_for_all();
+
+        let published = notifier.0.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        assert!(published[0].uri.path().ends_with("Cargo.toml"));
+        assert_eq!(published[0].diagnostics.len(), 1);
+        let message = &published[0].diagnostics[0].message;
+        assert!(message.contains("`openssl-sys`"), "{}", message);
+        assert!(message.contains("could not find system library 'openssl'"), "{}", message);
+    }
+
+    #[test]
+    fn deduplicate_identical_diagnostics() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let message = read_fixture("compiler_message/use-after-move.json");
+
+        let pbh = post_build_handler(&notifier, &mut jobs);
+        pbh.handle(success(vec![message.clone()]));
+        jobs.wait_for_all();
+        let expected = notifier.0.lock().unwrap().pop().unwrap().diagnostics;
+
+        // The same diagnostics from a message that differs, e.g., in whitespace.
+        let pbh = post_build_handler(&notifier, &mut jobs);
+        pbh.handle(success(vec![message.clone(), format!(" {}", message)]));
+        jobs.wait_for_all();
+        let published = notifier.0.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].diagnostics, expected);
+    }
+
+    #[test]
+    fn refresh_semantic_tokens_after_build() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let pbh = post_build_handler(&notifier, &mut jobs);
+        pbh.handle(success(vec![]));
+        jobs.wait_for_all();
+        assert_eq!(notifier.2.load(Ordering::SeqCst), 0);
+
+        let mut pbh = post_build_handler(&notifier, &mut jobs);
+        pbh.semantic_tokens_refresh_support = true;
+        pbh.handle(success(vec![]));
+        jobs.wait_for_all();
+        assert_eq!(notifier.2.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn forward_build_stats() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let pbh = post_build_handler(&notifier, &mut jobs);
+        let duration = Duration::from_millis(1500);
+        pbh.handle(BuildResult::Success(
+            fixtures_dir().to_owned(),
+            vec![],
+            vec![],
+            HashMap::new(),
+            true,
+            duration,
+        ));
+        // Replayed builds aren't timed.
+        post_build_handler(&notifier, &mut jobs).handle(success(vec![]));
+        jobs.wait_for_all();
+
+        let stats = notifier.3.lock().unwrap();
+        assert_eq!(*stats, vec![BuildStatsParams { duration_ms: 1500, success: true }]);
+    }
+
+    #[test]
+    fn diagnostics_begin_and_end() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let mut pbh = post_build_handler(&notifier, &mut jobs);
+        pbh.build_id = 7;
+        pbh.priority = BuildPriority::Cargo;
+        pbh.handle(BuildResult::Success(
+            fixtures_dir().to_owned(),
+            vec![read_fixture("compiler_message/use-after-move.json")],
+            vec![],
+            HashMap::new(),
+            false,
+            Duration::from_millis(1500),
+        ));
+        jobs.wait_for_all();
+
+        let begins = notifier.4.lock().unwrap();
+        assert_eq!(
+            *begins,
+            vec![DiagnosticsBeginParams { build_id: 7, priority: "cargo".to_owned() }]
+        );
+        let ends = notifier.5.lock().unwrap();
+        assert_eq!(
+            *ends,
+            vec![DiagnosticsEndParams {
+                build_id: 7,
+                success: false,
+                warnings: 0,
+                errors: 1,
+                duration_ms: 1500,
+            }]
+        );
+    }
+
+    #[test]
+    fn build_queue_status_transitions() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let scheduler = Arc::new(SharedBuildScheduler::new());
+        let queue = BuildQueue::with_scheduler(
+            Arc::new(Vfs::new()),
+            Arc::default(),
+            Arc::clone(&scheduler),
+        );
+        let mut statuses = queue.status_receiver();
+        let mut next_status = || statuses.recv_timeout(Duration::from_secs(10));
+        assert_eq!(queue.status_receiver().get(), BuildQueueStatus::Idle);
+
+        // Keep the build pending by occupying the scheduler...
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let other_build = thread::spawn(move || {
+            scheduler.run(BuildPriority::Normal, Path::new("/other"), || {
+                started_tx.send(()).unwrap();
+                release_rx.recv().unwrap()
+            })
+        });
+        started_rx.recv().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let pbh = post_build_handler(&notifier, &mut jobs);
+        queue.request_build(dir.path(), BuildPriority::Cargo, Box::new(NoProgress), pbh);
+        let priority = BuildPriority::Cargo;
+        assert_eq!(next_status(), Some(BuildQueueStatus::Pending { priority }));
+
+        // ...and then keep it running by holding the lock Cargo needs.
+        let env_lock = EnvironmentLock::get();
+        let guard = env_lock.lock();
+        release_tx.send(()).unwrap();
+        other_build.join().unwrap();
+        assert_eq!(next_status(), Some(BuildQueueStatus::Building { priority }));
+
+        drop(guard);
+        assert_eq!(next_status(), Some(BuildQueueStatus::Idle));
+        jobs.wait_for_all();
+    }
+
+    #[test]
+    fn idle_build_is_dropped_if_queue_busy() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let scheduler = Arc::new(SharedBuildScheduler::new());
+        let queue = BuildQueue::with_scheduler(
+            Arc::new(Vfs::new()),
+            Arc::default(),
+            Arc::clone(&scheduler),
+        );
+
+        // Keep a build pending by occupying the scheduler.
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let other_build = thread::spawn(move || {
+            scheduler.run(BuildPriority::Normal, Path::new("/other"), || {
+                started_tx.send(()).unwrap();
+                release_rx.recv().unwrap()
+            })
+        });
+        started_rx.recv().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let pbh = post_build_handler(&notifier, &mut jobs);
+        queue.request_build(dir.path(), BuildPriority::Cargo, Box::new(NoProgress), pbh);
+        let pbh = post_build_handler(&notifier, &mut jobs);
+        let active_build_count = Arc::clone(&pbh.active_build_count);
+        queue.request_build(dir.path(), BuildPriority::Idle, Box::new(NoProgress), pbh);
+        assert_eq!(queue.stats().squashed, 1);
+        assert_eq!(active_build_count.load(Ordering::SeqCst), 0);
+        let priority = BuildPriority::Cargo;
+        assert_eq!(queue.status_receiver().get(), BuildQueueStatus::Pending { priority });
+
+        release_tx.send(()).unwrap();
+        other_build.join().unwrap();
+        jobs.wait_for_all();
+    }
+
+    #[test]
+    fn drain_build_queue() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let scheduler = Arc::new(SharedBuildScheduler::new());
+        let queue = BuildQueue::with_scheduler(
+            Arc::new(Vfs::new()),
+            Arc::default(),
+            Arc::clone(&scheduler),
+        );
+
+        // Keep the builds pending by occupying the scheduler.
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let other_build = thread::spawn(move || {
+            scheduler.run(BuildPriority::Normal, Path::new("/other"), || {
+                started_tx.send(()).unwrap();
+                release_rx.recv().unwrap()
+            })
+        });
+        started_rx.recv().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut active_build_counts = vec![];
+        for &priority in &[BuildPriority::Cargo, BuildPriority::Normal, BuildPriority::Idle] {
+            let pbh = post_build_handler(&notifier, &mut jobs);
+            active_build_counts.push(Arc::clone(&pbh.active_build_count));
+            queue.request_build(dir.path(), priority, Box::new(NoProgress), pbh);
+        }
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            release_tx.send(()).unwrap();
+        });
+        queue.drain();
+        assert!(queue.build_ready());
+        assert_eq!(queue.status_receiver().get(), BuildQueueStatus::Idle);
+
+        releaser.join().unwrap();
+        other_build.join().unwrap();
+        jobs.wait_for_all();
+        for active_build_count in active_build_counts {
+            assert_eq!(active_build_count.load(Ordering::SeqCst), 0);
+        }
+    }
+
+    #[test]
+    fn run_on_build_complete() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let mut pbh = post_build_handler(&notifier, &mut jobs);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        pbh.on_build_complete = Some(BuildCommand {
+            command: "echo done".to_owned(),
+            log: Box::new(move |output| sender.send(output).unwrap()),
+        });
+        pbh.handle(success(vec![]));
+        jobs.wait_for_all();
+
+        let output = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(output.contains("done"), "{}", output);
+    }
+
+    #[test]
+    fn replay_partial_build_publishes_diagnostics() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let pbh = post_build_handler(&notifier, &mut jobs);
+
+        let build_queue = BuildQueue::new(Arc::new(Vfs::new()), Arc::default());
+        let message = read_fixture("compiler_message/use-after-move.json");
+        let result = BuildResult::PartialSuccess(
+            fixtures_dir().to_owned(),
+            vec![message],
+            vec![],
+            HashMap::new(),
+            vec![],
+            Duration::default(),
+        );
+        build_queue.replay_build(result, move |result| pbh.handle(result));
+        jobs.wait_for_all();
+
+        let published = notifier.0.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        assert!(published[0].diagnostics[0].message.starts_with("use of moved value: `s`"));
+    }
+
+    #[test]
+    fn rebuild_clears_stale_diagnostics() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let build_queue = BuildQueue::new(Arc::new(Vfs::new()), Arc::default());
+
+        let pbh = post_build_handler(&notifier, &mut jobs);
+        let previous_build_results = Arc::clone(&pbh.previous_build_results);
+        let message = read_fixture("compiler_message/use-after-move.json");
+        build_queue.replay_build(success(vec![message]), move |result| pbh.handle(result));
+
+        let mut pbh = post_build_handler(&notifier, &mut jobs);
+        pbh.previous_build_results = previous_build_results;
+        build_queue.replay_build(success(vec![]), move |result| pbh.handle(result));
+        jobs.wait_for_all();
+
+        let published = notifier.0.lock().unwrap();
+        assert_eq!(published.len(), 2);
+        assert!(!published[0].diagnostics.is_empty());
+        // The file no longer has any diagnostics, so these need to be cleared.
+        assert_eq!(published[1].uri, published[0].uri);
+        assert!(published[1].diagnostics.is_empty());
+    }
+
+    #[test]
+    fn machine_applicable_suggestions_are_pending() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let pbh = post_build_handler(&notifier, &mut jobs);
+        let pending_suggestions = Arc::clone(&pbh.pending_suggestions);
+
+        let build_queue = BuildQueue::new(Arc::new(Vfs::new()), Arc::default());
+        let message = read_fixture("compiler_message/unused-mut.json");
+        build_queue.replay_build(success(vec![message]), move |result| pbh.handle(result));
+        jobs.wait_for_all();
+
+        let published = notifier.0.lock().unwrap();
+        let diagnostic = &published[0].diagnostics[0];
+        let pending_suggestions = pending_suggestions.lock().unwrap();
+        let edits = &pending_suggestions[&(published[0].uri.clone(), diagnostic.range)];
+        assert_eq!(
+            *edits,
+            vec![TextEdit {
+                range: Range { start: Position::new(1, 8), end: Position::new(1, 12) },
+                new_text: String::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn deduplicate_diagnostics_across_files() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let mut pbh = post_build_handler(&notifier, &mut jobs);
+        pbh.diagnostic_deduplicate_across_files = true;
+
+        let diagnostic = |message: &str| Diagnostic {
+            message: message.to_owned(),
+            code: Some(NumberOrString::String("E0308".to_owned())),
+            severity: Some(DiagnosticSeverity::Error),
+            ..Diagnostic::default()
+        };
+        let mut results = BuildResults::new();
+        results.insert(
+            fixtures_dir().join("src/a.rs"),
+            vec![
+                (diagnostic("mismatched types"), vec![]),
+                (diagnostic("mismatched types"), vec![]),
+            ],
+        );
+        results.insert(
+            fixtures_dir().join("src/b.rs"),
+            vec![(diagnostic("mismatched types"), vec![]), (diagnostic("other"), vec![])],
+        );
+        pbh.emit_notifications(&results);
+
+        {
+            let published = notifier.0.lock().unwrap();
+            assert_eq!(published.len(), 2);
+            assert!(published[0].uri.path().ends_with("src/a.rs"));
+            // Duplicates within a single file are kept.
+            assert_eq!(published[0].diagnostics.len(), 2);
+            assert!(published[1].uri.path().ends_with("src/b.rs"));
+            let messages: Vec<_> = published[1].diagnostics.iter().map(|d| &*d.message).collect();
+            assert_eq!(messages, ["other"]);
+        }
+
+        pbh.finalize();
+        jobs.wait_for_all();
+    }
+
+    #[test]
+    fn max_diagnostics_per_file() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+        let mut pbh = post_build_handler(&notifier, &mut jobs);
+        pbh.max_diagnostics_per_file = 5;
+
+        let diagnostic = |i| Diagnostic {
+            message: format!("error #{}", i),
+            severity: Some(DiagnosticSeverity::Error),
+            ..Diagnostic::default()
+        };
+        let mut results = BuildResults::new();
+        results.insert(
+            fixtures_dir().join("src/lib.rs"),
+            (0..10).map(|i| (diagnostic(i), vec![])).collect(),
+        );
+        pbh.emit_notifications(&results);
+
+        {
+            let published = notifier.0.lock().unwrap();
+            assert_eq!(published.len(), 1);
+            let messages: Vec<_> = published[0].diagnostics.iter().map(|d| &*d.message).collect();
+            assert_eq!(
+                messages,
+                [
+                    "error #0",
+                    "error #1",
+                    "error #2",
+                    "error #3",
+                    "error #4",
+                    "5 more diagnostics suppressed"
+                ]
+            );
+        }
+
+        pbh.finalize();
+        jobs.wait_for_all();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn exceeding_memory_limit_clears_analysis() {
+        let notifier = MockNotifier::default();
+        let mut jobs = Jobs::default();
+
+        let mut pbh = post_build_handler(&notifier, &mut jobs);
+        assert!(!pbh.enforce_memory_limit());
+        pbh.max_analysis_memory_mb = Some(1_000_000);
+        assert!(!pbh.enforce_memory_limit());
+        assert!(notifier.1.lock().unwrap().is_empty());
+
+        pbh.max_analysis_memory_mb = Some(1);
+        assert!(pbh.enforce_memory_limit());
+        {
+            let warnings = notifier.1.lock().unwrap();
+            assert_eq!(warnings.len(), 1);
+            assert!(warnings[0].contains("`max_analysis_memory_mb` (1 MiB)"));
+        }
+        assert!(pbh.memory_cleared_at_mb.load(Ordering::SeqCst) > 1);
+
+        // Not cleared again until the memory use grew since...
+        pbh.memory_cleared_at_mb.store(1_000_000, Ordering::SeqCst);
+        assert!(!pbh.enforce_memory_limit());
+        // ...and without another warning.
+        pbh.memory_cleared_at_mb.store(1, Ordering::SeqCst);
+        assert!(pbh.enforce_memory_limit());
+        assert_eq!(notifier.1.lock().unwrap().len(), 1);
+
+        pbh.finalize();
+        jobs.wait_for_all();
+    }
+}
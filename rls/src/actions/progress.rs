@@ -1,6 +1,12 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::server::{Notification, Output};
+use crate::build::{BuildQueueStatus, StatusReceiver};
+use crate::lsp_data::{
+    BuildStats, BuildStatsParams, DiagnosticsBegin, DiagnosticsBeginParams, DiagnosticsEnd,
+    DiagnosticsEndParams, SemanticTokensRefresh, WorkDoneProgress, WorkDoneProgressCreate,
+    WorkDoneProgressCreateParams, WorkDoneProgressParams, WorkDoneProgressValue,
+};
+use crate::server::{Notification, Output, Request};
 use lazy_static::lazy_static;
 use lsp_types::notification::{Progress, PublishDiagnostics, ShowMessage};
 use lsp_types::{MessageType, ProgressParams, PublishDiagnosticsParams, ShowMessageParams};
@@ -16,6 +22,8 @@ pub trait ProgressNotifier: Send {
 pub enum ProgressUpdate {
     Message(String),
     Percentage(f64),
+    /// A warning about the build, shown to the user (window/showMessage).
+    Warning(String),
 }
 
 /// Trait for communication of diagnostics (i.e., build results) back to the rest of
@@ -23,10 +31,14 @@ pub enum ProgressUpdate {
 // This trait only really exists to work around the object safety rules (Output
 // is not object-safe).
 pub trait DiagnosticsNotifier: Send {
-    fn notify_begin_diagnostics(&self);
+    fn notify_begin_diagnostics(&self, _: DiagnosticsBeginParams);
     fn notify_publish_diagnostics(&self, _: PublishDiagnosticsParams);
     fn notify_error_diagnostics(&self, msg: String);
-    fn notify_end_diagnostics(&self);
+    fn notify_warning(&self, msg: String);
+    /// Asks the client to refresh its semantic tokens, see `SemanticTokensRefresh`.
+    fn request_semantic_tokens_refresh(&self);
+    fn notify_build_stats(&self, _: BuildStatsParams);
+    fn notify_end_diagnostics(&self, _: DiagnosticsEndParams);
 }
 
 /// Generates a new progress params with a unique ID and the given title.
@@ -55,8 +67,11 @@ pub struct BuildProgressNotifier<O: Output> {
 }
 
 impl<O: Output> BuildProgressNotifier<O> {
-    pub fn new(out: O) -> BuildProgressNotifier<O> {
-        BuildProgressNotifier { out, progress_params: new_progress_params("Building".into()) }
+    pub fn new(out: O, project_name: &str) -> BuildProgressNotifier<O> {
+        BuildProgressNotifier {
+            out,
+            progress_params: new_progress_params(format!("Building {}", project_name)),
+        }
     }
 }
 
@@ -70,6 +85,13 @@ impl<O: Output> ProgressNotifier for BuildProgressNotifier<O> {
         match update {
             ProgressUpdate::Message(s) => params.message = Some(s),
             ProgressUpdate::Percentage(p) => params.percentage = Some(p),
+            ProgressUpdate::Warning(message) => {
+                self.out.notify(Notification::<ShowMessage>::new(ShowMessageParams {
+                    typ: MessageType::Warning,
+                    message,
+                }));
+                return;
+            }
         }
         self.out.notify(Notification::<Progress>::new(params));
     }
@@ -80,6 +102,44 @@ impl<O: Output> ProgressNotifier for BuildProgressNotifier<O> {
     }
 }
 
+/// Reports the status of a build queue through `WorkDoneProgress` until the
+/// queue is dropped. A progress is created whenever the queue stops being idle
+/// and ended once it's idle again.
+pub fn report_build_queue_status<O: Output>(mut statuses: StatusReceiver, out: &O) {
+    let mut progress_count = 0;
+    let mut token = None;
+    while let Some(status) = statuses.recv() {
+        let message = match status {
+            BuildQueueStatus::Idle => {
+                if let Some(token) = token.take() {
+                    let value = WorkDoneProgressValue::End { message: None };
+                    out.notify(Notification::<WorkDoneProgress>::new(WorkDoneProgressParams {
+                        token,
+                        value,
+                    }));
+                }
+                continue;
+            }
+            BuildQueueStatus::Pending { .. } => "Waiting to build".to_owned(),
+            BuildQueueStatus::Building { .. } => "Building".to_owned(),
+        };
+        let value = match token {
+            Some(_) => WorkDoneProgressValue::Report { message: Some(message) },
+            None => {
+                let new_token = format!("build_queue_{}", progress_count);
+                progress_count += 1;
+                let id = out.provide_id();
+                let params = WorkDoneProgressCreateParams { token: new_token.clone() };
+                out.request(Request::<WorkDoneProgressCreate>::new(id, params));
+                token = Some(new_token);
+                WorkDoneProgressValue::Begin { title: "Build".to_owned(), message: Some(message) }
+            }
+        };
+        let token = token.clone().unwrap();
+        out.notify(Notification::<WorkDoneProgress>::new(WorkDoneProgressParams { token, value }));
+    }
+}
+
 /// Notifier of diagnostics after the build has completed.
 pub struct BuildDiagnosticsNotifier<O: Output> {
     out: O,
@@ -101,7 +161,8 @@ impl<O: Output> BuildDiagnosticsNotifier<O> {
 }
 
 impl<O: Output> DiagnosticsNotifier for BuildDiagnosticsNotifier<O> {
-    fn notify_begin_diagnostics(&self) {
+    fn notify_begin_diagnostics(&self, params: DiagnosticsBeginParams) {
+        self.out.notify(Notification::<DiagnosticsBegin>::new(params));
         let params = self.progress_params.clone();
         self.out.notify(Notification::<Progress>::new(params));
     }
@@ -114,9 +175,23 @@ impl<O: Output> DiagnosticsNotifier for BuildDiagnosticsNotifier<O> {
             message,
         }));
     }
-    fn notify_end_diagnostics(&self) {
-        let mut params = self.progress_params.clone();
-        params.done = Some(true);
-        self.out.notify(Notification::<Progress>::new(params));
+    fn notify_warning(&self, message: String) {
+        self.out.notify(Notification::<ShowMessage>::new(ShowMessageParams {
+            typ: MessageType::Warning,
+            message,
+        }));
+    }
+    fn request_semantic_tokens_refresh(&self) {
+        let id = self.out.provide_id();
+        self.out.request(Request::<SemanticTokensRefresh>::new(id, ()));
+    }
+    fn notify_build_stats(&self, params: BuildStatsParams) {
+        self.out.notify(Notification::<BuildStats>::new(params));
+    }
+    fn notify_end_diagnostics(&self, params: DiagnosticsEndParams) {
+        let mut progress_params = self.progress_params.clone();
+        progress_params.done = Some(true);
+        self.out.notify(Notification::<Progress>::new(progress_params));
+        self.out.notify(Notification::<DiagnosticsEnd>::new(params));
     }
 }
@@ -1,36 +1,62 @@
 //! Requests that the RLS can respond to.
 
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::path::Path;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
 
 use itertools::Itertools;
 use jsonrpc_core::types::ErrorCode;
 use log::{debug, trace, warn};
-use rls_analysis::SymbolQuery;
+use rls_analysis::{AnalysisHost, Def, DefKind, Id, SymbolQuery};
 use rls_data as data;
 use rls_span as span;
-use rls_vfs::FileContents;
+use rls_vfs::{FileContents, PositionEncoding, Vfs};
 use rustfmt_nightly::{Edition as RustfmtEdition, FileLines, FileName, Range as RustfmtRange};
 use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
 use url::Url;
 
+use crate::actions::breadcrumbs::collect_breadcrumbs;
+use crate::actions::call_hierarchy::{incoming_calls, outgoing_calls, prepare_call_hierarchy};
+use crate::actions::document_colors::{collect_document_colors, color_presentations};
+use crate::actions::extract::extract_fn_edit;
+use crate::actions::folding::collect_folding_ranges;
+use crate::actions::format::edits_within;
 use crate::actions::hover;
-use crate::actions::run::collect_run_actions;
+use crate::actions::inlay_hints::collect_inlay_hints;
+use crate::actions::inline_values::collect_inline_values;
+use crate::actions::lens::{collect_code_lenses, resolve_code_lens};
+use crate::actions::links::collect_document_links;
+use crate::actions::on_type_formatting::{closed_block, reindent_line};
+use crate::actions::organize_imports::organize_imports;
+use crate::actions::selection_range::collect_selection_ranges;
+use crate::actions::signature_help::signature_help;
 use crate::actions::InitActionContext;
-use crate::build::Edition;
+use crate::build::{environment, Edition};
 use crate::lsp_data;
+use crate::lsp_data::notification::PublishDiagnostics;
 use crate::lsp_data::request::ApplyWorkspaceEdit;
 pub use crate::lsp_data::request::{
-    CodeActionRequest as CodeAction, CodeLensRequest, Completion,
-    DocumentHighlightRequest as DocumentHighlight, DocumentSymbolRequest as Symbols,
-    ExecuteCommand, Formatting, GotoDefinition as Definition, GotoImplementation as Implementation,
-    HoverRequest as Hover, RangeFormatting, References, Rename,
-    ResolveCompletionItem as ResolveCompletion, WorkspaceSymbol,
+    CodeActionRequest as CodeAction, CodeLensRequest, CodeLensResolve, ColorPresentationRequest,
+    Completion, DocumentColor, DocumentHighlightRequest as DocumentHighlight, DocumentLinkRequest,
+    DocumentSymbolRequest as Symbols, ExecuteCommand, FoldingRangeRequest, Formatting,
+    GotoDefinition as Definition, GotoImplementation as Implementation, HoverRequest as Hover,
+    OnTypeFormatting, PrepareRenameRequest as PrepareRename, RangeFormatting, References, Rename,
+    ResolveCompletionItem as ResolveCompletion, SelectionRangeRequest, SignatureHelpRequest,
+    WorkspaceSymbol,
 };
 use crate::lsp_data::*;
+pub use crate::lsp_data::{
+    Breadcrumbs, CallHierarchyIncomingCalls, CallHierarchyOutgoingCalls, CallHierarchyPrepare,
+    CodeActionResolve, EnvDiff, InlayHintRequest, InlineValue, PrepareTypeHierarchy,
+    TypeHierarchySubtypes, TypeHierarchySupertypes, UnsavedChanges, WorkspaceDiagnostic,
+};
 use crate::server;
-use crate::server::{Ack, Output, Request, RequestAction, ResponseError, ResponseWithMessage};
+use crate::server::{
+    Ack, Notification, Output, Request, RequestAction, ResponseError, ResponseWithMessage,
+};
 
 /// The result of a deglob action for a single wildcard import.
 ///
@@ -44,6 +70,13 @@ pub struct DeglobResult {
     pub new_text: String,
 }
 
+/// A code action whose edit is only computed once it's resolved by the client
+/// via `codeAction/resolve`.
+#[derive(Debug, Clone)]
+pub enum PendingCodeAction {
+    ExtractFunction { file_path: PathBuf, uri: Url, range: Range },
+}
+
 impl RequestAction for WorkspaceSymbol {
     type Response = Vec<SymbolInformation>;
 
@@ -55,15 +88,42 @@ impl RequestAction for WorkspaceSymbol {
         ctx: InitActionContext,
         params: Self::Params,
     ) -> Result<Self::Response, ResponseError> {
+        let limit = ctx.config.lock().unwrap().workspace_symbol_search_limit;
+        // Bounds the matches which are sorted, since a short query may match
+        // most of the definitions, including the ones of `std`.
+        let pre_limit = limit.saturating_mul(WORKSPACE_SYMBOL_PRE_LIMIT_FACTOR);
         let analysis = ctx.analysis;
-        let query = SymbolQuery::subsequence(&params.query).limit(512);
-        let defs = analysis.query_defs(query).unwrap_or_else(|_| vec![]);
+        // A path, e.g. `io::Read`, scopes the results to the items of a module.
+        // Otherwise, the items of every crate (i.e., every workspace member in
+        // workspace mode) are searched. The limit only applies once the best
+        // matches are sorted first.
+        let (name, mut defs) = match params.query.rfind("::") {
+            Some(idx) => {
+                let name = params.query[idx + 2..].to_lowercase();
+                let mut defs = analysis.items_in_module(&params.query[..idx]).unwrap_or_default();
+                defs.retain(|def| is_subsequence(&name, &def.name.to_lowercase()));
+                defs.truncate(pre_limit);
+                (name, defs)
+            }
+            None => {
+                // The (alphabetically) first subsequence matches may leave out
+                // the best ones, which start with the query.
+                let query = |query: SymbolQuery| {
+                    analysis.query_defs(query.limit(pre_limit)).unwrap_or_else(|_| vec![])
+                };
+                let mut defs = query(SymbolQuery::prefix(&params.query));
+                defs.extend(query(SymbolQuery::subsequence(&params.query)));
+                (params.query.to_lowercase(), defs)
+            }
+        };
+        defs.sort_by_cached_key(|def| match_order(&name, &def.name));
 
         Ok(defs
             .into_iter()
             // Sometimes analysis will return duplicate symbols
             // for the same location, fix that up.
             .unique_by(|d| (d.span.clone(), d.name.clone()))
+            .take(limit)
             .map(|d| SymbolInformation {
                 name: d.name,
                 kind: source_kind_from_def_kind(d.kind),
@@ -78,6 +138,34 @@ impl RequestAction for WorkspaceSymbol {
     }
 }
 
+/// How many times `workspace_symbol_search_limit` matches are sorted by
+/// `WorkspaceSymbol`, of which the best ones are returned.
+const WORKSPACE_SYMBOL_PRE_LIMIT_FACTOR: usize = 4;
+
+/// Returns the key sorting the symbol `name` among the ones matching the
+/// lowercase `query`: exact matches first, then prefix, substring and (other)
+/// subsequence matches, each sorted alphabetically.
+fn match_order(query: &str, name: &str) -> (u8, String, String) {
+    let lowercase = name.to_lowercase();
+    let rank = if lowercase == query {
+        0
+    } else if lowercase.starts_with(query) {
+        1
+    } else if lowercase.contains(query) {
+        2
+    } else {
+        3
+    };
+    (rank, lowercase, name.to_owned())
+}
+
+/// Returns `true` if the characters of `query` appear in `name` in order, as
+/// for `SymbolQuery::subsequence`.
+fn is_subsequence(query: &str, name: &str) -> bool {
+    let mut name = name.chars();
+    query.chars().all(|c| name.any(|n| n == c))
+}
+
 impl RequestAction for Symbols {
     type Response = Vec<SymbolInformation>;
 
@@ -116,23 +204,55 @@ impl RequestAction for Symbols {
     }
 }
 
+/// A `textDocument/hover` response, optionally carrying the hover actions
+/// (see the `hover_actions` config option) in the `data` field.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HoverResponse {
+    #[serde(flatten)]
+    pub hover: lsp_data::Hover,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<hover::HoverActions>,
+}
+
 impl RequestAction for Hover {
-    type Response = lsp_data::Hover;
+    type Response = HoverResponse;
 
     fn fallback_response() -> Result<Self::Response, ResponseError> {
-        Ok(lsp_data::Hover { contents: HoverContents::Array(vec![]), range: None })
+        Ok(HoverResponse {
+            hover: lsp_data::Hover { contents: HoverContents::Array(vec![]), range: None },
+            data: None,
+        })
     }
 
     fn handle(
         ctx: InitActionContext,
         params: Self::Params,
     ) -> Result<Self::Response, ResponseError> {
-        let tooltip = hover::tooltip(&ctx, &params)?;
+        let file_path = parse_file_path!(&params.text_document.uri, "hover")?;
+        if let Some(response) = ctx.hover_cache.lock().unwrap().get(&file_path, params.position) {
+            trace!("hover cache hit: {:?} {:?}", file_path, params.position);
+            return Ok(response);
+        }
 
-        Ok(lsp_data::Hover {
-            contents: HoverContents::Array(tooltip.contents),
-            range: Some(ls_util::rls_to_range(tooltip.range)),
-        })
+        let tooltip = hover::tooltip(&ctx, &params)?;
+        let response = HoverResponse {
+            hover: lsp_data::Hover {
+                contents: HoverContents::Array(tooltip.contents),
+                range: Some(ls_util::rls_to_range(tooltip.range)),
+            },
+            data: ctx.hover_actions(&params),
+        };
+
+        let capacity = ctx.config.lock().unwrap().hover_cache_size;
+        if capacity > 0 {
+            ctx.hover_cache.lock().unwrap().insert(
+                file_path,
+                params.position,
+                response.clone(),
+                capacity,
+            );
+        }
+        Ok(response)
     }
 }
 
@@ -167,6 +287,144 @@ impl RequestAction for Implementation {
     }
 }
 
+impl RequestAction for PrepareTypeHierarchy {
+    type Response = Option<Vec<TypeHierarchyItem>>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "prepare_type_hierarchy")?;
+        let span = ctx.convert_pos_to_span(file_path, params.position);
+
+        let def = ctx.analysis.id(&span).and_then(|id| ctx.analysis.get_def(id));
+        Ok(def.ok().filter(is_type_hierarchy_def).map(|def| vec![type_hierarchy_item(def)]))
+    }
+}
+
+impl RequestAction for TypeHierarchySupertypes {
+    type Response = Option<Vec<TypeHierarchyItem>>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let id = type_hierarchy_id(&ctx.analysis, &params.item)?;
+        let supertypes = ctx.analysis.find_supertypes(id).map_err(|_| ResponseError::Empty)?;
+        Ok(Some(type_hierarchy_items(&ctx.analysis, supertypes)))
+    }
+}
+
+impl RequestAction for TypeHierarchySubtypes {
+    type Response = Option<Vec<TypeHierarchyItem>>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let id = type_hierarchy_id(&ctx.analysis, &params.item)?;
+        let subtypes = ctx.analysis.find_subtypes(id).map_err(|_| ResponseError::Empty)?;
+        Ok(Some(type_hierarchy_items(&ctx.analysis, subtypes)))
+    }
+}
+
+fn is_type_hierarchy_def(def: &Def) -> bool {
+    match def.kind {
+        DefKind::Struct | DefKind::Enum | DefKind::Union | DefKind::Trait => true,
+        _ => false,
+    }
+}
+
+fn type_hierarchy_item(def: Def) -> TypeHierarchyItem {
+    let Location { uri, range } = ls_util::rls_to_location(&def.span);
+    TypeHierarchyItem {
+        name: def.name,
+        kind: source_kind_from_def_kind(def.kind),
+        detail: Some(def.qualname),
+        uri,
+        range,
+        selection_range: range,
+    }
+}
+
+/// Finds the definition of a type hierarchy item, which was previously
+/// returned by one of the type hierarchy requests.
+fn type_hierarchy_id(
+    analysis: &AnalysisHost,
+    item: &TypeHierarchyItem,
+) -> Result<Id, ResponseError> {
+    let file_path = parse_file_path!(&item.uri, "type_hierarchy")?;
+    let span = crate::Span::from_range(ls_util::range_to_rls(item.selection_range), file_path);
+    analysis.id(&span).map_err(|_| ResponseError::Empty)
+}
+
+fn type_hierarchy_items(analysis: &AnalysisHost, ids: Vec<Id>) -> Vec<TypeHierarchyItem> {
+    ids.into_iter()
+        .unique()
+        .filter_map(|id| analysis.get_def(id).ok())
+        .map(type_hierarchy_item)
+        .collect()
+}
+
+impl RequestAction for CallHierarchyPrepare {
+    type Response = Option<Vec<CallHierarchyItem>>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "prepare_call_hierarchy")?;
+
+        Ok(prepare_call_hierarchy(&ctx, &file_path, params.position).map(|item| vec![item]))
+    }
+}
+
+impl RequestAction for CallHierarchyIncomingCalls {
+    type Response = Option<Vec<CallHierarchyIncomingCall>>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        Ok(incoming_calls(&ctx, &params.item))
+    }
+}
+
+impl RequestAction for CallHierarchyOutgoingCalls {
+    type Response = Option<Vec<CallHierarchyOutgoingCall>>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        Ok(outgoing_calls(&ctx, &params.item))
+    }
+}
+
 impl RequestAction for Definition {
     type Response = Vec<Location>;
 
@@ -179,8 +437,8 @@ impl RequestAction for Definition {
         params: Self::Params,
     ) -> Result<Self::Response, ResponseError> {
         // Save-analysis thread.
-        let file_path = parse_file_path!(&params.text_document.uri, "goto_def")?;
-        let span = ctx.convert_pos_to_span(file_path.clone(), params.position);
+        ctx.wait_for_fresh_analysis()?;
+        let (file_path, span) = ctx.get_identifier_at_position(&params)?;
 
         if let Ok(out) = ctx.analysis.goto_def(&span) {
             let result = vec![ls_util::rls_to_location(&out)];
@@ -261,7 +519,8 @@ impl RequestAction for Completion {
         let is_use_stmt = racer::is_use_stmt(&file_path, location, &session);
 
         let code_completion_has_snippet_support =
-            ctx.client_capabilities.code_completion_has_snippet_support;
+            ctx.client_capabilities.code_completion_has_snippet_support
+                && ctx.config.lock().unwrap().completion_snippets;
 
         Ok(results
             .map(|comp| {
@@ -269,7 +528,11 @@ impl RequestAction for Completion {
                 if is_use_stmt && comp.mtype.is_function() {
                     item.insert_text = Some(comp.matchstr);
                 } else if code_completion_has_snippet_support {
-                    let snippet = racer::snippet_for_match(&comp, &session);
+                    // Prefer the signature of the save-analysis data, which
+                    // is accurate for functions racer can't fully parse.
+                    let snippet = analysis_fn_signature(&ctx, &comp)
+                        .and_then(|sig| function_snippet(&comp.matchstr, &sig))
+                        .unwrap_or_else(|| racer::snippet_for_match(&comp, &session));
                     if !snippet.is_empty() {
                         item.insert_text = Some(snippet);
                         item.insert_text_format = Some(InsertTextFormat::Snippet);
@@ -281,6 +544,101 @@ impl RequestAction for Completion {
     }
 }
 
+/// Returns the signature of the function matched by racer as recorded in the
+/// save-analysis data, e.g., `fn (a: u32, b: &str) -> bool`.
+fn analysis_fn_signature(ctx: &InitActionContext, m: &racer::Match) -> Option<String> {
+    let (row, col_start) = from_racer_coord(m.coords?);
+    let row = row.zero_indexed();
+    let col_end = span::Column::new_zero_indexed(col_start.0 + m.matchstr.len() as u32);
+    let span = span::Span::new(row, row, col_start, col_end, m.filepath.clone());
+
+    let def = ctx.analysis.id(&span).and_then(|id| ctx.analysis.get_def(id)).ok()?;
+    match def.kind {
+        DefKind::Function | DefKind::Method => Some(def.value),
+        _ => None,
+    }
+}
+
+/// Returns a snippet calling the function `name` with a tab stop for each of
+/// the parameters in `signature`, e.g., `foo(${1:a}, ${2:b})` for
+/// `fn (a: u32, b: &str) -> bool`. The `self` parameter of methods is skipped.
+fn function_snippet(name: &str, signature: &str) -> Option<String> {
+    let placeholders: Vec<_> = signature_params(signature)?
+        .into_iter()
+        .filter(|pat| !is_self_param(pat))
+        .enumerate()
+        .map(|(i, pat)| {
+            let pat = pat.replace('\\', "\\\\").replace('$', "\\$").replace('}', "\\}");
+            format!("${{{}:{}}}", i + 1, pat)
+        })
+        .collect();
+    Some(format!("{}({})", name, placeholders.join(", ")))
+}
+
+/// Whether `pat` is the `self` parameter of a method, e.g., `&'a mut self`.
+pub(super) fn is_self_param(pat: &str) -> bool {
+    pat.rsplit(|c: char| c == '&' || c.is_whitespace()).next() == Some("self")
+}
+
+/// Returns the patterns of the parameters in `signature`, e.g., `a` and `b` for
+/// `fn (a: u32, b: &str) -> bool`, including the `self` parameter of methods.
+pub(super) fn signature_params(signature: &str) -> Option<Vec<&str>> {
+    Some(
+        signature_param_ranges(signature)?
+            .into_iter()
+            .map(|range| signature[range].split(':').next().unwrap_or_default().trim())
+            .filter(|pat| !pat.is_empty())
+            .collect(),
+    )
+}
+
+/// Returns the byte ranges of the parameters in `signature`, e.g., of `a: u32`
+/// and `b: &str` for `fn (a: u32, b: &str) -> bool`, without the surrounding
+/// whitespace.
+pub(super) fn signature_param_ranges(signature: &str) -> Option<Vec<std::ops::Range<usize>>> {
+    let start = signature.find('(')?;
+    let mut depth = 0;
+    let mut params = vec![];
+    let mut param_start = start + 1;
+    for (i, c) in signature[start..].char_indices().map(|(i, c)| (start + i, c)) {
+        match c {
+            '(' | '[' | '<' => depth += 1,
+            // `->` in the types of function pointers.
+            '>' if signature[..i].ends_with('-') => {}
+            ')' | ']' | '>' => {
+                depth -= 1;
+                if depth == 0 {
+                    params.push(param_start..i);
+                    break;
+                }
+            }
+            ',' if depth == 1 => {
+                params.push(param_start..i);
+                param_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return None;
+    }
+
+    Some(
+        params
+            .into_iter()
+            .filter_map(|range| {
+                let param = &signature[range.clone()];
+                let trimmed = param.trim_start();
+                if trimmed.trim_end().is_empty() {
+                    return None;
+                }
+                let start = range.start + param.len() - trimmed.len();
+                Some(start..start + trimmed.trim_end().len())
+            })
+            .collect(),
+    )
+}
+
 impl RequestAction for DocumentHighlight {
     type Response = Vec<lsp_data::DocumentHighlight>;
 
@@ -293,17 +651,39 @@ impl RequestAction for DocumentHighlight {
         params: Self::Params,
     ) -> Result<Self::Response, ResponseError> {
         let file_path = parse_file_path!(&params.text_document.uri, "highlight")?;
-        let span = ctx.convert_pos_to_span(file_path.clone(), params.position);
-
-        let result = ctx.analysis.find_all_refs(&span, true, false).unwrap_or_else(|_| vec![]);
+        let pos = ls_util::position_to_rls(params.position);
+        let (span, word) = match ctx.vfs.word_at_position(&file_path, &pos, PositionEncoding::Utf16)
+        {
+            Some(word) => word,
+            None => return Ok(vec![]),
+        };
+
+        let result = match ctx.analysis.find_all_refs(&span, true, false) {
+            Ok(result) => result,
+            // No analysis data (yet), so fall back to the text of the file.
+            Err(_) => {
+                return match ctx.vfs.load_file(&file_path) {
+                    Ok(FileContents::Text(text)) => Ok(word_highlights(&text, &word)),
+                    _ => Ok(vec![]),
+                };
+            }
+        };
+        // The definition is the only write, as the analysis doesn't tell
+        // whether a reference assigns to the symbol.
+        let def = ctx.analysis.goto_def(&span).ok();
 
         Ok(result
             .iter()
             .filter_map(|span| {
                 if span.file == file_path {
+                    let kind = if Some(span) == def.as_ref() {
+                        DocumentHighlightKind::Write
+                    } else {
+                        DocumentHighlightKind::Read
+                    };
                     Some(lsp_data::DocumentHighlight {
                         range: ls_util::rls_to_range(span.range),
-                        kind: Some(DocumentHighlightKind::Text),
+                        kind: Some(kind),
                     })
                 } else {
                     None
@@ -313,6 +693,31 @@ impl RequestAction for DocumentHighlight {
     }
 }
 
+/// Highlights the occurrences of `word` in `text` as a whole identifier.
+fn word_highlights(text: &str, word: &str) -> Vec<lsp_data::DocumentHighlight> {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let len = word.chars().count() as u64;
+    text.lines()
+        .enumerate()
+        .flat_map(|(row, line)| {
+            line.match_indices(word)
+                .filter(move |&(i, _)| {
+                    !line[..i].ends_with(is_ident) && !line[i + word.len()..].starts_with(is_ident)
+                })
+                .map(move |(i, _)| {
+                    let start = Position::new(row as u64, line[..i].chars().count() as u64);
+                    lsp_data::DocumentHighlight {
+                        range: Range {
+                            start,
+                            end: Position::new(start.line, start.character + len),
+                        },
+                        kind: Some(DocumentHighlightKind::Text),
+                    }
+                })
+        })
+        .collect()
+}
+
 impl RequestAction for Rename {
     type Response = ResponseWithMessage<WorkspaceEdit>;
 
@@ -329,10 +734,14 @@ impl RequestAction for Rename {
         // data is ready.
         ctx.block_on_build();
 
-        let file_path =
-            parse_file_path!(&params.text_document_position.text_document.uri, "rename")?;
-        let span = ctx.convert_pos_to_span(file_path, params.text_document_position.position);
+        let (_, span) = ctx.get_identifier_at_position(&params.text_document_position)?;
 
+        let def = match renameable_def(&ctx, &span) {
+            Ok(def) => def,
+            Err(reason) => {
+                return Ok(ResponseWithMessage::Warn(format!("Rename failed: {}", reason)))
+            }
+        };
         let analysis = ctx.analysis;
 
         macro_rules! unwrap_or_fallback {
@@ -346,22 +755,6 @@ impl RequestAction for Rename {
             };
         }
 
-        let id = unwrap_or_fallback!(
-            analysis.crate_local_id(&span),
-            "Rename failed: no information for symbol"
-        );
-        let def =
-            unwrap_or_fallback!(analysis.get_def(id), "Rename failed: no definition for symbol");
-        if def.name == "self" || def.name == "Self"
-            // FIXME(#578)
-            || def.kind == data::DefKind::Mod
-        {
-            return Ok(ResponseWithMessage::Warn(format!(
-                "Rename failed: cannot rename {}",
-                if def.kind == data::DefKind::Mod { "modules" } else { &def.name }
-            )));
-        }
-
         let result = unwrap_or_fallback!(
             analysis.find_all_refs(&span, true, true),
             "Rename failed: error finding references"
@@ -374,6 +767,16 @@ impl RequestAction for Rename {
             ));
         }
 
+        // Text edits are only valid where the symbol is spelled out in the source.
+        let vfs = &ctx.vfs;
+        let (result, macro_sites): (Vec<_>, Vec<_>) =
+            result.into_iter().partition(|span| !is_macro_generated(vfs, span, &def.name));
+        if result.is_empty() {
+            return Ok(ResponseWithMessage::Warn(
+                "Rename failed: all references are generated by macros".to_owned(),
+            ));
+        }
+
         let mut edits: HashMap<Url, Vec<TextEdit>> = HashMap::new();
 
         for item in &result {
@@ -390,17 +793,120 @@ impl RequestAction for Rename {
             ));
         }
 
-        Ok(ResponseWithMessage::Response(WorkspaceEdit {
-            changes: Some(edits),
-            document_changes: None,
+        let edit = WorkspaceEdit { changes: Some(edits), document_changes: None };
+        if macro_sites.is_empty() {
+            Ok(ResponseWithMessage::Response(edit))
+        } else {
+            let project = &ctx.current_project;
+            let sites = macro_sites
+                .iter()
+                .map(|span| {
+                    let file = span.file.strip_prefix(project).unwrap_or(&span.file);
+                    format!(
+                        "{}:{}:{}",
+                        file.display(),
+                        span.range.row_start.one_indexed().0,
+                        span.range.col_start.one_indexed().0
+                    )
+                })
+                .join(", ");
+            Ok(ResponseWithMessage::ResponseWithWarn(
+                edit,
+                format!("Rename skipped references generated by macros: {}", sites),
+            ))
+        }
+    }
+}
+
+impl RequestAction for PrepareRename {
+    type Response = Option<PrepareRenameResponse>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let (_, span) = ctx.get_identifier_at_position(&params)?;
+        let def = renameable_def(&ctx, &span).map_err(|reason| {
+            ResponseError::Message(ErrorCode::InvalidParams, format!("Cannot rename: {}", reason))
+        })?;
+
+        Ok(Some(PrepareRenameResponse::RangeWithPlaceholder {
+            range: ls_util::rls_to_range(span.range),
+            placeholder: def.name,
         }))
     }
 }
 
-#[derive(Debug)]
+/// Keywords, which the analysis may attribute to a definition, e.g. `Self`.
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type",
+    "unsafe", "use", "where", "while",
+];
+
+const BUILTIN_TYPES: &[&str] = &[
+    "bool", "char", "str", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64",
+    "u128", "usize", "f32", "f64",
+];
+
+/// Returns the definition of the symbol at `span` if it can be renamed, or the
+/// reason why it can't.
+fn renameable_def(
+    ctx: &InitActionContext,
+    span: &span::Span<span::ZeroIndexed>,
+) -> Result<Def, String> {
+    let name = ctx.vfs.load_span(span.clone()).unwrap_or_default();
+    if KEYWORDS.contains(&name.as_str()) {
+        return Err(format!("`{}` is a keyword", name));
+    }
+    if BUILTIN_TYPES.contains(&name.as_str()) {
+        return Err(format!("`{}` is a built-in type", name));
+    }
+
+    let analysis = &ctx.analysis;
+    let id = analysis.crate_local_id(span).map_err(|_| match analysis.id(span) {
+        Ok(_) => "symbol is defined in an external crate".to_owned(),
+        Err(_) => "no information for symbol".to_owned(),
+    })?;
+    let def = analysis.get_def(id).map_err(|_| "no definition for symbol".to_owned())?;
+    if def.name == "self" || def.name == "Self" {
+        return Err(format!("cannot rename {}", def.name));
+    }
+    // FIXME(#578)
+    if def.kind == data::DefKind::Mod {
+        return Err("cannot rename modules".to_owned());
+    }
+    Ok(def)
+}
+
+/// Returns whether `span` refers to code generated by a macro expansion. Such
+/// references are attributed to the macro invocation or definition, rather
+/// than to an occurrence of the symbol `name` in the source text.
+fn is_macro_generated(vfs: &Vfs, span: &span::Span<span::ZeroIndexed>, name: &str) -> bool {
+    match vfs.load_span(span.clone()) {
+        // Raw identifiers are spelled with an `r#` prefix.
+        Ok(text) => text.trim_start_matches("r#") != name,
+        Err(e) => {
+            debug!("is_macro_generated: couldn't load {:?}: {:?}", span, e);
+            false
+        }
+    }
+}
+
 pub enum ExecuteCommandResponse {
     /// Response/client request containing workspace edits.
     ApplyEdit(ApplyWorkspaceEditParams),
+    /// Queues a build of the project, which needs the output to report it.
+    Build(InitActionContext),
+    /// Publishes empty diagnostics for the given files.
+    ClearDiagnostics(Vec<Url>),
+    /// The result of the command.
+    Value(serde_json::Value),
 }
 
 impl server::Response for ExecuteCommandResponse {
@@ -414,6 +920,16 @@ impl server::Response for ExecuteCommandResponse {
                 let request = Request::<ApplyWorkspaceEdit>::new(id, params);
                 out.request(request);
             }
+            ExecuteCommandResponse::Build(ref ctx) => {
+                ctx.rebuild_current_project(out);
+            }
+            ExecuteCommandResponse::ClearDiagnostics(ref uris) => {
+                for uri in uris {
+                    let params = PublishDiagnosticsParams { uri: uri.clone(), diagnostics: vec![] };
+                    out.notify(Notification::<PublishDiagnostics>::new(params));
+                }
+            }
+            ExecuteCommandResponse::Value(value) => return value.send(id, out),
         }
 
         // The formal request response is a simple ACK, though the objective
@@ -429,7 +945,9 @@ impl RequestAction for ExecuteCommand {
         Err(ResponseError::Empty)
     }
 
-    /// Currently supports "rls.applySuggestion", "rls.deglobImports".
+    /// Currently supports "rls.applySuggestion", "rls.deglobImports", "rls.extractFunction",
+    /// "rls.organizeImports", "rls.reloadConfig", "rls.rebuildProject", "rls.clearDiagnostics",
+    /// "rls.showAnalysisStats".
     fn handle(
         ctx: InitActionContext,
         params: ExecuteCommandParams,
@@ -438,6 +956,26 @@ impl RequestAction for ExecuteCommand {
             apply_suggestion(&params.arguments).map(ExecuteCommandResponse::ApplyEdit)
         } else if params.command.starts_with("rls.deglobImports") {
             apply_deglobs(params.arguments, &ctx).map(ExecuteCommandResponse::ApplyEdit)
+        } else if params.command.starts_with("rls.extractFunction") {
+            apply_extract_fn(&params.arguments).map(ExecuteCommandResponse::ApplyEdit)
+        } else if params.command.starts_with("rls.organizeImports") {
+            apply_organize_imports(&params.arguments, &ctx).map(ExecuteCommandResponse::ApplyEdit)
+        } else if params.command.starts_with("rls.reloadConfig") {
+            // Options relevant to Cargo may have changed, see `DidChangeConfiguration`.
+            ctx.reload_config_file()
+                .map_err(|e| ResponseError::Message(ErrorCode::InvalidParams, e))?;
+            Ok(ExecuteCommandResponse::Build(ctx))
+        } else if params.command.starts_with("rls.rebuildProject") {
+            Ok(ExecuteCommandResponse::Build(ctx))
+        } else if params.command.starts_with("rls.clearDiagnostics") {
+            Ok(ExecuteCommandResponse::ClearDiagnostics(ctx.clear_diagnostics()))
+        } else if params.command.starts_with("rls.showAnalysisStats") {
+            let stats = ctx.analysis.stats().map_err(|_| ResponseError::Empty)?;
+            Ok(ExecuteCommandResponse::Value(json!({
+                "crates": stats.crates,
+                "defs": stats.defs,
+                "refs": stats.refs,
+            })))
         } else {
             debug!("Unknown command: {}", params.command);
             Err(ResponseError::Message(ErrorCode::MethodNotFound, "Unknown command".to_owned()))
@@ -453,6 +991,42 @@ fn apply_suggestion(args: &[serde_json::Value]) -> Result<ApplyWorkspaceEditPara
     Ok(ApplyWorkspaceEditParams { edit: make_workspace_edit(location, new_text) })
 }
 
+fn apply_extract_fn(args: &[serde_json::Value]) -> Result<ApplyWorkspaceEditParams, ResponseError> {
+    let edit =
+        args.get(0).and_then(|arg| serde_json::from_value(arg.clone()).ok()).ok_or_else(|| {
+            ResponseError::Message(ErrorCode::InvalidParams, "Expected a workspace edit".to_owned())
+        })?;
+
+    trace!("apply_extract_fn {:?}", edit);
+    Ok(ApplyWorkspaceEditParams { edit })
+}
+
+fn apply_organize_imports(
+    args: &[serde_json::Value],
+    ctx: &InitActionContext,
+) -> Result<ApplyWorkspaceEditParams, ResponseError> {
+    let uri: Url =
+        args.get(0).and_then(|arg| serde_json::from_value(arg.clone()).ok()).ok_or_else(|| {
+            ResponseError::Message(ErrorCode::InvalidParams, "Expected a document URI".to_owned())
+        })?;
+    let file_path = parse_file_path!(&uri, "organize_imports")?;
+
+    trace!("apply_organize_imports {:?}", file_path);
+    let text_edits = match ctx.vfs.load_file(&file_path) {
+        Ok(FileContents::Text(text)) => organize_imports(&text).into_iter().collect(),
+        Ok(FileContents::Binary(_)) => vec![],
+        Err(e) => {
+            debug!("Couldn't load {:?}: {}", file_path, e);
+            return Err(ResponseError::Empty);
+        }
+    };
+    let changes: HashMap<_, _> = vec![(uri, text_edits)].into_iter().collect();
+
+    Ok(ApplyWorkspaceEditParams {
+        edit: WorkspaceEdit { changes: Some(changes), document_changes: None },
+    })
+}
+
 fn apply_deglobs(
     args: Vec<serde_json::Value>,
     ctx: &InitActionContext,
@@ -504,11 +1078,39 @@ fn make_suggestion_fix_actions(
                 command: format!("rls.applySuggestion-{}", ctx.pid),
                 arguments: Some(vec![span, new_text]),
             };
-            code_actions_result.push(cmd);
+            code_actions_result.push(ResolvableCodeActionOrCommand::Command(cmd));
         }
     }
 }
 
+/// Creates quick fix `CodeAction`s applying the machine-applicable compiler
+/// suggestions for diagnostics in the requested range.
+/// The results are appended to `code_actions_result`.
+fn make_quickfix_actions(
+    params: &<CodeAction as lsp_data::request::Request>::Params,
+    ctx: &InitActionContext,
+    code_actions_result: &mut <CodeAction as RequestAction>::Response,
+) {
+    let pending_suggestions = ctx.pending_suggestions.lock().unwrap();
+    let mut quickfixes: Vec<_> = pending_suggestions
+        .iter()
+        .filter(|((uri, range), _)| {
+            *uri == params.text_document.uri && range.overlaps(&params.range)
+        })
+        .collect();
+    quickfixes.sort_by_key(|((_, range), _)| range.start);
+
+    for ((uri, _), edits) in quickfixes {
+        let changes = vec![(uri.clone(), edits.clone())].into_iter().collect();
+        code_actions_result.push(ResolvableCodeActionOrCommand::CodeAction(ResolvableCodeAction {
+            title: "Apply suggested fix".to_owned(),
+            kind: Some(code_action_kind::QUICKFIX.to_owned()),
+            edit: Some(WorkspaceEdit { changes: Some(changes), document_changes: None }),
+            data: None,
+        }));
+    }
+}
+
 /// Creates `CodeAction`s for performing deglobbing when a wildcard import is found.
 /// The results are appended to `code_actions_result`.
 fn make_deglob_actions(
@@ -559,11 +1161,74 @@ fn make_deglob_actions(
                 command: format!("rls.deglobImports-{}", ctx.pid),
                 arguments: Some(deglob_results),
             };
-            code_actions_result.push(cmd);
+            code_actions_result.push(ResolvableCodeActionOrCommand::Command(cmd));
         }
     };
 }
 
+/// Creates a `Command` sorting the `use` items of the file, if these aren't
+/// organized already. The result is appended to `code_actions_result`.
+fn make_organize_imports_action(
+    params: &<CodeAction as lsp_data::request::Request>::Params,
+    file_path: &Path,
+    ctx: &InitActionContext,
+    code_actions_result: &mut <CodeAction as RequestAction>::Response,
+) {
+    let needs_organizing = match ctx.vfs.load_file(file_path) {
+        Ok(FileContents::Text(text)) => organize_imports(&text).is_some(),
+        _ => false,
+    };
+    if needs_organizing {
+        let uri = serde_json::to_value(&params.text_document.uri).unwrap();
+        let cmd = Command {
+            title: "Organize imports".to_owned(),
+            command: format!("rls.organizeImports-{}", ctx.pid),
+            arguments: Some(vec![uri]),
+        };
+        code_actions_result.push(ResolvableCodeActionOrCommand::Command(cmd));
+    }
+}
+
+/// Creates a `CodeAction` extracting the selected code into a new function.
+/// The results are appended to `code_actions_result`.
+fn make_extract_fn_actions(
+    params: &<CodeAction as lsp_data::request::Request>::Params,
+    file_path: &Path,
+    ctx: &InitActionContext,
+    code_actions_result: &mut <CodeAction as RequestAction>::Response,
+) {
+    // Computing the edit is costly, so leave it to the resolve request if possible.
+    if ctx.client_capabilities.code_action_resolve_support {
+        if params.range.start != params.range.end {
+            let id = ctx.next_code_action_id.fetch_add(1, Ordering::SeqCst) as u64;
+            let pending = PendingCodeAction::ExtractFunction {
+                file_path: file_path.to_owned(),
+                uri: params.text_document.uri.clone(),
+                range: params.range,
+            };
+            ctx.pending_code_actions.lock().unwrap().insert(id, pending);
+
+            let action = ResolvableCodeAction {
+                title: "Extract to function".to_owned(),
+                kind: Some(code_action_kind::REFACTOR_EXTRACT.to_owned()),
+                edit: None,
+                data: Some(id.into()),
+            };
+            code_actions_result.push(ResolvableCodeActionOrCommand::CodeAction(action));
+        }
+        return;
+    }
+
+    if let Some(edit) = extract_fn_edit(ctx, file_path, &params.text_document.uri, params.range) {
+        let cmd = Command {
+            title: "Extract to function".to_owned(),
+            command: format!("rls.extractFunction-{}", ctx.pid),
+            arguments: Some(vec![serde_json::to_value(&edit).unwrap()]),
+        };
+        code_actions_result.push(ResolvableCodeActionOrCommand::Command(cmd));
+    }
+}
+
 // Ideally we'd use Rustfmt for this, but reparsing is a bit of a pain.
 fn sort_deglob_str(s: &str) -> String {
     let mut substrings = s.split(',').map(str::trim).collect::<Vec<_>>();
@@ -594,7 +1259,7 @@ fn sort_deglob_str(s: &str) -> String {
 }
 
 impl RequestAction for CodeAction {
-    type Response = Vec<Command>;
+    type Response = Vec<ResolvableCodeActionOrCommand>;
 
     fn fallback_response() -> Result<Self::Response, ResponseError> {
         Ok(vec![])
@@ -608,17 +1273,83 @@ impl RequestAction for CodeAction {
 
         let file_path = parse_file_path!(&params.text_document.uri, "code_action")?;
 
+        // Only the actions of the latest request can be resolved.
+        ctx.pending_code_actions.lock().unwrap().clear();
+
         let mut cmds = vec![];
         if ctx.build_ready() {
+            make_quickfix_actions(&params, &ctx, &mut cmds);
             make_suggestion_fix_actions(&params, &file_path, &ctx, &mut cmds);
         }
         if ctx.analysis_ready() {
             make_deglob_actions(&params, &file_path, &ctx, &mut cmds);
+            make_extract_fn_actions(&params, &file_path, &ctx, &mut cmds);
         }
+        make_organize_imports_action(&params, &file_path, &ctx, &mut cmds);
         Ok(cmds)
     }
 }
 
+impl RequestAction for CodeActionResolve {
+    type Response = ResolvableCodeAction;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Err(ResponseError::Message(
+            ErrorCode::InternalError,
+            "Code action could not be resolved".into(),
+        ))
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        mut params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        trace!("code_action_resolve {:?}", params);
+
+        let id = params.data.as_ref().and_then(serde_json::Value::as_u64);
+        let pending = id.and_then(|id| ctx.pending_code_actions.lock().unwrap().remove(&id));
+        let edit = match pending {
+            Some(PendingCodeAction::ExtractFunction { file_path, uri, range }) => {
+                extract_fn_edit(&ctx, &file_path, &uri, range)
+            }
+            None => {
+                return Err(ResponseError::Message(
+                    ErrorCode::InvalidParams,
+                    "Unknown or outdated code action".into(),
+                ))
+            }
+        };
+
+        match edit {
+            Some(edit) => {
+                params.edit = Some(edit);
+                Ok(params)
+            }
+            None => Err(ResponseError::Message(
+                ErrorCode::InternalError,
+                format!("`{}` can't be applied", params.title),
+            )),
+        }
+    }
+}
+
+impl RequestAction for DocumentLinkRequest {
+    type Response = Vec<DocumentLink>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "document_link")?;
+
+        Ok(collect_document_links(&ctx, &file_path))
+    }
+}
+
 impl RequestAction for Formatting {
     type Response = Vec<TextEdit>;
 
@@ -655,6 +1386,40 @@ impl RequestAction for RangeFormatting {
     }
 }
 
+impl RequestAction for OnTypeFormatting {
+    type Response = Vec<TextEdit>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        if !ctx.config.lock().unwrap().on_type_formatting {
+            return Ok(vec![]);
+        }
+        let file_path = parse_file_path!(&params.text_document.uri, "on_type_formatting")?;
+        let text = match ctx.vfs.load_file(&file_path) {
+            Ok(FileContents::Text(text)) => text,
+            _ => return Ok(vec![]),
+        };
+
+        match params.ch.as_str() {
+            // Format the whole block the brace closes.
+            "}" => match closed_block(&text, params.position) {
+                Some(block) => reformat(&params.text_document, Some(block), &params.options, &ctx),
+                None => Ok(vec![]),
+            },
+            "{" | ";" => {
+                Ok(reindent_line(&text, params.position, &params.options).into_iter().collect())
+            }
+            _ => Ok(vec![]),
+        }
+    }
+}
+
 fn reformat(
     doc: &TextDocumentIdentifier,
     selection: Option<Range>,
@@ -711,7 +1476,11 @@ fn reformat(
         }
     }
 
-    if let Some(r) = selection {
+    // Older versions of Rustfmt don't support `--file-lines`, in which case
+    // we format the whole file and only keep the edits of the selection.
+    let formatter = ctx.formatter();
+    let supports_file_lines = formatter.supports_file_lines();
+    if let (Some(r), true) = (selection, supports_file_lines) {
         let range_of_rls = ls_util::range_to_rls(r).one_indexed();
         let range =
             RustfmtRange::new(range_of_rls.row_start.0 as usize, range_of_rls.row_end.0 as usize);
@@ -721,9 +1490,15 @@ fn reformat(
         config.set().file_lines(file_lines);
     };
 
-    let text_edits = ctx
-        .formatter()
+    let text_edits = formatter
         .calc_text_edits(input, config)
+        .map(|text_edits| match selection {
+            Some(selection) if !supports_file_lines => {
+                debug!("Reformat: `--file-lines` unsupported, formatting the whole file");
+                edits_within(text_edits, selection)
+            }
+            _ => text_edits,
+        })
         .map_err(|msg| ResponseError::Message(ErrorCode::InternalError, msg.to_string()))?;
 
     // Note that we don't need to update the VFS, the client echos back the
@@ -739,6 +1514,244 @@ fn reformat(
     Ok(text_edits)
 }
 
+impl RequestAction for EnvDiff {
+    type Response = Vec<(String, environment::Change)>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(ctx: InitActionContext, _: Self::Params) -> Result<Self::Response, ResponseError> {
+        Ok(ctx.build_queue.last_env_diff())
+    }
+}
+
+impl RequestAction for UnsavedChanges {
+    type Response = Option<String>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.uri, "unsaved_changes")?;
+
+        Ok(ctx.vfs.diff_against_disk(&file_path))
+    }
+}
+
+impl RequestAction for Breadcrumbs {
+    type Response = Vec<BreadcrumbItem>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "breadcrumbs")?;
+
+        Ok(collect_breadcrumbs(&ctx, &file_path, params.position))
+    }
+}
+
+impl RequestAction for SelectionRangeRequest {
+    type Response = Vec<SelectionRange>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "selection_range")?;
+
+        Ok(collect_selection_ranges(&ctx, &file_path, params.positions))
+    }
+}
+
+impl RequestAction for InlineValue {
+    type Response = Vec<InlineValueText>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        if !ctx.client_capabilities.inline_value_support
+            || !ctx.config.lock().unwrap().inline_const_values
+        {
+            return Ok(vec![]);
+        }
+        let file_path = parse_file_path!(&params.text_document.uri, "inline_value")?;
+
+        Ok(collect_inline_values(&ctx, &file_path, params.range))
+    }
+}
+
+impl RequestAction for InlayHintRequest {
+    type Response = Vec<InlayHint>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "inlay_hint")?;
+
+        Ok(collect_inlay_hints(&ctx, &file_path, params.range))
+    }
+}
+
+impl RequestAction for SignatureHelpRequest {
+    type Response = Option<SignatureHelp>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(None)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "signature_help")?;
+
+        Ok(signature_help(&ctx, &file_path, params.position))
+    }
+}
+
+impl RequestAction for FoldingRangeRequest {
+    type Response = Vec<FoldingRange>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        let file_path = parse_file_path!(&params.text_document.uri, "folding_range")?;
+
+        Ok(collect_folding_ranges(&ctx, &file_path))
+    }
+}
+
+impl RequestAction for DocumentColor {
+    type Response = Vec<ColorInformation>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        if !ctx.config.lock().unwrap().document_colors {
+            return Ok(vec![]);
+        }
+        let file_path = parse_file_path!(&params.text_document.uri, "document_color")?;
+
+        Ok(collect_document_colors(&ctx, &file_path))
+    }
+}
+
+impl RequestAction for ColorPresentationRequest {
+    type Response = Vec<ColorPresentation>;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(vec![])
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        if !ctx.config.lock().unwrap().document_colors {
+            return Ok(vec![]);
+        }
+        let file_path = parse_file_path!(&params.text_document.uri, "color_presentation")?;
+
+        Ok(color_presentations(&ctx, &file_path, params.color, params.range))
+    }
+}
+
+impl RequestAction for WorkspaceDiagnostic {
+    type Response = WorkspaceDiagnosticReport;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Ok(WorkspaceDiagnosticReport { items: vec![] })
+    }
+
+    /// Returns the diagnostics of every file the last build reported
+    /// diagnostics for, including the files whose diagnostics got cleared
+    /// since. Files whose diagnostics match the result id the client
+    /// already has are reported as unchanged.
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        if !ctx.config.lock().unwrap().pull_diagnostics {
+            return Self::fallback_response();
+        }
+
+        let previous: HashMap<_, _> = params
+            .previous_result_ids
+            .into_iter()
+            .map(|PreviousResultId { uri, value }| (uri, value))
+            .collect();
+
+        let results = ctx.previous_build_results.lock().unwrap();
+        let mut items: Vec<_> = results
+            .iter()
+            .filter_map(|(file_path, diagnostics)| {
+                let uri = Url::from_file_path(file_path).ok()?;
+                let diagnostics: Vec<_> =
+                    diagnostics.iter().map(|(diag, _)| diag.clone()).collect();
+                // `Diagnostic` isn't `Hash`, so hash its serialized form.
+                let mut hasher = DefaultHasher::new();
+                serde_json::to_string(&diagnostics).ok()?.hash(&mut hasher);
+                let result_id = format!("{:016x}", hasher.finish());
+
+                Some(if previous.get(&uri) == Some(&result_id) {
+                    WorkspaceDocumentDiagnosticReport::Unchanged { uri, version: None, result_id }
+                } else {
+                    WorkspaceDocumentDiagnosticReport::Full {
+                        uri,
+                        version: None,
+                        result_id,
+                        items: diagnostics,
+                    }
+                })
+            })
+            .collect();
+        items.sort_by(|a, b| report_uri(a).cmp(report_uri(b)));
+
+        Ok(WorkspaceDiagnosticReport { items })
+    }
+}
+
+fn report_uri(report: &WorkspaceDocumentDiagnosticReport) -> &str {
+    match report {
+        WorkspaceDocumentDiagnosticReport::Full { uri, .. }
+        | WorkspaceDocumentDiagnosticReport::Unchanged { uri, .. } => uri.as_str(),
+    }
+}
+
 impl RequestAction for ResolveCompletion {
     type Response = CompletionItem;
 
@@ -793,27 +1806,189 @@ impl RequestAction for CodeLensRequest {
         ctx: InitActionContext,
         params: Self::Params,
     ) -> Result<Self::Response, ResponseError> {
-        let mut ret = Vec::new();
-        if ctx.client_supports_cmd_run {
-            let file_path = parse_file_path!(&params.text_document.uri, "code_lens")?;
-            for action in collect_run_actions(&ctx, &file_path) {
-                let command = Command {
-                    title: action.label,
-                    command: "rls.run".to_string(),
-                    arguments: Some(vec![serde_json::to_value(&action.cmd).unwrap()]),
-                };
-                let range = ls_util::rls_to_range(action.target_element);
-                let lens = CodeLens { range, command: Some(command), data: None };
-                ret.push(lens);
-            }
+        if !ctx.config.lock().unwrap().code_lens {
+            return Ok(vec![]);
         }
-        Ok(ret)
+        let file_path = parse_file_path!(&params.text_document.uri, "code_lens")?;
+
+        Ok(collect_code_lenses(&ctx, &file_path, &params.text_document.uri))
+    }
+}
+
+impl RequestAction for CodeLensResolve {
+    type Response = CodeLens;
+
+    fn fallback_response() -> Result<Self::Response, ResponseError> {
+        Err(ResponseError::Empty)
+    }
+
+    fn handle(
+        ctx: InitActionContext,
+        params: Self::Params,
+    ) -> Result<Self::Response, ResponseError> {
+        resolve_code_lens(&ctx, params)
+            .map_err(|msg| ResponseError::Message(ErrorCode::InvalidParams, msg))
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use rls_analysis::Target;
+    use std::sync::Arc;
+
+    /// Asserts that executing `command` with `arguments` fails with an
+    /// `InvalidParams` error.
+    fn assert_invalid_params(command: &str, arguments: Vec<serde_json::Value>) {
+        let ctx = InitActionContext::new(
+            Arc::new(AnalysisHost::new(Target::Debug)),
+            Arc::new(Vfs::new()),
+            <_>::default(),
+            <_>::default(),
+            <_>::default(),
+            "/some/dir".into(),
+            123,
+            false,
+        );
+        let params = ExecuteCommandParams { command: format!("{}-123", command), arguments };
+        match ExecuteCommand::handle(ctx, params) {
+            Err(ResponseError::Message(ErrorCode::InvalidParams, _)) => {}
+            Err(e) => panic!("{}: unexpected error {:?}", command, e),
+            Ok(_) => panic!("{}: unexpected success", command),
+        }
+    }
+
+    #[test]
+    fn malformed_command_arguments() {
+        assert_invalid_params("rls.organizeImports", vec![]);
+        assert_invalid_params("rls.organizeImports", vec![json!(42)]);
+        assert_invalid_params("rls.extractFunction", vec![]);
+        assert_invalid_params("rls.extractFunction", vec![json!("not an edit")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn range_formatting_without_file_lines_support() {
+        use crate::actions::format::stub_rustfmt;
+        use crate::config::Config;
+        use std::sync::Mutex;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("rustfmt.toml"), "edition = \"2018\"\n").unwrap();
+        // Formats the whole file, in the `ModifiedLines` emit mode, and
+        // records its arguments. Fails if asked for unstable features.
+        let rustfmt = stub_rustfmt(
+            dir.path(),
+            "case \"$*\" in *--unstable-features*) exit 1;; esac\n\
+             echo \"$@\" >args\n\
+             cat >/dev/null\n\
+             printf '2 1 1\\n    let a = 1;\\n6 1 1\\n    let b = 2;\\n'",
+        );
+        let config =
+            Config { rustfmt_path: Some(rustfmt.display().to_string()), ..Config::default() };
+        let ctx = InitActionContext::new(
+            Arc::new(AnalysisHost::new(Target::Debug)),
+            Arc::new(Vfs::new()),
+            Arc::new(Mutex::new(config)),
+            <_>::default(),
+            <_>::default(),
+            dir.path().to_owned(),
+            123,
+            false,
+        );
+        let path = dir.path().join("src/lib.rs");
+        ctx.vfs
+            .set_file(&path, "fn first() {\n  let a = 1;\n}\n\nfn second() {\n  let b = 2;\n}\n");
+
+        let params = DocumentRangeFormattingParams {
+            text_document: TextDocumentIdentifier::new(Url::from_file_path(&path).unwrap()),
+            range: Range { start: Position::new(4, 0), end: Position::new(6, 1) },
+            options: FormattingOptions {
+                tab_size: 4,
+                insert_spaces: true,
+                properties: HashMap::new(),
+            },
+        };
+        let edits = RangeFormatting::handle(ctx, params).unwrap();
+        assert_eq!(
+            edits,
+            vec![TextEdit {
+                range: Range { start: Position::new(5, 0), end: Position::new(6, 0) },
+                new_text: "    let b = 2;\n".to_owned(),
+            }]
+        );
+        let args = std::fs::read_to_string(dir.path().join("args")).unwrap();
+        assert!(!args.contains("--file-lines"), "unexpected arguments: {}", args);
+    }
+
+    #[test]
+    fn function_snippets() {
+        assert_eq!(
+            function_snippet("foo", "fn (a: u32, b: &str) -> bool").as_deref(),
+            Some("foo(${1:a}, ${2:b})")
+        );
+        assert_eq!(
+            function_snippet("map", "fn <F>(&'a mut self, f: F, (x, y): (i32, i32))").as_deref(),
+            Some("map(${1:f}, ${2:(x, y)})")
+        );
+        assert_eq!(
+            function_snippet("call", "fn (f: fn(u8) -> u8, v: Vec<u8>)").as_deref(),
+            Some("call(${1:f}, ${2:v})")
+        );
+        assert_eq!(function_snippet("new", "fn () -> Self").as_deref(), Some("new()"));
+        assert_eq!(function_snippet("broken", "fn (a: u32"), None);
+    }
+
+    #[test]
+    fn workspace_symbol_order() {
+        let mut names = vec!["make_widget", "wide_gadget", "WidgetFactory", "Widget", "widget"];
+        names.sort_by_key(|name| match_order("widget", name));
+        assert_eq!(names, ["Widget", "widget", "WidgetFactory", "make_widget", "wide_gadget"]);
+    }
+
+    #[test]
+    fn subsequences() {
+        assert!(is_subsequence("rbe", "readbytesext"));
+        assert!(is_subsequence("", "read"));
+        assert!(!is_subsequence("ber", "readbytesext"));
+    }
+
+    #[test]
+    fn macro_generated_references() {
+        let vfs = Vfs::new();
+        let path = Path::new("/project/src/main.rs");
+        vfs.set_file(path, "let r#foo = 1;\nprintln!(\"{}\", foo);\nfoo!();\n");
+
+        let span = |row: u32, col_start: u32, col_end: u32| {
+            span::Span::new(
+                span::Row::new_zero_indexed(row),
+                span::Row::new_zero_indexed(row),
+                span::Column::new_zero_indexed(col_start),
+                span::Column::new_zero_indexed(col_end),
+                path.to_owned(),
+            )
+        };
+        assert!(!is_macro_generated(&vfs, &span(0, 4, 9), "foo"));
+        // A symbol passed to a macro invocation can be renamed.
+        assert!(!is_macro_generated(&vfs, &span(1, 15, 18), "foo"));
+        // References from expansions are attributed to the invocation.
+        assert!(is_macro_generated(&vfs, &span(2, 0, 6), "foo"));
+    }
+
+    #[test]
+    fn word_highlights_without_analysis() {
+        let text = "let count = 1;\nlet counter = count + count_2;\n// count\n";
+        let ranges: Vec<_> =
+            word_highlights(text, "count").into_iter().map(|highlight| highlight.range).collect();
+        assert_eq!(
+            ranges,
+            vec![
+                Range { start: Position::new(0, 4), end: Position::new(0, 9) },
+                Range { start: Position::new(1, 14), end: Position::new(1, 19) },
+                Range { start: Position::new(2, 3), end: Position::new(2, 8) },
+            ]
+        );
+    }
 
     #[test]
     fn test_sort_deglob_str() {
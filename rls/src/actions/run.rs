@@ -5,7 +5,7 @@ use ordslice::Ext;
 use regex::Regex;
 use rls_span::{Column, Position, Range, Row, ZeroIndexed};
 use rls_vfs::FileContents;
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
 
 use std::{collections::HashMap, iter, path::Path};
 
@@ -80,22 +80,23 @@ pub struct RunAction {
     pub cmd: Cmd,
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Cmd {
     pub binary: String,
     pub args: Vec<String>,
     pub env: HashMap<String, String>,
 }
 
-pub struct LineIndex {
+pub struct LineIndex<'a> {
+    text: &'a str,
     newlines: Vec<usize>,
 }
 
-impl LineIndex {
-    pub fn new(text: &str) -> LineIndex {
+impl<'a> LineIndex<'a> {
+    pub fn new(text: &'a str) -> LineIndex<'a> {
         let newlines = text.bytes().enumerate().filter(|&(_i, b)| b == b'\n').map(|(i, _b)| i + 1);
         let newlines = iter::once(0).chain(newlines).collect();
-        LineIndex { newlines }
+        LineIndex { text, newlines }
     }
 
     pub fn offset_to_position(&self, offset: usize) -> Position<ZeroIndexed> {
@@ -104,4 +105,50 @@ impl LineIndex {
         let col = offset - line_start_offset;
         Position::new(Row::new_zero_indexed(line as u32), Column::new_zero_indexed(col as u32))
     }
+
+    /// Like `offset_to_position`, but the character is counted in UTF-16 code
+    /// units, as in the LSP.
+    pub fn offset_to_lsp_position(&self, offset: usize) -> lsp_types::Position {
+        let line = self.newlines.upper_bound(&offset) - 1;
+        let character = self.text[self.newlines[line]..offset].encode_utf16().count();
+        lsp_types::Position::new(line as u64, character as u64)
+    }
+
+    /// The inverse of `offset_to_lsp_position`, `None` if `pos` is past the end
+    /// of its line or within a surrogate pair.
+    pub fn lsp_position_to_offset(&self, pos: lsp_types::Position) -> Option<usize> {
+        let line_start = *self.newlines.get(pos.line as usize)?;
+        let line = self.text[line_start..].split('\n').next().unwrap_or_default();
+        let mut character = 0;
+        for (i, c) in line.char_indices().chain(iter::once((line.len(), '\n'))) {
+            if character == pos.character as usize {
+                return Some(line_start + i);
+            }
+            character += c.len_utf16();
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_index_counts_utf16_code_units() {
+        let text = "fn main() {}\nlet c = '🦀'; é\n";
+        let index = LineIndex::new(text);
+        let pos = lsp_types::Position::new;
+
+        let after_crab = text.find("';").unwrap();
+        assert_eq!(index.offset_to_lsp_position(after_crab), pos(1, 11));
+        assert_eq!(index.offset_to_position(after_crab).col.0, 13);
+        assert_eq!(index.lsp_position_to_offset(pos(1, 11)), Some(after_crab));
+        // Within the surrogate pair of the crab.
+        assert_eq!(index.lsp_position_to_offset(pos(1, 10)), None);
+
+        assert_eq!(index.lsp_position_to_offset(pos(1, 15)), Some(text.len() - 1));
+        assert_eq!(index.lsp_position_to_offset(pos(1, 16)), None);
+        assert_eq!(index.lsp_position_to_offset(pos(2, 0)), Some(text.len()));
+    }
 }
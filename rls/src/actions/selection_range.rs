@@ -0,0 +1,279 @@
+//! Selection ranges, i.e., the chain of increasingly larger ranges enclosing a
+//! position, see `SelectionRangeRequest`.
+//!
+//! The chain is made of the identifier at the position, the items enclosing it
+//! (see `breadcrumbs`) and the syntactic groups recovered from the source text
+//! by matching its delimiters: the list element or statement, the contents of
+//! the enclosing parentheses, brackets or braces, the group itself and, for
+//! parentheses and brackets, the call or index expression they belong to.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use log::error;
+use rls_vfs::{FileContents, PositionEncoding};
+
+use crate::actions::breadcrumbs::{collect_breadcrumbs, find_chars};
+use crate::actions::run::LineIndex;
+use crate::actions::InitActionContext;
+use crate::lsp_data::{ls_util, Position, Range, SelectionRange};
+
+/// Returns the selection ranges at each of `positions` in `file`.
+pub fn collect_selection_ranges(
+    ctx: &InitActionContext,
+    file: &Path,
+    positions: Vec<Position>,
+) -> Vec<SelectionRange> {
+    let text = match ctx.vfs.load_file(file) {
+        Ok(FileContents::Text(text)) => text,
+        Ok(FileContents::Binary(_)) => String::new(),
+        Err(e) => {
+            error!("failed to collect selection ranges: {}", e);
+            String::new()
+        }
+    };
+
+    positions
+        .into_iter()
+        .map(|pos| {
+            let word = ctx.vfs.word_at_position(
+                file,
+                &ls_util::position_to_rls(pos),
+                PositionEncoding::Utf16,
+            );
+            let known = word
+                .map(|(span, _)| ls_util::rls_to_range(span.range))
+                .into_iter()
+                .chain(collect_breadcrumbs(ctx, file, pos).into_iter().map(|item| item.range))
+                .collect();
+            selection_range(&text, known, pos)
+        })
+        .collect()
+}
+
+/// Returns the chain of ranges enclosing `pos` in `text`, made of the `known`
+/// ranges and the ones found by matching delimiters. Falls back to an empty
+/// range at `pos`, as every requested position needs a selection range.
+fn selection_range(text: &str, known: Vec<Range>, pos: Position) -> SelectionRange {
+    let index = LineIndex::new(text);
+    let empty = SelectionRange { range: Range { start: pos, end: pos }, parent: None };
+    let offset = match index.lsp_position_to_offset(pos) {
+        Some(offset) => offset,
+        None => return empty,
+    };
+
+    let mut ranges: Vec<_> = known
+        .into_iter()
+        .filter_map(|range| {
+            Some((
+                index.lsp_position_to_offset(range.start)?,
+                index.lsp_position_to_offset(range.end)?,
+            ))
+        })
+        .collect();
+    ranges.extend(delimited_ranges(text, offset));
+    ranges.push((0, text.len()));
+    ranges.retain(|&(start, end)| start <= offset && offset <= end && start < end);
+    ranges.sort_by_key(|&(start, end)| (end - start, start));
+
+    // Every range of the chain has to strictly contain the previous one.
+    let mut chain: Vec<(usize, usize)> = vec![];
+    for range in ranges {
+        match chain.last() {
+            Some(&last) if range == last || range.0 > last.0 || range.1 < last.1 => {}
+            _ => chain.push(range),
+        }
+    }
+
+    chain
+        .into_iter()
+        .rev()
+        .fold(None, |parent, (start, end)| {
+            Some(SelectionRange {
+                range: Range {
+                    start: index.offset_to_lsp_position(start),
+                    end: index.offset_to_lsp_position(end),
+                },
+                parent: parent.map(Box::new),
+            })
+        })
+        .unwrap_or(empty)
+}
+
+/// Returns the byte ranges of the syntactic groups enclosing `offset`, found by
+/// matching the delimiters in `text`.
+fn delimited_ranges(text: &str, offset: usize) -> Vec<(usize, usize)> {
+    let tokens = find_chars(text, &['{', '}', '(', ')', '[', ']', ',', ';']);
+
+    // The token indices of the matching delimiters.
+    let mut pairs = vec![];
+    let mut open = vec![];
+    for (t, &(_, c)) in tokens.iter().enumerate() {
+        match c {
+            '{' | '(' | '[' => open.push(t),
+            '}' | ')' | ']' => {
+                if let Some(o) = open.pop() {
+                    pairs.push((o, t));
+                }
+            }
+            _ => {}
+        }
+    }
+    let opening: HashMap<_, _> = pairs.iter().map(|&(o, c)| (tokens[c].0, tokens[o].0)).collect();
+
+    // The file is a block of items itself.
+    let mut ranges = vec![element(text, &tokens, '{', 0, text.len(), offset)];
+    for &(o, c) in &pairs {
+        let (open, kind) = tokens[o];
+        let close = tokens[c].0;
+        if offset <= open || close < offset {
+            continue;
+        }
+        ranges.push(element(text, &tokens[o + 1..c], kind, open + 1, close, offset));
+        ranges.push(trim(text, open + 1, close));
+        ranges.push((open, close + 1));
+        if kind != '{' {
+            let start = callee_start(text, open, &opening);
+            if start < open {
+                ranges.push((start, close + 1));
+            }
+        }
+    }
+    ranges
+}
+
+/// Returns the range of the element containing `offset` in the group of `kind`
+/// spanning `start..end`, i.e., a statement in a block (including its `;`) or
+/// an element of a list. `tokens` are the delimiters in the group.
+fn element(
+    text: &str,
+    tokens: &[(usize, char)],
+    kind: char,
+    start: usize,
+    end: usize,
+    offset: usize,
+) -> (usize, usize) {
+    let (mut element_start, mut element_end) = (start, end);
+    let mut depth = 0usize;
+    for &(i, c) in tokens {
+        let separator_end = match c {
+            '{' | '(' | '[' => {
+                depth += 1;
+                continue;
+            }
+            '}' | ')' | ']' => {
+                depth = depth.saturating_sub(1);
+                // A nested block ends a statement, e.g. an item or an `if`.
+                if depth == 0 && c == '}' && kind == '{' && ends_statement(&text[i + 1..]) {
+                    i + 1
+                } else {
+                    continue;
+                }
+            }
+            ';' if depth == 0 => i + 1,
+            ',' if depth == 0 && kind != '{' => i,
+            _ => continue,
+        };
+        if i < offset {
+            element_start = i + 1;
+        } else {
+            element_end = separator_end;
+            break;
+        }
+    }
+    trim(text, element_start, element_end)
+}
+
+/// Whether a block followed by `rest` ends a statement, as opposed to, e.g.,
+/// `if` expressions continued by `else` or blocks followed by a method call.
+fn ends_statement(rest: &str) -> bool {
+    let rest = rest.trim_start();
+    !rest.starts_with("else") && !rest.starts_with(|c| c == '.' || c == '?' || c == ';')
+}
+
+/// Returns the start of the expression called or indexed by the group opened
+/// at `open`, e.g. `foo.bar` in `foo.bar(..)` or `vec!` in `vec![..]`, or
+/// `open` if there is none. `opening` maps closing delimiters to their opening
+/// ones.
+fn callee_start(text: &str, open: usize, opening: &HashMap<usize, usize>) -> usize {
+    let mut start = open;
+    while let Some(c) = text[..start].chars().next_back() {
+        if c.is_alphanumeric() || "_.:!?".contains(c) {
+            start -= c.len_utf8();
+        } else if c == ')' || c == ']' {
+            match opening.get(&(start - 1)) {
+                Some(&group_open) => start = group_open,
+                None => break,
+            }
+        } else {
+            break;
+        }
+    }
+    start
+}
+
+/// Returns `start..end` without leading and trailing whitespace.
+fn trim(text: &str, start: usize, end: usize) -> (usize, usize) {
+    let slice = &text[start..end];
+    let trimmed_start = start + slice.len() - slice.trim_start().len();
+    let trimmed_end = start + slice.trim_end().len();
+    (trimmed_start, trimmed_end.max(trimmed_start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "\
+fn main() {
+    let items = vec![1, 2];
+    items.swap(index, 1);
+}
+";
+
+    fn range(start: (u64, u64), end: (u64, u64)) -> Range {
+        Range { start: Position::new(start.0, start.1), end: Position::new(end.0, end.1) }
+    }
+
+    #[test]
+    fn selection_range_in_call_argument() {
+        let known = vec![range((2, 15), (2, 20)), range((0, 3), (3, 1))];
+        let mut selection = Some(selection_range(TEXT, known, Position::new(2, 17)));
+        let mut ranges = vec![];
+        while let Some(s) = selection {
+            ranges.push(s.range);
+            selection = s.parent.map(|parent| *parent);
+        }
+
+        assert_eq!(
+            ranges,
+            vec![
+                // `index`
+                range((2, 15), (2, 20)),
+                // The arguments.
+                range((2, 15), (2, 23)),
+                range((2, 14), (2, 24)),
+                // The call and its statement.
+                range((2, 4), (2, 24)),
+                range((2, 4), (2, 25)),
+                // The body of `main`.
+                range((1, 4), (2, 25)),
+                range((0, 10), (3, 1)),
+                // `main` itself.
+                range((0, 3), (3, 1)),
+                range((0, 0), (3, 1)),
+                // The file.
+                range((0, 0), (4, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn selection_range_outside_text() {
+        let pos = Position::new(7, 0);
+        assert_eq!(
+            selection_range(TEXT, vec![], pos),
+            SelectionRange { range: Range { start: pos, end: pos }, parent: None }
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! Signature help, see `SignatureHelpRequest`.
+//!
+//! The call enclosing the cursor is found by walking the source text backwards
+//! from the cursor, skipping nested parentheses and brackets, and counting the
+//! commas between its arguments. The callee is then looked up in the
+//! save-analysis data, whose signature is split into the parameters.
+
+use std::path::Path;
+
+use log::error;
+use rls_analysis::DefKind;
+use rls_vfs::FileContents;
+
+use crate::actions::breadcrumbs::find_chars;
+use crate::actions::requests::{is_self_param, signature_param_ranges};
+use crate::actions::run::LineIndex;
+use crate::actions::InitActionContext;
+use crate::lsp_data::{
+    ls_util, Documentation, MarkupContent, MarkupKind, ParameterInformation, ParameterLabel,
+    Position, Range, SignatureHelp, SignatureInformation,
+};
+use crate::Span;
+
+/// Returns the signature of the function or method called at `pos` in `file`,
+/// with the argument under the cursor as the active parameter.
+pub fn signature_help(
+    ctx: &InitActionContext,
+    file: &Path,
+    pos: Position,
+) -> Option<SignatureHelp> {
+    let text = match ctx.vfs.load_file(file) {
+        Ok(FileContents::Text(text)) => text,
+        Ok(FileContents::Binary(_)) => return None,
+        Err(e) => {
+            error!("failed to provide signature help: {}", e);
+            return None;
+        }
+    };
+    let call = active_call(&text, pos)?;
+    let span = Span::from_range(ls_util::range_to_rls(call.callee), file.to_owned());
+    let def = ctx.analysis.id(&span).and_then(|id| ctx.analysis.get_def(id)).ok()?;
+    if def.kind != DefKind::Function && def.kind != DefKind::Method {
+        return None;
+    }
+
+    let max_docs_length = ctx.config.lock().unwrap().signature_help_max_docs_length;
+    let signature = signature_information(&def.name, &def.value, &def.docs, &call, max_docs_length);
+    Some(SignatureHelp {
+        signatures: vec![signature],
+        active_signature: Some(0),
+        active_parameter: Some(call.active_parameter as i64),
+    })
+}
+
+/// The call enclosing a position in the source text.
+#[derive(Debug, PartialEq)]
+struct ActiveCall {
+    /// The name of the callee.
+    callee: Range,
+    /// Whether it's a method call, i.e., `receiver.callee(..)`.
+    method: bool,
+    /// The index of the argument the position is in.
+    active_parameter: usize,
+}
+
+/// Returns the innermost call whose arguments enclose `pos` in `text`. Only
+/// the commas outside of nested parentheses and brackets separate its
+/// arguments, so the position after a trailing comma is in the next argument.
+fn active_call(text: &str, pos: Position) -> Option<ActiveCall> {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let index = LineIndex::new(text);
+    let offset = index.lsp_position_to_offset(pos)?;
+    let tokens = find_chars(&text[..offset], &['(', ')', '[', ']', '{', '}', ',', ';']);
+
+    let mut depth = 0;
+    let mut commas = 0;
+    for &(i, c) in tokens.iter().rev() {
+        match c {
+            ')' | ']' | '}' => depth += 1,
+            '(' | '[' | '{' if depth > 0 => depth -= 1,
+            ',' if depth == 0 => commas += 1,
+            ';' if depth == 0 => return None,
+            ',' | ';' => {}
+            '(' => {
+                let before = &text[..i];
+                let name_start = before.trim_end_matches(is_ident).len();
+                let name = &before[name_start..];
+                // A parenthesized expression or a tuple in the arguments of
+                // an outer call.
+                if name.is_empty() || name.starts_with(|c: char| c.is_numeric()) {
+                    commas = 0;
+                    continue;
+                }
+                // Not a call, but the parameters of a function definition.
+                if before[..name_start].trim_end().rsplit(|c: char| !is_ident(c)).next()
+                    == Some("fn")
+                {
+                    return None;
+                }
+                return Some(ActiveCall {
+                    callee: Range {
+                        start: index.offset_to_lsp_position(name_start),
+                        end: index.offset_to_lsp_position(i),
+                    },
+                    method: before[..name_start].ends_with('.'),
+                    active_parameter: commas,
+                });
+            }
+            // An array or a slice in the arguments of an outer call.
+            '[' => commas = 0,
+            // The position is in a block, not in the arguments of a call.
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Returns the signature information of the callee of `call`, called `name`,
+/// with the signature `signature` and the documentation `docs`. The
+/// documentation is cut off after `max_docs_length` characters.
+fn signature_information(
+    name: &str,
+    signature: &str,
+    docs: &str,
+    call: &ActiveCall,
+    max_docs_length: usize,
+) -> SignatureInformation {
+    // The signatures in the save-analysis data leave out the name.
+    let label = if signature.starts_with("fn (") {
+        format!("fn {}{}", name, &signature["fn ".len()..])
+    } else {
+        signature.to_owned()
+    };
+
+    let parameters = signature_param_ranges(&label).map(|mut ranges| {
+        if call.method && ranges.first().map_or(false, |range| is_self_param(&label[range.clone()]))
+        {
+            ranges.remove(0);
+        }
+        let utf16_len = |s: &str| s.encode_utf16().count() as u64;
+        ranges
+            .into_iter()
+            .map(|range| ParameterInformation {
+                label: ParameterLabel::LabelOffsets([
+                    utf16_len(&label[..range.start]),
+                    utf16_len(&label[..range.end]),
+                ]),
+                documentation: None,
+            })
+            .collect()
+    });
+
+    let docs = docs.trim();
+    let documentation = if docs.is_empty() || max_docs_length == 0 {
+        None
+    } else {
+        let mut value: String = docs.chars().take(max_docs_length).collect();
+        if value.len() < docs.len() {
+            value.push('…');
+        }
+        Some(Documentation::MarkupContent(MarkupContent { kind: MarkupKind::Markdown, value }))
+    };
+
+    SignatureInformation { label, documentation, parameters }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the active call at the position marked by `|` in `text`.
+    fn active_call_at(text: &str) -> Option<ActiveCall> {
+        let offset = text.find('|').unwrap();
+        let text = text.replacen('|', "", 1);
+        let pos = LineIndex::new(&text).offset_to_lsp_position(offset);
+        active_call(&text, pos)
+    }
+
+    fn range(start: (u64, u64), end: (u64, u64)) -> Range {
+        Range { start: Position::new(start.0, start.1), end: Position::new(end.0, end.1) }
+    }
+
+    #[test]
+    fn active_parameter_of_calls() {
+        let call = |callee, method, active_parameter| {
+            Some(ActiveCall { callee, method, active_parameter })
+        };
+
+        // Commas of nested calls, tuples and arrays don't count.
+        assert_eq!(active_call_at("f(g(1, 2), |)"), call(range((0, 0), (0, 1)), false, 1));
+        assert_eq!(active_call_at("f((1, 2), [3, 4], |)"), call(range((0, 0), (0, 1)), false, 2));
+        assert_eq!(active_call_at("f(g(1, |))"), call(range((0, 2), (0, 3)), false, 1));
+        assert_eq!(active_call_at("f(|)"), call(range((0, 0), (0, 1)), false, 0));
+        assert_eq!(
+            active_call_at("fn main() {\n    x.foo(1,\n        2, |"),
+            call(range((1, 6), (1, 9)), true, 2)
+        );
+
+        assert_eq!(active_call_at("fn foo(a: u32, |)"), None);
+        assert_eq!(active_call_at("f(1);\n|"), None);
+        assert_eq!(active_call_at("f(x, {\n    |"), None);
+    }
+
+    #[test]
+    fn active_parameter_after_trailing_comma() {
+        assert_eq!(
+            active_call_at("f(1, |"),
+            Some(ActiveCall { callee: range((0, 0), (0, 1)), method: false, active_parameter: 1 })
+        );
+        assert_eq!(
+            active_call_at("f(1,|"),
+            Some(ActiveCall { callee: range((0, 0), (0, 1)), method: false, active_parameter: 1 })
+        );
+    }
+
+    #[test]
+    fn signature_information_of_methods() {
+        let call = ActiveCall { callee: range((0, 2), (0, 5)), method: true, active_parameter: 0 };
+        let info = signature_information(
+            "foo",
+            "fn (&self, a: u32, b: &str) -> bool",
+            "Does foo.",
+            &call,
+            4,
+        );
+
+        assert_eq!(info.label, "fn foo(&self, a: u32, b: &str) -> bool");
+        let offsets: Vec<_> = info
+            .parameters
+            .unwrap()
+            .into_iter()
+            .map(|param| match param.label {
+                ParameterLabel::LabelOffsets(offsets) => offsets,
+                ParameterLabel::Simple(label) => panic!("unexpected label {}", label),
+            })
+            .collect();
+        assert_eq!(offsets, vec![[14, 20], [22, 29]]);
+        assert_eq!(
+            info.documentation,
+            Some(Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: "Does…".to_owned(),
+            }))
+        );
+    }
+}
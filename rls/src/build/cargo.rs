@@ -2,12 +2,16 @@ use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::ffi::OsString;
 use std::fmt::{self, Write};
-use std::fs::{read_dir, remove_file};
+use std::fs::{self, read_dir, remove_file, OpenOptions};
+use std::hash::Hash;
+use std::io::Write as _;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use cargo::core::compiler::{BuildConfig, CompileMode, Context, Executor, Unit};
 use cargo::core::resolver::ResolveError;
@@ -16,19 +20,25 @@ use cargo::core::{
     enable_nightly_features, PackageId, Shell, Target, TargetKind, Verbosity, Workspace,
 };
 use cargo::ops::{compile_with_exec, CompileFilter, CompileOptions, Packages};
+use cargo::util::interning::InternedString;
 use cargo::util::{
     config as cargo_config, errors::ManifestError, homedir, important_paths, CargoResult,
-    ConfigValue, ProcessBuilder,
+    ConfigValue, ProcessBuilder, ProcessError,
 };
+use cargo_metadata::Metadata;
 use log::{debug, trace, warn};
 use rls_data::Analysis;
 use rls_vfs::Vfs;
+use semver::Version;
 
 use crate::actions::progress::ProgressUpdate;
 use crate::build::cargo_plan::CargoPlan;
 use crate::build::environment::{self, Environment, EnvironmentLock};
 use crate::build::plan::{BuildPlan, Crate};
-use crate::build::{BufWriter, BuildResult, CompilationContext, Internals, PackageArg};
+use crate::build::{
+    BufWriter, BuildResult, CancellationToken, CompilationContext, CompilationMetric, FileVersion,
+    Internals, PackageArg,
+};
 use crate::config::Config;
 use crate::lsp_data::{Position, Range};
 
@@ -37,15 +47,19 @@ pub(super) fn cargo(
     internals: &Internals,
     package_arg: PackageArg,
     progress_sender: Sender<ProgressUpdate>,
+    cancel: &CancellationToken,
 ) -> BuildResult {
     let compilation_cx = Arc::clone(&internals.compilation_cx);
     let config = Arc::clone(&internals.config);
     let vfs = Arc::clone(&internals.vfs);
     let env_lock = Arc::clone(&internals.env_lock);
+    let metrics_sender = internals.metrics_sender.lock().unwrap().clone();
+    let cached_metadata = Arc::clone(&internals.cached_metadata);
 
     let diagnostics = Arc::default();
     let analysis = Arc::default();
     let input_files = Arc::default();
+    let package_status = Arc::default();
     let out = Arc::default();
 
     // Cargo may or may not spawn threads to run the various builds, since
@@ -56,7 +70,9 @@ pub(super) fn cargo(
         let diagnostics = Arc::clone(&diagnostics);
         let analysis = Arc::clone(&analysis);
         let input_files = Arc::clone(&input_files);
+        let package_status = Arc::clone(&package_status);
         let out = Arc::clone(&out);
+        let cancel = cancel.clone();
         || {
             run_cargo(
                 compilation_cx,
@@ -67,18 +83,46 @@ pub(super) fn cargo(
                 diagnostics,
                 analysis,
                 input_files,
+                package_status,
                 out,
                 progress_sender,
+                metrics_sender,
+                cached_metadata,
+                cancel,
             )
         }
     });
+    let result =
+        handle.join().map_err(|_| anyhow::Error::msg("thread panicked")).and_then(|res| res);
+    // Cargo fails once the executor refuses to compile anything else.
+    if cancel.is_cancelled() {
+        return BuildResult::Cancelled;
+    }
 
-    match handle.join().map_err(|_| anyhow::Error::msg("thread panicked")).and_then(|res| res) {
+    match result {
         Ok(ref cwd) => {
             let diagnostics = Arc::try_unwrap(diagnostics).unwrap().into_inner().unwrap();
             let analysis = Arc::try_unwrap(analysis).unwrap().into_inner().unwrap();
             let input_files = Arc::try_unwrap(input_files).unwrap().into_inner().unwrap();
-            BuildResult::Success(cwd.clone(), diagnostics, analysis, input_files, true)
+            let package_status = Arc::try_unwrap(package_status).unwrap().into_inner().unwrap();
+            match failed_packages(package_status) {
+                Some(failed) => BuildResult::PartialSuccess(
+                    cwd.clone(),
+                    diagnostics,
+                    analysis,
+                    input_files,
+                    failed,
+                    Duration::default(),
+                ),
+                None => BuildResult::Success(
+                    cwd.clone(),
+                    diagnostics,
+                    analysis,
+                    input_files,
+                    true,
+                    Duration::default(),
+                ),
+            }
         }
         Err(error) => {
             let stdout = String::from_utf8(out.lock().unwrap().to_owned()).unwrap();
@@ -92,6 +136,90 @@ pub(super) fn cargo(
     }
 }
 
+/// How long `cargo metadata` fetched ahead of a build is used for, see
+/// `take_cached_metadata`.
+const CACHED_METADATA_TTL: Duration = Duration::from_secs(30);
+
+/// `cargo metadata` of a workspace, fetched in the background ahead of the
+/// next Cargo build, see `BuildQueue::pre_warm_metadata`.
+pub(super) struct CachedMetadata {
+    manifest_path: PathBuf,
+    started_at: Instant,
+    metadata: JoinHandle<Option<Metadata>>,
+}
+
+impl CachedMetadata {
+    /// Starts fetching the metadata of the workspace containing `project_path`.
+    pub(super) fn fetch(project_path: &Path) -> Option<CachedMetadata> {
+        let manifest_path = important_paths::find_root_manifest_for_wd(project_path).ok()?;
+        Some(CachedMetadata {
+            metadata: pre_warm_metadata(&manifest_path),
+            manifest_path,
+            started_at: Instant::now(),
+        })
+    }
+}
+
+/// Runs `cargo metadata` for the workspace of `manifest_path` on a new thread.
+pub(super) fn pre_warm_metadata(manifest_path: &Path) -> JoinHandle<Option<Metadata>> {
+    let manifest_path = manifest_path.to_owned();
+    thread::spawn(move || {
+        trace!("pre-warming metadata {:?}", manifest_path);
+        cargo_metadata::MetadataCommand::new().manifest_path(manifest_path).exec().ok()
+    })
+}
+
+/// Takes the metadata fetched for `manifest_path`, waiting for it if it's
+/// still being fetched, unless the fetch started more than
+/// `CACHED_METADATA_TTL` ago, since the manifests may have changed since.
+pub(super) fn take_cached_metadata(
+    cache: &Mutex<Option<CachedMetadata>>,
+    manifest_path: &Path,
+) -> Option<Metadata> {
+    let cached = cache.lock().unwrap().take()?;
+    if cached.manifest_path != manifest_path || cached.started_at.elapsed() > CACHED_METADATA_TTL {
+        return None;
+    }
+    cached.metadata.join().ok()?
+}
+
+/// Returns `true` if none of the `built_files` was modified after the jobs of
+/// the build plan were last run, judging by the modification times of the
+/// dep-info (`.d`) files the compiler writes next to its output. Running Cargo
+/// again would then produce the same results.
+pub(super) fn depinfo_is_current(
+    plan: &BuildPlan,
+    built_files: &HashMap<PathBuf, FileVersion>,
+) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    let commands = plan.compiler_commands();
+    let depinfo_times: Option<Vec<_>> =
+        commands.iter().map(|cmd| depinfo_path(cmd).and_then(|path| modified(&path))).collect();
+    let oldest_depinfo = match depinfo_times.and_then(|times| times.into_iter().min()) {
+        Some(time) => time,
+        None => return false,
+    };
+
+    built_files.keys().all(|file| modified(file).map_or(false, |time| time <= oldest_depinfo))
+}
+
+/// Returns the path of the dep-info file written by a compiler command, i.e.
+/// `<out-dir>/<crate-name><extra-filename>.d`.
+fn depinfo_path(cmd: &ProcessBuilder) -> Option<PathBuf> {
+    let args: Vec<_> = cmd.get_args().iter().filter_map(|arg| arg.to_str()).collect();
+    let value_of =
+        |flag: &str| args.iter().position(|arg| *arg == flag).and_then(|i| args.get(i + 1));
+
+    let out_dir = value_of("--out-dir")?;
+    let crate_name = value_of("--crate-name")?;
+    let extra_filename = args
+        .iter()
+        .find(|arg| arg.starts_with("extra-filename="))
+        .map_or("", |arg| &arg["extra-filename=".len()..]);
+    Some(Path::new(out_dir).join(format!("{}{}.d", crate_name, extra_filename)))
+}
+
 fn run_cargo(
     compilation_cx: Arc<Mutex<CompilationContext>>,
     package_arg: PackageArg,
@@ -101,8 +229,12 @@ fn run_cargo(
     compiler_messages: Arc<Mutex<Vec<String>>>,
     analysis: Arc<Mutex<Vec<Analysis>>>,
     input_files: Arc<Mutex<HashMap<PathBuf, HashSet<Crate>>>>,
+    package_status: Arc<Mutex<HashMap<PackageId, bool>>>,
     out: Arc<Mutex<Vec<u8>>>,
     progress_sender: Sender<ProgressUpdate>,
+    metrics_sender: Option<Sender<CompilationMetric>>,
+    cached_metadata: Arc<Mutex<Option<CachedMetadata>>>,
+    cancel: CancellationToken,
 ) -> Result<PathBuf, anyhow::Error> {
     // Lock early to guarantee synchronized access to env var for the scope of Cargo routine.
     // Additionally we need to pass inner lock to `RlsExecutor`, since it needs to hand it down
@@ -142,7 +274,10 @@ fn run_cargo(
         compiler_messages,
         analysis,
         input_files,
+        package_status,
         progress_sender,
+        metrics_sender,
+        &cached_metadata,
         inner_lock,
         restore_env,
         &manifest_path,
@@ -160,7 +295,10 @@ fn run_cargo_ws(
     compiler_messages: Arc<Mutex<Vec<String>>>,
     analysis: Arc<Mutex<Vec<Analysis>>>,
     input_files: Arc<Mutex<HashMap<PathBuf, HashSet<Crate>>>>,
+    package_status: Arc<Mutex<HashMap<PackageId, bool>>>,
     progress_sender: Sender<ProgressUpdate>,
+    metrics_sender: Option<Sender<CompilationMetric>>,
+    cached_metadata: &Mutex<Option<CachedMetadata>>,
     inner_lock: environment::InnerLock,
     mut restore_env: Environment<'_>,
     manifest_path: &PathBuf,
@@ -174,13 +312,13 @@ fn run_cargo_ws(
 
     // TODO: it might be feasible to keep this `CargoOptions` structure cached and regenerate
     // it on every relevant configuration change.
-    let (opts, rustflags, clear_env_rust_log, cfg_test) = {
+    let (opts, rustflags, removed_flags, clear_env_rust_log, cfg_test, extra_env) = {
         // We mustn't lock configuration for the whole build process
         let rls_config = rls_config.lock().unwrap();
 
         let opts = CargoOptions::new(&rls_config);
         trace!("Cargo compilation options:\n{:?}", opts);
-        let rustflags = prepare_cargo_rustflags(&rls_config);
+        let (rustflags, removed_flags) = prepare_cargo_rustflags(&rls_config);
 
         for package in &packages {
             if ws.members().find(|x| *x.name() == *package).is_none() {
@@ -191,10 +329,59 @@ fn run_cargo_ws(
                 );
             }
         }
+        if let Some(example) = &rls_config.build_example {
+            let exists = ws
+                .members()
+                .flat_map(Package::targets)
+                .any(|target| target.is_example() && target.name() == example);
+            if !exists {
+                warn!(
+                    "couldn't find example `{}` specified in `build_example` configuration",
+                    example
+                );
+            }
+        }
+
+        if let Some(profile) = &rls_config.cargo_profile {
+            let reported = &mut compilation_cx.lock().unwrap().undefined_profile;
+            if let Some(warning) = check_cargo_profile(ws.root(), profile, reported) {
+                progress_sender
+                    .send(ProgressUpdate::Warning(warning))
+                    .expect("failed to send progress update");
+            }
+        }
 
-        (opts, rustflags, rls_config.clear_env_rust_log, rls_config.cfg_test)
+        if rls_config.use_clippy
+            && super::rustc::clippy_driver(rls_config.sysroot.as_deref()).is_none()
+        {
+            warn!("couldn't find `clippy-driver` for `use_clippy`");
+            progress_sender
+                .send(ProgressUpdate::Warning(
+                    "`clippy-driver` wasn't found, install it with `rustup component add clippy` \
+                     (checking the project without Clippy)"
+                        .to_owned(),
+                ))
+                .expect("failed to send progress update");
+        }
+
+        // `analyze_all_targets` is `all_targets` (see `CargoOptions::new`) with
+        // `cfg_test`.
+        let cfg_test = rls_config.cfg_test || rls_config.analyze_all_targets;
+        let mut extra_env = BTreeMap::new();
+        environment::merge_extra_env(&mut extra_env, &rls_config.extra_env);
+        (opts, rustflags, removed_flags, rls_config.clear_env_rust_log, cfg_test, extra_env)
     };
 
+    for flag in removed_flags {
+        warn!("ignoring incompatible rustflag `{}`", flag);
+        progress_sender
+            .send(ProgressUpdate::Warning(format!(
+                "Ignoring `{}` in the rustflags, it's incompatible with the RLS",
+                flag
+            )))
+            .expect("failed to send progress update");
+    }
+
     let spec = Packages::from_flags(all, Vec::new(), packages)?;
 
     let pkg_names = spec
@@ -206,10 +393,11 @@ fn run_cargo_ws(
 
     // Since the Cargo build routine will try to regenerate the unit dep graph,
     // we need to clear the existing dep graph.
+    let metadata = take_cached_metadata(cached_metadata, manifest_path);
     compilation_cx.lock().unwrap().build_plan =
-        BuildPlan::Cargo(CargoPlan::with_packages(manifest_path, pkg_names));
+        BuildPlan::Cargo(CargoPlan::with_packages(manifest_path, pkg_names, metadata));
 
-    let compile_opts = CompileOptions {
+    let mut compile_opts = CompileOptions {
         spec,
         filter: CompileFilter::from_raw_arguments(
             opts.lib,
@@ -218,11 +406,11 @@ fn run_cargo_ws(
             // TODO: support more crate target types.
             Vec::new(),
             // Check all integration tests under `tests/`.
-            cfg_test,
-            Vec::new(),
-            false,
+            cfg_test || opts.tests,
+            opts.example,
+            opts.examples,
             Vec::new(),
-            false,
+            opts.benches,
             opts.all_targets,
         ),
         build_config: BuildConfig::new(
@@ -236,6 +424,9 @@ fn run_cargo_ws(
         no_default_features: opts.no_default_features,
         ..CompileOptions::new(&config, CompileMode::Check { test: cfg_test })?
     };
+    if let Some(profile) = &opts.profile {
+        compile_opts.build_config.requested_profile = InternedString::new(profile);
+    }
 
     // Create a custom environment for running cargo, the environment is reset
     // afterwards automatically.
@@ -244,6 +435,9 @@ fn run_cargo_ws(
     if clear_env_rust_log {
         restore_env.push_var("RUST_LOG", &None);
     }
+    for (key, value) in &extra_env {
+        restore_env.push_var(key, value);
+    }
 
     let reached_primary = Arc::new(AtomicBool::new(false));
 
@@ -256,12 +450,15 @@ fn run_cargo_ws(
         compiler_messages,
         analysis,
         input_files,
+        package_status,
         progress_sender,
+        metrics_sender,
         Arc::clone(&reached_primary),
+        cancel,
     );
 
-    let exec = Arc::new(exec) as Arc<dyn Executor>;
-    match compile_with_exec(&ws, &compile_opts, &exec) {
+    let exec = Arc::new(exec);
+    match compile_with_exec(&ws, &compile_opts, &(Arc::clone(&exec) as Arc<dyn Executor>)) {
         Ok(_) => {
             trace!(
                 "created build plan after Cargo compilation routine: {:?}",
@@ -269,6 +466,7 @@ fn run_cargo_ws(
             );
         }
         Err(e) => {
+            exec.report_failed_build_script(&e);
             if !reached_primary.load(Ordering::SeqCst) {
                 debug!("error running `compile_with_exec`: {:?}", e);
                 return Err(e);
@@ -302,16 +500,42 @@ struct RlsExecutor {
     /// Packages which are directly a member of the workspace, for which
     /// analysis and diagnostics will be provided.
     member_packages: Mutex<HashSet<PackageId>>,
+    /// Workspace members excluded via `workspace_mode_blacklist`, which are
+    /// built like any other dependency.
+    blacklisted_members: HashSet<PackageId>,
+    /// Direct package dependencies of every unit Cargo asked us to prepare,
+    /// used to find crates affected by a failed build script.
+    package_deps: Mutex<HashMap<PackageId, HashSet<PackageId>>>,
+    /// Manifests of the primary packages Cargo asked us to prepare, which a
+    /// failed build script of their dependencies is reported on.
+    manifests: Mutex<HashMap<PackageId, PathBuf>>,
+    /// Source files of the build scripts of primary packages, which their
+    /// `cargo:warning=` output is reported for. See `build_script_messages`.
+    build_scripts: Mutex<HashMap<PackageId, PathBuf>>,
+    /// When the build started, truncated to seconds (the coarsest granularity
+    /// of file modification times), to tell whether a build script ran during
+    /// this build.
+    build_started: SystemTime,
     input_files: Arc<Mutex<HashMap<PathBuf, HashSet<Crate>>>>,
+    /// Whether all of the compiled units of a given primary package built
+    /// successfully.
+    package_status: Arc<Mutex<HashMap<PackageId, bool>>>,
     /// JSON compiler messages emitted for each primary compiled crate.
     compiler_messages: Arc<Mutex<Vec<String>>>,
     progress_sender: Mutex<Sender<ProgressUpdate>>,
+    /// Receives compilation metrics of every intercepted rustc invocation.
+    metrics_sender: Option<Mutex<Sender<CompilationMetric>>>,
     /// Set to true if attempt to compile a primary crate. If we don't track
     /// this then errors which prevent giving type info won't be shown to the
     /// user. This feels a bit hacky, but I can't see how to otherwise
     /// distinguish compile errors on dependent crates from the primary crate
     /// (which are handled directly by the RLS).
     reached_primary: Arc<AtomicBool>,
+    /// File every rustc command line is appended to, if `log_build_commands`
+    /// is set. Locked while writing, since units are compiled in parallel.
+    build_command_log: Option<Mutex<PathBuf>>,
+    /// Makes the executor fail every compilation once the build is cancelled.
+    cancel: CancellationToken,
 }
 
 impl RlsExecutor {
@@ -324,10 +548,32 @@ impl RlsExecutor {
         compiler_messages: Arc<Mutex<Vec<String>>>,
         analysis: Arc<Mutex<Vec<Analysis>>>,
         input_files: Arc<Mutex<HashMap<PathBuf, HashSet<Crate>>>>,
+        package_status: Arc<Mutex<HashMap<PackageId, bool>>>,
         progress_sender: Sender<ProgressUpdate>,
+        metrics_sender: Option<Sender<CompilationMetric>>,
         reached_primary: Arc<AtomicBool>,
+        cancel: CancellationToken,
     ) -> RlsExecutor {
-        let member_packages = ws.members().map(Package::package_id).collect();
+        let (blacklisted_members, member_packages): (HashSet<_>, HashSet<_>) = {
+            let config = config.lock().unwrap();
+            ws.members().map(Package::package_id).partition(|id| {
+                config.workspace_mode_blacklist.iter().any(|name| *name == *id.name())
+            })
+        };
+        // The target directory already is the RLS-specific `<target_dir>/rls`.
+        let build_command_log = if config.lock().unwrap().log_build_commands {
+            let path = ws.target_dir().into_path_unlocked().join(BUILD_COMMAND_LOG);
+            Some(Mutex::new(path))
+        } else {
+            None
+        };
+        if !blacklisted_members.is_empty() {
+            trace!("workspace members excluded from analysis: {:?}", blacklisted_members);
+        }
+        let build_started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()))
+            .unwrap_or(UNIX_EPOCH);
 
         RlsExecutor {
             compilation_cx,
@@ -336,17 +582,88 @@ impl RlsExecutor {
             vfs,
             analysis,
             input_files,
+            package_status,
             member_packages: Mutex::new(member_packages),
+            blacklisted_members,
+            package_deps: Mutex::default(),
+            manifests: Mutex::default(),
+            build_scripts: Mutex::default(),
+            build_started,
             compiler_messages,
             progress_sender: Mutex::new(progress_sender),
+            metrics_sender: metrics_sender.map(Mutex::new),
             reached_primary,
+            build_command_log,
+            cancel,
+        }
+    }
+
+    /// Appends the command line of `cmd` to the build command log, if enabled.
+    fn log_build_command(&self, cmd: &ProcessBuilder) {
+        if let Some(path) = &self.build_command_log {
+            let path = path.lock().unwrap();
+            if let Err(e) = append_build_command(&path, cmd) {
+                debug!("error logging build command to {:?}: {}", path, e);
+            }
+        }
+    }
+
+    /// Records whether a unit of a given primary package was built successfully.
+    fn record_package_status(&self, id: PackageId, success: bool) {
+        *self.package_status.lock().unwrap().entry(id).or_insert(true) &= success;
+    }
+
+    /// Reports the build script of a primary package which failed to run, if
+    /// that's what Cargo failed the build with (`error`). The `cargo:warning=`
+    /// and `cargo:error=` output of the script and its stderr are reported on
+    /// its source. Cargo doesn't compile the crates depending on the script,
+    /// so the primary packages depending on it get a single error on their
+    /// manifest instead of no diagnostics at all.
+    fn report_failed_build_script(&self, error: &anyhow::Error) {
+        let failed = self.build_scripts.lock().unwrap().iter().find_map(|(&id, build_script)| {
+            let context = format!("failed to run custom build command for `{}`", id);
+            let mut causes = error.chain().skip_while(|cause| cause.to_string() != context);
+            causes.next()?;
+            let output = causes.find_map(|cause| cause.downcast_ref::<ProcessError>());
+            Some((id, build_script.clone(), output.and_then(|e| e.output.clone())))
+        });
+        let (id, build_script, output) = match failed {
+            Some(failed) => failed,
+            None => return,
+        };
+        trace!("build script failed - {}", id.name());
+
+        let (stdout, stderr) = output
+            .map(|output| {
+                let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+                (stdout, String::from_utf8_lossy(&output.stderr).into_owned())
+            })
+            .unwrap_or_default();
+        let mut messages = build_script_messages(&stdout, &build_script);
+        messages.push(build_script_failure_message(&build_script, &stderr));
+
+        let failed = vec![id].into_iter().collect();
+        let package_deps = self.package_deps.lock().unwrap();
+        for (&dependent, manifest) in self.manifests.lock().unwrap().iter() {
+            if dependent != id && failed_dependency(dependent, &package_deps, &failed).is_some() {
+                messages.push(dependency_build_script_message(manifest, &id.name(), &stderr));
+                self.record_package_status(dependent, false);
+            }
         }
+        self.record_package_status(id, false);
+
+        self.compiler_messages.lock().unwrap().append(&mut messages);
+        self.reached_primary.store(true, Ordering::SeqCst);
     }
 
     /// Returns `true if a given package is a primary one (every member of the
-    /// workspace is considered as such). Used to determine whether the RLS
-    /// should cache invocations for these packages and rebuild them on changes.
+    /// workspace, unless blacklisted, is considered as such). Used to determine
+    /// whether the RLS should cache invocations for these packages and rebuild
+    /// them on changes.
     fn is_primary_package(&self, id: PackageId) -> bool {
+        if self.blacklisted_members.contains(&id) {
+            return false;
+        }
         id.source_id().is_path() || self.member_packages.lock().unwrap().contains(&id)
     }
 }
@@ -366,6 +683,15 @@ impl Executor for RlsExecutor {
         let only_primary = |unit: &Unit| self.is_primary_package(unit.pkg.package_id());
 
         plan.emplace_dep_with_filter(unit, cx, &only_primary);
+
+        let mut package_deps = self.package_deps.lock().unwrap();
+        let deps = package_deps.entry(unit.pkg.package_id()).or_default();
+        deps.extend(cx.unit_deps(unit).iter().map(|dep| dep.unit.pkg.package_id()));
+
+        if only_primary(unit) {
+            let mut manifests = self.manifests.lock().unwrap();
+            manifests.insert(unit.pkg.package_id(), unit.pkg.manifest_path().to_owned());
+        }
     }
 
     fn force_rebuild(&self, unit: &Unit) -> bool {
@@ -388,9 +714,12 @@ impl Executor for RlsExecutor {
         id: PackageId,
         target: &Target,
         mode: CompileMode,
-        _on_stdout_line: &mut dyn FnMut(&str) -> CargoResult<()>,
-        _on_stderr_line: &mut dyn FnMut(&str) -> CargoResult<()>,
+        on_stdout_line: &mut dyn FnMut(&str) -> CargoResult<()>,
+        on_stderr_line: &mut dyn FnMut(&str) -> CargoResult<()>,
     ) -> CargoResult<()> {
+        if self.cancel.is_cancelled() {
+            return Err(anyhow::format_err!("build cancelled"));
+        }
         // Enforce JSON output so that we can parse the rustc output by
         // stripping --error-format if it was specified (e.g. Cargo pipelined
         // build)
@@ -465,13 +794,14 @@ impl Executor for RlsExecutor {
         // Add args and envs to cmd.
         let mut args: Vec<_> =
             cargo_args.iter().map(|a| a.clone().into_string().unwrap()).collect();
-        let envs = cargo_cmd.get_envs().clone();
+        let mut envs = cargo_cmd.get_envs().clone();
 
         let sysroot = super::rustc::current_sysroot()
             .expect("need to specify `SYSROOT` env var or use rustup or multirust");
 
         {
             let config = self.config.lock().unwrap();
+            environment::merge_extra_env(&mut envs, &config.extra_env);
             if config.sysroot.is_none() {
                 args.push("--sysroot".to_owned());
                 args.push(sysroot);
@@ -506,6 +836,7 @@ impl Executor for RlsExecutor {
                 // By running the original command (rather than using our shim), we
                 // avoid producing save-analysis data.
                 trace!("crate is blacklisted");
+                self.log_build_command(&cargo_cmd);
                 return cargo_cmd.exec();
             }
             // Only include public symbols in externally compiled deps data
@@ -517,7 +848,31 @@ impl Executor for RlsExecutor {
             })?;
             cmd.env("RUST_SAVE_ANALYSIS_CONFIG", &OsString::from(save_config));
 
-            return cmd.exec();
+            if is_build_script && self.is_primary_package(id) {
+                if let Some(path) = target.src_path().path() {
+                    self.build_scripts.lock().unwrap().insert(id, path.to_owned());
+                }
+            }
+
+            self.log_build_command(&cmd);
+            if !(is_build_script && self.is_primary_package(id)) {
+                return cmd.exec();
+            }
+            // Report the diagnostics of compiling the build script like those
+            // of the other crates of primary packages.
+            self.reached_primary.store(true, Ordering::SeqCst);
+            let result = cmd.exec_with_streaming(
+                on_stdout_line,
+                &mut |line| {
+                    if is_compiler_message(line) {
+                        self.compiler_messages.lock().unwrap().push(line.to_owned());
+                    }
+                    on_stderr_line(line)
+                },
+                false,
+            );
+            self.record_package_status(id, result.is_ok());
+            return result.map(drop);
         }
 
         trace!("rustc intercepted - args: {:?} envs: {:?}", args, envs,);
@@ -547,7 +902,9 @@ impl Executor for RlsExecutor {
             cx.build_dir.clone().unwrap()
         };
 
-        if let BuildResult::Success(_, mut messages, mut analysis, input_files, success) =
+        self.log_build_command(&cmd);
+        let start = Instant::now();
+        if let BuildResult::Success(_, mut messages, mut analysis, input_files, success, _) =
             super::rustc::rustc(
                 &self.vfs,
                 &args,
@@ -556,11 +913,35 @@ impl Executor for RlsExecutor {
                 &build_dir,
                 Arc::clone(&self.config),
                 &self.env_lock.as_facade(),
+                &self.cancel,
             )
         {
+            if let Some(metrics_sender) = &self.metrics_sender {
+                let (warnings, errors) = count_diagnostics(&messages);
+                let metric =
+                    CompilationMetric { crate_name, duration: start.elapsed(), warnings, errors };
+                // The receiving end may have been dropped, which is fine.
+                let _ = metrics_sender.lock().unwrap().send(metric);
+            }
+
             self.compiler_messages.lock().unwrap().append(&mut messages);
             self.analysis.lock().unwrap().append(&mut analysis);
 
+            // Cargo saves the output of the build script next to its `OUT_DIR`,
+            // and keeps it while the script doesn't need to run again. Report
+            // it only if the script ran during this build, and only once per
+            // package, e.g. not again for `cfg(test)`.
+            let build_script = self.build_scripts.lock().unwrap().remove(&id);
+            if let (Some(build_script), Some(Some(out_dir))) = (build_script, envs.get("OUT_DIR")) {
+                let output_file = Path::new(out_dir).with_file_name("output");
+                if modified_since(&output_file, self.build_started) {
+                    if let Ok(output) = fs::read_to_string(&output_file) {
+                        let mut messages = build_script_messages(&output, &build_script);
+                        self.compiler_messages.lock().unwrap().append(&mut messages);
+                    }
+                }
+            }
+
             // Cache calculated input files for a given rustc invocation.
             {
                 let mut cx = self.compilation_cx.lock().unwrap();
@@ -574,6 +955,8 @@ impl Executor for RlsExecutor {
                 self_input_files.entry(file).or_default().extend(inputs);
             }
 
+            self.record_package_status(id, success);
+
             if !success {
                 return Err(anyhow::format_err!("Build error"));
             }
@@ -583,17 +966,148 @@ impl Executor for RlsExecutor {
     }
 }
 
+/// Returns a JSON compiler message of the given `level` with the `text`,
+/// reported at the start of `file`.
+fn file_message(file: &Path, level: &str, text: &str) -> String {
+    serde_json::json!({
+        "message": text,
+        "code": null,
+        "level": level,
+        "spans": [{
+            "file_name": file.display().to_string(),
+            "byte_start": 0,
+            "byte_end": 0,
+            "line_start": 1,
+            "line_end": 1,
+            "column_start": 1,
+            "column_end": 1,
+            "is_primary": true,
+            "text": [],
+            "label": null,
+            "suggested_replacement": null,
+            "expansion": null,
+        }],
+        "children": [],
+        "rendered": format!("{}: {}", level, text),
+    })
+    .to_string()
+}
+
+/// Returns the JSON compiler message reported on the `manifest` of a package
+/// which wasn't compiled, because the build script of its dependency `dep`
+/// failed with the given `stderr`.
+pub(crate) fn dependency_build_script_message(manifest: &Path, dep: &str, stderr: &str) -> String {
+    let text = with_stderr(format!("dependency build script failed: `{}`", dep), stderr);
+    file_message(manifest, "error", &text)
+}
+
+/// Returns the JSON compiler message reported on the source of `build_script`,
+/// which failed with the given `stderr`.
+fn build_script_failure_message(build_script: &Path, stderr: &str) -> String {
+    file_message(build_script, "error", &with_stderr("build script failed".to_owned(), stderr))
+}
+
+/// Appends the `stderr` of a failed process, if any, to the message `text`.
+fn with_stderr(mut text: String, stderr: &str) -> String {
+    if !stderr.trim().is_empty() {
+        write!(text, "\n{}", stderr.trim_end()).unwrap();
+    }
+    text
+}
+
+/// Returns whether the file at `path` was modified at or after `time`, e.g.
+/// whether a build script which saved its output to `path` ran since.
+fn modified_since(path: &Path, time: SystemTime) -> bool {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .map(|modified| modified >= time)
+        .unwrap_or(false)
+}
+
+/// Returns whether `line` is a JSON compiler message, e.g. a diagnostic rather
+/// than an artifact notification.
+fn is_compiler_message(line: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(line)
+        .map(|json| json.get("message").is_some() && json.get("level").is_some())
+        .unwrap_or(false)
+}
+
+/// Turns the `cargo:warning=<text>` and `cargo:error=<text>` lines of the
+/// `output` of a build script into JSON compiler messages reported for the
+/// `build_script` file. Lines which are JSON compiler messages already are
+/// passed through as they are.
+fn build_script_messages(output: &str, build_script: &Path) -> Vec<String> {
+    let message = |level: &str, text: &str| file_message(build_script, level, text);
+
+    output
+        .lines()
+        .filter_map(|line| {
+            if line.starts_with("cargo:warning=") {
+                Some(message("warning", &line["cargo:warning=".len()..]))
+            } else if line.starts_with("cargo:error=") {
+                Some(message("error", &line["cargo:error=".len()..]))
+            } else if is_compiler_message(line) {
+                Some(line.to_owned())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Name of the build command log in the RLS target directory.
+const BUILD_COMMAND_LOG: &str = "build-commands.log";
+/// Size above which the build command log is rotated, keeping one old log.
+const BUILD_COMMAND_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Appends a line with a timestamp (in seconds since the Unix epoch), the
+/// explicitly set env vars, the program and the args of `cmd` to the log at
+/// `path`.
+fn append_build_command(path: &Path, cmd: &ProcessBuilder) -> std::io::Result<()> {
+    if fs::metadata(path).map(|meta| meta.len() > BUILD_COMMAND_LOG_MAX_SIZE).unwrap_or(false) {
+        fs::rename(path, path.with_extension("log.old"))?;
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let quote = |arg: &str| {
+        if arg.is_empty() || arg.contains(char::is_whitespace) {
+            format!("{:?}", arg)
+        } else {
+            arg.to_owned()
+        }
+    };
+    let mut envs: Vec<_> = cmd.get_envs().iter().collect();
+    envs.sort();
+    let envs = envs
+        .into_iter()
+        .filter_map(|(k, v)| v.as_ref().map(|v| quote(&format!("{}={}", k, v.to_string_lossy()))));
+    let program = std::iter::once(quote(&cmd.get_program().to_string_lossy()));
+    let args = cmd.get_args().iter().map(|arg| quote(&arg.to_string_lossy()));
+    let command_line: Vec<_> = envs.chain(program).chain(args).collect();
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut log = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(log, "[{}] {}", timestamp, command_line.join(" "))
+}
+
 #[derive(Debug)]
 struct CargoOptions {
     target: Option<String>,
     lib: bool,
     bin: Vec<String>,
     bins: bool,
+    tests: bool,
+    benches: bool,
+    example: Vec<String>,
+    examples: bool,
     all_features: bool,
     no_default_features: bool,
     features: Vec<String>,
     jobs: Option<u32>,
     all_targets: bool,
+    profile: Option<String>,
 }
 
 impl Default for CargoOptions {
@@ -603,30 +1117,144 @@ impl Default for CargoOptions {
             lib: false,
             bin: vec![],
             bins: false,
+            tests: false,
+            benches: false,
+            example: vec![],
+            examples: false,
             all_features: false,
             no_default_features: false,
             features: vec![],
             jobs: None,
             all_targets: false,
+            profile: None,
         }
     }
 }
 
 impl CargoOptions {
     fn new(config: &Config) -> CargoOptions {
+        // Checking particular targets takes precedence over `all_targets`, but
+        // not over `analyze_all_targets`.
+        let targets_selected = config.build_tests
+            || config.build_benches
+            || config.build_examples
+            || config.build_example.is_some();
         CargoOptions {
+            tests: config.build_tests,
+            benches: config.build_benches,
+            example: config.build_example.iter().cloned().collect(),
+            examples: config.build_examples,
             target: config.target.clone(),
             features: config.features.clone(),
             all_features: config.all_features,
             no_default_features: config.no_default_features,
             jobs: config.jobs,
-            all_targets: config.all_targets,
+            all_targets: config.analyze_all_targets || (config.all_targets && !targets_selected),
+            profile: config.cargo_profile.clone(),
             ..CargoOptions::default()
         }
     }
 }
 
-fn prepare_cargo_rustflags(config: &Config) -> String {
+/// Returns a warning if `profile` isn't defined by the workspace at `ws_root`
+/// (profiles are only read from the root manifest), unless it's the profile
+/// last `reported`.
+fn check_cargo_profile(
+    ws_root: &Path,
+    profile: &str,
+    reported: &mut Option<String>,
+) -> Option<String> {
+    let manifest = fs::read_to_string(ws_root.join("Cargo.toml")).unwrap_or_default();
+    if defines_profile(&manifest, profile) {
+        *reported = None;
+        return None;
+    }
+    if reported.as_deref() == Some(profile) {
+        return None;
+    }
+    warn!("couldn't find profile `{}` specified in `cargo_profile`", profile);
+    *reported = Some(profile.to_owned());
+    Some(format!("The `{}` profile set in `cargo_profile` isn't defined by the workspace", profile))
+}
+
+/// Whether `name` is a built-in Cargo profile or one defined in the `[profile]`
+/// table of the workspace `manifest`.
+fn defines_profile(manifest: &str, name: &str) -> bool {
+    const BUILT_IN_PROFILES: &[&str] = &["dev", "release", "test", "bench"];
+    if BUILT_IN_PROFILES.contains(&name) {
+        return true;
+    }
+    let manifest: toml::Value = match manifest.parse() {
+        Ok(manifest) => manifest,
+        // Cargo reports invalid manifests itself.
+        Err(_) => return true,
+    };
+    manifest.get("profile").and_then(|profiles| profiles.get(name)).is_some()
+}
+
+/// Oldest `cargo` release compatible with the Cargo library the RLS is built with.
+const MIN_CARGO_VERSION: &str = "1.41.0";
+
+/// Newest `cargo` release compatible with the Cargo library the RLS is built
+/// with, i.e., the release of that library itself. Patch releases of it are
+/// compatible as well.
+fn max_cargo_version() -> Version {
+    let linked = cargo::version();
+    Version::new(linked.major.into(), linked.minor.into(), 0)
+}
+
+/// Checks that the `cargo` of the user's toolchain is compatible with the Cargo
+/// library the RLS is built with, returning its version if so. Otherwise,
+/// returns a message explaining the incompatibility.
+pub fn check_cargo_version() -> Result<Version, String> {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+    let output = Command::new(&cargo)
+        .arg("--version")
+        .output()
+        .map_err(|e| format!("Couldn't run `{} --version`: {}", cargo, e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = parse_cargo_version(&stdout)
+        .ok_or_else(|| format!("Couldn't parse the Cargo version from `{}`", stdout.trim()))?;
+
+    check_cargo_version_range(version)
+}
+
+/// Parses the output of `cargo --version`, e.g. `cargo 1.42.0 (86334295e 2020-01-31)`.
+fn parse_cargo_version(output: &str) -> Option<Version> {
+    let version = output.split_whitespace().nth(1)?;
+    Version::parse(version).ok()
+}
+
+fn check_cargo_version_range(version: Version) -> Result<Version, String> {
+    let min = Version::parse(MIN_CARGO_VERSION).unwrap();
+    let max = max_cargo_version();
+    // Compare releases only, so that e.g. `1.43.0-nightly` counts as `1.43`.
+    let release = (version.major, version.minor);
+    if release < (min.major, min.minor) || release > (max.major, max.minor) {
+        return Err(format!(
+            "Cargo {} is not supported by this RLS, which requires Cargo {} up to {}. \
+             Please make sure that the RLS and Cargo come from the same toolchain.",
+            version, MIN_CARGO_VERSION, max
+        ));
+    }
+    Ok(version)
+}
+
+/// Returns the number of warnings and errors among JSON compiler messages.
+fn count_diagnostics(messages: &[String]) -> (usize, usize) {
+    messages.iter().filter_map(|msg| serde_json::from_str::<serde_json::Value>(msg).ok()).fold(
+        (0, 0),
+        |(warnings, errors), msg| match msg["level"].as_str() {
+            Some("warning") => (warnings + 1, errors),
+            Some(level) if level.starts_with("error") => (warnings, errors + 1),
+            _ => (warnings, errors),
+        },
+    )
+}
+
+/// Returns the rustflags to build with and the flags which were removed from
+/// them, see `sanitize_flags`.
+fn prepare_cargo_rustflags(config: &Config) -> (String, Vec<String>) {
     let mut flags = env::var("RUSTFLAGS").unwrap_or_else(|_| String::new());
 
     if let Some(config_flags) = &config.rustflags {
@@ -637,7 +1265,67 @@ fn prepare_cargo_rustflags(config: &Config) -> String {
         write!(flags, " --sysroot {}", sysroot).unwrap();
     }
 
-    dedup_flags(&flags)
+    if config.error_on_unused_imports {
+        flags.push_str(" -D unused_imports");
+    }
+
+    let mut flags = dedup_flags(&flags);
+    if !config.extra_rustflags.is_empty() {
+        if !flags.is_empty() {
+            flags.push(' ');
+        }
+        flags.push_str(&config.extra_rustflags.join(" "));
+    }
+    sanitize_flags(&flags)
+}
+
+/// Removes the flags which are incompatible with the JSON error format the RLS
+/// relies on, i.e., `--color` and any `--error-format` other than
+/// `--error-format=json`, which is kept in place of the latter. Returns the
+/// remaining flags and the removed ones.
+fn sanitize_flags(flags: &str) -> (String, Vec<String>) {
+    const JSON_ERROR_FORMAT: &str = "--error-format=json";
+
+    let mut kept: Vec<String> = vec![];
+    let mut removed = vec![];
+    let mut bits = flags.split_whitespace().peekable();
+    while let Some(bit) = bits.next() {
+        let name = bit.splitn(2, '=').next().unwrap_or_default();
+        if name != "--color" && name != "--error-format" {
+            kept.push(bit.to_owned());
+            continue;
+        }
+
+        // Handle `--color always` the same way as `--color=always`.
+        let value = match bit.find('=') {
+            Some(idx) => bit[idx + 1..].to_owned(),
+            None => match bits.peek() {
+                Some(value) if !value.starts_with('-') => bits.next().unwrap().to_owned(),
+                _ => String::new(),
+            },
+        };
+
+        if name == "--error-format" {
+            if !kept.iter().any(|flag| flag == JSON_ERROR_FORMAT) {
+                kept.push(JSON_ERROR_FORMAT.to_owned());
+            }
+            if value == "json" {
+                continue;
+            }
+        }
+        removed.push(if bit.contains('=') || value.is_empty() {
+            bit.to_owned()
+        } else {
+            format!("{} {}", name, value)
+        });
+    }
+
+    if removed.is_empty() {
+        // Keep the flags as they are, so that the `RUSTFLAGS` fingerprint doesn't change.
+        (flags.to_owned(), removed)
+    } else {
+        (kept.join(" "), removed)
+    }
 }
 
 /// Constructs a cargo configuration for the given build and target directories
@@ -714,7 +1402,17 @@ fn dedup_flags(flag_str: &str) -> String {
     // This is a bit complicated because of the variety of ways args can be specified.
 
     // Retain flags order to prevent complete project rebuild due to `RUSTFLAGS` fingerprint change.
-    let mut flags = BTreeMap::new();
+    // Standalone args are stored under an empty key and are never deduplicated.
+    // Values are stored along with the `=` or space separating them from the
+    // flag, so that `--foo=bar` and `--foo bar` shadow each other.
+    let mut flags: Vec<(String, String)> = vec![];
+    let insert = |flags: &mut Vec<(String, String)>, key: String, value: String| {
+        if let Some(entry) = flags.iter_mut().find(|(k, _)| *k == key) {
+            entry.1 = value;
+        } else {
+            flags.push((key, value));
+        }
+    };
     let mut bits = flag_str.split_whitespace().peekable();
 
     while let Some(bit) = bits.next() {
@@ -733,38 +1431,58 @@ fn dedup_flags(flag_str: &str) -> String {
                 // Split only on the first equals sign (there may be more than one).
                 let bits: Vec<_> = bit.splitn(2, '=').collect();
                 assert!(bits.len() == 2);
-                flags.insert(bits[0].to_owned() + "=", bits[1].to_owned());
+                insert(&mut flags, bits[0].to_owned(), format!("={}", bits[1]));
             } else if bits.peek().is_some() && !bits.peek().unwrap().starts_with('-') {
-                flags.insert(bit, bits.next().unwrap().to_owned());
+                insert(&mut flags, bit, format!(" {}", bits.next().unwrap()));
             } else {
-                flags.insert(bit, String::new());
+                insert(&mut flags, bit, String::new());
             }
         } else {
-            // A standalone arg with no flag, no deduplication to do. We merge these
-            // together, which is probably not ideal, but is simple.
-            flags.entry(String::new()).or_insert_with(String::new).push_str(&format!(" {}", bit));
+            // A standalone arg with no flag, no deduplication to do.
+            flags.push((String::new(), bit));
         }
     }
 
-    // Put the map back together as a string.
+    // Put the flags back together as a string.
     let mut result = String::new();
     for (k, v) in &flags {
-        if k.is_empty() {
-            result.push_str(v);
-        } else {
-            result.push(' ');
-            result.push_str(k);
-            if !v.is_empty() {
-                if !k.ends_with('=') {
-                    result.push(' ');
-                }
-                result.push_str(v);
-            }
-        }
+        result.push(' ');
+        result.push_str(k);
+        result.push_str(v);
     }
     result
 }
 
+/// Returns the primary packages which failed to build, if only some of them did.
+fn failed_packages(package_status: HashMap<PackageId, bool>) -> Option<Vec<PackageId>> {
+    let (failed, succeeded): (Vec<_>, Vec<_>) =
+        package_status.into_iter().partition(|&(_, success)| !success);
+    if failed.is_empty() || succeeded.is_empty() {
+        return None;
+    }
+
+    Some(failed.into_iter().map(|(id, _)| id).collect())
+}
+
+/// Returns `id` or any of its transitive dependencies (as recorded in `deps`)
+/// which is contained in `failed`.
+fn failed_dependency<T>(id: T, deps: &HashMap<T, HashSet<T>>, failed: &HashSet<T>) -> Option<T>
+where
+    T: Copy + Eq + Hash,
+{
+    let mut visited = HashSet::new();
+    let mut queue = vec![id];
+    while let Some(id) = queue.pop() {
+        if failed.contains(&id) {
+            return Some(id);
+        }
+        if visited.insert(id) {
+            queue.extend(deps.get(&id).into_iter().flatten().copied());
+        }
+    }
+    None
+}
+
 /// Removes a selected flag of a `--flag=VALUE` or `--flag VALUE` shape from `args` (command line args for Rust).
 fn filter_arg(args: &[OsString], key: &str) -> Vec<String> {
     let key_as_prefix = key.to_owned() + "=";
@@ -868,7 +1586,19 @@ impl std::error::Error for ManifestAwareError {}
 
 #[cfg(test)]
 mod test {
-    use super::{dedup_flags, filter_arg};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use super::{
+        build_script_messages, check_cargo_profile, check_cargo_version_range, count_diagnostics,
+        dedup_flags, defines_profile, failed_dependency, failed_packages, filter_arg,
+        modified_since, parse_cargo_version, prepare_cargo_rustflags, sanitize_flags,
+        take_cached_metadata, CachedMetadata, CargoOptions,
+    };
+    use crate::config::Config;
 
     #[test]
     fn test_dedup_flags() {
@@ -880,6 +1610,7 @@ mod test {
         let result = dedup_flags("-Z foo foo bar");
         assert!(result.matches("foo").count() == 2);
         assert!(result.matches("bar").count() == 1);
+        assert!(dedup_flags("a -Zfoo b a") == " a -Zfoo b a");
 
         // These should get deduplicated.
         assert!(dedup_flags("-Zfoo -Zfoo") == " -Zfoo");
@@ -900,6 +1631,218 @@ mod test {
                 "-C link-args=-fuse-ld=gold -C target-cpu=native -C link-args=-fuse-ld=gold"
             ) == " -Clink-args=-fuse-ld=gold -Ctarget-cpu=native"
         );
+
+        assert_eq!(dedup_flags("--edition 2021"), " --edition 2021");
+        assert_eq!(dedup_flags("--edition 2021 --edition 2018"), " --edition 2018");
+        assert_eq!(dedup_flags("--edition=2021 --edition 2018"), " --edition 2018");
+        assert_eq!(dedup_flags("--edition 2021 --edition=2018"), " --edition=2018");
+    }
+
+    #[test]
+    fn test_build_script_messages() {
+        let diagnostic = r#"{"message": "unused variable", "level": "warning", "spans": []}"#;
+        let output = format!(
+            "cargo:rerun-if-changed=build.rs\n\
+             cargo:warning=libfoo not found, using the bundled version\n\
+             cargo:error=unsupported target\n\
+             {}\n\
+             {{\"reason\": \"unrelated\"}}\n",
+            diagnostic
+        );
+        let build_script = Path::new("/project/build.rs");
+        let messages = build_script_messages(&output, build_script);
+        assert_eq!(messages.len(), 3);
+
+        let parse = |msg: &str| serde_json::from_str::<serde_json::Value>(msg).unwrap();
+        let warning = parse(&messages[0]);
+        assert_eq!(warning["level"], "warning");
+        assert_eq!(warning["message"], "libfoo not found, using the bundled version");
+        assert_eq!(warning["spans"][0]["file_name"], "/project/build.rs");
+        assert_eq!(parse(&messages[1])["level"], "error");
+        assert_eq!(messages[2], diagnostic);
+        assert_eq!(count_diagnostics(&messages), (2, 1));
+    }
+
+    #[test]
+    fn test_modified_since() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+        assert!(!modified_since(&output, UNIX_EPOCH));
+
+        fs::write(&output, "cargo:warning=stale\n").unwrap();
+        assert!(modified_since(&output, SystemTime::now() - Duration::from_secs(60)));
+        assert!(!modified_since(&output, SystemTime::now() + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_error_on_unused_imports() {
+        let config = Config { error_on_unused_imports: true, ..Config::default() };
+        assert!(prepare_cargo_rustflags(&config).0.contains("-Dunused_imports"));
+
+        let config = Config { error_on_unused_imports: false, ..Config::default() };
+        assert!(!prepare_cargo_rustflags(&config).0.contains("unused_imports"));
+    }
+
+    #[test]
+    fn test_sanitize_flags() {
+        let (flags, removed) = sanitize_flags("--color=always --error-format=human");
+        assert_eq!(flags, "--error-format=json");
+        assert_eq!(removed, ["--color=always", "--error-format=human"]);
+
+        let (flags, removed) = sanitize_flags("-Zfoo --color never --error-format=json");
+        assert_eq!(flags, "-Zfoo --error-format=json");
+        assert_eq!(removed, ["--color never"]);
+
+        let (flags, removed) = sanitize_flags(" -Zfoo -Dwarnings");
+        assert_eq!(flags, " -Zfoo -Dwarnings");
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn test_cargo_version() {
+        let version = |s| parse_cargo_version(s).unwrap();
+        let stable = version("cargo 1.42.0 (86334295e 2020-01-31)\n");
+        assert_eq!(stable, semver::Version::new(1, 42, 0));
+        assert!(parse_cargo_version("cargo").is_none());
+        assert!(parse_cargo_version("").is_none());
+
+        assert!(check_cargo_version_range(stable).is_ok());
+        assert!(check_cargo_version_range(version("cargo 1.43.0-nightly (abc 2020-02-18)")).is_ok());
+        assert!(check_cargo_version_range(version("cargo 1.40.0")).is_err());
+
+        // Any release up to the one of the linked Cargo library is supported.
+        let max = super::max_cargo_version();
+        let (major, minor) = (max.major, max.minor);
+        assert!(check_cargo_version_range(semver::Version::new(major, minor, 2)).is_ok());
+        let nightly = format!("cargo {}.{}.0-nightly", major, minor + 1);
+        assert!(check_cargo_version_range(version(nightly.as_str())).is_err());
+    }
+
+    #[test]
+    fn test_count_diagnostics() {
+        let messages = vec![
+            r#"{"message":"unused variable: `x`","level":"warning"}"#.to_owned(),
+            r#"{"message":"mismatched types","level":"error"}"#.to_owned(),
+            r#"{"message":"aborting due to previous error","level":"error"}"#.to_owned(),
+            r#"{"message":"it panicked","level":"error: internal compiler error"}"#.to_owned(),
+            r#"{"message":"for more information","level":"note"}"#.to_owned(),
+        ];
+        assert_eq!(count_diagnostics(&messages), (1, 3));
+        assert_eq!(count_diagnostics(&[]), (0, 0));
+    }
+
+    #[test]
+    fn test_cargo_profile() {
+        let config = Config { cargo_profile: Some("rls".to_owned()), ..Config::default() };
+        assert_eq!(CargoOptions::new(&config).profile, Some("rls".to_owned()));
+
+        assert_eq!(CargoOptions::new(&Config::default()).profile, None);
+    }
+
+    #[test]
+    fn test_defines_profile() {
+        let manifest = "[package]\nname = \"foo\"\n\n[profile.dev-optimized]\nopt-level = 1\n";
+        assert!(defines_profile(manifest, "dev-optimized"));
+        assert!(defines_profile(manifest, "release"));
+        assert!(!defines_profile(manifest, "rls"));
+        assert!(!defines_profile("[package]\nname = \"foo\"\n", "rls"));
+    }
+
+    #[test]
+    fn test_check_cargo_profile() {
+        let root = tempfile::tempdir().unwrap();
+        let manifest = "[workspace]\nmembers = [\"foo\"]\n\n[profile.rls]\nopt-level = 1\n";
+        fs::write(root.path().join("Cargo.toml"), manifest).unwrap();
+
+        let mut reported = None;
+        assert_eq!(check_cargo_profile(root.path(), "rls", &mut reported), None);
+        // An undefined profile is only reported once.
+        assert!(check_cargo_profile(root.path(), "other", &mut reported).is_some());
+        assert_eq!(check_cargo_profile(root.path(), "other", &mut reported), None);
+
+        fs::write(root.path().join("Cargo.toml"), "[workspace]\nmembers = [\"foo\"]\n").unwrap();
+        assert!(check_cargo_profile(root.path(), "rls", &mut reported).is_some());
+        assert_eq!(check_cargo_profile(root.path(), "rls", &mut reported), None);
+    }
+
+    #[test]
+    fn test_build_targets() {
+        let config = Config {
+            build_tests: true,
+            build_examples: true,
+            build_example: Some("demo".to_owned()),
+            ..Config::default()
+        };
+        let opts = CargoOptions::new(&config);
+        assert!(opts.tests && opts.examples && !opts.benches);
+        assert_eq!(opts.example, vec!["demo".to_owned()]);
+        // The selected targets are checked instead of all of them.
+        assert!(config.all_targets && !opts.all_targets);
+
+        assert!(CargoOptions::new(&Config::default()).all_targets);
+    }
+
+    #[test]
+    fn test_analyze_all_targets() {
+        let config = Config {
+            analyze_all_targets: true,
+            all_targets: false,
+            build_examples: true,
+            ..Config::default()
+        };
+        assert!(CargoOptions::new(&config).all_targets);
+
+        let config = Config { all_targets: false, ..Config::default() };
+        assert!(!CargoOptions::new(&config).all_targets);
+    }
+
+    #[test]
+    fn test_extra_rustflags() {
+        let config = Config {
+            extra_rustflags: vec!["-Cinstrument-coverage".to_owned()],
+            ..Config::default()
+        };
+        let (flags, _) = prepare_cargo_rustflags(&config);
+        assert!(flags.ends_with("-Cinstrument-coverage"), "{}", flags);
+    }
+
+    #[test]
+    fn test_failed_packages() {
+        use cargo::core::{PackageId, SourceId};
+        use std::path::Path;
+
+        let package = |name: &str| {
+            let source = SourceId::for_path(Path::new("/workspace").join(name).as_path()).unwrap();
+            PackageId::new(name, "0.1.0", source).unwrap()
+        };
+        let (lib, bin) = (package("lib"), package("bin"));
+
+        let status = vec![(lib, true), (bin, false)].into_iter().collect();
+        assert_eq!(failed_packages(status), Some(vec![bin]));
+        // Not a partial success if every package either succeeded or failed.
+        assert_eq!(failed_packages(vec![(lib, true), (bin, true)].into_iter().collect()), None);
+        assert_eq!(failed_packages(vec![(lib, false), (bin, false)].into_iter().collect()), None);
+    }
+
+    #[test]
+    fn test_failed_dependency() {
+        use std::collections::{HashMap, HashSet};
+
+        // `bin` -> `lib` -> `sys`, `bin` -> `util`
+        let deps: HashMap<_, HashSet<_>> = vec![
+            ("bin", vec!["lib", "util"].into_iter().collect()),
+            ("lib", vec!["sys"].into_iter().collect()),
+            ("sys", HashSet::new()),
+        ]
+        .into_iter()
+        .collect();
+
+        let failed = vec!["sys"].into_iter().collect();
+        assert_eq!(failed_dependency("sys", &deps, &failed), Some("sys"));
+        assert_eq!(failed_dependency("lib", &deps, &failed), Some("sys"));
+        assert_eq!(failed_dependency("bin", &deps, &failed), Some("sys"));
+        assert_eq!(failed_dependency("util", &deps, &failed), None);
+        assert_eq!(failed_dependency("bin", &deps, &HashSet::new()), None);
     }
 
     #[test]
@@ -918,4 +1861,31 @@ mod test {
         assert_eq!(filter_arg(&args("-a --error-format json -b"), "--error-format"), ["-a", "-b"]);
         assert_eq!(filter_arg(&args("-a -b -x"), "--error-format"), ["-a", "-b", "-x"]);
     }
+
+    #[test]
+    fn test_take_cached_metadata() {
+        let fixture = Path::new(env!("FIXTURES_DIR")).join("cargo_metadata.json");
+        let fixture = std::fs::read_to_string(fixture).unwrap();
+        let manifest_path = PathBuf::from("/workspace/Cargo.toml");
+        let cache = |started_at| {
+            let metadata = serde_json::from_str(&fixture).unwrap();
+            Mutex::new(Some(CachedMetadata {
+                manifest_path: manifest_path.clone(),
+                started_at,
+                metadata: thread::spawn(move || Some(metadata)),
+            }))
+        };
+
+        let fresh = cache(Instant::now());
+        let metadata = take_cached_metadata(&fresh, &manifest_path).unwrap();
+        assert_eq!(metadata.workspace_members.len(), 3);
+        // The metadata is only used by a single build.
+        assert!(take_cached_metadata(&fresh, &manifest_path).is_none());
+
+        let other_workspace = cache(Instant::now());
+        assert!(take_cached_metadata(&other_workspace, Path::new("/other/Cargo.toml")).is_none());
+
+        let stale = cache(Instant::now() - Duration::from_secs(31));
+        assert!(take_cached_metadata(&stale, &manifest_path).is_none());
+    }
 }
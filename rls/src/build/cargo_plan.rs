@@ -23,9 +23,10 @@ use cargo::core::compiler::{CompileKind, CompileMode, Context, Unit};
 use cargo::core::profiles::Profile;
 use cargo::core::{PackageId, Target, TargetKind};
 use cargo::util::ProcessBuilder;
+use cargo_metadata::Metadata;
 use log::{error, trace};
 
-use crate::build::plan::{BuildGraph, BuildKey, JobQueue, WorkStatus};
+use crate::build::plan::{BuildGraph, BuildKey, JobQueue, NinjaJob, PackageGraph, WorkStatus};
 use crate::build::rustc::src_path;
 use crate::build::PackageArg;
 
@@ -63,12 +64,21 @@ pub(crate) struct CargoPlan {
 }
 
 impl CargoPlan {
-    pub(crate) fn with_manifest(manifest_path: &Path) -> CargoPlan {
-        CargoPlan { package_map: Some(PackageMap::new(manifest_path)), ..Default::default() }
+    /// Creates an empty plan for the workspace of `manifest_path`, whose
+    /// `cargo metadata` is fetched unless it's passed.
+    pub(crate) fn with_manifest(manifest_path: &Path, metadata: Option<Metadata>) -> CargoPlan {
+        CargoPlan {
+            package_map: Some(PackageMap::new(manifest_path, metadata)),
+            ..Default::default()
+        }
     }
 
-    pub(crate) fn with_packages(manifest_path: &Path, pkgs: HashSet<String>) -> CargoPlan {
-        CargoPlan { built_packages: pkgs, ..Self::with_manifest(manifest_path) }
+    pub(crate) fn with_packages(
+        manifest_path: &Path,
+        pkgs: HashSet<String>,
+        metadata: Option<Metadata>,
+    ) -> CargoPlan {
+        CargoPlan { built_packages: pkgs, ..Self::with_manifest(manifest_path, metadata) }
     }
 
     /// Returns `true` if a build plan has cached compiler invocations and dep
@@ -284,6 +294,35 @@ impl CargoPlan {
         transitive
     }
 
+    /// Returns the cached compiler jobs in dependency order, see
+    /// `BuildPlan::serialize_for_ninja`.
+    pub(crate) fn ninja_jobs(&self) -> Vec<NinjaJob<'_>> {
+        // Only the keys are used as the starting points of the sorting.
+        let all_units = self.units.keys().map(|key| (key.clone(), HashSet::new())).collect();
+        // Jobs that have to run first are *last* in the topological sorting.
+        let mut keys: Vec<_> = CargoPlan::topological_sort(self, &all_units)
+            .into_iter()
+            .filter(|key| self.compiler_jobs.contains_key(key))
+            .collect();
+        keys.reverse();
+        let indices: HashMap<_, _> =
+            keys.iter().enumerate().map(|(idx, key)| (key.clone(), idx)).collect();
+
+        keys.iter()
+            .map(|key| {
+                let mut deps: Vec<_> = self
+                    .dep_graph
+                    .get(key)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|dep| indices.get(dep).cloned())
+                    .collect();
+                deps.sort();
+                NinjaJob { command: &self.compiler_jobs[key], deps }
+            })
+            .collect()
+    }
+
     /// Creates a dirty reverse dependency graph using a set of given dirty units.
     fn dirty_rev_dep_graph(
         &self,
@@ -403,21 +442,29 @@ struct PackageMap {
 }
 
 impl PackageMap {
-    fn new(manifest_path: &Path) -> PackageMap {
+    fn new(manifest_path: &Path, metadata: Option<Metadata>) -> PackageMap {
         PackageMap {
-            package_paths: Self::discover_package_paths(manifest_path),
+            package_paths: Self::discover_package_paths(manifest_path, metadata),
             map_cache: Mutex::new(HashMap::new()),
         }
     }
 
     // Finds each package in the workspace and record the root directory and package name.
-    fn discover_package_paths(manifest_path: &Path) -> HashMap<PathBuf, String> {
-        trace!("read metadata {:?}", manifest_path);
-        cargo_metadata::MetadataCommand::new()
-            .manifest_path(manifest_path)
-            .exec()
-            .iter()
-            .flat_map(|meta| meta.workspace_members.iter().map(move |id| &meta[id]))
+    fn discover_package_paths(
+        manifest_path: &Path,
+        metadata: Option<Metadata>,
+    ) -> HashMap<PathBuf, String> {
+        let metadata = match metadata {
+            Some(metadata) => Ok(metadata),
+            None => {
+                trace!("read metadata {:?}", manifest_path);
+                cargo_metadata::MetadataCommand::new().manifest_path(manifest_path).exec()
+            }
+        };
+        metadata
+            .map(|meta| PackageGraph::import_from_cargo_metadata(&meta))
+            .unwrap_or_default()
+            .workspace_members()
             .filter_map(|pkg| {
                 let dir = pkg.manifest_path.parent()?.to_path_buf();
                 Some((dir, pkg.name.clone()))
@@ -1,9 +1,12 @@
 use lazy_static::lazy_static;
-use std::collections::BTreeMap;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::ffi::OsString;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Instant;
 
 // Ensures we don't race on the env vars. This is only also important in tests,
 // where we have multiple copies of the RLS running in the same process.
@@ -11,25 +14,52 @@ lazy_static! {
     static ref ENV_LOCK: Arc<EnvironmentLock> = Arc::new(EnvironmentLock::new());
 }
 
+/// Prefix of the keys of `Config::extra_env` that override the variables set
+/// by Cargo.
+const FORCE_PREFIX: &str = "RLS_FORCE_";
+
+/// Adds `extra_env` (see `Config::extra_env`) to `envs`. Variables already in
+/// `envs`, i.e., set by Cargo, take precedence, unless the key of the extra
+/// variable is prefixed with `RLS_FORCE_`, which is stripped. A forced variable
+/// also takes precedence over an unprefixed one of the same name.
+pub fn merge_extra_env(
+    envs: &mut BTreeMap<String, Option<OsString>>,
+    extra_env: &HashMap<String, String>,
+) {
+    let (forced, unforced): (Vec<_>, Vec<_>) =
+        extra_env.iter().partition(|(key, _)| key.starts_with(FORCE_PREFIX));
+    for (key, value) in unforced {
+        envs.entry(key.clone()).or_insert_with(|| Some(value.into()));
+    }
+    for (key, value) in forced {
+        envs.insert(key[FORCE_PREFIX.len()..].to_owned(), Some(value.into()));
+    }
+}
+
 /// An RAII helper to set and reset the env vars.
 /// Requires supplying an external lock guard to guarantee env var consistency across multiple threads.
+/// The old values are recorded on the `EnvironmentLock`'s restoration stack,
+/// see `EnvironmentLock::release_all`.
 pub struct Environment<'a> {
-    old_vars: BTreeMap<String, Option<OsString>>,
+    restoration_id: usize,
     old_cwd: PathBuf,
-    _guard: MutexGuard<'a, ()>,
+    _guard: EnvironmentGuard<'a>,
 }
 
 impl<'a> Environment<'a> {
     pub fn push_with_lock(
         envs: &BTreeMap<String, Option<OsString>>,
         cwd: Option<&Path>,
-        lock: MutexGuard<'a, ()>,
+        lock: EnvironmentGuard<'a>,
     ) -> Environment<'a> {
-        let mut result = Environment {
+        let old_cwd = env::current_dir().expect("failed to read cwd");
+        let restoration_id = ENV_LOCK.next_restoration_id.fetch_add(1, Ordering::SeqCst);
+        ENV_LOCK.restoration_stack().push(Restoration {
+            id: restoration_id,
             old_vars: BTreeMap::new(),
-            old_cwd: env::current_dir().expect("failed to read cwd"),
-            _guard: lock,
-        };
+            old_cwd: old_cwd.clone(),
+        });
+        let mut result = Environment { restoration_id, old_cwd, _guard: lock };
 
         if let Some(cwd) = cwd {
             env::set_current_dir(cwd).expect("failed to change cwd");
@@ -42,7 +72,10 @@ impl<'a> Environment<'a> {
     }
 
     pub fn push_var(&mut self, key: &str, value: &Option<OsString>) {
-        self.old_vars.insert(key.to_owned(), env::var_os(key));
+        let mut stack = ENV_LOCK.restoration_stack();
+        if let Some(restoration) = stack.iter_mut().find(|r| r.id == self.restoration_id) {
+            restoration.old_vars.insert(key.to_owned(), env::var_os(key));
+        }
         match *value {
             Some(ref v) => env::set_var(key, v),
             None => env::remove_var(key),
@@ -52,14 +85,85 @@ impl<'a> Environment<'a> {
     pub fn get_old_cwd(&self) -> &Path {
         &self.old_cwd
     }
+
+    /// Returns the changes made to the environment variables between the
+    /// `before` and `after` snapshots, sorted by variable name.
+    pub fn diff(before: &Snapshot, after: &Snapshot) -> Vec<(String, Change)> {
+        let mut changes: Vec<_> = after
+            .0
+            .iter()
+            .filter_map(|(key, to)| match before.0.get(key) {
+                None => Some((key.clone(), Change::Added(to.clone()))),
+                Some(from) if from != to => {
+                    Some((key.clone(), Change::Modified { from: from.clone(), to: to.clone() }))
+                }
+                Some(_) => None,
+            })
+            .chain(
+                before
+                    .0
+                    .keys()
+                    .filter(|key| !after.0.contains_key(*key))
+                    .map(|key| (key.clone(), Change::Removed)),
+            )
+            .collect();
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        changes
+    }
+}
+
+/// A copy of the environment variables at a given point in time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot(HashMap<String, String>);
+
+/// Captures the current environment variables. Names and values that are not
+/// valid Unicode are converted lossily.
+pub fn snapshot() -> Snapshot {
+    Snapshot(
+        env::vars_os()
+            .map(|(k, v)| (k.to_string_lossy().into_owned(), v.to_string_lossy().into_owned()))
+            .collect(),
+    )
+}
+
+/// A change of a single environment variable between two `Snapshot`s.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Change {
+    /// The variable was set to the given value.
+    Added(String),
+    /// The variable was unset.
+    Removed,
+    /// The value of the variable has changed.
+    Modified { from: String, to: String },
 }
 
 impl<'a> Drop for Environment<'a> {
     fn drop(&mut self) {
+        let restoration = {
+            let mut stack = ENV_LOCK.restoration_stack();
+            let index = stack.iter().position(|r| r.id == self.restoration_id);
+            index.map(|index| stack.remove(index))
+        };
+        // Nothing to do if `EnvironmentLock::release_all` restored it already.
+        if let Some(restoration) = restoration {
+            restoration.apply();
+        }
+    }
+}
+
+/// The environment to restore when an `Environment` is dropped.
+struct Restoration {
+    id: usize,
+    old_vars: BTreeMap<String, Option<OsString>>,
+    old_cwd: PathBuf,
+}
+
+impl Restoration {
+    fn apply(self) {
         drop(env::set_current_dir(&self.old_cwd));
-        for (k, v) in &self.old_vars {
-            match *v {
-                Some(ref v) => env::set_var(k, v),
+        for (k, v) in self.old_vars {
+            match v {
+                Some(v) => env::set_var(k, v),
                 None => env::remove_var(k),
             }
         }
@@ -81,9 +185,15 @@ impl<'a> Drop for Environment<'a> {
 /// We're using linked Cargo and rustc to optimize serialization and IPC overhead, which means
 /// we don't spawn different processes, hence why we share a single environment and need to provide
 /// synchronized access to it.
+///
+/// Both locks are handed over in arrival order, so that concurrent builds (e.g., of several RLS
+/// instances in the same process) can't starve each other, see `contention_stats`.
 pub struct EnvironmentLock {
-    outer: Mutex<()>,
-    inner: Mutex<()>,
+    outer: FairMutex,
+    inner: FairMutex,
+    /// The pending restorations of the live `Environment`s, in push order.
+    restoration_stack: Mutex<Vec<Restoration>>,
+    next_restoration_id: AtomicUsize,
 }
 
 /// Helper type that provides a unified way to access both outer and inner types of
@@ -96,7 +206,7 @@ pub enum EnvironmentLockFacade {
 impl<'a> EnvironmentLockFacade {
     /// Retrieves access to an underlying, corresponding `Mutex` lock of `EnvironmentLock` and
     /// additionally returns `InnerLock` if the underlying lock is an `OuterLock`.
-    pub fn lock(&self) -> (MutexGuard<'a, ()>, Option<InnerLock>) {
+    pub fn lock(&self) -> (EnvironmentGuard<'a>, Option<InnerLock>) {
         match *self {
             EnvironmentLockFacade::Outer(ref lock) => {
                 let (guard, inner) = lock.lock();
@@ -109,7 +219,12 @@ impl<'a> EnvironmentLockFacade {
 
 impl<'a> EnvironmentLock {
     fn new() -> EnvironmentLock {
-        EnvironmentLock { outer: Mutex::new(()), inner: Mutex::new(()) }
+        EnvironmentLock {
+            outer: FairMutex::default(),
+            inner: FairMutex::default(),
+            restoration_stack: Mutex::new(vec![]),
+            next_restoration_id: AtomicUsize::new(0),
+        }
     }
 
     /// Retrieves a pointer to the single, static instance of an `EnvironmentLock`.
@@ -120,14 +235,40 @@ impl<'a> EnvironmentLock {
     /// Acquires the first, outer lock and additionally return `InnerLock` interface, through which
     /// user can access the second, inner lock. Does not enforce any guarantees regarding order of
     /// locking, since `InnerLock` can be copied outside 'a lifetime and locked there.
-    pub fn lock(&self) -> (MutexGuard<'a, ()>, InnerLock) {
-        (ENV_LOCK.outer.lock().unwrap(), InnerLock {})
+    pub fn lock(&self) -> (EnvironmentGuard<'a>, InnerLock) {
+        (ENV_LOCK.outer.lock(), InnerLock {})
     }
 
     /// Constructs a corresponding `EnvironmentLockFacade` value, erasing specific type of the lock.
     pub fn as_facade(&self) -> EnvironmentLockFacade {
         EnvironmentLockFacade::Outer(ENV_LOCK.clone())
     }
+
+    /// Restores the environment as it was before any of the live `Environment`s
+    /// were pushed, applying their restorations in reverse order, regardless of
+    /// the state of the locks. Dropping these `Environment`s afterwards does
+    /// nothing. Meant for test teardown, e.g., after a panic was caught while an
+    /// `Environment` was leaked.
+    pub fn release_all(&self) {
+        let restorations = std::mem::replace(&mut *self.restoration_stack(), vec![]);
+        for restoration in restorations.into_iter().rev() {
+            restoration.apply();
+        }
+    }
+
+    /// Returns how often and how long callers waited for either lock so far.
+    pub fn contention_stats(&self) -> ContentionStats {
+        let (outer, inner) = (ENV_LOCK.outer.contention_stats(), ENV_LOCK.inner.contention_stats());
+        ContentionStats {
+            total_waits: outer.total_waits + inner.total_waits,
+            total_wait_ns: outer.total_wait_ns + inner.total_wait_ns,
+        }
+    }
+
+    fn restoration_stack(&self) -> MutexGuard<'_, Vec<Restoration>> {
+        // A panic while holding the lock doesn't leave the stack inconsistent.
+        self.restoration_stack.lock().unwrap_or_else(|e| e.into_inner())
+    }
 }
 
 /// Acts as an interface through which user can acquire the second, inner lock of `EnvironmentLock`.
@@ -135,8 +276,8 @@ pub struct InnerLock;
 
 impl<'a> InnerLock {
     /// Acquires the second, inner environment lock.
-    pub fn lock(&self) -> MutexGuard<'a, ()> {
-        ENV_LOCK.inner.lock().unwrap()
+    pub fn lock(&self) -> EnvironmentGuard<'a> {
+        ENV_LOCK.inner.lock()
     }
 
     /// Constructs a corresponding `EnvironmentLockFacade` value, erasing specific type of the lock.
@@ -144,3 +285,220 @@ impl<'a> InnerLock {
         EnvironmentLockFacade::Inner(Self {})
     }
 }
+
+/// How often and how long callers had to wait for an `EnvironmentLock`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContentionStats {
+    /// The number of lock acquisitions that had to wait for another holder.
+    pub total_waits: u64,
+    /// The total time spent waiting, in nanoseconds.
+    pub total_wait_ns: u64,
+}
+
+/// A mutex that is handed over to waiters in arrival order (i.e., a ticket
+/// lock), unlike `std::sync::Mutex`, which makes no fairness guarantee.
+#[derive(Default)]
+struct FairMutex {
+    tickets: Mutex<Tickets>,
+    /// Notified (with `tickets` locked) when the lock is released.
+    released: Condvar,
+    total_waits: AtomicU64,
+    total_wait_ns: AtomicU64,
+}
+
+#[derive(Default)]
+struct Tickets {
+    /// The ticket of the next caller of `lock`.
+    next: u64,
+    /// The ticket of the current holder of the lock.
+    serving: u64,
+}
+
+impl FairMutex {
+    fn lock(&self) -> EnvironmentGuard<'_> {
+        let mut tickets = self.tickets();
+        let ticket = tickets.next;
+        tickets.next += 1;
+        if tickets.serving != ticket {
+            let start = Instant::now();
+            while tickets.serving != ticket {
+                tickets = self.released.wait(tickets).unwrap_or_else(|e| e.into_inner());
+            }
+            let waited = start.elapsed().as_nanos() as u64;
+            self.total_waits.fetch_add(1, Ordering::SeqCst);
+            self.total_wait_ns.fetch_add(waited, Ordering::SeqCst);
+        }
+        EnvironmentGuard { mutex: self }
+    }
+
+    fn contention_stats(&self) -> ContentionStats {
+        ContentionStats {
+            total_waits: self.total_waits.load(Ordering::SeqCst),
+            total_wait_ns: self.total_wait_ns.load(Ordering::SeqCst),
+        }
+    }
+
+    fn tickets(&self) -> MutexGuard<'_, Tickets> {
+        // The tickets are only updated while locked, never left inconsistent.
+        self.tickets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Holds either lock of the `EnvironmentLock` until dropped.
+pub struct EnvironmentGuard<'a> {
+    mutex: &'a FairMutex,
+}
+
+impl<'a> Drop for EnvironmentGuard<'a> {
+    fn drop(&mut self) {
+        let mut tickets = self.mutex.tickets();
+        tickets.serving += 1;
+        self.mutex.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn diff_env_snapshots() {
+        let (_guard, _) = EnvironmentLock::get().lock();
+        env::set_var("RLS_TEST_ENV_DIFF_REMOVED", "1");
+        env::set_var("RLS_TEST_ENV_DIFF_MODIFIED", "before");
+        env::remove_var("RLS_TEST_ENV_DIFF_ADDED");
+
+        let before = snapshot();
+        env::remove_var("RLS_TEST_ENV_DIFF_REMOVED");
+        env::set_var("RLS_TEST_ENV_DIFF_MODIFIED", "after");
+        env::set_var("RLS_TEST_ENV_DIFF_ADDED", "2");
+        let after = snapshot();
+
+        env::remove_var("RLS_TEST_ENV_DIFF_MODIFIED");
+        env::remove_var("RLS_TEST_ENV_DIFF_ADDED");
+
+        let diff: Vec<_> = Environment::diff(&before, &after)
+            .into_iter()
+            .filter(|(key, _)| key.starts_with("RLS_TEST_ENV_DIFF_"))
+            .collect();
+        assert_eq!(
+            diff,
+            vec![
+                ("RLS_TEST_ENV_DIFF_ADDED".to_owned(), Change::Added("2".to_owned())),
+                (
+                    "RLS_TEST_ENV_DIFF_MODIFIED".to_owned(),
+                    Change::Modified { from: "before".to_owned(), to: "after".to_owned() }
+                ),
+                ("RLS_TEST_ENV_DIFF_REMOVED".to_owned(), Change::Removed),
+            ]
+        );
+        assert!(Environment::diff(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn release_all_restores_env() {
+        let (guard, inner) = EnvironmentLock::get().lock();
+        env::set_var("RLS_TEST_ENV_RELEASE_MODIFIED", "before");
+        env::remove_var("RLS_TEST_ENV_RELEASE_ADDED");
+
+        let envs = |key: &str, value: &str| {
+            let mut envs = BTreeMap::new();
+            envs.insert(key.to_owned(), Some(OsString::from(value)));
+            envs
+        };
+        let outer =
+            Environment::push_with_lock(&envs("RLS_TEST_ENV_RELEASE_MODIFIED", "1"), None, guard);
+        let nested = Environment::push_with_lock(
+            &envs("RLS_TEST_ENV_RELEASE_ADDED", "2"),
+            None,
+            inner.lock(),
+        );
+        assert_eq!(env::var("RLS_TEST_ENV_RELEASE_MODIFIED"), Ok("1".to_owned()));
+        assert_eq!(env::var("RLS_TEST_ENV_RELEASE_ADDED"), Ok("2".to_owned()));
+
+        EnvironmentLock::get().release_all();
+        assert_eq!(env::var("RLS_TEST_ENV_RELEASE_MODIFIED"), Ok("before".to_owned()));
+        assert!(env::var_os("RLS_TEST_ENV_RELEASE_ADDED").is_none());
+
+        // The restorations were applied already.
+        env::set_var("RLS_TEST_ENV_RELEASE_MODIFIED", "after");
+        drop(nested);
+        drop(outer);
+        assert_eq!(env::var("RLS_TEST_ENV_RELEASE_MODIFIED"), Ok("after".to_owned()));
+        env::remove_var("RLS_TEST_ENV_RELEASE_MODIFIED");
+    }
+
+    #[test]
+    fn fair_mutex_is_first_come_first_served() {
+        use std::thread;
+        use std::time::Duration;
+
+        let mutex = Arc::new(FairMutex::default());
+        // Waits until `count` callers hold or wait for the lock.
+        let wait_for_callers = |count: u64| {
+            while mutex.tickets().next < count {
+                thread::yield_now();
+            }
+        };
+
+        let mut in_order = 0;
+        let rounds = 100;
+        for _ in 0..rounds {
+            let order = Arc::new(Mutex::new(vec![]));
+            let guard = mutex.lock();
+            let start = mutex.tickets().next;
+            let threads: Vec<_> = (0..2)
+                .map(|caller| {
+                    let (mutex, order) = (Arc::clone(&mutex), Arc::clone(&order));
+                    let thread = thread::spawn(move || {
+                        let _guard = mutex.lock();
+                        order.lock().unwrap().push(caller);
+                    });
+                    wait_for_callers(start + caller + 1);
+                    thread
+                })
+                .collect();
+            thread::sleep(Duration::from_millis(1));
+            drop(guard);
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            if *order.lock().unwrap() == [0, 1] {
+                in_order += 1;
+            }
+        }
+        assert!(in_order * 100 >= rounds * 95, "{} of {} in order", in_order, rounds);
+
+        let stats = mutex.contention_stats();
+        assert_eq!(stats.total_waits, 2 * rounds);
+        assert!(stats.total_wait_ns > 0);
+    }
+
+    #[test]
+    fn merge_extra_env_into_cargo_env() {
+        let mut envs = BTreeMap::new();
+        envs.insert("CARGO_PKG_NAME".to_owned(), Some(OsString::from("foo")));
+        envs.insert("CARGO_PKG_VERSION".to_owned(), Some(OsString::from("0.1.0")));
+        envs.insert("OUT_DIR".to_owned(), None);
+
+        let extra_env: HashMap<_, _> = vec![
+            ("CUSTOM_VAR", "1"),
+            ("CARGO_PKG_NAME", "bar"),
+            ("RLS_FORCE_CARGO_PKG_VERSION", "0.2.0"),
+            ("RLS_FORCE_FORCED_VAR", "forced"),
+            ("FORCED_VAR", "unforced"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+        merge_extra_env(&mut envs, &extra_env);
+
+        let var = |key: &str| envs[key].as_ref().map(|v| v.to_str().unwrap().to_owned());
+        assert_eq!(var("CUSTOM_VAR"), Some("1".to_owned()));
+        assert_eq!(var("CARGO_PKG_NAME"), Some("foo".to_owned()));
+        assert_eq!(var("CARGO_PKG_VERSION"), Some("0.2.0".to_owned()));
+        assert_eq!(var("FORCED_VAR"), Some("forced".to_owned()));
+        assert_eq!(var("OUT_DIR"), None);
+        assert!(!envs.contains_key("RLS_FORCE_FORCED_VAR"));
+    }
+}
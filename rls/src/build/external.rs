@@ -18,15 +18,16 @@ use std::io::BufRead;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::Duration;
 
-use crate::build::plan::{BuildGraph, BuildKey, JobQueue, WorkStatus};
+use crate::build::plan::{BuildGraph, BuildKey, JobQueue, NinjaJob, WorkStatus};
 use crate::build::rustc::src_path;
 use crate::build::BuildResult;
 
 use cargo::util::{process, ProcessBuilder};
 use log::trace;
 use rls_data::{Analysis, CompilationOptions};
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
 fn cmd_line_to_command<S: AsRef<str>>(cmd_line: &S, cwd: &Path) -> Result<Command, ()> {
     let cmd_line = cmd_line.as_ref();
@@ -86,7 +87,15 @@ pub(super) fn build_with_external_cmd<S: AsRef<str>>(
     };
 
     let plan = plan_from_analysis(&analyses, &build_dir);
-    (BuildResult::Success(build_dir, vec![], analyses, HashMap::default(), false), plan)
+    let result = BuildResult::Success(
+        build_dir,
+        vec![],
+        analyses,
+        HashMap::default(),
+        false,
+        Duration::default(),
+    );
+    (result, plan)
 }
 
 /// Reads and deserializes given save-analysis JSON files into corresponding
@@ -157,13 +166,13 @@ fn plan_from_analysis(analysis: &[Analysis], build_dir: &Path) -> Result<Externa
     ExternalPlan::try_from_raw(build_dir, RawPlan { invocations })
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 /// Build plan as emitted by `cargo build --build-plan -Zunstable-options`.
 pub(crate) struct RawPlan {
     pub(crate) invocations: Vec<RawInvocation>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct RawInvocation {
     pub(crate) deps: Vec<usize>,
     pub(crate) outputs: Vec<PathBuf>,
@@ -258,6 +267,35 @@ impl ExternalPlan {
         self.rev_deps.entry(dep).or_insert_with(HashSet::new).insert(key);
     }
 
+    /// Returns the source files of the invocations, i.e., the crate roots.
+    pub(crate) fn input_files(&self) -> HashSet<PathBuf> {
+        self.units.values().filter_map(|unit| unit.src_path.clone()).collect()
+    }
+
+    pub(crate) fn compiler_commands(&self) -> Vec<&ProcessBuilder> {
+        self.units.values().map(|unit| &unit.command).collect()
+    }
+
+    /// Returns the invocations in dependency order, see
+    /// `BuildPlan::serialize_for_ninja`.
+    pub(crate) fn ninja_jobs(&self) -> Vec<NinjaJob<'_>> {
+        // Jobs that have to run first are *last* in the topological sorting.
+        let mut units = self.topological_sort(self.units());
+        units.reverse();
+        let indices: HashMap<_, _> =
+            units.iter().enumerate().map(|(idx, unit)| (unit.key(), idx)).collect();
+
+        units
+            .iter()
+            .map(|unit| {
+                let mut deps: Vec<_> =
+                    self.deps(unit.key()).iter().map(|dep| indices[&dep.key()]).collect();
+                deps.sort();
+                NinjaJob { command: &unit.command, deps }
+            })
+            .collect()
+    }
+
     pub(crate) fn try_from_raw(build_dir: &Path, raw: RawPlan) -> Result<ExternalPlan, ()> {
         // Sanity check: each dependency (index) has to be inside the build plan.
         if raw
@@ -524,6 +562,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn serialize_for_ninja() {
+        let plan = r#"{"invocations": [
+            { "deps": [],  "program": "rustc", "args": ["--crate-name", "build_script_build", "/my/repo/build.rs"], "env": {}, "outputs": [] },
+            { "deps": [0], "program": "rustc", "args": ["--crate-name", "repo", "--cfg", "feature=\"default\"", "/my/repo/src/lib.rs"], "env": {"CARGO_PKG_NAME": "repo"}, "outputs": [] }
+        ]}"#;
+        let build_dir = std::env::temp_dir();
+        let plan = serde_json::from_str::<RawPlan>(&plan).unwrap();
+        let plan = ExternalPlan::try_from_raw(&build_dir, plan).unwrap();
+        let plan = crate::build::plan::BuildPlan::External(plan);
+
+        assert_eq!(
+            plan.serialize_for_ninja(),
+            "rule rls_job\n  command = $cmd\n\
+             \n\
+             build job_0: rls_job\n  \
+             cmd = rustc --crate-name build_script_build /my/repo/build.rs\n\
+             \n\
+             build job_1: rls_job | job_0\n  \
+             cmd = env CARGO_PKG_NAME=repo rustc --crate-name repo --cfg 'feature=\"default\"' \
+             /my/repo/src/lib.rs\n"
+        );
+    }
+
     #[test]
     fn topological_sort() {
         let plan = r#"{"invocations": [
@@ -2,21 +2,31 @@
 
 use std::collections::{HashMap, HashSet};
 use std::io::{self, Write};
+use std::iter;
 use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use log::{debug, info, trace};
+use cargo::core::PackageId;
+use log::{debug, info, trace, warn};
 use rls_data::Analysis;
 use rls_vfs::Vfs;
+use serde::{Deserialize, Serialize};
 
-use self::environment::EnvironmentLock;
+pub use self::cargo::check_cargo_version;
+#[cfg(test)]
+pub(crate) use self::cargo::dependency_build_script_message;
+use self::environment::{Environment, EnvironmentLock};
 use self::plan::{BuildGraph, BuildPlan, WorkStatus};
 pub use self::plan::{Crate, Edition};
+pub(crate) use self::rustc::current_sysroot;
+pub use self::scheduler::SharedBuildScheduler;
+pub use self::status::{BuildQueueStatus, StatusReceiver};
+pub use self::thread_pool::ThreadPool;
 use crate::actions::post_build::PostBuildHandler;
 use crate::actions::progress::{ProgressNotifier, ProgressUpdate};
 use crate::config::Config;
@@ -30,6 +40,9 @@ mod external;
 mod ipc;
 mod plan;
 mod rustc;
+mod scheduler;
+mod status;
+mod thread_pool;
 
 /// Manages builds.
 ///
@@ -64,6 +77,12 @@ pub struct BuildQueue {
     // (low, high) priority builds.
     // This lock should only be held transiently.
     queued: Arc<Mutex<(Build, Build)>>,
+    // Pool to run the build thread on. If `None`, a new thread is spawned
+    // whenever the build thread is started.
+    pool: Option<Arc<ThreadPool>>,
+    // Scheduler shared with the build queues of other projects, see
+    // `with_scheduler`.
+    scheduler: Option<Arc<SharedBuildScheduler>>,
 }
 
 /// Used when tracking modified files across different builds.
@@ -82,10 +101,83 @@ struct Internals {
     // This lock should only be held transiently.
     config: Arc<Mutex<Config>>,
     building: AtomicBool,
+    /// Set while `BuildQueue::drain` waits, so that the build thread stops
+    /// after the current build.
+    draining: AtomicBool,
+    /// The `build_id` of the last build run, see `PostBuildHandler::build_id`.
+    last_build_id: AtomicU64,
     /// A list of threads blocked on the current build queue. They should be
     /// resumed when there are no builds to run.
     blocked: Mutex<Vec<thread::Thread>>,
+    /// Notified (with `blocked` locked) when there are no builds to run, see
+    /// `BuildQueue::wait_for_build`.
+    build_done: Condvar,
     last_build_duration: RwLock<Option<Duration>>,
+    /// Environment variable changes observed across the last Cargo invocation.
+    last_env_diff: Mutex<Vec<(String, environment::Change)>>,
+    /// Receives compilation metrics of the crates compiled by Cargo, if set.
+    metrics_sender: Mutex<Option<Sender<CompilationMetric>>>,
+    /// The hash of the rustc used by Cargo, computed once per session.
+    rustc_hash_cache: rustc::RustcHashCache,
+    stats: Mutex<BuildStats>,
+    /// Outcome of the last successful Cargo build, reused if Cargo would be
+    /// run again although nothing changed. See `reuse_last_cargo_build`.
+    last_cargo_build: Mutex<Option<LastCargoBuild>>,
+    /// See `BuildQueue::pre_warm_metadata`.
+    cached_metadata: Arc<Mutex<Option<cargo::CachedMetadata>>>,
+    /// See `BuildQueue::status_receiver`.
+    status: status::StatusSender,
+}
+
+/// See `Internals::last_cargo_build`.
+struct LastCargoBuild {
+    /// The `Debug` representation of the config the build was run with.
+    config: String,
+    build_dir: Option<PathBuf>,
+    cwd: PathBuf,
+    diagnostics: Vec<String>,
+    input_files: HashMap<PathBuf, HashSet<Crate>>,
+}
+
+/// Statistics of the builds requested from a `BuildQueue`. See
+/// `BuildQueue::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BuildStats {
+    /// Number of builds which were run.
+    pub builds: usize,
+    /// Number of build requests which were squashed by a more recent one.
+    pub squashed: usize,
+    /// Total duration of the builds which were run.
+    pub total_duration: Duration,
+    /// Build directory of the most recently run build.
+    pub last_build_dir: Option<PathBuf>,
+}
+
+impl BuildStats {
+    /// Returns the average duration of the builds run, if there were any.
+    pub fn average_duration(&self) -> Option<Duration> {
+        if self.builds == 0 {
+            None
+        } else {
+            Some(self.total_duration / self.builds as u32)
+        }
+    }
+
+    fn record_build(&mut self, build_dir: &Path, duration: Duration) {
+        self.builds += 1;
+        self.total_duration += duration;
+        self.last_build_dir = Some(build_dir.to_owned());
+    }
+}
+
+/// Compilation statistics of a single primary crate, as compiled during a
+/// Cargo build. See `BuildQueue::metrics_channel`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompilationMetric {
+    pub crate_name: String,
+    pub duration: Duration,
+    pub warnings: usize,
+    pub errors: usize,
 }
 
 /// The result of a build request.
@@ -94,10 +186,25 @@ pub enum BuildResult {
     /// Build was performed without any internal errors. The payload
     /// contains current directory at the time, emitted raw diagnostics,
     /// Analysis data and list of input files to the compilation.
-    /// Final bool is true if and only if compiler's exit code would be 0.
-    Success(PathBuf, Vec<String>, Vec<Analysis>, HashMap<PathBuf, HashSet<Crate>>, bool),
+    /// The bool is true if and only if compiler's exit code would be 0.
+    /// Finally, how long the build took, which is only measured for whole
+    /// builds (see `Internals::run_build`) and zero otherwise.
+    Success(PathBuf, Vec<String>, Vec<Analysis>, HashMap<PathBuf, HashSet<Crate>>, bool, Duration),
+    /// Workspace build where some of the primary packages failed to compile.
+    /// The payload is the same as for `Success`, except for the list of
+    /// packages which failed to build in place of the bool.
+    PartialSuccess(
+        PathBuf,
+        Vec<String>,
+        Vec<Analysis>,
+        HashMap<PathBuf, HashSet<Crate>>,
+        Vec<PackageId>,
+        Duration,
+    ),
     /// Build was coalesced with another build.
     Squashed,
+    /// Build was cancelled through its `CancellationToken` after it started.
+    Cancelled,
     /// There was an error attempting to build.
     /// 0: error cause
     /// 1: command which caused the error
@@ -111,6 +218,18 @@ pub enum BuildResult {
     },
 }
 
+impl BuildResult {
+    /// Records how long a (partially) successful build took.
+    fn set_duration(&mut self, elapsed: Duration) {
+        match self {
+            BuildResult::Success(.., duration) | BuildResult::PartialSuccess(.., duration) => {
+                *duration = elapsed
+            }
+            _ => {}
+        }
+    }
+}
+
 /// Priority for a build request.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum BuildPriority {
@@ -121,6 +240,10 @@ pub enum BuildPriority {
     Cargo,
     /// A regular build request (e.g., on a minor edit).
     Normal,
+    /// A background build (e.g., when the editor window loses focus), which
+    /// only runs if no other build is queued or running and is squashed by any
+    /// other build.
+    Idle,
 }
 
 impl BuildPriority {
@@ -133,16 +256,28 @@ impl BuildPriority {
 }
 
 /// Information passed to Cargo/rustc to build.
-#[derive(Debug)]
+///
+/// Saved to disk after Cargo builds, see `Internals::save_compilation_context`.
+#[derive(Debug, Serialize, Deserialize)]
 struct CompilationContext {
     cwd: Option<PathBuf>,
     /// The build directory is supplied by the client and passed to Cargo.
     build_dir: Option<PathBuf>,
     /// `true` if we need to perform a Cargo rebuild.
+    #[serde(skip)]
     needs_rebuild: bool,
     /// Build plan, which should know all the inter-package/target dependencies
     /// along with args/envs.
+    #[serde(with = "plan::raw_plan")]
     build_plan: BuildPlan,
+    /// `true` if the last build was retried because of E0514, see
+    /// `Internals::retry_incompatible_rustc`.
+    #[serde(skip)]
+    e0514_retried: bool,
+    /// The `cargo_profile` last reported as undefined, so it's only reported
+    /// again once the configuration or the manifest changes.
+    #[serde(skip)]
+    undefined_profile: Option<String>,
 }
 
 impl CompilationContext {
@@ -152,6 +287,8 @@ impl CompilationContext {
             build_dir: None,
             needs_rebuild: true,
             build_plan: BuildPlan::new(),
+            e0514_retried: false,
+            undefined_profile: None,
         }
     }
 }
@@ -184,6 +321,28 @@ struct PendingBuild {
     built_files: HashMap<PathBuf, FileVersion>,
     notifier: Box<dyn ProgressNotifier>,
     pbh: PostBuildHandler,
+    cancel: CancellationToken,
+}
+
+/// Interrupts a requested build, see `BuildQueue::request_build`. The build
+/// checks the token between its steps (e.g., before running Cargo or each
+/// rustc invocation), so it may take a while for the build to stop.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancels the build. Does nothing if the build has finished already.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
 }
 
 impl Build {
@@ -204,6 +363,13 @@ impl Build {
         }
     }
 
+    fn priority(&self) -> Option<BuildPriority> {
+        match *self {
+            Build::Pending(ref build) => Some(build.priority),
+            _ => None,
+        }
+    }
+
     fn try_into_pending(self) -> Result<PendingBuild, ()> {
         match self {
             Build::Pending(b) => Ok(*b),
@@ -218,9 +384,32 @@ impl BuildQueue {
         BuildQueue {
             internals: Arc::new(Internals::new(vfs, config)),
             queued: Arc::new(Mutex::new((Build::None, Build::None))),
+            pool: None,
+            scheduler: None,
         }
     }
 
+    /// Constructs a new build queue, whose build thread is run on the given
+    /// thread pool rather than on a freshly spawned thread.
+    pub fn with_thread_pool(
+        vfs: Arc<Vfs>,
+        config: Arc<Mutex<Config>>,
+        pool: Arc<ThreadPool>,
+    ) -> BuildQueue {
+        BuildQueue { pool: Some(pool), ..BuildQueue::new(vfs, config) }
+    }
+
+    /// Constructs a new build queue, whose builds are run by the given
+    /// scheduler. Build queues sharing a scheduler, e.g. the ones of the
+    /// projects in a multi-root workspace, don't run builds concurrently.
+    pub fn with_scheduler(
+        vfs: Arc<Vfs>,
+        config: Arc<Mutex<Config>>,
+        scheduler: Arc<SharedBuildScheduler>,
+    ) -> BuildQueue {
+        BuildQueue { scheduler: Some(scheduler), ..BuildQueue::new(vfs, config) }
+    }
+
     /// Requests a build (see comments on `BuildQueue` for what that means).
     ///
     /// Now for the complicated bits. Not all builds are equal - they might have
@@ -244,7 +433,8 @@ impl BuildQueue {
     /// ## Implementation
     ///
     /// This layer of the build queue is single-threaded and we aim to return
-    /// quickly. A single build thread is spawned to do any building (we never
+    /// quickly. A single build thread is spawned (or submitted to the thread
+    /// pool, see `with_thread_pool`) to do any building (we never
     /// do parallel builds so that we don't hog the CPU, we might want to change
     /// that in the future).
     ///
@@ -252,13 +442,16 @@ impl BuildQueue {
     /// (we might want to do a high priority build, then a low priority one). So
     /// our build queue is just a single slot (for each priority). We record if
     /// a build is waiting and if not, if a build is running.
+    ///
+    /// The returned token cancels the build, in which case `pbh` handles
+    /// `BuildResult::Cancelled`.
     pub fn request_build(
         &self,
         new_build_dir: &Path,
         mut priority: BuildPriority,
         notifier: Box<dyn ProgressNotifier>,
         pbh: PostBuildHandler,
-    ) {
+    ) -> CancellationToken {
         trace!("request_build {:?}", priority);
         if self.internals.compilation_cx.lock().unwrap().needs_rebuild {
             priority = BuildPriority::Cargo;
@@ -269,23 +462,42 @@ impl BuildQueue {
             priority,
             notifier,
             pbh,
+            cancel: CancellationToken::new(),
         };
+        let cancel = build.cancel.clone();
 
         let mut queued = self.queued.lock().unwrap();
-        Self::push_build(&mut queued, build);
+        Self::push_build(&self.internals, &mut queued, build);
 
         // Need to spawn while holding the lock on queued so that we don't race.
         if !self.internals.building.swap(true, Ordering::SeqCst) {
-            thread::spawn({
+            let build_thread = {
                 let queued = Arc::clone(&self.queued);
                 let internals = Arc::clone(&self.internals);
+                let scheduler = self.scheduler.clone();
                 move || {
-                    BuildQueue::run_thread(queued, &internals);
+                    BuildQueue::run_thread(queued, &internals, scheduler.as_ref().map(|s| &**s));
                     let building = internals.building.swap(false, Ordering::SeqCst);
                     assert!(building);
+                    internals.notify_build_done();
                 }
-            });
+            };
+            match self.pool {
+                Some(ref pool) => pool.submit(Box::new(build_thread)),
+                None => {
+                    thread::spawn(build_thread);
+                }
+            }
         }
+        cancel
+    }
+
+    /// Fetches `cargo metadata` of the workspace containing `project_path` in
+    /// the background, so that the next Cargo build, e.g. the first one after
+    /// opening the project, doesn't have to wait for it.
+    pub fn pre_warm_metadata(&self, project_path: &Path) {
+        *self.internals.cached_metadata.lock().unwrap() =
+            cargo::CachedMetadata::fetch(project_path);
     }
 
     /// Blocks until any currently queued builds are complete.
@@ -309,38 +521,132 @@ impl BuildQueue {
         }
     }
 
+    /// Blocks until there are no builds to run or `timeout` elapses. Unlike
+    /// `block_on_build`, this can be called from any thread. Returns `true` if
+    /// no build is running anymore.
+    pub fn wait_for_build(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut blocked = self.internals.blocked.lock().unwrap();
+        while self.internals.building.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            blocked = self.internals.build_done.wait_timeout(blocked, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    /// Squashes any pending builds and blocks until the build in progress, if
+    /// any, is complete, e.g., when shutting down.
+    pub fn drain(&self) {
+        self.internals.draining.store(true, Ordering::SeqCst);
+        {
+            let mut queued = self.queued.lock().unwrap();
+            // Leave the in-progress marker to the build thread.
+            if queued.1.is_pending() {
+                Self::squash_build(&self.internals, &mut queued.1);
+            }
+            if queued.0.is_pending() {
+                Self::squash_build(&self.internals, &mut queued.0);
+            }
+            Self::update_status(&self.internals, &queued);
+        }
+
+        let mut blocked = self.internals.blocked.lock().unwrap();
+        while self.internals.building.load(Ordering::SeqCst) {
+            blocked = self.internals.build_done.wait(blocked).unwrap();
+        }
+        self.internals.draining.store(false, Ordering::SeqCst);
+    }
+
+    /// Marks a build as running or finished, without running one.
+    #[cfg(test)]
+    pub(crate) fn set_building(&self, building: bool) {
+        self.internals.building.store(building, Ordering::SeqCst);
+        if !building {
+            self.internals.notify_build_done();
+        }
+    }
+
+    /// Hands a pre-computed build result straight to `and_then` on the calling
+    /// thread, bypassing the build thread (and Cargo/rustc) entirely.
+    ///
+    /// This is intended for exercising post-build handling, e.g., in tests.
+    pub fn replay_build<F>(&self, result: BuildResult, and_then: F)
+    where
+        F: FnOnce(BuildResult) + Send + 'static,
+    {
+        trace!("replay_build");
+        and_then(result);
+    }
+
     /// Essentially this is the opposite of 'would block' (see `block_on_build`). If this is
     /// true, then it is safe to rely on data from the build.
     pub fn build_ready(&self) -> bool {
         !self.internals.building.load(Ordering::SeqCst)
     }
 
+    /// Returns a receiver of the status of the queue, i.e., whether a build is
+    /// pending or running.
+    pub fn status_receiver(&self) -> StatusReceiver {
+        self.internals.status.subscribe()
+    }
+
     // Takes the unlocked build queue and pushes an incoming build onto it.
-    fn push_build(queued: &mut (Build, Build), build: PendingBuild) {
-        if build.priority == BuildPriority::Normal {
-            Self::squash_build(&mut queued.0);
+    fn push_build(internals: &Internals, queued: &mut (Build, Build), build: PendingBuild) {
+        if build.priority == BuildPriority::Idle {
+            match *queued {
+                (Build::None, Build::None) => queued.0 = Build::Pending(build.into()),
+                _ => {
+                    internals.stats.lock().unwrap().squashed += 1;
+                    build.pbh.handle(BuildResult::Squashed);
+                    return;
+                }
+            }
+        } else if build.priority == BuildPriority::Normal {
+            Self::squash_build(internals, &mut queued.0);
             queued.0 = Build::Pending(build.into());
         } else {
-            Self::squash_build(&mut queued.0);
-            Self::squash_build(&mut queued.1);
+            Self::squash_build(internals, &mut queued.0);
+            Self::squash_build(internals, &mut queued.1);
             queued.1 = Build::Pending(build.into());
         }
+        if let BuildQueueStatus::Building { .. } = internals.status.get() {
+            return;
+        }
+        Self::update_status(internals, queued);
+    }
+
+    // Sets the status of the queue to the higher priority pending build, if
+    // any, or to idle otherwise.
+    fn update_status(internals: &Internals, queued: &(Build, Build)) {
+        let status = match queued.1.priority().or_else(|| queued.0.priority()) {
+            Some(priority) => BuildQueueStatus::Pending { priority },
+            None => BuildQueueStatus::Idle,
+        };
+        internals.status.send(status);
     }
 
     // Takes a reference to a build in the queue in preparation for pushing a
     // new build into the queue. The build is removed (if it exists) and its
     // closure is notified that the build is squashed.
-    fn squash_build(build: &mut Build) {
+    fn squash_build(internals: &Internals, build: &mut Build) {
         let mut old_build = Build::None;
         mem::swap(build, &mut old_build);
         if let Build::Pending(build) = old_build {
+            internals.stats.lock().unwrap().squashed += 1;
             build.pbh.handle(BuildResult::Squashed);
         }
     }
 
     // Run the build thread. This thread will keep going until the build queue is
     // empty, then terminate.
-    fn run_thread(queued: Arc<Mutex<(Build, Build)>>, internals: &Internals) {
+    fn run_thread(
+        queued: Arc<Mutex<(Build, Build)>>,
+        internals: &Internals,
+        scheduler: Option<&SharedBuildScheduler>,
+    ) {
         loop {
             // Find the next build to run, or terminate if there are no builds.
             let build = {
@@ -358,9 +664,13 @@ impl BuildQueue {
                 }
             };
 
-            // Normal priority threads sleep before starting up.
-            if build.priority == BuildPriority::Normal {
-                let build_wait = internals.build_wait();
+            // Normal and idle priority threads sleep before starting up.
+            let build_wait = match build.priority {
+                BuildPriority::Normal => Some(internals.build_wait()),
+                BuildPriority::Idle => Some(internals.idle_build_wait()),
+                _ => None,
+            };
+            if let Some(build_wait) = build_wait {
                 debug!("sleeping {:.1?}", build_wait);
                 thread::sleep(build_wait);
                 trace!("waking");
@@ -368,55 +678,25 @@ impl BuildQueue {
                 // Check if a new build arrived while we were sleeping.
                 let interrupt = {
                     let queued = queued.lock().unwrap();
-                    queued.0.is_pending() || queued.1.is_pending()
+                    queued.0.is_pending()
+                        || queued.1.is_pending()
+                        || internals.draining.load(Ordering::SeqCst)
                 };
                 if interrupt {
+                    internals.stats.lock().unwrap().squashed += 1;
                     build.pbh.handle(BuildResult::Squashed);
                     continue;
                 }
             }
 
-            // Channel to get progress updates out for the async build.
-            let (progress_sender, progress_receiver) = channel::<ProgressUpdate>();
-
-            // Notifier of window/progress.
-            let notifier = build.notifier;
-
-            // Use this thread to propagate the progress messages until the sender is dropped.
-            let progress_thread = thread::Builder::new()
-                .name("progress-notifier".into())
-                .spawn(move || {
-                    // Window/progress notification that we are about to build.
-                    notifier.notify_begin_progress();
-                    while let Ok(progress) = progress_receiver.recv() {
-                        notifier.notify_progress(progress);
-                    }
-                    notifier.notify_end_progress();
-                })
-                .expect("Failed to start progress-notifier thread");
-
-            // Run the build.
-            let result = internals.run_build(
-                &build.build_dir,
-                build.priority,
-                &build.built_files,
-                progress_sender,
-            );
-            // Assert that the build was not squashed.
-            if let BuildResult::Squashed = result {
-                unreachable!();
-            }
-
-            let mut pbh = build.pbh;
-            {
-                let mut blocked = internals.blocked.lock().unwrap();
-                pbh.blocked_threads.extend(blocked.drain(..));
+            let (priority, build_dir) = (build.priority, build.build_dir.clone());
+            match scheduler {
+                Some(scheduler) => {
+                    scheduler.run(priority, &build_dir, || Self::run_one(build, internals))
+                }
+                None => Self::run_one(build, internals),
             }
 
-            // wait for progress to complete before starting analysis
-            progress_thread.join().expect("progress-notifier panicked!");
-            pbh.handle(result);
-
             // Remove the in-progress marker from the build queue.
             let mut queued = queued.lock().unwrap();
             if let Build::InProgress = queued.1 {
@@ -424,7 +704,119 @@ impl BuildQueue {
             } else if let Build::InProgress = queued.0 {
                 queued.0 = Build::None;
             }
+            Self::update_status(internals, &queued);
+            if internals.draining.load(Ordering::SeqCst) {
+                return;
+            }
+        }
+    }
+
+    // Runs a build taken off the queue and handles its result.
+    fn run_one(build: PendingBuild, internals: &Internals) {
+        internals.status.send(BuildQueueStatus::Building { priority: build.priority });
+
+        // Channel to get progress updates out for the async build.
+        let (progress_sender, progress_receiver) = channel::<ProgressUpdate>();
+
+        // Notifier of window/progress.
+        let notifier = build.notifier;
+
+        // Use this thread to propagate the progress messages until the sender is dropped.
+        let progress_thread = thread::Builder::new()
+            .name("progress-notifier".into())
+            .spawn(move || {
+                // Window/progress notification that we are about to build.
+                notifier.notify_begin_progress();
+                while let Ok(progress) = progress_receiver.recv() {
+                    notifier.notify_progress(progress);
+                }
+                notifier.notify_end_progress();
+            })
+            .expect("Failed to start progress-notifier thread");
+
+        // Run the build.
+        let result = internals.run_build(
+            &build.build_dir,
+            build.priority,
+            &build.built_files,
+            progress_sender,
+            &build.cancel,
+        );
+        // Assert that the build was not squashed.
+        if let BuildResult::Squashed = result {
+            unreachable!();
+        }
+
+        let mut pbh = build.pbh;
+        pbh.build_id = internals.last_build_id.fetch_add(1, Ordering::SeqCst) + 1;
+        pbh.priority = build.priority;
+        {
+            let mut blocked = internals.blocked.lock().unwrap();
+            pbh.blocked_threads.extend(blocked.drain(..));
+        }
+
+        // wait for progress to complete before starting analysis
+        progress_thread.join().expect("progress-notifier panicked!");
+        pbh.handle(result);
+    }
+
+    /// Returns the environment variable changes that were observed across the
+    /// most recent Cargo invocation. Useful when debugging env var leaks.
+    pub fn last_env_diff(&self) -> Vec<(String, environment::Change)> {
+        self.internals.last_env_diff.lock().unwrap().clone()
+    }
+
+    /// Returns a channel receiving a `CompilationMetric` for every primary crate
+    /// compiled in subsequent Cargo builds. Only the most recently created
+    /// channel receives the metrics.
+    pub fn metrics_channel(&self) -> Receiver<CompilationMetric> {
+        let (sender, receiver) = channel();
+        *self.internals.metrics_sender.lock().unwrap() = Some(sender);
+        receiver
+    }
+
+    /// Returns statistics of the builds requested so far.
+    pub fn stats(&self) -> BuildStats {
+        self.internals.stats.lock().unwrap().clone()
+    }
+
+    /// Returns the build statistics (see `stats`) and the current state of the
+    /// build queue as a human-readable, multi-line report, e.g. for logging.
+    pub fn statistics_report(&self) -> String {
+        fn describe(build: &Build) -> &'static str {
+            match *build {
+                Build::InProgress => "in progress",
+                Build::Pending(_) => "pending",
+                Build::None => "none",
+            }
         }
+
+        let stats = self.stats();
+        let (normal, high) = {
+            let queued = self.queued.lock().unwrap();
+            (describe(&queued.0), describe(&queued.1))
+        };
+
+        let mut report = String::from("Build statistics:\n");
+        report.push_str(&format!("  builds run: {}\n", stats.builds));
+        report.push_str(&format!("  builds squashed: {}\n", stats.squashed));
+        match stats.average_duration() {
+            Some(average) => report.push_str(&format!("  average duration: {:.1?}\n", average)),
+            None => report.push_str("  average duration: n/a\n"),
+        }
+        report.push_str(&format!("  queue: normal priority {}, high priority {}\n", normal, high));
+        match stats.last_build_dir {
+            Some(dir) => report.push_str(&format!("  last build directory: {}\n", dir.display())),
+            None => report.push_str("  last build directory: n/a\n"),
+        }
+        report
+    }
+
+    /// Makes the next Cargo build run Cargo, even if nothing seems to have
+    /// changed since the last one. Used when the user or the client forces a
+    /// rebuild, see `Internals::reuse_last_cargo_build`.
+    pub fn forget_last_cargo_build(&self) {
+        *self.internals.last_cargo_build.lock().unwrap() = None;
     }
 
     /// Marks a given versioned file as dirty since last build. The dirty flag
@@ -434,6 +826,17 @@ impl BuildQueue {
         trace!("Marking file as dirty: {:?} ({})", file, version);
         self.internals.dirty_files.lock().unwrap().insert(file, version);
     }
+
+    /// Returns the files marked as dirty since last build (with their versions)
+    /// and clears the dirty set, as one atomic operation.
+    pub fn drain_dirty_files(&self) -> HashMap<PathBuf, FileVersion> {
+        mem::replace(&mut *self.internals.dirty_files.lock().unwrap(), HashMap::new())
+    }
+
+    /// Returns the number of files marked as dirty since last build.
+    pub fn dirty_file_count(&self) -> usize {
+        self.internals.dirty_files.lock().unwrap().len()
+    }
 }
 
 impl Internals {
@@ -447,11 +850,28 @@ impl Internals {
             // instances, be sure to use a global lock to ensure env var consistency
             env_lock: EnvironmentLock::get(),
             building: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+            last_build_id: AtomicU64::new(0),
             blocked: Mutex::new(vec![]),
+            build_done: Condvar::new(),
             last_build_duration: RwLock::default(),
+            last_env_diff: Mutex::default(),
+            metrics_sender: Mutex::default(),
+            rustc_hash_cache: rustc::RustcHashCache::default(),
+            stats: Mutex::default(),
+            last_cargo_build: Mutex::default(),
+            cached_metadata: Arc::default(),
+            status: status::status_channel().0,
         }
     }
 
+    fn notify_build_done(&self) {
+        // Lock, so that waiters can't miss the notification between checking
+        // `building` and waiting.
+        let _blocked = self.blocked.lock().unwrap();
+        self.build_done.notify_all();
+    }
+
     // Entry point method for building.
     fn run_build(
         &self,
@@ -459,26 +879,80 @@ impl Internals {
         priority: BuildPriority,
         built_files: &HashMap<PathBuf, FileVersion>,
         progress_sender: Sender<ProgressUpdate>,
+        cancel: &CancellationToken,
     ) -> BuildResult {
         trace!("run_build, {:?} {:?}", new_build_dir, priority);
+        let start = Instant::now();
+
+        if self.config.lock().unwrap().require_clean_vfs {
+            let stale_files = self.stale_files();
+            if !stale_files.is_empty() {
+                let names: Vec<_> =
+                    stale_files.iter().map(|file| file.display().to_string()).collect();
+                return BuildResult::Err(
+                    format!(
+                        "Files were modified on disk without the RLS being notified: {}",
+                        names.join(", ")
+                    ),
+                    None,
+                );
+            }
+        }
 
         // Check if the build directory changed and update it.
-        {
+        let new_project = {
             let mut compilation_cx = self.compilation_cx.lock().unwrap();
-            if compilation_cx.build_dir.as_ref().map_or(true, |dir| dir != new_build_dir) {
+            let new_project =
+                compilation_cx.build_dir.as_ref().map_or(true, |dir| dir != new_build_dir);
+            if new_project {
                 // We'll need to re-run cargo in this case.
                 assert!(priority.is_cargo());
                 (*compilation_cx).build_dir = Some(new_build_dir.to_owned());
             }
 
             compilation_cx.needs_rebuild = priority.is_cargo();
+            new_project
+        };
+
+        if priority.is_cargo() {
+            // Cargo can be skipped for a new project, e.g., on startup, if it
+            // was built before and the dependencies didn't change since.
+            let saved_cx = if new_project { self.load_compilation_context() } else { None };
+            match saved_cx {
+                Some(cx) => {
+                    info!("reusing the compilation context saved by a previous session");
+                    // Rebuild every crate of the plan to get its analysis data.
+                    let input_files = cx.build_plan.input_files();
+                    *self.compilation_cx.lock().unwrap() = cx;
+                    let mut dirty_files = self.dirty_files.lock().unwrap();
+                    for file in input_files {
+                        dirty_files.entry(file).or_insert(0);
+                    }
+                }
+                None => self.invalidate_compilation_context(),
+            }
+
+            let target_dir = self.config.lock().unwrap().target_dir.as_ref().clone();
+            let rustc_hash = self.rustc_hash_cache.rustc_hash();
+            if let (Some(target_dir), Some(hash)) = (target_dir, rustc_hash) {
+                if let Err(e) = rustc::rustc_incremental_cleanup(&target_dir, &hash) {
+                    warn!("failed to remove stale incremental data: {}", e);
+                }
+            }
         }
 
-        let result = self.build(progress_sender);
+        let build_start = Instant::now();
+        let mut result = self.build(built_files, progress_sender.clone(), cancel);
+        if self.retry_incompatible_rustc(&result) {
+            info!("dependencies were built by an incompatible rustc, rebuilding with Cargo");
+            result = self.build(built_files, progress_sender, cancel);
+        }
+        result.set_duration(build_start.elapsed());
+        self.stats.lock().unwrap().record_build(new_build_dir, start.elapsed());
         // On a successful build, clear dirty files that were successfully built
         // now. It's possible that a build was scheduled with given files, but
         // user later changed them. These should still be left as dirty (not built).
-        if let BuildResult::Success(..) = result {
+        if let BuildResult::Success(..) | BuildResult::PartialSuccess(..) = result {
             let mut dirty_files = self.dirty_files.lock().unwrap();
             dirty_files.retain(|file, dirty_version| {
                 built_files
@@ -491,10 +965,60 @@ impl Internals {
         result
     }
 
+    /// Prepares a Cargo rebuild if `result` reports that dependencies were
+    /// built by an incompatible version of rustc (E0514), e.g., after a
+    /// toolchain update. Returns `true` if the build should be retried, which
+    /// is only done once in a row, in case rebuilding doesn't help.
+    fn retry_incompatible_rustc(&self, result: &BuildResult) -> bool {
+        let mut cx = self.compilation_cx.lock().unwrap();
+        if !has_incompatible_rustc_error(result) {
+            cx.e0514_retried = false;
+            return false;
+        }
+        if cx.e0514_retried {
+            return false;
+        }
+        cx.e0514_retried = true;
+        cx.needs_rebuild = true;
+        cx.build_plan = BuildPlan::new();
+        *self.last_cargo_build.lock().unwrap() = None;
+        true
+    }
+
+    /// Returns the files of the build plan which the VFS considers to be in
+    /// sync with the disk, but whose content on disk differs, e.g. because
+    /// they were modified outside of the editor.
+    fn stale_files(&self) -> Vec<PathBuf> {
+        let input_files = self.compilation_cx.lock().unwrap().build_plan.input_files();
+        let mut stale_files: Vec<_> = input_files
+            .into_iter()
+            .filter(|file| {
+                // Files with unsaved changes are expected to differ.
+                if self.vfs.file_is_synced(file).ok() != Some(true) {
+                    return false;
+                }
+                match (self.vfs.file_hash(file), std::fs::read(file)) {
+                    (Some(hash), Ok(content)) => hash != rls_vfs::content_hash(&content),
+                    _ => false,
+                }
+            })
+            .collect();
+        stale_files.sort();
+        stale_files
+    }
+
     // Build the project.
-    fn build(&self, progress_sender: Sender<ProgressUpdate>) -> BuildResult {
+    fn build(
+        &self,
+        built_files: &HashMap<PathBuf, FileVersion>,
+        progress_sender: Sender<ProgressUpdate>,
+        cancel: &CancellationToken,
+    ) -> BuildResult {
         trace!("running build");
         let start = Instant::now();
+        if cancel.is_cancelled() {
+            return BuildResult::Cancelled;
+        }
         // When we change build directory (presumably because the IDE is
         // changing project), we must do a cargo build of the whole project.
         // Otherwise we just use rustc directly.
@@ -541,6 +1065,8 @@ impl Internals {
             } else {
                 // Cargo plan is recreated and `needs_rebuild` reset if we run `cargo::cargo()`.
                 match cx.build_plan {
+                    // Loaded by `load_compilation_context`.
+                    BuildPlan::External(ref plan) if !needs_rebuild => plan.prepare_work(&modified),
                     BuildPlan::External(_) => WorkStatus::NeedsCargo(PackageArg::Default),
                     BuildPlan::Cargo(ref plan) => {
                         match plan.prepare_work(&modified) {
@@ -557,11 +1083,41 @@ impl Internals {
         trace!("specified work: {:#?}", work);
 
         let result = match work {
-            WorkStatus::NeedsCargo(package_arg) => cargo::cargo(self, package_arg, progress_sender),
-            WorkStatus::Execute(job_queue) => job_queue.execute(self, progress_sender),
+            WorkStatus::NeedsCargo(package_arg) => {
+                if let Some(result) = self.reuse_last_cargo_build(built_files) {
+                    debug!("nothing changed since the last Cargo build, reusing its results");
+                    return result;
+                }
+                if cancel.is_cancelled() {
+                    return BuildResult::Cancelled;
+                }
+                let env_before = environment::snapshot();
+                let result = cargo::cargo(self, package_arg, progress_sender, cancel);
+                *self.last_env_diff.lock().unwrap() =
+                    Environment::diff(&env_before, &environment::snapshot());
+                *self.last_cargo_build.lock().unwrap() = match result {
+                    BuildResult::Success(ref cwd, ref diagnostics, _, ref input_files, true, _) => {
+                        Some(LastCargoBuild {
+                            config: format!("{:?}", *self.config.lock().unwrap()),
+                            build_dir: self.compilation_cx.lock().unwrap().build_dir.clone(),
+                            cwd: cwd.clone(),
+                            diagnostics: diagnostics.clone(),
+                            input_files: input_files.clone(),
+                        })
+                    }
+                    _ => None,
+                };
+                if let BuildResult::Success(.., true, _) = result {
+                    if let Err(e) = self.save_compilation_context() {
+                        warn!("failed to save the compilation context: {}", e);
+                    }
+                }
+                result
+            }
+            WorkStatus::Execute(job_queue) => job_queue.execute(self, progress_sender, cancel),
         };
 
-        if let BuildResult::Success(.., true) = result {
+        if let BuildResult::Success(.., true, _) = result {
             let elapsed = start.elapsed();
             *self.last_build_duration.write().unwrap() = Some(elapsed);
             info!("build finished in {:.1?}", elapsed);
@@ -570,6 +1126,134 @@ impl Internals {
         result
     }
 
+    /// Returns the results of the last Cargo build again if it was run with
+    /// the same config and neither the `built_files`, the input files of the
+    /// last build nor the manifests and build scripts of their packages were
+    /// modified since (see `cargo::depinfo_is_current`). The analysis data is
+    /// left out, since the `AnalysisHost` has it loaded already.
+    fn reuse_last_cargo_build(
+        &self,
+        built_files: &HashMap<PathBuf, FileVersion>,
+    ) -> Option<BuildResult> {
+        let last = self.last_cargo_build.lock().unwrap();
+        let last = last.as_ref()?;
+        if last.config != format!("{:?}", *self.config.lock().unwrap()) {
+            return None;
+        }
+        // Files with unsaved changes are built from the VFS, whatever their
+        // modification time on disk is.
+        if built_files.keys().any(|file| self.vfs.file_is_synced(file).ok() == Some(false)) {
+            return None;
+        }
+
+        let cx = self.compilation_cx.lock().unwrap();
+        let build_dir = cx.build_dir.as_ref()?;
+        if last.build_dir.as_ref() != Some(build_dir) {
+            return None;
+        }
+        let mut files = built_files.clone();
+        files.extend(last.input_files.keys().map(|file| (file.clone(), 0)));
+        let package_dirs: HashSet<_> = last
+            .input_files
+            .values()
+            .flatten()
+            .filter_map(|krate| krate.src_path.as_ref())
+            .filter_map(|src_path| {
+                src_path.ancestors().skip(1).find(|dir| dir.join("Cargo.toml").exists())
+            })
+            .map(Path::to_path_buf)
+            .chain(iter::once(build_dir.clone()))
+            .collect();
+        for dir in package_dirs {
+            for name in &["Cargo.toml", "Cargo.lock", "build.rs"] {
+                let path = dir.join(name);
+                if path.exists() {
+                    files.insert(path, 0);
+                }
+            }
+        }
+        if !cargo::depinfo_is_current(&cx.build_plan, &files) {
+            return None;
+        }
+
+        Some(BuildResult::Success(
+            last.cwd.clone(),
+            last.diagnostics.clone(),
+            vec![],
+            last.input_files.clone(),
+            true,
+            Duration::default(),
+        ))
+    }
+
+    /// Returns the path of the file the compilation context is saved to, i.e.,
+    /// `compilation_context.json` in the RLS target directory.
+    fn compilation_context_path(&self) -> Option<PathBuf> {
+        let target_dir = self.config.lock().unwrap().target_dir.as_ref().clone();
+        let target_dir = match target_dir {
+            Some(target_dir) => target_dir,
+            None => self.compilation_cx.lock().unwrap().build_dir.as_ref()?.join("target/rls"),
+        };
+        Some(target_dir.join("compilation_context.json"))
+    }
+
+    /// Saves the compilation context, so that a later session can run rustc
+    /// without running Cargo first, see `load_compilation_context`.
+    fn save_compilation_context(&self) -> io::Result<()> {
+        let path = self
+            .compilation_context_path()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no build directory"))?;
+        let json = serde_json::to_string(&*self.compilation_cx.lock().unwrap())?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, json)
+    }
+
+    /// Loads the compilation context saved for the current build directory,
+    /// unless `Cargo.lock` was modified after it was saved.
+    fn load_compilation_context(&self) -> Option<CompilationContext> {
+        let path = self.compilation_context_path()?;
+        let build_dir = self.compilation_cx.lock().unwrap().build_dir.clone()?;
+        let saved = std::fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        if let Ok(lock_modified) =
+            std::fs::metadata(build_dir.join("Cargo.lock")).and_then(|m| m.modified())
+        {
+            if lock_modified >= saved {
+                return None;
+            }
+        }
+
+        let json = std::fs::read_to_string(&path).ok()?;
+        let cx: CompilationContext = match serde_json::from_str(&json) {
+            Ok(cx) => cx,
+            Err(e) => {
+                warn!("failed to load the compilation context: {}", e);
+                return None;
+            }
+        };
+        if cx.build_dir.as_ref() != Some(&build_dir) {
+            return None;
+        }
+        Some(cx)
+    }
+
+    /// Removes the saved compilation context, e.g., before running Cargo.
+    fn invalidate_compilation_context(&self) {
+        if let Some(path) = self.compilation_context_path() {
+            if let Err(e) = std::fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("failed to remove the compilation context: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Returns the wait time before starting an idle priority build.
+    fn idle_build_wait(&self) -> Duration {
+        Duration::from_millis(self.config.lock().unwrap().idle_wait_to_build)
+    }
+
     /// Returns a pre-build wait time facilitating build debouncing.
     ///
     /// Uses client configured value, or attempts to infer an appropriate duration.
@@ -603,6 +1287,22 @@ impl Write for BufWriter {
     }
 }
 
+/// Returns `true` if `result` contains an E0514 error, i.e., a crate was
+/// compiled by an incompatible version of rustc.
+fn has_incompatible_rustc_error(result: &BuildResult) -> bool {
+    let diagnostics = match result {
+        BuildResult::Success(_, diagnostics, ..)
+        | BuildResult::PartialSuccess(_, diagnostics, ..) => diagnostics,
+        BuildResult::Err(cause, _) => return cause.contains("E0514"),
+        _ => return false,
+    };
+    diagnostics.iter().any(|diagnostic| {
+        serde_json::from_str::<serde_json::Value>(diagnostic)
+            .map(|diagnostic| diagnostic["code"]["code"] == "E0514")
+            .unwrap_or(false)
+    })
+}
+
 #[test]
 fn auto_tune_build_wait_no_config() {
     let i = Internals::new(Arc::new(Vfs::new()), Arc::default());
@@ -638,3 +1338,274 @@ fn dont_auto_tune_build_wait_configured() {
     *i.last_build_duration.write().unwrap() = Some(Duration::from_millis(70));
     assert_eq!(i.build_wait(), Duration::from_millis(350));
 }
+
+#[test]
+fn require_clean_vfs_rejects_stale_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib = dir.path().join("lib.rs");
+    std::fs::write(&lib, "fn foo() {}\n").unwrap();
+
+    let vfs = Arc::new(Vfs::new());
+    let config = Arc::new(Mutex::new(Config { require_clean_vfs: true, ..Config::default() }));
+    let internals = Internals::new(Arc::clone(&vfs), config);
+    {
+        let raw_plan = serde_json::json!({"invocations": [{
+            "deps": [], "outputs": [], "program": "rustc", "env": {},
+            "args": ["--crate-name", "foo", lib.to_str().unwrap()],
+        }]});
+        let raw_plan = serde_json::from_value(raw_plan).unwrap();
+        let plan = external::ExternalPlan::try_from_raw(dir.path(), raw_plan).unwrap();
+        let mut cx = internals.compilation_cx.lock().unwrap();
+        cx.build_plan = BuildPlan::External(plan);
+        cx.build_dir = Some(dir.path().to_owned());
+    }
+
+    // The VFS caches the content, which is then modified behind its back.
+    vfs.load_file(&lib).unwrap();
+    std::fs::write(&lib, "fn bar() {}\n").unwrap();
+
+    let (sender, _receiver) = channel();
+    match internals.run_build(
+        dir.path(),
+        BuildPriority::Normal,
+        &HashMap::new(),
+        sender,
+        &CancellationToken::new(),
+    ) {
+        BuildResult::Err(message, None) => assert!(message.contains(&lib.display().to_string())),
+        _ => panic!("build with a stale file should be rejected"),
+    }
+    assert!(internals.stale_files().contains(&lib));
+
+    // Unsaved changes made through the VFS are fine.
+    vfs.set_file(&lib, "fn baz() {}\n");
+    assert!(internals.stale_files().is_empty());
+}
+
+#[test]
+fn reuse_cargo_build_if_depinfo_is_current() {
+    let dir = tempfile::tempdir().unwrap();
+    let lib = dir.path().join("lib.rs");
+    let out_dir = dir.path().join("deps");
+    std::fs::write(&lib, "fn foo() {}\n").unwrap();
+    std::fs::create_dir(&out_dir).unwrap();
+
+    let queue = BuildQueue::new(Arc::new(Vfs::new()), Arc::default());
+    let internals = &queue.internals;
+    {
+        let raw_plan = serde_json::json!({"invocations": [{
+            "deps": [], "outputs": [], "program": "rustc", "env": {},
+            "args": ["--crate-name", "foo", lib.to_str().unwrap(), "--out-dir",
+                     out_dir.to_str().unwrap(), "-C", "extra-filename=-1234"],
+        }]});
+        let raw_plan = serde_json::from_value(raw_plan).unwrap();
+        let plan = external::ExternalPlan::try_from_raw(dir.path(), raw_plan).unwrap();
+        let mut cx = internals.compilation_cx.lock().unwrap();
+        cx.build_plan = BuildPlan::External(plan);
+        cx.build_dir = Some(dir.path().to_owned());
+    }
+    let mut built_files = HashMap::new();
+    built_files.insert(lib.clone(), 1);
+
+    // The crate wasn't compiled yet.
+    assert!(!cargo::depinfo_is_current(
+        &internals.compilation_cx.lock().unwrap().build_plan,
+        &built_files
+    ));
+    std::fs::write(out_dir.join("foo-1234.d"), "").unwrap();
+    assert!(cargo::depinfo_is_current(
+        &internals.compilation_cx.lock().unwrap().build_plan,
+        &built_files
+    ));
+
+    *internals.last_cargo_build.lock().unwrap() = Some(LastCargoBuild {
+        config: format!("{:?}", Config::default()),
+        build_dir: Some(dir.path().to_owned()),
+        cwd: dir.path().to_owned(),
+        diagnostics: vec!["warning".to_owned()],
+        input_files: HashMap::new(),
+    });
+    // Running Cargo would fail, since there is no manifest.
+    let (sender, _receiver) = channel();
+    match internals.run_build(
+        dir.path(),
+        BuildPriority::Cargo,
+        &built_files,
+        sender,
+        &CancellationToken::new(),
+    ) {
+        BuildResult::Success(_, diagnostics, analysis, _, true, duration) => {
+            assert_eq!(diagnostics, vec!["warning".to_owned()]);
+            assert!(analysis.is_empty());
+            assert!(duration > Duration::default());
+        }
+        _ => panic!("the last Cargo build should be reused"),
+    }
+
+    assert!(internals.reuse_last_cargo_build(&built_files).is_some());
+
+    // A forced rebuild runs Cargo again.
+    queue.forget_last_cargo_build();
+    assert!(internals.reuse_last_cargo_build(&built_files).is_none());
+}
+
+#[test]
+fn save_and_load_compilation_context() {
+    let dir = tempfile::tempdir().unwrap();
+    let internals = Internals::new(Arc::new(Vfs::new()), Arc::default());
+    {
+        let raw_plan = serde_json::json!({"invocations": [
+            {"deps": [], "outputs": [], "program": "rustc", "env": {"CARGO_PKG_NAME": "dep"},
+             "args": ["--crate-name", "dep", "/deps/dep/src/lib.rs"], "cwd": "/deps/dep"},
+            {"deps": [0], "outputs": [], "program": "rustc", "env": {"CARGO_PKG_NAME": "foo"},
+             "args": ["--crate-name", "foo", "src/lib.rs"], "cwd": dir.path()},
+        ]});
+        let raw_plan = serde_json::from_value(raw_plan).unwrap();
+        let plan = external::ExternalPlan::try_from_raw(dir.path(), raw_plan).unwrap();
+        let mut cx = internals.compilation_cx.lock().unwrap();
+        cx.build_plan = BuildPlan::External(plan);
+        cx.cwd = Some(dir.path().to_owned());
+        cx.build_dir = Some(dir.path().to_owned());
+    }
+    let ninja = internals.compilation_cx.lock().unwrap().build_plan.serialize_for_ninja();
+
+    internals.save_compilation_context().unwrap();
+    assert!(dir.path().join("target/rls/compilation_context.json").exists());
+    let cx = internals.load_compilation_context().unwrap();
+    assert_eq!(cx.cwd.as_deref(), Some(dir.path()));
+    assert_eq!(cx.build_dir.as_deref(), Some(dir.path()));
+    assert!(!cx.needs_rebuild);
+    assert_eq!(cx.build_plan.serialize_for_ninja(), ninja);
+    let input_files: HashSet<_> =
+        vec![dir.path().join("src/lib.rs"), PathBuf::from("/deps/dep/src/lib.rs")]
+            .into_iter()
+            .collect();
+    assert_eq!(cx.build_plan.input_files(), input_files);
+
+    // Dependencies may have changed since.
+    std::fs::write(dir.path().join("Cargo.lock"), "").unwrap();
+    assert!(internals.load_compilation_context().is_none());
+
+    internals.invalidate_compilation_context();
+    assert!(!dir.path().join("target/rls/compilation_context.json").exists());
+}
+
+#[test]
+fn cancelled_build_is_not_run() {
+    let internals = Internals::new(Arc::new(Vfs::new()), Arc::default());
+    let build_dir = Path::new("/project");
+    let lib = build_dir.join("src/lib.rs");
+    internals.compilation_cx.lock().unwrap().build_dir = Some(build_dir.to_owned());
+    internals.dirty_files.lock().unwrap().insert(lib.clone(), 1);
+    let built_files = internals.dirty_files.lock().unwrap().clone();
+
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+    let (sender, _receiver) = channel();
+    match internals.run_build(build_dir, BuildPriority::Normal, &built_files, sender, &cancel) {
+        BuildResult::Cancelled => {}
+        _ => panic!("a cancelled build shouldn't be run"),
+    }
+    // The file still needs to be built.
+    assert!(internals.dirty_files.lock().unwrap().contains_key(&lib));
+}
+
+#[test]
+fn retry_cargo_build_after_e0514() {
+    let internals = Internals::new(Arc::new(Vfs::new()), Arc::default());
+    internals.compilation_cx.lock().unwrap().needs_rebuild = false;
+    let result = |diagnostic: &str| {
+        BuildResult::Success(
+            PathBuf::new(),
+            vec![diagnostic.to_owned()],
+            vec![],
+            HashMap::new(),
+            false,
+            Duration::default(),
+        )
+    };
+    let e0514 = result(
+        r#"{"message": "found crate `serde` compiled by an incompatible version of rustc",
+            "code": {"code": "E0514", "explanation": null}, "level": "error", "spans": [],
+            "children": [], "rendered": null}"#,
+    );
+    let needs_rebuild = || internals.compilation_cx.lock().unwrap().needs_rebuild;
+
+    assert!(internals.retry_incompatible_rustc(&e0514));
+    assert!(needs_rebuild());
+    // Don't retry again if the Cargo build fails the same way.
+    internals.compilation_cx.lock().unwrap().needs_rebuild = false;
+    assert!(!internals.retry_incompatible_rustc(&e0514));
+    assert!(!needs_rebuild());
+
+    // Other errors reset the limit.
+    let e0425 = result(r#"{"message": "cannot find value `x`", "code": {"code": "E0425"}}"#);
+    assert!(!internals.retry_incompatible_rustc(&e0425));
+    assert!(internals.retry_incompatible_rustc(&e0514));
+}
+
+#[test]
+fn wait_for_build() {
+    let queue = BuildQueue::new(Arc::new(Vfs::new()), Arc::default());
+    assert!(queue.wait_for_build(Duration::from_millis(0)));
+
+    queue.set_building(true);
+    assert!(!queue.wait_for_build(Duration::from_millis(10)));
+
+    let finisher = {
+        let queue = queue.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            queue.set_building(false);
+        })
+    };
+    assert!(queue.wait_for_build(Duration::from_secs(10)));
+    finisher.join().unwrap();
+}
+
+#[test]
+fn drain_dirty_files() {
+    let queue = BuildQueue::new(Arc::new(Vfs::new()), Arc::default());
+    queue.mark_file_dirty(PathBuf::from("/project/src/lib.rs"), 1);
+    queue.mark_file_dirty(PathBuf::from("/project/src/foo.rs"), 2);
+    queue.mark_file_dirty(PathBuf::from("/project/src/bar.rs"), 3);
+    queue.mark_file_dirty(PathBuf::from("/project/src/lib.rs"), 4);
+    assert_eq!(queue.dirty_file_count(), 3);
+
+    let dirty_files = queue.drain_dirty_files();
+    assert_eq!(dirty_files.len(), 3);
+    assert_eq!(dirty_files[Path::new("/project/src/lib.rs")], 4);
+    assert_eq!(dirty_files[Path::new("/project/src/foo.rs")], 2);
+    assert_eq!(dirty_files[Path::new("/project/src/bar.rs")], 3);
+
+    assert_eq!(queue.dirty_file_count(), 0);
+    assert!(queue.drain_dirty_files().is_empty());
+}
+
+#[test]
+fn build_statistics_report() {
+    let queue = BuildQueue::new(Arc::new(Vfs::new()), Arc::default());
+    assert!(queue.statistics_report().contains("builds run: 0\n"));
+    assert!(queue.statistics_report().contains("average duration: n/a\n"));
+
+    {
+        let mut stats = queue.internals.stats.lock().unwrap();
+        stats.record_build(Path::new("/project/target"), Duration::from_millis(300));
+        stats.squashed += 1;
+        stats.record_build(Path::new("/project/target/rls"), Duration::from_millis(100));
+    }
+
+    let stats = queue.stats();
+    assert_eq!(stats.builds, 2);
+    assert_eq!(stats.average_duration(), Some(Duration::from_millis(200)));
+
+    let report = queue.statistics_report();
+    assert!(report.contains("builds run: 2\n"));
+    assert!(report.contains("builds squashed: 1\n"));
+    assert!(report.contains("average duration: 200.0ms\n"));
+    assert!(report.contains("queue: normal priority none, high priority none\n"));
+    assert!(report.contains(&format!(
+        "last build directory: {}\n",
+        Path::new("/project/target/rls").display()
+    )));
+}
@@ -4,22 +4,28 @@
 //! Currently, there are 2 types of build plans:
 //! * Cargo - used when we run Cargo in-process and intercept it
 //! * External - dependency graph between invocations
+//!
+//! Additionally, a `PackageGraph` between the packages can be imported from
+//! `cargo metadata` without compiling anything.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::ffi::OsStr;
 use std::hash::Hash;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
+use std::time::Duration;
 
 use cargo::util::ProcessBuilder;
+use cargo_metadata::{Metadata, PackageId};
 use log::trace;
 use serde::{Deserialize, Serialize};
 
 use crate::actions::progress::ProgressUpdate;
 use crate::build::cargo_plan::CargoPlan;
-use crate::build::external::ExternalPlan;
-use crate::build::{BuildResult, Internals, PackageArg};
+use crate::build::external::{ExternalPlan, RawInvocation, RawPlan};
+use crate::build::{BuildResult, CancellationToken, Internals, PackageArg};
 
 pub(crate) trait BuildKey {
     type Key: Eq + Hash;
@@ -72,6 +78,141 @@ impl BuildPlan {
             _ => None,
         }
     }
+
+    /// Returns the source files known to be read by the jobs in the plan.
+    pub fn input_files(&self) -> HashSet<PathBuf> {
+        match self {
+            BuildPlan::External(plan) => plan.input_files(),
+            BuildPlan::Cargo(plan) => plan.input_files.values().flatten().cloned().collect(),
+        }
+    }
+
+    /// Returns the cached compiler command of every job in the plan.
+    pub fn compiler_commands(&self) -> Vec<&ProcessBuilder> {
+        match self {
+            BuildPlan::External(plan) => plan.compiler_commands(),
+            BuildPlan::Cargo(plan) => plan.compiler_jobs.values().collect(),
+        }
+    }
+
+    /// Emits a fragment of a Ninja `build.ninja` file, with a `build` statement
+    /// running the cached compiler command of every job in the plan. Jobs are
+    /// emitted in dependency order and depend on the jobs they need built first.
+    pub fn serialize_for_ninja(&self) -> String {
+        let jobs = match self {
+            BuildPlan::External(plan) => plan.ninja_jobs(),
+            BuildPlan::Cargo(plan) => plan.ninja_jobs(),
+        };
+
+        let mut ninja = String::from("rule rls_job\n  command = $cmd\n");
+        for (idx, job) in jobs.iter().enumerate() {
+            ninja.push_str(&format!("\nbuild job_{}: rls_job", idx));
+            if !job.deps.is_empty() {
+                let deps: Vec<_> = job.deps.iter().map(|dep| format!("job_{}", dep)).collect();
+                ninja.push_str(&format!(" | {}", deps.join(" ")));
+            }
+            ninja.push_str(&format!("\n  cmd = {}\n", ninja_command(job.command)));
+        }
+        ninja
+    }
+
+    /// Returns the compiler jobs of the plan in the format of
+    /// `cargo build --build-plan`, e.g., to save them to disk.
+    pub fn to_raw(&self) -> RawPlan {
+        let jobs = match self {
+            BuildPlan::External(plan) => plan.ninja_jobs(),
+            BuildPlan::Cargo(plan) => plan.ninja_jobs(),
+        };
+
+        let invocations = jobs
+            .into_iter()
+            .map(|job| RawInvocation {
+                deps: job.deps,
+                outputs: vec![],
+                links: BTreeMap::new(),
+                program: job.command.get_program().to_string_lossy().into_owned(),
+                args: job
+                    .command
+                    .get_args()
+                    .iter()
+                    .map(|a| a.to_string_lossy().into_owned())
+                    .collect(),
+                env: job
+                    .command
+                    .get_envs()
+                    .iter()
+                    .filter_map(|(k, v)| {
+                        Some((k.clone(), v.as_ref()?.to_string_lossy().into_owned()))
+                    })
+                    .collect(),
+                cwd: job.command.get_cwd().map(Path::to_owned),
+            })
+            .collect();
+        RawPlan { invocations }
+    }
+}
+
+/// (De)serializes a `BuildPlan` as its compiler jobs, see `BuildPlan::to_raw`.
+/// A deserialized plan is always an external one, which lets the RLS run the
+/// jobs without running Cargo first.
+pub(crate) mod raw_plan {
+    use std::path::Path;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::BuildPlan;
+    use crate::build::external::{ExternalPlan, RawPlan};
+
+    pub fn serialize<S: Serializer>(plan: &BuildPlan, serializer: S) -> Result<S::Ok, S::Error> {
+        plan.to_raw().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BuildPlan, D::Error> {
+        let raw = RawPlan::deserialize(deserializer)?;
+        // The saved jobs have their working directory set, so there's no
+        // build directory to fall back to.
+        ExternalPlan::try_from_raw(Path::new(""), raw)
+            .map(BuildPlan::External)
+            .map_err(|()| D::Error::custom("build plan has a dependency outside of it"))
+    }
+}
+
+/// A compiler job emitted by `BuildPlan::serialize_for_ninja`.
+pub(crate) struct NinjaJob<'a> {
+    pub(crate) command: &'a ProcessBuilder,
+    /// Indices of the jobs this one depends on, all of which precede it.
+    pub(crate) deps: Vec<usize>,
+}
+
+/// Renders a command as a shell command line, escaped for a Ninja variable.
+fn ninja_command(command: &ProcessBuilder) -> String {
+    fn quote(arg: &str) -> String {
+        if !arg.is_empty() && arg.chars().all(|c| c.is_alphanumeric() || "-_=+/.,:@%".contains(c)) {
+            arg.to_owned()
+        } else {
+            format!("'{}'", arg.replace('\'', r"'\''"))
+        }
+    }
+
+    let mut words = vec![];
+    if let Some(cwd) = command.get_cwd() {
+        words.extend(vec!["cd".to_owned(), quote(&cwd.to_string_lossy()), "&&".to_owned()]);
+    }
+    let mut envs: Vec<_> = command.get_envs().iter().collect();
+    envs.sort();
+    let envs: Vec<_> = envs
+        .into_iter()
+        .filter_map(|(k, v)| v.as_ref().map(|v| quote(&format!("{}={}", k, v.to_string_lossy()))))
+        .collect();
+    if !envs.is_empty() {
+        words.push("env".to_owned());
+        words.extend(envs);
+    }
+    words.push(quote(&command.get_program().to_string_lossy()));
+    words.extend(command.get_args().iter().map(|arg| quote(&arg.to_string_lossy())));
+
+    words.join(" ").replace('$', "$$").replace('\n', "$\n")
 }
 
 #[derive(Debug)]
@@ -104,6 +245,7 @@ impl JobQueue {
         mut self,
         internals: &Internals,
         progress_sender: Sender<ProgressUpdate>,
+        cancel: &CancellationToken,
     ) -> BuildResult {
         // TODO: In case of an empty job queue we shouldn't be here, since the
         // returned results will replace currently held diagnostics/analyses.
@@ -180,8 +322,9 @@ impl JobQueue {
                 &build_dir,
                 Arc::clone(&internals.config),
                 &internals.env_lock.as_facade(),
+                cancel,
             ) {
-                BuildResult::Success(c, mut messages, mut analysis, files, success) => {
+                BuildResult::Success(c, mut messages, mut analysis, files, success, _) => {
                     compiler_messages.append(&mut messages);
                     analyses.append(&mut analysis);
                     for (file, inputs) in files {
@@ -199,6 +342,7 @@ impl JobQueue {
                             analyses,
                             input_files,
                             false,
+                            Duration::default(),
                         );
                     }
                 }
@@ -206,6 +350,7 @@ impl JobQueue {
                     let cmd = format!("{} {}", program, args.join(" "));
                     return BuildResult::Err(cause, Some(cmd));
                 }
+                BuildResult::Cancelled => return BuildResult::Cancelled,
                 _ => {}
             }
         }
@@ -216,10 +361,53 @@ impl JobQueue {
             analyses,
             input_files,
             true,
+            Duration::default(),
         )
     }
 }
 
+/// The packages of a workspace and their dependencies, as reported by
+/// `cargo metadata`. Used to build the `PackageMap` of a `CargoPlan`.
+///
+/// Unlike the build plans, it's available without running Cargo's compilation
+/// pipeline, but it doesn't know the compiler invocations of the packages.
+#[derive(Debug, Default)]
+pub(crate) struct PackageGraph {
+    pub(crate) packages: HashMap<PackageId, PackageNode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PackageNode {
+    pub(crate) name: String,
+    pub(crate) manifest_path: PathBuf,
+    pub(crate) is_workspace_member: bool,
+}
+
+impl PackageGraph {
+    /// Builds the graph from the output of `cargo metadata --format-version 1`.
+    pub(crate) fn import_from_cargo_metadata(metadata: &Metadata) -> PackageGraph {
+        let packages = metadata
+            .packages
+            .iter()
+            .map(|pkg| {
+                let node = PackageNode {
+                    name: pkg.name.clone(),
+                    manifest_path: pkg.manifest_path.clone(),
+                    is_workspace_member: metadata.workspace_members.contains(&pkg.id),
+                };
+                (pkg.id.clone(), node)
+            })
+            .collect();
+
+        PackageGraph { packages }
+    }
+
+    /// Returns the packages which are members of the workspace.
+    pub(crate) fn workspace_members(&self) -> impl Iterator<Item = &PackageNode> {
+        self.packages.values().filter(|pkg| pkg.is_workspace_member)
+    }
+}
+
 /// Build system-agnostic, basic compilation unit
 #[derive(PartialEq, Eq, Hash, Debug, Clone, Deserialize, Serialize)]
 pub struct Crate {
@@ -244,7 +432,7 @@ impl Default for Edition {
     }
 }
 
-impl std::convert::TryFrom<&str> for Edition {
+impl TryFrom<&str> for Edition {
     type Error = &'static str;
 
     fn try_from(val: &str) -> Result<Self, Self::Error> {
@@ -255,3 +443,36 @@ impl std::convert::TryFrom<&str> for Edition {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_from_cargo_metadata() {
+        let path = Path::new(env!("FIXTURES_DIR")).join("cargo_metadata.json");
+        let metadata: Metadata =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let graph = PackageGraph::import_from_cargo_metadata(&metadata);
+
+        let id = |name: &str| PackageId {
+            repr: format!("{} 0.1.0 (path+file:///workspace/{})", name, name),
+        };
+        let names = |pkgs: Vec<&PackageNode>| {
+            let mut names: Vec<_> = pkgs.into_iter().map(|pkg| pkg.name.clone()).collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(graph.packages.len(), 3);
+        assert_eq!(names(graph.workspace_members().collect()), ["app", "shapes", "util"]);
+        assert_eq!(
+            graph.packages[&id("app")],
+            PackageNode {
+                name: "app".to_owned(),
+                manifest_path: PathBuf::from("/workspace/app/Cargo.toml"),
+                is_workspace_member: true,
+            }
+        );
+    }
+}
@@ -6,16 +6,20 @@ extern crate rustc_save_analysis;
 extern crate rustc_session;
 extern crate rustc_span;
 
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::ffi::OsString;
+use std::fs;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use log::trace;
+use log::{info, trace};
 use rls_data::Analysis;
 use rls_vfs::Vfs;
 
@@ -28,9 +32,9 @@ use self::rustc_session::config::Input;
 use self::rustc_session::Session;
 use self::rustc_span::edition::Edition as RustcEdition;
 use self::rustc_span::source_map::{FileLoader, RealFileLoader};
-use crate::build::environment::{Environment, EnvironmentLockFacade};
+use crate::build::environment::{self, Environment, EnvironmentLockFacade};
 use crate::build::plan::{Crate, Edition};
-use crate::build::{BufWriter, BuildResult};
+use crate::build::{BufWriter, BuildResult, CancellationToken};
 use crate::config::{ClippyPreference, Config};
 
 // Runs a single instance of Rustc.
@@ -42,6 +46,7 @@ pub(crate) fn rustc(
     build_dir: &Path,
     rls_config: Arc<Mutex<Config>>,
     env_lock: &EnvironmentLockFacade,
+    cancel: &CancellationToken,
 ) -> BuildResult {
     trace!(
         "rustc - args: `{:?}`, envs: {:?}, cwd: {:?}, build dir: {:?}",
@@ -50,18 +55,29 @@ pub(crate) fn rustc(
         cwd,
         build_dir
     );
+    if cancel.is_cancelled() {
+        return BuildResult::Cancelled;
+    }
 
     let changed = vfs.get_cached_files();
 
     let mut envs = envs.clone();
 
-    let clippy_preference = {
+    let (clippy_preference, clippy_driver) = {
         let config = rls_config.lock().unwrap();
         if config.clear_env_rust_log {
             envs.insert(String::from("RUST_LOG"), None);
         }
-
-        config.clippy_preference
+        environment::merge_extra_env(&mut envs, &config.extra_env);
+
+        let in_process_clippy =
+            cfg!(feature = "clippy") && config.clippy_preference != ClippyPreference::Off;
+        let clippy_driver = if config.use_clippy && !in_process_clippy {
+            clippy_driver(config.sysroot.as_deref())
+        } else {
+            None
+        };
+        (config.clippy_preference, clippy_driver)
     };
 
     let lock_environment = |envs, cwd| {
@@ -87,14 +103,33 @@ pub(crate) fn rustc(
 
     let stderr = String::from_utf8(stderr).unwrap();
     log::debug!("rustc - stderr: {}", &stderr);
-    let stderr_json_msgs: Vec<_> = stderr.lines().map(String::from).collect();
+    let mut stderr_json_msgs: Vec<_> = stderr.lines().map(String::from).collect();
+    // Clippy doesn't lint crates with errors, so don't bother running it then.
+    if let (Some(clippy_driver), true) = (clippy_driver, result.is_ok()) {
+        let vfs_files = vfs.get_cached_files();
+        stderr_json_msgs.extend(run_clippy_driver(
+            &clippy_driver,
+            &args,
+            &envs,
+            cwd,
+            &input_files,
+            &vfs_files,
+        ));
+    }
 
     let analysis = analysis.map(|analysis| vec![analysis]).unwrap_or_else(Vec::new);
     log::debug!("rustc: analysis read successfully?: {}", !analysis.is_empty());
 
     let cwd = cwd.unwrap_or_else(|| Path::new(".")).to_path_buf();
 
-    BuildResult::Success(cwd, stderr_json_msgs, analysis, input_files, result.is_ok())
+    BuildResult::Success(
+        cwd,
+        stderr_json_msgs,
+        analysis,
+        input_files,
+        result.is_ok(),
+        Duration::default(),
+    )
 }
 
 /// Resulting data from compiling a crate (in the rustc sense)
@@ -379,7 +414,195 @@ fn abs_path(path: &Path) -> Option<PathBuf> {
     }
 }
 
-pub(super) fn current_sysroot() -> Option<String> {
+/// The hash of the rustc used by Cargo (see `current_rustc_hash`), computed
+/// once per session rather than by a `rustc -vV` process per Cargo build. The
+/// cached hash is invalidated whenever the environment selecting the rustc
+/// changes.
+#[derive(Debug, Default)]
+pub(crate) struct RustcHashCache {
+    hash: Mutex<Option<(RustcKey, Option<String>)>>,
+}
+
+/// Environment variables which determine the result of `current_rustc_hash`.
+type RustcKey = Vec<Option<OsString>>;
+
+impl RustcHashCache {
+    /// Returns the hash of the rustc used by Cargo, computing it only if it's
+    /// not known yet or the environment has changed since it was computed.
+    pub(crate) fn rustc_hash(&self) -> Option<String> {
+        const VARS: &[&str] =
+            &["RUSTUP_HOME", "MULTIRUST_HOME", "RUSTUP_TOOLCHAIN", "MULTIRUST_TOOLCHAIN", "RUSTC"];
+        let key = VARS.iter().map(env::var_os).collect();
+        self.rustc_hash_with(key, current_rustc_hash)
+    }
+
+    fn rustc_hash_with(
+        &self,
+        key: RustcKey,
+        compute: impl FnOnce() -> Option<String>,
+    ) -> Option<String> {
+        let mut cached = self.hash.lock().unwrap();
+        match *cached {
+            Some((ref cached_key, ref hash)) if *cached_key == key => hash.clone(),
+            _ => {
+                trace!("RustcHashCache: hashing rustc");
+                let hash = compute();
+                *cached = Some((key, hash.clone()));
+                hash
+            }
+        }
+    }
+}
+
+/// Returns the `clippy-driver` next to the `rustc` of `sysroot` (or of the
+/// current sysroot), if it's installed.
+pub(super) fn clippy_driver(sysroot: Option<&str>) -> Option<PathBuf> {
+    let sysroot = sysroot.map(ToOwned::to_owned).or_else(current_sysroot)?;
+    let driver_name = format!("clippy-driver{}", env::consts::EXE_SUFFIX);
+    let driver = Path::new(&sysroot).join("bin").join(driver_name);
+    if driver.is_file() {
+        Some(driver)
+    } else {
+        None
+    }
+}
+
+/// Runs `clippy-driver` on a crate which was just compiled in-process with
+/// `args`. The driver can't read the VFS, so it checks a copy of the crate's
+/// `input_files` (with their unsaved contents) in a temporary directory, which
+/// is also where it emits metadata to, rather than overwriting Cargo's
+/// artifacts and the save-analysis data. Returns the JSON messages of the
+/// Clippy lints, i.e., without those of rustc, which the in-process
+/// compilation reported already.
+fn run_clippy_driver(
+    clippy_driver: &Path,
+    args: &[String],
+    envs: &BTreeMap<String, Option<OsString>>,
+    cwd: Option<&Path>,
+    input_files: &HashMap<PathBuf, HashSet<Crate>>,
+    vfs_files: &HashMap<PathBuf, String>,
+) -> Vec<String> {
+    let output = tempfile::tempdir().and_then(|temp_dir| {
+        let src_dir = temp_dir.path().join("src");
+        let out_dir = temp_dir.path().join("out");
+        let root = copy_input_files(input_files.keys(), vfs_files, &src_dir)?;
+        fs::create_dir(&out_dir)?;
+
+        let mut cmd = Command::new(clippy_driver);
+        // The first argument is the rustc executable.
+        cmd.args(clippy_driver_args(&args[1..], cwd, &root, &src_dir, &out_dir));
+        for (k, v) in envs {
+            match v {
+                Some(v) => cmd.env(k, v),
+                None => cmd.env_remove(k),
+            };
+        }
+        cmd.env_remove("RUST_SAVE_ANALYSIS_CONFIG");
+        if let Some(cwd) = cwd {
+            cmd.current_dir(cwd);
+        }
+        cmd.output()
+    });
+    match output {
+        Ok(output) => clippy_messages(&String::from_utf8_lossy(&output.stderr)),
+        Err(e) => {
+            info!("failed to run {:?}: {}", clippy_driver, e);
+            vec![]
+        }
+    }
+}
+
+/// Copies `files` into `dir`, taking the contents of the files in the VFS from
+/// `vfs_files`. Returns the directory containing all `files`, whose layout is
+/// reproduced in `dir`.
+fn copy_input_files<'a>(
+    files: impl Iterator<Item = &'a PathBuf> + Clone,
+    vfs_files: &HashMap<PathBuf, String>,
+    dir: &Path,
+) -> io::Result<PathBuf> {
+    let root = common_ancestor(files.clone().map(PathBuf::as_path))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no input files"))?;
+    for file in files {
+        let copy = dir.join(file.strip_prefix(&root).unwrap());
+        fs::create_dir_all(copy.parent().unwrap())?;
+        match vfs_files.get(file) {
+            Some(contents) => fs::write(&copy, contents)?,
+            None => fs::copy(file, &copy).map(|_| ())?,
+        }
+    }
+    Ok(root)
+}
+
+/// Returns the deepest directory containing all `files`.
+fn common_ancestor<'a>(mut files: impl Iterator<Item = &'a Path>) -> Option<PathBuf> {
+    let mut ancestor = files.next()?.parent()?.to_path_buf();
+    for file in files {
+        while !file.starts_with(&ancestor) {
+            if !ancestor.pop() {
+                return None;
+            }
+        }
+    }
+    Some(ancestor)
+}
+
+/// Adapts the arguments of a rustc invocation (without the executable) for
+/// `clippy-driver` to check the copy in `src_dir` of the sources in `root`,
+/// emitting only metadata into `out_dir`. The diagnostics still refer to the
+/// sources in `root`.
+fn clippy_driver_args(
+    args: &[String],
+    cwd: Option<&Path>,
+    root: &Path,
+    src_dir: &Path,
+    out_dir: &Path,
+) -> Vec<String> {
+    let mut clippy_args = vec![];
+    let mut crate_root_seen = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out-dir" | "--emit" => {
+                args.next();
+            }
+            "-C" if args.as_slice().first().map_or(false, |a| a.starts_with("incremental=")) => {
+                args.next();
+            }
+            arg if arg.starts_with("--out-dir=")
+                || arg.starts_with("--emit=")
+                || arg.starts_with("-Cincremental=") => {}
+            arg if !crate_root_seen && Path::new(arg).extension().map_or(false, |e| e == "rs") => {
+                crate_root_seen = true;
+                let copy = src_path(cwd, arg)
+                    .and_then(|path| Some(src_dir.join(path.strip_prefix(root).ok()?)));
+                clippy_args
+                    .push(copy.map_or_else(|| arg.to_owned(), |copy| copy.display().to_string()));
+            }
+            arg => clippy_args.push(arg.to_owned()),
+        }
+    }
+    clippy_args.push("--emit=metadata".to_owned());
+    clippy_args.push("--out-dir".to_owned());
+    clippy_args.push(out_dir.display().to_string());
+    clippy_args.push(format!("--remap-path-prefix={}={}", src_dir.display(), root.display()));
+    clippy_args
+}
+
+/// Returns the JSON messages of Clippy lints, i.e., with a `clippy::` code,
+/// from the `stderr` of `clippy-driver`.
+fn clippy_messages(stderr: &str) -> Vec<String> {
+    let is_clippy_lint = |message: &str| {
+        serde_json::from_str::<serde_json::Value>(message)
+            .ok()
+            .and_then(|message| {
+                Some(message.pointer("/code/code")?.as_str()?.starts_with("clippy::"))
+            })
+            .unwrap_or(false)
+    };
+    stderr.lines().filter(|line| is_clippy_lint(line)).map(String::from).collect()
+}
+
+pub(crate) fn current_sysroot() -> Option<String> {
     let home = env::var("RUSTUP_HOME").or_else(|_| env::var("MULTIRUST_HOME"));
     let toolchain = env::var("RUSTUP_TOOLCHAIN").or_else(|_| env::var("MULTIRUST_TOOLCHAIN"));
     if let (Ok(home), Ok(toolchain)) = (home, toolchain) {
@@ -398,6 +621,58 @@ pub(super) fn current_sysroot() -> Option<String> {
     }
 }
 
+/// Returns a hash identifying the rustc used by Cargo, i.e., its version and
+/// commit.
+fn current_rustc_hash() -> Option<String> {
+    let rustc_exe = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let output = Command::new(rustc_exe).arg("-vV").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let mut hasher = DefaultHasher::new();
+    output.stdout.hash(&mut hasher);
+    Some(format!("{:016x}", hasher.finish()))
+}
+
+/// Name of the file in the RLS target directory recording the hash of the
+/// rustc which last built into it, see `rustc_incremental_cleanup`.
+const RUSTC_HASH_FILE: &str = ".rustc-hash";
+
+/// Removes the incremental compilation data from `target_dir` (the RLS target
+/// directory, e.g. `target/rls`) unless it was produced by the rustc identified
+/// by `current_rustc_hash`. Incremental data of another toolchain, e.g. after
+/// an update, leads to rather opaque errors. If no rustc was recorded yet, the
+/// data is assumed to be of the current one.
+pub(super) fn rustc_incremental_cleanup(
+    target_dir: &Path,
+    current_rustc_hash: &str,
+) -> io::Result<()> {
+    let hash_file = target_dir.join(RUSTC_HASH_FILE);
+    let stale = match fs::read_to_string(&hash_file) {
+        Ok(hash) => hash.trim() != current_rustc_hash,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e),
+    };
+
+    if stale && target_dir.is_dir() {
+        // Cargo keeps the incremental data of each profile separately, e.g.
+        // in `debug/incremental`.
+        let mut dirs = vec![target_dir.to_owned()];
+        for entry in fs::read_dir(target_dir)? {
+            dirs.push(entry?.path());
+        }
+        for incremental in dirs.into_iter().map(|dir| dir.join("incremental")) {
+            if incremental.is_dir() {
+                info!("removing stale incremental data in {}", incremental.display());
+                fs::remove_dir_all(&incremental)?;
+            }
+        }
+    }
+
+    fs::create_dir_all(target_dir)?;
+    fs::write(hash_file, current_rustc_hash)
+}
+
 pub fn src_path(cwd: Option<&Path>, path: impl AsRef<Path>) -> Option<PathBuf> {
     let path = path.as_ref();
 
@@ -411,3 +686,141 @@ pub fn src_path(cwd: Option<&Path>, path: impl AsRef<Path>) -> Option<PathBuf> {
 fn unwrap_shared<T: std::fmt::Debug>(shared: Arc<Mutex<T>>, msg: &'static str) -> T {
     Arc::try_unwrap(shared).expect(msg).into_inner().unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn rustc_hash_cache_reuses_hash() {
+        let cache = RustcHashCache::default();
+        let computed = Cell::new(0);
+        let compute = |hash: &str| {
+            computed.set(computed.get() + 1);
+            Some(hash.to_owned())
+        };
+        let key = |toolchain: &str| vec![Some(OsString::from(toolchain))];
+
+        assert_eq!(cache.rustc_hash_with(key("stable"), || compute("1")).unwrap(), "1");
+        assert_eq!(cache.rustc_hash_with(key("stable"), || compute("1")).unwrap(), "1");
+        assert_eq!(computed.get(), 1);
+
+        // Switching the toolchain changes the rustc.
+        assert_eq!(cache.rustc_hash_with(key("nightly"), || compute("2")).unwrap(), "2");
+        assert_eq!(computed.get(), 2);
+    }
+
+    #[test]
+    fn incremental_cleanup_on_rustc_change() {
+        let target_dir = tempfile::tempdir().unwrap();
+        let incremental = target_dir.path().join("incremental");
+        let debug_incremental = target_dir.path().join("debug").join("incremental");
+        let hash_file = target_dir.path().join(RUSTC_HASH_FILE);
+
+        fs::create_dir_all(&incremental).unwrap();
+        fs::create_dir_all(&debug_incremental).unwrap();
+
+        // No rustc recorded yet, the data is kept.
+        rustc_incremental_cleanup(target_dir.path(), "old").unwrap();
+        assert!(incremental.is_dir() && debug_incremental.is_dir());
+        assert_eq!(fs::read_to_string(&hash_file).unwrap(), "old");
+
+        // Same rustc, the data is kept.
+        rustc_incremental_cleanup(target_dir.path(), "old").unwrap();
+        assert!(incremental.is_dir() && debug_incremental.is_dir());
+
+        rustc_incremental_cleanup(target_dir.path(), "new").unwrap();
+        assert!(!incremental.exists() && !debug_incremental.exists());
+        assert!(target_dir.path().join("debug").is_dir());
+        assert_eq!(fs::read_to_string(&hash_file).unwrap(), "new");
+    }
+
+    #[test]
+    fn clippy_messages_only() {
+        let message = |code: &str| {
+            serde_json::json!({
+                "message": "lint",
+                "code": {"code": code, "explanation": null},
+                "level": "warning",
+                "spans": [],
+                "children": [],
+                "rendered": null,
+            })
+            .to_string()
+        };
+        let clippy_message = message("clippy::redundant_clone");
+        let stderr = [
+            message("unused_variables"),
+            clippy_message.clone(),
+            "{\"message\": \"aborting due to previous error\", \"code\": null}".to_owned(),
+            "not JSON".to_owned(),
+        ]
+        .join("\n");
+        assert_eq!(clippy_messages(&stderr), vec![clippy_message]);
+    }
+
+    #[test]
+    fn clippy_driver_checks_copied_sources() {
+        let args: Vec<_> = [
+            "--crate-name",
+            "foo",
+            "--edition=2018",
+            "src/lib.rs",
+            "--error-format=json",
+            "--emit=dep-info,metadata",
+            "-C",
+            "incremental=/project/target/debug/incremental",
+            "--out-dir",
+            "/project/target/debug/deps",
+            "-L",
+            "dependency=/project/target/debug/deps",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+
+        let args = clippy_driver_args(
+            &args,
+            Some(Path::new("/project")),
+            Path::new("/project/src"),
+            Path::new("/tmp/src"),
+            Path::new("/tmp/out"),
+        );
+        assert_eq!(
+            args,
+            [
+                "--crate-name",
+                "foo",
+                "--edition=2018",
+                "/tmp/src/lib.rs",
+                "--error-format=json",
+                "-L",
+                "dependency=/project/target/debug/deps",
+                "--emit=metadata",
+                "--out-dir",
+                "/tmp/out",
+                "--remap-path-prefix=/tmp/src=/project/src",
+            ]
+        );
+    }
+
+    #[test]
+    fn copy_input_files_with_vfs_contents() {
+        let project = tempfile::tempdir().unwrap();
+        let lib = project.path().join("src").join("lib.rs");
+        let module = project.path().join("src").join("foo").join("mod.rs");
+        fs::create_dir_all(module.parent().unwrap()).unwrap();
+        fs::write(&lib, "mod foo;").unwrap();
+        fs::write(&module, "saved").unwrap();
+
+        let copy = tempfile::tempdir().unwrap();
+        let vfs_files = vec![(module.clone(), "unsaved".to_owned())].into_iter().collect();
+        let root = copy_input_files([lib, module].iter(), &vfs_files, copy.path()).unwrap();
+
+        assert_eq!(root, project.path().join("src"));
+        assert_eq!(fs::read_to_string(copy.path().join("lib.rs")).unwrap(), "mod foo;");
+        let module_copy = copy.path().join("foo").join("mod.rs");
+        assert_eq!(fs::read_to_string(module_copy).unwrap(), "unsaved");
+    }
+}
@@ -0,0 +1,147 @@
+//! Scheduling of the builds of several projects, e.g. the ones of a multi-root
+//! workspace, which would otherwise each run builds on their own `BuildQueue`
+//! and compete for the CPU (and the environment lock).
+
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+use log::trace;
+
+use super::BuildPriority;
+
+/// Runs the builds submitted by `BuildQueue`s sharing it one at a time. When a
+/// build finishes, the highest priority pending build runs next, regardless of
+/// the project it belongs to. Builds of the same priority run in the order they
+/// were submitted.
+#[derive(Default)]
+pub struct SharedBuildScheduler {
+    state: Mutex<SchedulerState>,
+    /// Notified (with `state` locked) when a build finishes.
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    running: bool,
+    /// Builds waiting for their turn, with their submission number.
+    pending: Vec<(BuildPriority, PathBuf, u64)>,
+    next_id: u64,
+}
+
+impl SchedulerState {
+    /// Returns the submission number of the build to run next.
+    fn next(&self) -> Option<u64> {
+        self.pending
+            .iter()
+            .min_by_key(|&&(priority, _, id)| {
+                let rank = match priority {
+                    BuildPriority::Idle => 2,
+                    BuildPriority::Normal => 1,
+                    _ => 0,
+                };
+                (rank, id)
+            })
+            .map(|&(.., id)| id)
+    }
+}
+
+impl SharedBuildScheduler {
+    pub fn new() -> SharedBuildScheduler {
+        SharedBuildScheduler::default()
+    }
+
+    /// Blocks until it's the turn of the build of `project_path` and then runs
+    /// it, i.e., `build`, on the calling thread.
+    pub fn run<T>(
+        &self,
+        priority: BuildPriority,
+        project_path: &Path,
+        build: impl FnOnce() -> T,
+    ) -> T {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.pending.push((priority, project_path.to_owned(), id));
+
+        while state.running || state.next() != Some(id) {
+            state = self.finished.wait(state).unwrap();
+        }
+        state.pending.retain(|&(.., pending_id)| pending_id != id);
+        state.running = true;
+        drop(state);
+
+        trace!("scheduled build of {:?} ({:?})", project_path, priority);
+        let _finished = Finished(self);
+        build()
+    }
+
+    /// Returns the projects with builds waiting for their turn, with the
+    /// priorities of the builds.
+    pub fn pending(&self) -> Vec<(BuildPriority, PathBuf)> {
+        let state = self.state.lock().unwrap();
+        state.pending.iter().map(|(priority, path, _)| (*priority, path.clone())).collect()
+    }
+}
+
+/// Lets the next build run when dropped, even if the build panicked.
+struct Finished<'a>(&'a SharedBuildScheduler);
+
+impl Drop for Finished<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.running = false;
+        self.0.finished.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn higher_priority_build_runs_first() {
+        let scheduler = Arc::new(SharedBuildScheduler::new());
+        let (order_tx, order_rx) = channel();
+
+        // Keep the scheduler busy until both projects have submitted a build.
+        let (started_tx, started_rx) = channel();
+        let (release_tx, release_rx) = channel::<()>();
+        let running = {
+            let scheduler = Arc::clone(&scheduler);
+            thread::spawn(move || {
+                scheduler.run(BuildPriority::Normal, Path::new("/running"), || {
+                    started_tx.send(()).unwrap();
+                    release_rx.recv().unwrap()
+                })
+            })
+        };
+        started_rx.recv().unwrap();
+
+        let submit = |priority, project: &'static str| {
+            let scheduler = Arc::clone(&scheduler);
+            let order_tx = order_tx.clone();
+            thread::spawn(move || {
+                scheduler.run(priority, Path::new(project), || order_tx.send(project).unwrap())
+            })
+        };
+        let low = submit(BuildPriority::Normal, "/first");
+        while scheduler.pending().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let high = submit(BuildPriority::Cargo, "/second");
+        while scheduler.pending().len() < 2 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        release_tx.send(()).unwrap();
+        running.join().unwrap();
+        low.join().unwrap();
+        high.join().unwrap();
+        let order: Vec<_> = order_rx.try_iter().collect();
+        assert_eq!(order, ["/second", "/first"]);
+    }
+}
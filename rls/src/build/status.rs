@@ -0,0 +1,150 @@
+//! Status of a `BuildQueue`, observable through `BuildQueue::status_receiver`.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use super::BuildPriority;
+
+/// What a `BuildQueue` is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildQueueStatus {
+    /// No build is queued or running.
+    Idle,
+    /// A build is queued, but not running yet, e.g., because a normal priority
+    /// build waits for further changes before it starts.
+    Pending { priority: BuildPriority },
+    /// A build is running.
+    Building { priority: BuildPriority },
+}
+
+impl Default for BuildQueueStatus {
+    fn default() -> BuildQueueStatus {
+        BuildQueueStatus::Idle
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    /// The current status, the number of times it changed and whether the
+    /// sender was dropped.
+    state: Mutex<(BuildQueueStatus, u64, bool)>,
+    /// Notified (with `state` locked) when `state` changes.
+    changed: Condvar,
+}
+
+/// Creates a channel holding the latest status sent, which any number of
+/// receivers can observe. Much like a `watch` channel, receivers only see
+/// the latest status, skipping the ones sent in between.
+pub fn status_channel() -> (StatusSender, StatusReceiver) {
+    let shared = Arc::new(Shared::default());
+    (StatusSender(Arc::clone(&shared)), StatusReceiver { shared, seen: 0 })
+}
+
+pub struct StatusSender(Arc<Shared>);
+
+impl StatusSender {
+    /// Sets the status, notifying the receivers if it changed.
+    pub fn send(&self, status: BuildQueueStatus) {
+        let mut state = self.0.state.lock().unwrap();
+        if state.0 != status {
+            state.0 = status;
+            state.1 += 1;
+            self.0.changed.notify_all();
+        }
+    }
+
+    pub fn get(&self) -> BuildQueueStatus {
+        self.0.state.lock().unwrap().0
+    }
+
+    pub fn subscribe(&self) -> StatusReceiver {
+        let seen = self.0.state.lock().unwrap().1;
+        StatusReceiver { shared: Arc::clone(&self.0), seen }
+    }
+}
+
+impl Drop for StatusSender {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.2 = true;
+        self.0.changed.notify_all();
+    }
+}
+
+#[derive(Clone)]
+pub struct StatusReceiver {
+    shared: Arc<Shared>,
+    /// The number of changes of the last status seen.
+    seen: u64,
+}
+
+impl StatusReceiver {
+    /// Returns the current status, without marking it as seen.
+    pub fn get(&self) -> BuildQueueStatus {
+        self.shared.state.lock().unwrap().0
+    }
+
+    /// Blocks until the status changes from the last one seen and returns it.
+    /// Returns `None` once the sender was dropped.
+    pub fn recv(&mut self) -> Option<BuildQueueStatus> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.1 == self.seen && !state.2 {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        self.see(&state)
+    }
+
+    /// Like `recv`, but gives up after `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<BuildQueueStatus> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        while state.1 == self.seen && !state.2 {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = self.shared.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        self.see(&state)
+    }
+
+    fn see(&mut self, state: &(BuildQueueStatus, u64, bool)) -> Option<BuildQueueStatus> {
+        if state.1 == self.seen {
+            return None;
+        }
+        self.seen = state.1;
+        Some(state.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn receive_latest_status() {
+        let (sender, mut receiver) = status_channel();
+        assert_eq!(receiver.get(), BuildQueueStatus::Idle);
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), None);
+
+        let pending = BuildQueueStatus::Pending { priority: BuildPriority::Normal };
+        let building = BuildQueueStatus::Building { priority: BuildPriority::Normal };
+        sender.send(pending);
+        sender.send(building);
+        // Only the latest status is received.
+        assert_eq!(receiver.recv(), Some(building));
+        // Sending the same status again isn't a change.
+        sender.send(building);
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), None);
+
+        let mut late_receiver = sender.subscribe();
+        let waiter = thread::spawn(move || (receiver.recv(), receiver.recv()));
+        sender.send(BuildQueueStatus::Idle);
+        assert_eq!(late_receiver.recv(), Some(BuildQueueStatus::Idle));
+        drop(sender);
+        let (first, second) = waiter.join().unwrap();
+        assert_eq!(first, Some(BuildQueueStatus::Idle));
+        assert_eq!(second, None);
+    }
+}
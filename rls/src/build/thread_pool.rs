@@ -0,0 +1,85 @@
+//! A thread pool on which build threads are run, so that these can be reused
+//! across builds instead of spawning a new OS thread for each of them.
+
+use log::warn;
+use std::sync::Mutex;
+use std::thread;
+
+/// A (work-stealing) pool of threads running submitted tasks.
+pub struct ThreadPool {
+    // `None` once the pool was shut down.
+    pool: Mutex<Option<rayon::ThreadPool>>,
+}
+
+impl ThreadPool {
+    /// Creates a new pool with `size` worker threads.
+    pub fn new(size: usize) -> ThreadPool {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(size)
+            .thread_name(|num| format!("build-worker-{}", num))
+            .build()
+            .expect("failed to create build thread pool");
+
+        ThreadPool { pool: Mutex::new(Some(pool)) }
+    }
+
+    /// Runs `task` on one of the pool's threads. If the pool was already shut
+    /// down, a new thread is spawned for it instead.
+    pub fn submit(&self, task: Box<dyn FnOnce() + Send>) {
+        match *self.pool.lock().unwrap() {
+            Some(ref pool) => pool.spawn(task),
+            None => {
+                warn!("Thread pool was shut down, running task on a new thread");
+                thread::spawn(task);
+            }
+        }
+    }
+
+    /// Shuts the pool down. Already submitted tasks are run to completion, after
+    /// which the worker threads exit.
+    pub fn shutdown(&self) {
+        self.pool.lock().unwrap().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn tasks_run_on_pool_threads() {
+        let pool = ThreadPool::new(2);
+        let (tx, rx) = channel();
+
+        for _ in 0..8 {
+            let tx = tx.clone();
+            pool.submit(Box::new(move || {
+                let current = thread::current();
+                tx.send((current.id(), current.name().map(ToOwned::to_owned))).unwrap();
+            }));
+        }
+        drop(tx);
+
+        let results: Vec<_> = rx.iter().collect();
+        assert_eq!(results.len(), 8);
+        for (_, name) in &results {
+            assert!(name.as_ref().unwrap().starts_with("build-worker-"));
+        }
+        let thread_ids: HashSet<_> = results.iter().map(|(id, _)| *id).collect();
+        assert!(thread_ids.len() <= 2);
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn submit_after_shutdown() {
+        let pool = ThreadPool::new(1);
+        pool.shutdown();
+
+        let (tx, rx) = channel();
+        pool.submit(Box::new(move || tx.send(()).unwrap()));
+        rx.recv().unwrap();
+    }
+}
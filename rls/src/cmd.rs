@@ -4,6 +4,7 @@
 
 use crate::actions::requests;
 use crate::config::Config;
+use crate::lsp_data::InitializeRequestParams;
 use crate::server::{self, LsService, Notification, Request, RequestId};
 use rls_analysis::{AnalysisHost, Target};
 use rls_vfs::Vfs;
@@ -12,11 +13,12 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use lsp_types::{
     ClientCapabilities, CodeActionContext, CodeActionParams, CompletionItem,
     DocumentFormattingParams, DocumentRangeFormattingParams, DocumentSymbolParams,
-    FormattingOptions, InitializeParams, Position, Range, RenameParams, TextDocumentIdentifier,
-    TextDocumentPositionParams, TraceOption, WindowClientCapabilities, WorkspaceSymbolParams,
+    FormattingOptions, InitializeParams, Location, Position, Range, ReferenceContext,
+    ReferenceParams, RenameParams, TextDocumentIdentifier, TextDocumentPositionParams, TraceOption,
+    WindowClientCapabilities, WorkspaceSymbolParams,
 };
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::io::{stdin, stdout, Write};
 use std::marker::PhantomData;
@@ -39,7 +41,8 @@ macro_rules! print_verb {
 
 /// Runs the RLS in command line mode.
 pub fn run() {
-    let sender = init();
+    let output = PrintlnOutput::default();
+    let sender = init(&::std::env::current_dir().unwrap(), output.clone());
 
     loop {
         // Present a prompt and read from stdin.
@@ -64,6 +67,14 @@ pub fn run() {
                 let col = bits.next().expect("Expected column number");
                 def(file_name, row, col).to_string()
             }
+            "refs" | "references" => {
+                let file_name = bits.next().expect("Expected file name");
+                let row = bits.next().expect("Expected line number");
+                let col = bits.next().expect("Expected column number");
+                let request = references(file_name, row, col);
+                output.references.lock().unwrap().insert(request.id.clone());
+                request.to_string()
+            }
             "rename" => {
                 let file_name = bits.next().expect("Expected file name");
                 let row = bits.next().expect("Expected line number");
@@ -183,6 +194,20 @@ fn def(file_name: &str, row: &str, col: &str) -> Request<requests::Definition> {
     Request { id: next_id(), params, received: Instant::now(), _action: PhantomData }
 }
 
+fn references(file_name: &str, row: &str, col: &str) -> Request<requests::References> {
+    let params = ReferenceParams {
+        text_document_position: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier::new(url(file_name)),
+            position: Position::new(
+                u64::from_str(row).expect("Bad line number"),
+                u64::from_str(col).expect("Bad column number"),
+            ),
+        },
+        context: ReferenceContext { include_declaration: true },
+    };
+    Request { id: next_id(), params, received: Instant::now(), _action: PhantomData }
+}
+
 fn rename(file_name: &str, row: &str, col: &str, new_name: &str) -> Request<requests::Rename> {
     let params = RenameParams {
         text_document_position: TextDocumentPositionParams {
@@ -300,6 +325,8 @@ fn initialize(root_path: String) -> Request<server::InitializeRequest> {
         trace: Some(TraceOption::Off),
         workspace_folders: None,
     };
+    // The capabilities the server reads from the JSON are sent as part of `params`.
+    let params = InitializeRequestParams { params, capabilities: serde_json::Value::Null };
     Request { id: next_id(), params, received: Instant::now(), _action: PhantomData }
 }
 
@@ -321,8 +348,12 @@ fn next_id() -> RequestId {
 }
 
 // Custom reader and output for the RLS server.
-#[derive(Clone)]
-struct PrintlnOutput;
+#[derive(Clone, Default)]
+struct PrintlnOutput {
+    /// The IDs of the pending `references` requests, whose results are
+    /// printed as `file:line:col`.
+    references: Arc<Mutex<HashSet<RequestId>>>,
+}
 
 impl server::Output for PrintlnOutput {
     fn response(&self, output: String) {
@@ -334,10 +365,29 @@ impl server::Output for PrintlnOutput {
     }
 
     fn success<D: ::serde::Serialize + fmt::Debug>(&self, id: RequestId, data: &D) {
+        if self.references.lock().unwrap().remove(&id) {
+            let locations =
+                serde_json::to_value(data).and_then(serde_json::from_value::<Vec<Location>>);
+            if let Ok(locations) = locations {
+                print_locations(&locations);
+                return;
+            }
+        }
         println!("{}: {:#?}", id, data);
     }
 }
 
+// Print the locations, e.g. of references, one `file:line:col` per line.
+fn print_locations(locations: &[Location]) {
+    for location in locations {
+        let file = match location.uri.to_file_path() {
+            Ok(path) => path.display().to_string(),
+            Err(()) => location.uri.to_string(),
+        };
+        println!("{}:{}:{}", file, location.range.start.line, location.range.start.character);
+    }
+}
+
 struct ChannelMsgReader {
     channel: Mutex<Receiver<String>>,
 }
@@ -356,9 +406,10 @@ impl server::MessageReader for ChannelMsgReader {
     }
 }
 
-// Initialize a server, returns the sender end of a channel for posting messages.
-// The initialized server will live on its own thread and look after the receiver.
-fn init() -> Sender<String> {
+// Initialize a server for the project at `root_path`, returns the sender end of a
+// channel for posting messages. The initialized server will live on its own thread
+// and look after the receiver, its responses are passed to `output`.
+fn init<O: server::Output>(root_path: &Path, output: O) -> Sender<String> {
     let analysis = Arc::new(AnalysisHost::new(Target::Debug));
     let vfs = Arc::new(Vfs::new());
     let (sender, receiver) = channel();
@@ -368,15 +419,13 @@ fn init() -> Sender<String> {
         vfs,
         // Don't clear `RUST_LOG` in CLI mode since it's intended for debugging purposes.
         Arc::new(Mutex::new(Config { clear_env_rust_log: false, ..Default::default() })),
-        Box::new(ChannelMsgReader::new(receiver)),
-        PrintlnOutput,
+        Box::new(server::BufferedMsgReader::new(ChannelMsgReader::new(receiver))),
+        output,
     );
     thread::spawn(|| LsService::run(service));
 
     sender
-        .send(
-            initialize(::std::env::current_dir().unwrap().to_str().unwrap().to_owned()).to_string(),
-        )
+        .send(initialize(root_path.to_str().unwrap().to_owned()).to_string())
         .expect("Error sending init");
     println!("Initializing (look for `progress[done:true]` message)...");
 
@@ -399,6 +448,10 @@ Supported commands:
                   textDocument/definition
                   used for 'goto def'
 
+    references    file_name line_number column_number
+                  textDocument/references
+                  used for 'find all references', also available as 'refs'
+
     rename        file_name line_number column_number new_name
                   textDocument/rename
                   used for 'rename'
@@ -439,3 +492,45 @@ fn url_workaround_unc_canonicals() {
     let url_str = format!("{}", url);
     assert!(!url_str.starts_with(r"file:////?\"), "Unexpected UNC url {}", url);
 }
+
+#[test]
+fn references_response_is_json_array() {
+    // Passes the responses of the server on to the test.
+    #[derive(Clone)]
+    struct ChannelOutput(Arc<Mutex<Sender<(RequestId, String)>>>);
+
+    impl server::Output for ChannelOutput {
+        fn response(&self, _output: String) {}
+
+        fn provide_id(&self) -> RequestId {
+            RequestId::Num(0)
+        }
+
+        fn success<D: ::serde::Serialize + fmt::Debug>(&self, id: RequestId, data: &D) {
+            let data = serde_json::to_string(data).unwrap();
+            let _ = self.0.lock().unwrap().send((id, data));
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let main = dir.path().join("main.rs");
+    ::std::fs::write(&main, "fn main() {\n    let x = 1;\n    let _ = x;\n}\n").unwrap();
+
+    let (tx, rx) = channel();
+    let sender = init(dir.path(), ChannelOutput(Arc::new(Mutex::new(tx))));
+    let request = references(main.to_str().unwrap(), "1", "8");
+    let id = request.id.clone();
+    sender.send(request.to_string()).unwrap();
+
+    let response = loop {
+        let (response_id, data) = rx.recv_timeout(Duration::from_secs(30)).expect("No response");
+        if response_id == id {
+            break data;
+        }
+    };
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert!(response.is_array(), "Unexpected response {}", response);
+
+    sender.send(shutdown().to_string()).unwrap();
+    sender.send(exit().to_string()).unwrap();
+}
@@ -1,7 +1,7 @@
 //! Configuration for the workspace that RLS is operating within and options for
 //! tweaking the RLS's behavior itself.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fmt;
 use std::fmt::Debug;
@@ -18,7 +18,7 @@ use cargo::CargoResult;
 use serde::de::{Deserialize, Deserializer, Visitor};
 use serde_derive::{Deserialize, Serialize};
 
-use log::trace;
+use log::{trace, warn};
 
 use rustfmt_nightly::Config as RustfmtConfig;
 use rustfmt_nightly::{load_config, CliOptions, EmitMode, Verbosity};
@@ -123,6 +123,12 @@ pub fn unstable_features_allowed() -> bool {
 #[serde(default)]
 pub struct Config {
     pub sysroot: Option<String>,
+    /// Path to the `library` directory of a custom `rust-src` component, where
+    /// the source of the standard library is looked up, e.g., when jumping to
+    /// the definition of an item of `std`. Inferred from the sysroot if the
+    /// `rust-src` component is installed.
+    /// Default: `None`.
+    pub sysroot_src: Option<PathBuf>,
     pub target: Option<String>,
     pub rustflags: Option<String>,
     pub build_lib: Inferrable<bool>,
@@ -130,6 +136,10 @@ pub struct Config {
     pub cfg_test: bool,
     pub unstable_features: bool,
     pub wait_to_build: Option<u64>,
+    /// Time (ms) to wait before starting a background build, e.g., one
+    /// requested when the editor window loses focus.
+    /// Default: `5000`.
+    pub idle_wait_to_build: u64,
     pub show_warnings: bool,
     /// `true` to clear the `RUST_LOG` env variable before calling rustc/cargo.
     /// Default: `true`.
@@ -154,6 +164,12 @@ pub struct Config {
     pub racer_completion: bool,
     #[serde(deserialize_with = "deserialize_clippy_preference")]
     pub clippy_preference: ClippyPreference,
+    /// `true` to lint the project with the `clippy-driver` of the toolchain,
+    /// like `cargo clippy`, if Clippy isn't built into the RLS (or is disabled
+    /// by `clippy_preference`). Falls back to `cargo check` with a warning if
+    /// `clippy-driver` isn't installed.
+    /// Default: `false`.
+    pub use_clippy: bool,
     /// Instructs cargo to enable full documentation extraction during save-analysis
     /// while building the crate. This has no effect on the pre-built standard library,
     /// which is built without full_docs enabled. Hover tooltips currently extract
@@ -165,6 +181,10 @@ pub struct Config {
     /// local variable declaration. When set to false, the content is only available when
     /// holding the `Ctrl` key in some editors.
     pub show_hover_context: bool,
+    /// Attach "Go to Definition", "Find References" and "Open Documentation"
+    /// commands to hover responses (under the `data` field).
+    /// Default: `false`.
+    pub hover_actions: bool,
     /// Use provided rustfmt binary instead of the statically linked one.
     /// (requires unstable features).
     pub rustfmt_path: Option<String>,
@@ -175,12 +195,148 @@ pub struct Config {
     pub build_command: Option<String>,
     /// DEPRECATED: Use `crate_blacklist` instead.
     pub use_crate_blacklist: Option<bool>,
+    /// Maximum number of diagnostics published for a single file. Any excess
+    /// diagnostics are replaced with a single summary diagnostic.
+    /// Default: `usize::MAX`.
+    pub max_diagnostics_per_file: usize,
+    /// Names of workspace members which should not be treated as primary
+    /// packages, i.e. for which no analysis or diagnostics are provided.
+    /// Default: `[]`.
+    pub workspace_mode_blacklist: Vec<String>,
+    /// `true` to report unused imports as errors (`-D unused_imports`).
+    /// Default: `false`.
+    pub error_on_unused_imports: bool,
+    /// Name of the project, shown in the title of the build progress
+    /// notifications. If unset, the name of the workspace directory is used.
+    /// Default: `None`.
+    pub project_name: Option<String>,
+    /// Cargo profile used when building the project, e.g. a custom
+    /// `[profile.rls]`. Custom profiles require the `named-profiles` Cargo
+    /// feature. If unset, the default `dev` profile is used.
+    /// Default: `None`.
+    pub cargo_profile: Option<String>,
+    /// Additional flags passed to rustc, appended to `RUSTFLAGS` as-is (i.e.
+    /// these are not deduplicated), e.g. `["-Cinstrument-coverage"]`.
+    /// Default: `[]`.
+    pub extra_rustflags: Vec<String>,
+    /// Additional environment variables set for every rustc invocation, e.g.
+    /// `{"CUSTOM_VAR": "1"}`. Variables set by Cargo take precedence, unless
+    /// the key is prefixed with `RLS_FORCE_` (which is stripped).
+    /// Default: `{}`.
+    pub extra_env: HashMap<String, String>,
+    /// How the compiler's suggestions to import an unresolved name are applied
+    /// by code actions.
+    /// Default: `"use_statement"`.
+    pub auto_import_style: AutoImportStyle,
+    /// `true` to show the values of `const` and `static` items inline, if the
+    /// client supports `textDocument/inlineValue`.
+    /// Default: `false`.
+    pub inline_const_values: bool,
+    /// `true` to format code as it's typed, i.e., the block closed by a `}` or
+    /// the line ended by a `{` or `;`.
+    /// Default: `false`.
+    pub on_type_formatting: bool,
+    /// `true` to log the command line of every rustc invocation to
+    /// `build-commands.log` in the RLS target directory.
+    /// Default: `false`.
+    pub log_build_commands: bool,
+    /// `true` to serve the diagnostics of the last build on `workspace/diagnostic`
+    /// requests.
+    /// Default: `false`.
+    pub pull_diagnostics: bool,
+    /// `true` to refuse to build when files of the build plan were modified on
+    /// disk without the editor notifying the RLS (e.g., by `git checkout`),
+    /// which would make the analysis inconsistent with the code.
+    /// Default: `false`.
+    pub require_clean_vfs: bool,
+    /// If set, the resident memory (in MiB) above which the RLS drops and
+    /// reloads the analysis data after a build, to discard the data of crates
+    /// which are no longer part of it. Only supported on Linux.
+    /// Default: `None`.
+    pub max_analysis_memory_mb: Option<u64>,
+    /// `true` to complete functions with snippets which have a tab stop for
+    /// each parameter, if the client supports snippets.
+    /// Default: `true`.
+    pub completion_snippets: bool,
+    /// `true` to publish a diagnostic only for the first file it's reported
+    /// for, if the same message (and code) is reported for several files,
+    /// e.g. for a module shared by multiple workspace members.
+    /// Default: `false`.
+    pub diagnostic_deduplicate_across_files: bool,
+    /// `true` to analyze the library, binaries, tests, benchmarks and examples
+    /// of the packages at once, with the tests compiled under `cfg(test)`, as
+    /// for `cargo check --all-targets --profile test`. This is `all_targets`
+    /// and `cfg_test` combined, except that it also takes precedence over the
+    /// `build_*` target selections.
+    /// Default: `false`.
+    pub analyze_all_targets: bool,
+    /// The number of hover responses to cache, so that repeated hover requests
+    /// at the same position don't query the analysis data again. `0` disables
+    /// the cache.
+    /// Default: `10`.
+    pub hover_cache_size: usize,
+    /// The maximum number of symbols returned by a `workspace/symbol` search.
+    /// Default: `128`.
+    pub workspace_symbol_search_limit: usize,
+    /// Shell command run in the project directory after each successful
+    /// build, e.g. to regenerate files derived from the build. Its output is
+    /// logged (`window/logMessage`), and it's killed if it runs for longer than
+    /// 30 seconds.
+    /// Default: `None`.
+    pub on_build_complete: Option<String>,
+    /// `true` to serve the colors of constant items which look like they
+    /// represent RGB(A) colors, e.g. `const RED_COLOR: u32 = 0xFF0000;`, to be
+    /// shown by the client (`textDocument/documentColor`).
+    /// Default: `false`.
+    pub document_colors: bool,
+    /// `true` to show code lenses above test functions (to run or debug them,
+    /// if the client supports `cmdRun`), functions (with the number of their
+    /// references) and traits (with the number of their implementations).
+    /// Default: `true`.
+    pub code_lens: bool,
+    /// `true` to show the types of bindings without a type annotation as
+    /// inlay hints (`textDocument/inlayHint`).
+    /// Default: `true`.
+    pub inlay_type_hints: bool,
+    /// `true` to show the names of the parameters of calls with several
+    /// arguments as inlay hints (`textDocument/inlayHint`).
+    /// Default: `true`.
+    pub inlay_parameter_hints: bool,
+    /// The number of characters of the documentation of a function to show
+    /// in its signature help (`textDocument/signatureHelp`), or `0` to leave
+    /// the documentation out.
+    /// Default: `1000`.
+    pub signature_help_max_docs_length: usize,
+    /// `true` to check the integration tests, as if passing `--tests` to
+    /// Cargo. Takes precedence over `all_targets`, as do the other `build_*`
+    /// target selections.
+    /// Default: `false`.
+    pub build_tests: bool,
+    /// `true` to check the benchmarks, as if passing `--benches` to Cargo.
+    /// Default: `false`.
+    pub build_benches: bool,
+    /// `true` to check the examples, as if passing `--examples` to Cargo.
+    /// Default: `false`.
+    pub build_examples: bool,
+    /// The example to check, as if passing `--example <build_example>` to
+    /// Cargo.
+    /// Default: `None`.
+    pub build_example: Option<String>,
+    /// How requests relying on the analysis data (e.g., go to definition) are
+    /// served while a build is running.
+    /// Default: `StaleAnalysis::ReturnStaleData`.
+    pub stale_analysis: StaleAnalysis,
+    /// The time (ms) to wait for a running build, if `stale_analysis` is
+    /// `StaleAnalysis::WaitForBuild`.
+    /// Default: `3000`.
+    pub stale_analysis_timeout: u64,
 }
 
 impl Default for Config {
     fn default() -> Config {
         let mut result = Config {
             sysroot: None,
+            sysroot_src: None,
             target: None,
             rustflags: None,
             build_lib: Inferrable::Inferred(false),
@@ -188,6 +344,7 @@ impl Default for Config {
             cfg_test: false,
             unstable_features: false,
             wait_to_build: None,
+            idle_wait_to_build: 5000,
             show_warnings: true,
             clear_env_rust_log: true,
             build_on_save: false,
@@ -200,11 +357,44 @@ impl Default for Config {
             all_targets: true,
             racer_completion: true,
             clippy_preference: ClippyPreference::default(),
+            use_clippy: false,
             full_docs: Inferrable::Inferred(false),
             show_hover_context: true,
+            hover_actions: false,
             rustfmt_path: None,
             build_command: None,
             use_crate_blacklist: None,
+            max_diagnostics_per_file: usize::max_value(),
+            workspace_mode_blacklist: vec![],
+            error_on_unused_imports: false,
+            project_name: None,
+            cargo_profile: None,
+            extra_rustflags: vec![],
+            extra_env: HashMap::new(),
+            auto_import_style: AutoImportStyle::default(),
+            inline_const_values: false,
+            on_type_formatting: false,
+            log_build_commands: false,
+            pull_diagnostics: false,
+            require_clean_vfs: false,
+            max_analysis_memory_mb: None,
+            completion_snippets: true,
+            diagnostic_deduplicate_across_files: false,
+            analyze_all_targets: false,
+            hover_cache_size: 10,
+            workspace_symbol_search_limit: 128,
+            on_build_complete: None,
+            document_colors: false,
+            code_lens: true,
+            inlay_type_hints: true,
+            inlay_parameter_hints: true,
+            signature_help_max_docs_length: 1000,
+            build_tests: false,
+            build_benches: false,
+            build_examples: false,
+            build_example: None,
+            stale_analysis: StaleAnalysis::default(),
+            stale_analysis_timeout: 3000,
         };
         result.normalise();
         result
@@ -271,7 +461,121 @@ impl Config {
         Err(())
     }
 
-    /// Join this configuration with the new config.
+    /// Returns the default configuration, overridden by the values set via
+    /// `RLS_<OPTION>` environment variables (e.g., `RLS_SHOW_WARNINGS=false`).
+    pub fn from_env() -> Config {
+        let mut config = Config::default();
+        config.apply_env_overrides(env::vars());
+        config
+    }
+
+    /// Overrides the options for which an `RLS_<OPTION>` variable is given in
+    /// `vars`. The values are parsed as JSON, falling back to a plain string
+    /// (e.g., `RLS_TARGET=wasm32-unknown-unknown`). Values which are not valid
+    /// for the option are ignored.
+    fn apply_env_overrides(&mut self, vars: impl IntoIterator<Item = (String, String)>) {
+        macro_rules! override_options {
+            ($overrides: ident, $option: expr, [$($field: ident),* $(,)?]) => {
+                match $option {
+                    $(stringify!($field) => self.$field = $overrides.$field,)*
+                    _ => trace!("Ignoring unknown config option `{}` set in environment", $option),
+                }
+            };
+        }
+
+        for (var, value) in vars {
+            if !var.starts_with("RLS_") {
+                continue;
+            }
+            let option = var["RLS_".len()..].to_lowercase();
+            let value = serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value));
+            let mut object = serde_json::Map::new();
+            object.insert(option.clone(), value);
+
+            let overrides = match Config::try_deserialize(
+                &serde_json::Value::Object(object),
+                &mut HashMap::new(),
+                &mut vec![],
+                &mut vec![],
+            ) {
+                Ok(overrides) => overrides,
+                Err(()) => {
+                    warn!("Ignoring invalid value of environment variable `{}`", var);
+                    continue;
+                }
+            };
+            override_options!(
+                overrides,
+                option.as_str(),
+                [
+                    sysroot,
+                    sysroot_src,
+                    target,
+                    rustflags,
+                    build_lib,
+                    build_bin,
+                    cfg_test,
+                    unstable_features,
+                    wait_to_build,
+                    idle_wait_to_build,
+                    show_warnings,
+                    clear_env_rust_log,
+                    build_on_save,
+                    crate_blacklist,
+                    target_dir,
+                    features,
+                    all_features,
+                    no_default_features,
+                    jobs,
+                    all_targets,
+                    racer_completion,
+                    clippy_preference,
+                    use_clippy,
+                    full_docs,
+                    show_hover_context,
+                    hover_actions,
+                    rustfmt_path,
+                    build_command,
+                    use_crate_blacklist,
+                    max_diagnostics_per_file,
+                    workspace_mode_blacklist,
+                    error_on_unused_imports,
+                    project_name,
+                    cargo_profile,
+                    extra_rustflags,
+                    extra_env,
+                    auto_import_style,
+                    inline_const_values,
+                    on_type_formatting,
+                    log_build_commands,
+                    pull_diagnostics,
+                    require_clean_vfs,
+                    max_analysis_memory_mb,
+                    completion_snippets,
+                    diagnostic_deduplicate_across_files,
+                    analyze_all_targets,
+                    hover_cache_size,
+                    workspace_symbol_search_limit,
+                    on_build_complete,
+                    document_colors,
+                    code_lens,
+                    inlay_type_hints,
+                    inlay_parameter_hints,
+                    signature_help_max_docs_length,
+                    build_tests,
+                    build_benches,
+                    build_examples,
+                    build_example,
+                    stale_analysis,
+                    stale_analysis_timeout,
+                ]
+            );
+        }
+        self.normalise();
+    }
+
+    /// Join this configuration with the new config. Options set via
+    /// environment variables (see `from_env`) take precedence over `new`.
     pub fn update(&mut self, mut new: Config) {
         macro_rules! combine_option_with_default {
             ($ident: ident, $val: expr) => {
@@ -286,6 +590,7 @@ impl Config {
         combine_option_with_default!(full_docs, false);
         combine_option_with_default!(crate_blacklist, CrateBlacklist::default());
         *self = new;
+        self.apply_env_overrides(env::vars());
     }
 
     /// Ensures that unstable options are only allowed if `unstable_features` is
@@ -306,6 +611,17 @@ impl Config {
             self.rustfmt_path = None;
             self.build_command = None;
         }
+
+        let mut seen_features = HashSet::new();
+        self.features.retain(|feature| seen_features.insert(feature.clone()));
+
+        self.extra_rustflags.retain(|flag| {
+            let valid = flag.starts_with('-');
+            if !valid {
+                warn!("ignoring `extra_rustflags` entry `{}`: flags must start with `-`", flag);
+            }
+            valid
+        });
     }
 
     /// Checks if this config is incomplete, and needs additional values to be inferred.
@@ -316,7 +632,14 @@ impl Config {
     /// Tries to auto-detect certain option values if they were unspecified.
     /// Specifically, this:
     /// - detects correct `target/` build directory used by Cargo, if not specified.
-    pub fn infer_defaults(&mut self, project_dir: &Path) -> CargoResult<()> {
+    ///
+    /// Options set via environment variables (see `from_env`) take precedence
+    /// over the configuration sent by the client.
+    ///
+    /// Returns warnings about the configuration that should be shown to the user.
+    pub fn infer_defaults(&mut self, project_dir: &Path) -> CargoResult<Vec<String>> {
+        self.apply_env_overrides(env::vars());
+
         // Note that this may not be equal `build_dir` when inside a workspace member.
         let manifest_path = important_paths::find_root_manifest_for_wd(project_dir)?;
         trace!("root manifest_path: {:?}", &manifest_path);
@@ -349,7 +672,47 @@ impl Config {
             );
         }
 
-        Ok(())
+        if self.sysroot_src.is_none() {
+            self.sysroot_src = self.detect_sysroot_src();
+        }
+
+        Ok(self.validate_features(&ws))
+    }
+
+    /// Returns the `library` directory of the `rust-src` component installed in
+    /// the sysroot, if any.
+    fn detect_sysroot_src(&self) -> Option<PathBuf> {
+        let sysroot = match self.sysroot {
+            Some(ref sysroot) if !sysroot.is_empty() => sysroot.clone(),
+            _ => crate::build::current_sysroot()?,
+        };
+        let library = Path::new(&sysroot).join("lib/rustlib/src/rust/library");
+        if library.is_dir() {
+            Some(library)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a warning for each of the `features` which is not defined by
+    /// any of the workspace members.
+    pub fn validate_features(&self, ws: &Workspace<'_>) -> Vec<String> {
+        let is_defined = |feature: &str| {
+            ws.members().any(|pkg| {
+                pkg.summary().features().keys().any(|f| f.as_str() == feature)
+                    || pkg
+                        .dependencies()
+                        .iter()
+                        .any(|dep| dep.is_optional() && dep.name_in_toml().as_str() == feature)
+            })
+        };
+
+        self.features
+            .iter()
+            // Features of dependencies (`dep/feature`) are checked by Cargo.
+            .filter(|feature| !feature.contains('/') && !is_defined(feature))
+            .map(|feature| format!("Feature `{}` is not defined by any workspace member", feature))
+            .collect()
     }
 }
 
@@ -418,6 +781,44 @@ where
     deserializer.deserialize_any(ClippyPrefDeserializer(PhantomData))
 }
 
+/// Style of the code actions fixing an unresolved name, for which the compiler
+/// suggests e.g. `use std::collections::HashMap;`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoImportStyle {
+    /// Replace the name with its path, e.g. `std::collections::HashMap`.
+    PathOnly,
+    /// Insert the suggested `use` statement.
+    UseStatement,
+    /// Replace the name with its absolute path, e.g. `::std::collections::HashMap`.
+    AbsolutePath,
+}
+
+impl Default for AutoImportStyle {
+    fn default() -> Self {
+        AutoImportStyle::UseStatement
+    }
+}
+
+/// How requests relying on the analysis data are served while a build is
+/// running, i.e., while the data may not reflect the latest edits.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StaleAnalysis {
+    /// Serve the request from the data of the last build.
+    ReturnStaleData,
+    /// Wait for the build to finish, for at most `stale_analysis_timeout`.
+    WaitForBuild,
+    /// Respond with a `ContentModified` error, so the client may retry later.
+    ReturnError,
+}
+
+impl Default for StaleAnalysis {
+    fn default() -> Self {
+        StaleAnalysis::ReturnStaleData
+    }
+}
+
 /// A Rustfmt config (typically specified via `rustfmt.toml`).
 /// The `FmtConfig` is not an exact translation of the config
 /// Rustfmt generates from the user's TOML file, since when
@@ -536,3 +937,131 @@ fn blacklist_default() {
         Config::try_deserialize(&value, &mut Default::default(), &mut vec![], &mut vec![]).unwrap();
     assert_eq!(&*config.crate_blacklist.as_ref().0, &["serde".to_string()]);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::build::environment::EnvironmentLock;
+
+    #[test]
+    fn features_are_deduplicated() {
+        let mut config = Config {
+            features: vec!["foo".to_owned(), "bar".to_owned(), "foo".to_owned()],
+            ..Config::default()
+        };
+        config.normalise();
+        assert_eq!(config.features, vec!["foo".to_owned(), "bar".to_owned()]);
+    }
+
+    #[test]
+    fn invalid_extra_rustflags_are_dropped() {
+        let mut config = Config {
+            extra_rustflags: vec![
+                "-Cinstrument-coverage".to_owned(),
+                "Zsanitizer=address".to_owned(),
+            ],
+            ..Config::default()
+        };
+        config.normalise();
+        assert_eq!(config.extra_rustflags, vec!["-Cinstrument-coverage".to_owned()]);
+    }
+
+    #[test]
+    fn env_overrides_take_precedence_over_updates() {
+        let env_lock = EnvironmentLock::get();
+        let _guard = env_lock.lock();
+        env::set_var("RLS_SHOW_WARNINGS", "false");
+        let mut config = Config::from_env();
+        config.update(Config { show_warnings: true, ..Config::default() });
+        env::remove_var("RLS_SHOW_WARNINGS");
+        assert!(!config.show_warnings);
+    }
+
+    #[test]
+    fn env_overrides() {
+        let vars = vec![
+            ("RLS_WAIT_TO_BUILD", "350"),
+            ("RLS_TARGET", "wasm32-unknown-unknown"),
+            ("RLS_FEATURES", r#"["foo", "bar"]"#),
+            ("RLS_STALE_ANALYSIS", "wait_for_build"),
+            ("RLS_ALL_TARGETS", "not a bool"),
+            ("RLS_OUT_OF_PROCESS", "1"),
+            ("CARGO_HOME", "/cargo"),
+        ];
+        let mut config = Config { build_on_save: true, ..Config::default() };
+        config.apply_env_overrides(vars.into_iter().map(|(k, v)| (k.to_owned(), v.to_owned())));
+
+        assert_eq!(config.wait_to_build, Some(350));
+        assert_eq!(config.target.as_deref(), Some("wasm32-unknown-unknown"));
+        assert_eq!(config.features, vec!["foo".to_owned(), "bar".to_owned()]);
+        assert_eq!(config.stale_analysis, StaleAnalysis::WaitForBuild);
+        assert!(config.all_targets);
+        assert!(config.build_on_save);
+    }
+
+    #[test]
+    fn from_env() {
+        let env_lock = EnvironmentLock::get();
+        let _guard = env_lock.lock();
+        env::set_var("RLS_SHOW_WARNINGS", "false");
+        let config = Config::from_env();
+        env::remove_var("RLS_SHOW_WARNINGS");
+        assert!(!config.show_warnings);
+    }
+
+    #[test]
+    fn unknown_features_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n\n[features]\nbar = []\n";
+        std::fs::write(dir.path().join("Cargo.toml"), manifest).unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+
+        let mut config =
+            Config { features: vec!["bar".to_owned(), "baz".to_owned()], ..Config::default() };
+        let warnings = config.infer_defaults(dir.path()).unwrap();
+        assert_eq!(warnings, vec!["Feature `baz` is not defined by any workspace member"]);
+    }
+
+    fn lib_project() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n";
+        std::fs::write(dir.path().join("Cargo.toml"), manifest).unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+        dir
+    }
+
+    #[test]
+    fn sysroot_src_is_inferred_from_sysroot() {
+        let project = lib_project();
+        let sysroot = tempfile::tempdir().unwrap();
+        let library = sysroot.path().join("lib/rustlib/src/rust/library");
+        std::fs::create_dir_all(&library).unwrap();
+
+        let mut config = Config {
+            sysroot: Some(sysroot.path().to_str().unwrap().to_owned()),
+            ..Config::default()
+        };
+        config.infer_defaults(project.path()).unwrap();
+        assert_eq!(config.sysroot_src, Some(library));
+
+        // Without a `rust-src` component, there's nothing to infer.
+        let empty_sysroot = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            sysroot: Some(empty_sysroot.path().to_str().unwrap().to_owned()),
+            ..Config::default()
+        };
+        config.infer_defaults(project.path()).unwrap();
+        assert_eq!(config.sysroot_src, None);
+    }
+
+    #[test]
+    #[ignore] // Requires `rust-src` component, which isn't available in Rust CI.
+    fn sysroot_src_is_inferred_from_rustup_toolchain() {
+        let project = lib_project();
+        let mut config = Config::default();
+        config.infer_defaults(project.path()).unwrap();
+        assert!(config.sysroot_src.unwrap().ends_with("lib/rustlib/src/rust/library"));
+    }
+}
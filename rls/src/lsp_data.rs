@@ -287,12 +287,513 @@ impl InitializationOptions {
     }
 }
 
+/// Debug request returning the environment variable changes observed across
+/// the most recent Cargo invocation.
+#[derive(Debug)]
+pub enum EnvDiff {}
+
+impl LSPRequest for EnvDiff {
+    type Params = ();
+    type Result = Vec<(String, crate::build::environment::Change)>;
+    const METHOD: &'static str = "rls/envDiff";
+}
+
+/// Request returning a unified diff of the unsaved changes to a document, or
+/// `null` if there are none.
+#[derive(Debug)]
+pub enum UnsavedChanges {}
+
+impl LSPRequest for UnsavedChanges {
+    type Params = TextDocumentIdentifier;
+    type Result = Option<String>;
+    const METHOD: &'static str = "rls/unsavedChanges";
+}
+
+/// An item enclosing a position, see `Breadcrumbs`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct BreadcrumbItem {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub range: Range,
+}
+
+/// Request for the chain of items enclosing a position, from the file itself to
+/// the innermost item (e.g., `file > mod > impl > fn`).
+#[derive(Debug)]
+pub enum Breadcrumbs {}
+
+impl LSPRequest for Breadcrumbs {
+    type Params = TextDocumentPositionParams;
+    type Result = Vec<BreadcrumbItem>;
+    const METHOD: &'static str = "rls/breadcrumbs";
+}
+
+/// Parameters of the `InlineValue` request.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineValueParams {
+    pub text_document: TextDocumentIdentifier,
+    /// The visible part of the document.
+    pub range: Range,
+}
+
+/// A value shown at the end of `range`, see `InlineValue`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct InlineValueText {
+    pub range: Range,
+    pub text: String,
+}
+
+/// Request for the values shown inline in the editor (`textDocument/inlineValue`
+/// in LSP 3.17, which `lsp_types` doesn't support yet).
+#[derive(Debug)]
+pub enum InlineValue {}
+
+impl LSPRequest for InlineValue {
+    type Params = InlineValueParams;
+    type Result = Vec<InlineValueText>;
+    const METHOD: &'static str = "textDocument/inlineValue";
+}
+
+/// Parameters of the `InlayHintRequest`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlayHintParams {
+    pub text_document: TextDocumentIdentifier,
+    /// The visible part of the document.
+    pub range: Range,
+}
+
+/// The kind of an `InlayHint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct InlayHintKind(pub u8);
+
+impl InlayHintKind {
+    /// The type of a binding.
+    pub const TYPE: InlayHintKind = InlayHintKind(1);
+    /// The name of a parameter.
+    pub const PARAMETER: InlayHintKind = InlayHintKind(2);
+}
+
+/// A label shown inline in the editor at `position`, see `InlayHintRequest`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct InlayHint {
+    pub position: Position,
+    pub label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<InlayHintKind>,
+}
+
+/// Request for the implicit types of bindings and the parameter names of the
+/// arguments of calls in a range (`textDocument/inlayHint` in LSP 3.17).
+#[derive(Debug)]
+pub enum InlayHintRequest {}
+
+impl LSPRequest for InlayHintRequest {
+    type Params = InlayHintParams;
+    type Result = Vec<InlayHint>;
+    const METHOD: &'static str = "textDocument/inlayHint";
+}
+
+/// The result id a client got for a document in a previous `WorkspaceDiagnostic`
+/// response.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PreviousResultId {
+    pub uri: Url,
+    pub value: String,
+}
+
+/// Parameters of the `WorkspaceDiagnostic` request.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct WorkspaceDiagnosticParams {
+    pub previous_result_ids: Vec<PreviousResultId>,
+}
+
+/// Diagnostics of a single document, see `WorkspaceDiagnostic`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum WorkspaceDocumentDiagnosticReport {
+    Full {
+        uri: Url,
+        version: Option<i64>,
+        #[serde(rename = "resultId")]
+        result_id: String,
+        items: Vec<Diagnostic>,
+    },
+    /// The diagnostics didn't change since the client got `result_id`.
+    Unchanged {
+        uri: Url,
+        version: Option<i64>,
+        #[serde(rename = "resultId")]
+        result_id: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WorkspaceDiagnosticReport {
+    pub items: Vec<WorkspaceDocumentDiagnosticReport>,
+}
+
+/// Request pulling the diagnostics of the workspace (`workspace/diagnostic` in
+/// LSP 3.17, which `lsp_types` doesn't support yet).
+#[derive(Debug)]
+pub enum WorkspaceDiagnostic {}
+
+impl LSPRequest for WorkspaceDiagnostic {
+    type Params = WorkspaceDiagnosticParams;
+    type Result = WorkspaceDiagnosticReport;
+    const METHOD: &'static str = "workspace/diagnostic";
+}
+
+/// A type or trait in a type hierarchy.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeHierarchyItem {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub detail: Option<String>,
+    pub uri: Url,
+    pub range: Range,
+    pub selection_range: Range,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TypeHierarchyItemParams {
+    pub item: TypeHierarchyItem,
+}
+
+/// Request for the type hierarchy item of the type or trait at a position.
+#[derive(Debug)]
+pub enum PrepareTypeHierarchy {}
+
+impl LSPRequest for PrepareTypeHierarchy {
+    type Params = TextDocumentPositionParams;
+    type Result = Option<Vec<TypeHierarchyItem>>;
+    const METHOD: &'static str = "textDocument/prepareTypeHierarchy";
+}
+
+/// Request for the traits implemented by a type hierarchy item.
+#[derive(Debug)]
+pub enum TypeHierarchySupertypes {}
+
+impl LSPRequest for TypeHierarchySupertypes {
+    type Params = TypeHierarchyItemParams;
+    type Result = Option<Vec<TypeHierarchyItem>>;
+    const METHOD: &'static str = "typeHierarchy/supertypes";
+}
+
+/// Request for the types implementing a type hierarchy item.
+#[derive(Debug)]
+pub enum TypeHierarchySubtypes {}
+
+impl LSPRequest for TypeHierarchySubtypes {
+    type Params = TypeHierarchyItemParams;
+    type Result = Option<Vec<TypeHierarchyItem>>;
+    const METHOD: &'static str = "typeHierarchy/subtypes";
+}
+
+/// A function in a call hierarchy.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallHierarchyItem {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub detail: Option<String>,
+    pub uri: Url,
+    /// The whole definition of the function.
+    pub range: Range,
+    /// The name of the function.
+    pub selection_range: Range,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CallHierarchyItemParams {
+    pub item: CallHierarchyItem,
+}
+
+/// A function calling a call hierarchy item at `from_ranges`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallHierarchyIncomingCall {
+    pub from: CallHierarchyItem,
+    pub from_ranges: Vec<Range>,
+}
+
+/// A function called by a call hierarchy item at `from_ranges`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallHierarchyOutgoingCall {
+    pub to: CallHierarchyItem,
+    pub from_ranges: Vec<Range>,
+}
+
+/// Request for the call hierarchy item of the function named at, or enclosing,
+/// a position (LSP 3.16).
+#[derive(Debug)]
+pub enum CallHierarchyPrepare {}
+
+impl LSPRequest for CallHierarchyPrepare {
+    type Params = TextDocumentPositionParams;
+    type Result = Option<Vec<CallHierarchyItem>>;
+    const METHOD: &'static str = "textDocument/prepareCallHierarchy";
+}
+
+/// Request for the functions calling a call hierarchy item.
+#[derive(Debug)]
+pub enum CallHierarchyIncomingCalls {}
+
+impl LSPRequest for CallHierarchyIncomingCalls {
+    type Params = CallHierarchyItemParams;
+    type Result = Option<Vec<CallHierarchyIncomingCall>>;
+    const METHOD: &'static str = "callHierarchy/incomingCalls";
+}
+
+/// Request for the functions called by a call hierarchy item.
+#[derive(Debug)]
+pub enum CallHierarchyOutgoingCalls {}
+
+impl LSPRequest for CallHierarchyOutgoingCalls {
+    type Params = CallHierarchyItemParams;
+    type Result = Option<Vec<CallHierarchyOutgoingCall>>;
+    const METHOD: &'static str = "callHierarchy/outgoingCalls";
+}
+
+/// A code action literal, including the `data` field (LSP 3.16) which is kept
+/// between a `textDocument/codeAction` and a `codeAction/resolve` request.
+/// `lsp_types::CodeAction` doesn't support it yet.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolvableCodeAction {
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edit: Option<WorkspaceEdit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ResolvableCodeActionOrCommand {
+    Command(Command),
+    CodeAction(ResolvableCodeAction),
+}
+
+/// Request computing the `edit` of a code action sent without one.
+#[derive(Debug)]
+pub enum CodeActionResolve {}
+
+impl LSPRequest for CodeActionResolve {
+    type Params = ResolvableCodeAction;
+    type Result = ResolvableCodeAction;
+    const METHOD: &'static str = "codeAction/resolve";
+}
+
+/// Request asking the client to refresh the semantic tokens of all its open
+/// files, which are outdated after a build.
+#[derive(Debug)]
+pub enum SemanticTokensRefresh {}
+
+impl LSPRequest for SemanticTokensRefresh {
+    type Params = ();
+    type Result = ();
+    const METHOD: &'static str = "workspace/semanticTokens/refresh";
+}
+
+/// Notification asking the RLS to re-read all the files it tracks from disk,
+/// e.g. after many files were changed by a `git checkout`.
+#[derive(Debug)]
+pub enum ReloadVfs {}
+
+impl LSPNotification for ReloadVfs {
+    type Params = ();
+    const METHOD: &'static str = "rls/reloadVfs";
+}
+
+/// Parameters of the `DidChangeWindowFocus` notification.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DidChangeWindowFocusParams {
+    /// `true` if the editor window gained focus, `false` if it lost it.
+    pub focused: bool,
+}
+
+/// Notification telling the RLS that the editor window gained or lost focus.
+#[derive(Debug)]
+pub enum DidChangeWindowFocus {}
+
+impl LSPNotification for DidChangeWindowFocus {
+    type Params = DidChangeWindowFocusParams;
+    const METHOD: &'static str = "window/didChangeWindowFocus";
+}
+
+/// Parameters of the `WorkDoneProgressCreate` request.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WorkDoneProgressCreateParams {
+    pub token: String,
+}
+
+/// Request asking the client to create a progress, reported with `WorkDoneProgress`
+/// notifications (`window/workDoneProgress/create` in LSP 3.15, which
+/// `lsp_types` doesn't support yet).
+#[derive(Debug)]
+pub enum WorkDoneProgressCreate {}
+
+impl LSPRequest for WorkDoneProgressCreate {
+    type Params = WorkDoneProgressCreateParams;
+    type Result = ();
+    const METHOD: &'static str = "window/workDoneProgress/create";
+}
+
+/// A step of a progress created with `WorkDoneProgressCreate`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum WorkDoneProgressValue {
+    Begin { title: String, message: Option<String> },
+    Report { message: Option<String> },
+    End { message: Option<String> },
+}
+
+/// Parameters of the `WorkDoneProgress` notification.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WorkDoneProgressParams {
+    pub token: String,
+    pub value: WorkDoneProgressValue,
+}
+
+/// Notification reporting a progress created with `WorkDoneProgressCreate`.
+#[derive(Debug)]
+pub enum WorkDoneProgress {}
+
+impl LSPNotification for WorkDoneProgress {
+    type Params = WorkDoneProgressParams;
+    const METHOD: &'static str = "$/progress";
+}
+
+/// Parameters of the `BuildStats` notification.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildStatsParams {
+    pub duration_ms: u64,
+    /// Whether every crate compiled without errors.
+    pub success: bool,
+}
+
+/// Notification sent after each build, e.g., to show how long builds take or
+/// to detect pathologically slow ones.
+#[derive(Debug)]
+pub enum BuildStats {}
+
+impl LSPNotification for BuildStats {
+    type Params = BuildStatsParams;
+    const METHOD: &'static str = "rls/buildStats";
+}
+
+/// Parameters of the `DiagnosticsBegin` notification.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticsBeginParams {
+    /// Identifies the build, increasing with every build run.
+    pub build_id: u64,
+    /// The priority of the build, e.g., `"cargo"` or `"normal"`.
+    pub priority: String,
+}
+
+/// Notification sent when the diagnostics of a build are about to be
+/// published.
+#[derive(Debug)]
+pub enum DiagnosticsBegin {}
+
+impl LSPNotification for DiagnosticsBegin {
+    type Params = DiagnosticsBeginParams;
+    const METHOD: &'static str = "rls/diagnosticsBegin";
+}
+
+/// Parameters of the `DiagnosticsEnd` notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticsEndParams {
+    /// The `build_id` of the matching `DiagnosticsBegin` notification.
+    pub build_id: u64,
+    /// Whether every crate compiled without errors.
+    pub success: bool,
+    /// The number of warnings of the build.
+    pub warnings: u32,
+    /// The number of errors of the build.
+    pub errors: u32,
+    pub duration_ms: u64,
+}
+
+/// Notification sent when the diagnostics of a build were published and its
+/// analysis data was indexed.
+#[derive(Debug)]
+pub enum DiagnosticsEnd {}
+
+impl LSPNotification for DiagnosticsEnd {
+    type Params = DiagnosticsEndParams;
+    const METHOD: &'static str = "rls/diagnosticsEnd";
+}
+
 impl Default for InitializationOptions {
     fn default() -> Self {
         InitializationOptions { omit_init_build: false, cmd_run: false, settings: None }
     }
 }
 
+/// The `initialize` request. Its params keep the capabilities of the client as
+/// JSON, since `lsp_types` doesn't know about all of them yet.
+#[derive(Debug)]
+pub enum InitializeRequest {}
+
+impl LSPRequest for InitializeRequest {
+    type Params = InitializeRequestParams;
+    type Result = InitializeRequestResult;
+    const METHOD: &'static str = "initialize";
+}
+
+/// See `InitializeRequest`.
+#[derive(Debug, Serialize)]
+pub struct InitializeRequestParams {
+    #[serde(flatten)]
+    pub params: InitializeParams,
+    /// The `capabilities` of the client, as sent by it.
+    #[serde(skip)]
+    pub capabilities: serde_json::Value,
+}
+
+impl<'de> serde::Deserialize<'de> for InitializeRequestParams {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = <serde_json::Value as serde::Deserialize>::deserialize(deserializer)?;
+        let capabilities = value.get("capabilities").cloned().unwrap_or_default();
+        let params = serde_json::from_value(value).map_err(serde::de::Error::custom)?;
+        Ok(InitializeRequestParams { params, capabilities })
+    }
+}
+
+/// See `InitializeRequest`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct InitializeRequestResult {
+    pub capabilities: ExtendedServerCapabilities,
+}
+
+/// The `ServerCapabilities` of `lsp_types`, plus the ones it doesn't know
+/// about yet. These are only advertised here if the client can't register
+/// them dynamically, see the `Initialized` notification.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtendedServerCapabilities {
+    #[serde(flatten)]
+    pub capabilities: ServerCapabilities,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inlay_hint_provider: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub call_hierarchy_provider: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document_link_provider: Option<DocumentLinkOptions>,
+}
+
 // Subset of flags from lsp_types::ClientCapabilities that affects this RLS.
 // Passed in the `initialize` request under `capabilities`.
 #[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy, Default)]
@@ -300,15 +801,24 @@ impl Default for InitializationOptions {
 pub struct ClientCapabilities {
     pub code_completion_has_snippet_support: bool,
     pub related_information_support: bool,
+    pub code_action_resolve_support: bool,
+    pub inline_value_support: bool,
+    pub semantic_tokens_refresh_support: bool,
+    pub work_done_progress_support: bool,
+    pub inlay_hint_dynamic_registration: bool,
+    pub call_hierarchy_dynamic_registration: bool,
+    pub document_link_dynamic_registration: bool,
 }
 
 impl ClientCapabilities {
-    pub fn new(params: &lsp_types::InitializeParams) -> ClientCapabilities {
+    pub fn new(params: &InitializeRequestParams) -> ClientCapabilities {
         // `lsp_types::ClientCapabilities` is a rather awkward object to use internally
         // (for instance, it doesn't `Clone`). Instead we pick out the bits of it that we
         // are going to handle into `ClientCapabilities`. The upside of
         // using this very simple struct is that it can be kept thread safe
         // without mutex locking it on every request.
+        let capabilities = &params.capabilities;
+        let params = &params.params;
         let code_completion_has_snippet_support = params
             .capabilities
             .text_document
@@ -328,6 +838,52 @@ impl ClientCapabilities {
             .copied()
             .unwrap_or(false);
 
-        ClientCapabilities { code_completion_has_snippet_support, related_information_support }
+        // `lsp_types` doesn't know about `textDocument.codeAction.resolveSupport`
+        // yet, so clients opt in via an experimental capability instead.
+        let code_action_resolve_support = params
+            .capabilities
+            .experimental
+            .as_ref()
+            .and_then(|experimental| experimental.get("codeActionResolveSupport"))
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false);
+
+        // Nor about `textDocument.inlineValue`, which is read from the JSON.
+        let inline_value_support = capabilities.pointer("/textDocument/inlineValue").is_some();
+
+        // Likewise for `workspace.semanticTokens.refreshSupport`.
+        let semantic_tokens_refresh_support = capabilities
+            .pointer("/workspace/semanticTokens/refreshSupport")
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false);
+
+        // And for `window.workDoneProgress`.
+        let work_done_progress_support = capabilities
+            .pointer("/window/workDoneProgress")
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false);
+
+        // And for `textDocument.{inlayHint,callHierarchy,documentLink}.dynamicRegistration`.
+        let dynamic_registration = |capability: &str| {
+            capabilities
+                .pointer(&format!("/textDocument/{}/dynamicRegistration", capability))
+                .and_then(serde_json::Value::as_bool)
+                .unwrap_or(false)
+        };
+        let inlay_hint_dynamic_registration = dynamic_registration("inlayHint");
+        let call_hierarchy_dynamic_registration = dynamic_registration("callHierarchy");
+        let document_link_dynamic_registration = dynamic_registration("documentLink");
+
+        ClientCapabilities {
+            code_completion_has_snippet_support,
+            related_information_support,
+            code_action_resolve_support,
+            inline_value_support,
+            semantic_tokens_refresh_support,
+            work_done_progress_support,
+            inlay_hint_dynamic_registration,
+            call_hierarchy_dynamic_registration,
+            document_link_dynamic_registration,
+        }
     }
 }
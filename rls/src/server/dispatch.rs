@@ -93,13 +93,35 @@ define_dispatch_request_enum!(
     Hover,
     Implementation,
     DocumentHighlight,
+    DocumentLinkRequest,
     Rename,
+    PrepareRename,
     CodeAction,
+    CodeActionResolve,
     ResolveCompletion,
     Formatting,
     RangeFormatting,
     ExecuteCommand,
     CodeLensRequest,
+    CodeLensResolve,
+    EnvDiff,
+    UnsavedChanges,
+    Breadcrumbs,
+    InlineValue,
+    InlayHintRequest,
+    DocumentColor,
+    ColorPresentationRequest,
+    FoldingRangeRequest,
+    OnTypeFormatting,
+    SelectionRangeRequest,
+    WorkspaceDiagnostic,
+    PrepareTypeHierarchy,
+    TypeHierarchySupertypes,
+    TypeHierarchySubtypes,
+    CallHierarchyPrepare,
+    CallHierarchyIncomingCalls,
+    CallHierarchyOutgoingCalls,
+    SignatureHelpRequest,
 );
 
 /// Provides ability to dispatch requests to a worker thread that will
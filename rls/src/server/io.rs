@@ -6,7 +6,7 @@ use crate::lsp_data::{LSPNotification, LSPRequest};
 use std::fmt;
 use std::io::{self, BufRead, Write};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use jsonrpc_core::{self as jsonrpc, response, version, Id};
 
@@ -14,6 +14,41 @@ use jsonrpc_core::{self as jsonrpc, response, version, Id};
 pub trait MessageReader {
     /// Read the next input message.
     fn read_message(&self) -> Option<String>;
+
+    /// Returns the next input message without consuming it, such that it's
+    /// returned again by the following `read_message` call. Readers which
+    /// can't look ahead return `None`.
+    fn peek_message(&self) -> Option<String> {
+        None
+    }
+}
+
+/// A message reader wrapping another one, which buffers a single message to
+/// support `peek_message`.
+pub struct BufferedMsgReader<R> {
+    inner: R,
+    peeked: Mutex<Option<String>>,
+}
+
+impl<R: MessageReader> BufferedMsgReader<R> {
+    pub fn new(inner: R) -> BufferedMsgReader<R> {
+        BufferedMsgReader { inner, peeked: Mutex::new(None) }
+    }
+}
+
+impl<R: MessageReader> MessageReader for BufferedMsgReader<R> {
+    fn read_message(&self) -> Option<String> {
+        let peeked = self.peeked.lock().unwrap().take();
+        peeked.or_else(|| self.inner.read_message())
+    }
+
+    fn peek_message(&self) -> Option<String> {
+        let mut peeked = self.peeked.lock().unwrap();
+        if peeked.is_none() {
+            *peeked = self.inner.read_message();
+        }
+        peeked.clone()
+    }
 }
 
 /// A message reader that gets messages from `stdin`.
@@ -200,6 +235,35 @@ impl Output for StdioOutput {
 mod tests {
     use super::*;
 
+    struct VecMsgReader(Mutex<Vec<String>>);
+
+    impl MessageReader for VecMsgReader {
+        fn read_message(&self) -> Option<String> {
+            self.0.lock().unwrap().pop()
+        }
+    }
+
+    #[test]
+    fn buffered_reader_peek_does_not_consume() {
+        let messages = vec!["second".to_owned(), "first".to_owned()];
+        let reader = BufferedMsgReader::new(VecMsgReader(Mutex::new(messages)));
+
+        assert_eq!(reader.peek_message().as_ref().map(String::as_str), Some("first"));
+        assert_eq!(reader.peek_message().as_ref().map(String::as_str), Some("first"));
+        assert_eq!(reader.read_message().as_ref().map(String::as_str), Some("first"));
+        assert_eq!(reader.read_message().as_ref().map(String::as_str), Some("second"));
+        assert_eq!(reader.peek_message(), None);
+        assert_eq!(reader.read_message(), None);
+    }
+
+    #[test]
+    fn unbuffered_reader_cannot_peek() {
+        let reader = VecMsgReader(Mutex::new(vec!["message".to_owned()]));
+
+        assert_eq!(reader.peek_message(), None);
+        assert_eq!(reader.read_message().as_ref().map(String::as_str), Some("message"));
+    }
+
     #[test]
     fn read_message_returns_message_from_valid_lsr_input() {
         let mut input = io::Cursor::new("Content-Length: 7\r\n\r\nMessage");
@@ -56,11 +56,13 @@ impl From<()> for ResponseError {
 
 /// Some actions can succeed in LSP terms, but can't succeed in user terms.
 /// This response allows an action to send a message to the user (currently
-/// only a warning) or a proper response.
+/// only a warning) or a proper response, or both.
 #[derive(Debug)]
 pub enum ResponseWithMessage<R: DefaultResponse> {
     Response(R),
     Warn(String),
+    /// A proper response, which only partially completed the request.
+    ResponseWithWarn(R, String),
 }
 
 /// A response that has a default value.
@@ -81,6 +83,14 @@ impl<R: DefaultResponse> Response for ResponseWithMessage<R> {
                 let default = R::default();
                 default.send(id, out);
             }
+            ResponseWithMessage::ResponseWithWarn(r, s) => {
+                out.notify(Notification::<ShowMessage>::new(ShowMessageParams {
+                    typ: MessageType::Warning,
+                    message: s,
+                }));
+
+                out.success(id, &r);
+            }
         }
     }
 }
@@ -5,12 +5,14 @@
 use crate::actions::{notifications, requests, ActionContext};
 use crate::config::{Config, DEPRECATED_OPTIONS};
 use crate::lsp_data;
+pub use crate::lsp_data::InitializeRequest;
 use crate::lsp_data::{
-    InitializationOptions, LSPNotification, LSPRequest, MessageType, ShowMessageParams,
+    ExtendedServerCapabilities, InitializationOptions, InitializeRequestResult, LSPNotification,
+    LSPRequest, MessageType, ShowMessageParams,
 };
 use crate::server::dispatch::Dispatcher;
 pub use crate::server::dispatch::{RequestAction, DEFAULT_REQUEST_TIMEOUT};
-pub use crate::server::io::{MessageReader, Output};
+pub use crate::server::io::{BufferedMsgReader, MessageReader, Output};
 use crate::server::io::{StdioMsgReader, StdioOutput};
 use crate::server::message::RawMessage;
 pub use crate::server::message::{
@@ -21,12 +23,13 @@ use crate::version;
 use jsonrpc_core::{self as jsonrpc, types::error::ErrorCode, Id};
 use log::{debug, error, trace, warn};
 pub use lsp_types::notification::{Exit as ExitNotification, ShowMessage};
-pub use lsp_types::request::Initialize as InitializeRequest;
 pub use lsp_types::request::Shutdown as ShutdownRequest;
 use lsp_types::{
-    CodeActionProviderCapability, CodeLensOptions, CompletionOptions, ExecuteCommandOptions,
-    ImplementationProviderCapability, InitializeParams, InitializeResult, RenameProviderCapability,
-    ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind,
+    CodeActionProviderCapability, CodeLensOptions, ColorProviderCapability, CompletionOptions,
+    DocumentLinkOptions, DocumentOnTypeFormattingOptions, ExecuteCommandOptions,
+    FoldingRangeProviderCapability, ImplementationProviderCapability, InitializeParams,
+    RenameOptions, RenameProviderCapability, SelectionRangeProviderCapability, ServerCapabilities,
+    SignatureHelpOptions, TextDocumentSyncCapability, TextDocumentSyncKind,
 };
 use rls_analysis::AnalysisHost;
 use rls_vfs::Vfs;
@@ -46,7 +49,7 @@ pub fn run_server(analysis: Arc<AnalysisHost>, vfs: Arc<Vfs>) -> i32 {
     let service = LsService::new(
         analysis,
         vfs,
-        Arc::new(Mutex::new(Config::default())),
+        Arc::new(Mutex::new(Config::from_env())),
         Box::new(StdioMsgReader),
         StdioOutput::new(),
     );
@@ -65,8 +68,7 @@ impl BlockingRequestAction for ShutdownRequest {
         _out: O,
     ) -> Result<Self::Response, ResponseError> {
         if let Ok(ctx) = ctx.inited() {
-            // Currently we don't perform an explicit clean-up, other than storing state.
-            ctx.shut_down.store(true, Ordering::SeqCst);
+            ctx.shutdown();
             Ok(Ack)
         } else {
             Err(ResponseError::Message(
@@ -149,6 +151,7 @@ impl BlockingRequestAction for InitializeRequest {
         let mut unknowns = Vec::new();
         let mut deprecated = Vec::new();
         let init_options = params
+            .params
             .initialization_options
             .take()
             .and_then(|opt| {
@@ -162,7 +165,7 @@ impl BlockingRequestAction for InitializeRequest {
             })
             .unwrap_or_default();
 
-        trace!("init: {:?} -> {:?}", params.initialization_options, init_options);
+        trace!("init: {:?} -> {:?}", params.params.initialization_options, init_options);
 
         if ctx.inited().is_ok() {
             return Err(ResponseError::Message(
@@ -176,14 +179,16 @@ impl BlockingRequestAction for InitializeRequest {
         maybe_notify_deprecated_configs(&out, &deprecated);
         maybe_notify_duplicated_configs(&out, &dups);
 
-        let result = InitializeResult { capabilities: server_caps(ctx) };
+        let capabilities = lsp_data::ClientCapabilities::new(&params);
+        let result = InitializeRequestResult {
+            capabilities: extended_server_caps(ctx, &capabilities, &init_options),
+        };
 
         // Send response early before `ctx.init` to enforce
         // initialize-response-before-all-other-messages constraint.
         result.send(id, &out);
 
-        let capabilities = lsp_data::ClientCapabilities::new(&params);
-        ctx.init(get_root_path(&params), init_options, capabilities, &out).unwrap();
+        ctx.init(get_root_path(&params.params), init_options, capabilities, &out).unwrap();
 
         Ok(NoResponse)
     }
@@ -195,6 +200,7 @@ pub struct LsService<O: Output> {
     output: O,
     ctx: ActionContext,
     dispatcher: Dispatcher,
+    message_limit: Option<usize>,
 }
 
 impl<O: Output> LsService<O> {
@@ -213,16 +219,28 @@ impl<O: Output> LsService<O> {
             output,
             ctx: ActionContext::new(analysis, vfs, config),
             dispatcher,
+            message_limit: None,
         }
     }
 
+    /// Makes `run` return (with exit code 0) after handling `limit` messages,
+    /// e.g. to run the service on a fixed sequence of messages in tests.
+    pub fn with_message_limit(self, limit: usize) -> LsService<O> {
+        LsService { message_limit: Some(limit), ..self }
+    }
+
     /// Runs this language service.
     pub fn run(mut self) -> i32 {
+        let mut handled = 0;
         loop {
             match self.handle_message() {
                 ServerStateChange::Continue => (),
                 ServerStateChange::Break { exit_code } => return exit_code,
             }
+            handled += 1;
+            if self.message_limit == Some(handled) {
+                return 0;
+            }
         }
     }
 
@@ -298,7 +316,17 @@ impl<O: Output> LsService<O> {
                         }
                     }
                 )*
-                    _ => debug!("Method not found: {}", $method)
+                    _ => {
+                        debug!("Method not found: {}", $method);
+                        // Unlike notifications, every request needs a response.
+                        if msg.id != Id::Null {
+                            self.output.failure(msg.id.clone(), jsonrpc::Error {
+                                code: ErrorCode::MethodNotFound,
+                                message: format!("Method not found: {}", $method),
+                                data: None,
+                            });
+                        }
+                    }
                 }
             }
         }
@@ -318,10 +346,13 @@ impl<O: Output> LsService<O> {
             notifications:
                 notifications::Initialized,
                 notifications::DidOpenTextDocument,
+                notifications::DidCloseTextDocument,
                 notifications::DidChangeTextDocument,
                 notifications::DidSaveTextDocument,
                 notifications::DidChangeConfiguration,
                 notifications::DidChangeWatchedFiles,
+                notifications::DidChangeWindowFocus,
+                notifications::ReloadVfs,
                 notifications::Cancel;
             blocking_requests:
                 ShutdownRequest,
@@ -332,8 +363,11 @@ impl<O: Output> LsService<O> {
                 requests::RangeFormatting,
                 requests::ResolveCompletion,
                 requests::Rename,
+                requests::PrepareRename,
                 requests::CodeAction,
+                requests::CodeActionResolve,
                 requests::DocumentHighlight,
+                requests::DocumentLinkRequest,
                 requests::Implementation,
                 requests::Symbols,
                 requests::Hover,
@@ -341,7 +375,26 @@ impl<O: Output> LsService<O> {
                 requests::Definition,
                 requests::References,
                 requests::Completion,
-                requests::CodeLensRequest;
+                requests::CodeLensRequest,
+                requests::CodeLensResolve,
+                requests::EnvDiff,
+                requests::UnsavedChanges,
+                requests::Breadcrumbs,
+                requests::InlineValue,
+                requests::InlayHintRequest,
+                requests::DocumentColor,
+                requests::ColorPresentationRequest,
+                requests::FoldingRangeRequest,
+                requests::OnTypeFormatting,
+                requests::SelectionRangeRequest,
+                requests::WorkspaceDiagnostic,
+                requests::PrepareTypeHierarchy,
+                requests::TypeHierarchySupertypes,
+                requests::TypeHierarchySubtypes,
+                requests::CallHierarchyPrepare,
+                requests::CallHierarchyIncomingCalls,
+                requests::CallHierarchyOutgoingCalls,
+                requests::SignatureHelpRequest;
         );
         Ok(())
     }
@@ -417,7 +470,10 @@ pub enum ServerStateChange {
     Break { exit_code: i32 },
 }
 
-fn server_caps(ctx: &ActionContext) -> ServerCapabilities {
+fn server_caps(ctx: &ActionContext, init_options: &InitializationOptions) -> ServerCapabilities {
+    let document_colors =
+        init_options.settings.as_ref().map_or(false, |settings| settings.rust.document_colors);
+
     ServerCapabilities {
         text_document_sync: Some(TextDocumentSyncCapability::Kind(
             TextDocumentSyncKind::Incremental,
@@ -443,23 +499,57 @@ fn server_caps(ctx: &ActionContext) -> ServerCapabilities {
             commands: vec![
                 format!("rls.applySuggestion-{}", ctx.pid()),
                 format!("rls.deglobImports-{}", ctx.pid()),
+                format!("rls.extractFunction-{}", ctx.pid()),
+                format!("rls.organizeImports-{}", ctx.pid()),
+                format!("rls.reloadConfig-{}", ctx.pid()),
+                format!("rls.rebuildProject-{}", ctx.pid()),
+                format!("rls.clearDiagnostics-{}", ctx.pid()),
+                format!("rls.showAnalysisStats-{}", ctx.pid()),
             ],
         }),
-        rename_provider: Some(RenameProviderCapability::Simple(true)),
-        color_provider: None,
+        rename_provider: Some(RenameProviderCapability::Options(RenameOptions {
+            prepare_provider: Some(true),
+        })),
+        color_provider: if document_colors {
+            Some(ColorProviderCapability::Simple(true))
+        } else {
+            None
+        },
 
         // These are supported if the `unstable_features` option is set.
         // We'll update these capabilities dynamically when we get config
         // info from the client.
         document_range_formatting_provider: Some(false),
 
-        code_lens_provider: Some(CodeLensOptions { resolve_provider: Some(false) }),
-        document_on_type_formatting_provider: None,
-        signature_help_provider: None,
+        code_lens_provider: Some(CodeLensOptions { resolve_provider: Some(true) }),
+        document_on_type_formatting_provider: Some(DocumentOnTypeFormattingOptions {
+            first_trigger_character: "}".to_owned(),
+            more_trigger_character: Some(vec!["{".to_owned(), ";".to_owned()]),
+        }),
+        signature_help_provider: Some(SignatureHelpOptions {
+            trigger_characters: Some(vec!["(".to_owned(), ",".to_owned()]),
+        }),
 
-        folding_range_provider: None,
+        folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
         workspace: None,
-        selection_range_provider: None,
+        selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+    }
+}
+
+/// Adds the capabilities unknown to `lsp_types` to `server_caps`, unless the
+/// client registers them dynamically in response to `Initialized`.
+fn extended_server_caps(
+    ctx: &ActionContext,
+    client: &lsp_data::ClientCapabilities,
+    init_options: &InitializationOptions,
+) -> ExtendedServerCapabilities {
+    let unless = |dynamic_registration: bool| if dynamic_registration { None } else { Some(true) };
+    ExtendedServerCapabilities {
+        capabilities: server_caps(ctx, init_options),
+        inlay_hint_provider: unless(client.inlay_hint_dynamic_registration),
+        call_hierarchy_provider: unless(client.call_hierarchy_dynamic_registration),
+        document_link_provider: unless(client.document_link_dynamic_registration)
+            .map(|_| DocumentLinkOptions { resolve_provider: Some(false) }),
     }
 }
 
@@ -479,6 +569,8 @@ fn get_root_path(params: &InitializeParams) -> PathBuf {
 #[cfg(test)]
 mod test {
     use super::*;
+    use rls_analysis::Target;
+    use serde_json::json;
     use url::Url;
 
     fn get_default_params() -> InitializeParams {
@@ -542,4 +634,144 @@ mod test {
         let _request: Request<ShutdownRequest> =
             raw.parse_as_request().expect("Boring validation is happening");
     }
+
+    #[test]
+    fn client_capabilities_unknown_to_lsp_types() {
+        let raw = RawMessage::try_parse(
+            r#"{"jsonrpc": "2.0", "id": 0, "method": "initialize", "params": {
+                "processId": null, "rootPath": "/", "capabilities": {
+                    "textDocument": {
+                        "inlineValue": {"dynamicRegistration": false},
+                        "inlayHint": {"dynamicRegistration": true}
+                    },
+                    "workspace": {"semanticTokens": {"refreshSupport": true}},
+                    "window": {"workDoneProgress": true}
+                }
+            }}"#,
+        )
+        .ok()
+        .and_then(|x| x)
+        .expect("raw parse failed");
+
+        let request: Request<InitializeRequest> = raw.parse_as_request().unwrap();
+        let capabilities = lsp_data::ClientCapabilities::new(&request.params);
+        assert!(capabilities.inline_value_support);
+        assert!(capabilities.semantic_tokens_refresh_support);
+        assert!(capabilities.work_done_progress_support);
+        assert!(capabilities.inlay_hint_dynamic_registration);
+    }
+
+    #[test]
+    fn advertise_capabilities_unless_registered_dynamically() {
+        let ctx = ActionContext::new(
+            Arc::new(AnalysisHost::new(Target::Debug)),
+            Arc::new(Vfs::new()),
+            Arc::default(),
+        );
+        let init_options = InitializationOptions::default();
+        let advertised = |client| {
+            let caps =
+                serde_json::to_value(extended_server_caps(&ctx, &client, &init_options)).unwrap();
+            (caps.get("inlayHintProvider").cloned(), caps.get("callHierarchyProvider").cloned())
+        };
+
+        assert_eq!(
+            advertised(lsp_data::ClientCapabilities::default()),
+            (Some(json!(true)), Some(json!(true)))
+        );
+        let client = lsp_data::ClientCapabilities {
+            inlay_hint_dynamic_registration: true,
+            ..Default::default()
+        };
+        assert_eq!(advertised(client), (None, Some(json!(true))));
+
+        let document_link = |client| {
+            let caps =
+                serde_json::to_value(extended_server_caps(&ctx, &client, &init_options)).unwrap();
+            caps.get("documentLinkProvider").cloned()
+        };
+        assert_eq!(
+            document_link(lsp_data::ClientCapabilities::default()),
+            Some(json!({"resolveProvider": false}))
+        );
+        let client = lsp_data::ClientCapabilities {
+            document_link_dynamic_registration: true,
+            ..Default::default()
+        };
+        assert_eq!(document_link(client), None);
+    }
+
+    #[test]
+    fn advertise_color_provider_if_enabled() {
+        let ctx = ActionContext::new(
+            Arc::new(AnalysisHost::new(Target::Debug)),
+            Arc::new(Vfs::new()),
+            Arc::default(),
+        );
+        let color_provider = |init_options| {
+            let client = lsp_data::ClientCapabilities::default();
+            let caps = serde_json::to_value(extended_server_caps(&ctx, &client, &init_options));
+            caps.unwrap().get("colorProvider").cloned()
+        };
+
+        assert_eq!(color_provider(InitializationOptions::default()), None);
+        let settings = lsp_data::ChangeConfigSettings {
+            rust: Config { document_colors: true, ..Config::default() },
+        };
+        let init_options =
+            InitializationOptions { settings: Some(settings), ..InitializationOptions::default() };
+        assert_eq!(color_provider(init_options), Some(json!(true)));
+    }
+
+    struct VecMsgReader(Mutex<Vec<String>>);
+
+    impl MessageReader for VecMsgReader {
+        fn read_message(&self) -> Option<String> {
+            self.0.lock().unwrap().pop()
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordOutput(Arc<Mutex<Vec<String>>>);
+
+    impl Output for RecordOutput {
+        fn response(&self, output: String) {
+            self.0.lock().unwrap().push(output);
+        }
+
+        fn provide_id(&self) -> RequestId {
+            RequestId::Num(0)
+        }
+    }
+
+    #[test]
+    fn stop_after_message_limit() {
+        let root_uri = Url::from_directory_path(make_platform_path("path/a")).unwrap();
+        let messages = vec![
+            json!({"jsonrpc": "2.0", "id": 0, "method": "initialize", "params": {
+                "processId": null, "rootUri": root_uri, "capabilities": {},
+                "initializationOptions": {"omitInitBuild": true},
+            }}),
+            json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/hover", "params": {
+                "textDocument": {"uri": root_uri.join("src/lib.rs").unwrap()},
+                "position": {"line": 0, "character": 0},
+            }}),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "shutdown", "params": null}),
+        ];
+        let reader =
+            VecMsgReader(Mutex::new(messages.iter().rev().map(|m| m.to_string()).collect()));
+        let output = RecordOutput::default();
+
+        let service = LsService::new(
+            Arc::new(AnalysisHost::new(Target::Debug)),
+            Arc::new(Vfs::new()),
+            Arc::default(),
+            Box::new(reader),
+            output.clone(),
+        )
+        .with_message_limit(3);
+        // Reading a fourth message would fail with exit code 101.
+        assert_eq!(service.run(), 0);
+        assert!(output.0.lock().unwrap().iter().any(|response| response.contains(r#""id":2"#)));
+    }
 }
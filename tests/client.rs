@@ -7,7 +7,7 @@ use serde::de::Deserialize;
 use serde_json::json;
 
 use crate::support::project_builder::{project, ProjectBuilder};
-use crate::support::{basic_bin_manifest, fixtures_dir};
+use crate::support::{basic_bin_manifest, basic_lib_manifest, fixtures_dir};
 
 #[allow(dead_code)]
 mod support;
@@ -167,7 +167,7 @@ fn client_test_simple_workspace() {
         .messages()
         .iter()
         .filter(|msg| msg["method"] == "window/progress")
-        .filter(|msg| msg["params"]["title"] == "Building")
+        .filter(|msg| msg["params"]["title"] == "Building simple_workspace")
         .filter(|msg| {
             msg["params"]["message"].as_str().map(|x| x.starts_with("member_")).unwrap_or(false)
         })
@@ -175,6 +175,123 @@ fn client_test_simple_workspace() {
     assert_eq!(count, 4);
 }
 
+#[test]
+fn client_progress_title_uses_project_name() {
+    let p = ProjectBuilder::try_from_fixture(fixtures_dir().join("infer_lib")).unwrap().build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    let opts = json!({"settings": {"rust": {"project_name": "MyApp"} } });
+    rls.request::<Initialize>(0, initialize_params_with_opts(root_path, opts));
+
+    rls.wait_for_indexing();
+
+    let titles: Vec<_> = rls
+        .messages()
+        .iter()
+        .filter(|msg| msg["method"] == "window/progress")
+        .map(|msg| msg["params"]["title"].as_str().unwrap().to_owned())
+        .collect();
+    assert!(titles.iter().any(|title| title == "Building MyApp"));
+    assert!(titles.iter().all(|title| title == "Building MyApp" || title == "Indexing"));
+}
+
+#[test]
+fn client_unknown_request_returns_method_not_found() {
+    let p = ProjectBuilder::try_from_fixture(fixtures_dir().join("infer_lib")).unwrap().build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    rls.request::<Initialize>(0, initialize_params(root_path));
+
+    rls.send(json!({
+        "jsonrpc": "2.0",
+        "id": 42,
+        "method": "nonexistent/method",
+        "params": {},
+    }));
+    let response = rls.wait_for_message(|msg| msg["id"] == 42);
+
+    assert_eq!(response["jsonrpc"], "2.0");
+    assert_eq!(response["error"]["code"], -32601);
+    assert_eq!(response["error"]["message"], "Method not found: nonexistent/method");
+    assert!(response.get("result").is_none());
+}
+
+#[test]
+fn client_workspace_mode_blacklist() {
+    let p = project("simple_workspace")
+        .file(
+            "Cargo.toml",
+            r#"
+                [workspace]
+                members = [
+                "library",
+                "binary",
+                ]
+            "#,
+        )
+        .file(
+            "library/Cargo.toml",
+            r#"
+                [package]
+                name = "library"
+                version = "0.1.0"
+                authors = ["Example <rls@example.com>"]
+            "#,
+        )
+        .file(
+            "library/src/lib.rs",
+            r#"
+                pub fn fetch_u32() -> u32 {
+                    let unused = ();
+                    42
+                }
+            "#,
+        )
+        .file(
+            "binary/Cargo.toml",
+            r#"
+                [package]
+                name = "binary"
+                version = "0.1.0"
+                authors = ["Example <rls@example.com>"]
+
+                [dependencies]
+                library = { path = "../library" }
+            "#,
+        )
+        .file(
+            "binary/src/main.rs",
+            r#"
+                extern crate library;
+
+                fn main() {
+                    let val: u32 = library::fetch_u32();
+                }
+            "#,
+        )
+        .build();
+
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    let opts = json!({"settings": {"rust": {"workspace_mode_blacklist": ["library"] } } });
+    rls.request::<Initialize>(0, initialize_params_with_opts(root_path, opts));
+
+    let bin = rls.future_diagnostics("binary/src/main.rs");
+    let bin = rls.block_on(bin).unwrap();
+    assert!(bin.diagnostics[0].message.contains("unused variable: `val`"));
+
+    rls.wait_for_indexing();
+
+    // Blacklisted member is built as a regular dependency and is not analyzed.
+    assert!(!rls.messages().iter().any(|msg| {
+        msg["method"] == PublishDiagnostics::METHOD
+            && msg["params"]["uri"].as_str().unwrap().ends_with("library/src/lib.rs")
+    }));
+}
+
 #[test]
 fn client_changing_workspace_lib_retains_diagnostics() {
     let p = project("simple_workspace")
@@ -868,6 +985,81 @@ fn client_invalid_member_dependency_resolution() {
     assert!(diag.diagnostics[0].message.contains("no matching package named `nosuchdep123`"));
 }
 
+#[test]
+fn client_failed_build_script_is_reported_instead_of_dependents() {
+    let p = project("failed_build_script")
+        .file(
+            "Cargo.toml",
+            r#"
+                [workspace]
+                members = ["sys", "app"]
+            "#,
+        )
+        .file(
+            "sys/Cargo.toml",
+            r#"
+                [package]
+                name = "sys"
+                version = "0.1.0"
+                authors = ["Example <rls@example.com>"]
+            "#,
+        )
+        .file(
+            "sys/build.rs",
+            r#"
+                fn main() {
+                    println!("cargo:warning=probing libsys");
+                    panic!("libsys not found");
+                }
+            "#,
+        )
+        .file("sys/src/lib.rs", "")
+        .file(
+            "app/Cargo.toml",
+            r#"
+                [package]
+                name = "app"
+                version = "0.1.0"
+                authors = ["Example <rls@example.com>"]
+
+                [dependencies]
+                sys = { path = "../sys" }
+            "#,
+        )
+        // Fails to compile, if it's compiled despite the failed build script.
+        .file("app/src/lib.rs", "pub use sys::missing;")
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    rls.request::<Initialize>(0, initialize_params(root_path));
+
+    let build_script = rls.future_diagnostics("sys/build.rs");
+    let manifest = rls.future_diagnostics("app/Cargo.toml");
+    let (build_script, manifest) = rls.block_on(build_script.join(manifest)).unwrap();
+
+    let messages: Vec<_> = build_script.diagnostics.iter().map(|d| &d.message).collect();
+    assert!(messages.iter().any(|m| *m == "probing libsys"), "{:?}", messages);
+    assert!(
+        messages
+            .iter()
+            .any(|m| m.starts_with("build script failed") && m.contains("libsys not found")),
+        "{:?}",
+        messages
+    );
+
+    assert_eq!(manifest.diagnostics.len(), 1);
+    assert_eq!(manifest.diagnostics[0].severity, Some(DiagnosticSeverity::Error));
+    assert!(manifest.diagnostics[0].message.contains("dependency build script failed: `sys`"));
+
+    let app_diagnostics = rls.messages().iter().any(|msg| {
+        msg["method"] == PublishDiagnostics::METHOD
+            && msg["params"]["uri"].as_str().unwrap().ends_with("app/src/lib.rs")
+            && msg["params"]["diagnostics"].as_array().map_or(false, |d| !d.is_empty())
+    });
+    assert!(!app_diagnostics);
+}
+
 #[test]
 fn client_handle_utf16_unit_text_edits() {
     let p = project("client_handle_utf16_unit_text_edits")
@@ -971,7 +1163,7 @@ fn client_lens_run() {
     rls.wait_for_indexing();
     assert!(rls.messages().iter().count() >= 7);
 
-    let lens = rls.request::<CodeLensRequest>(
+    let lenses = rls.request::<CodeLensRequest>(
         1,
         CodeLensParams {
             text_document: TextDocumentIdentifier {
@@ -980,24 +1172,38 @@ fn client_lens_run() {
         },
     );
 
-    let expected = CodeLens {
-        command: Some(Command {
-            command: "rls.run".to_string(),
-            title: "Run test".to_string(),
-            arguments: Some(vec![json!({
+    // The lenses are resolved lazily.
+    let lenses = lenses.unwrap();
+    assert!(lenses.iter().all(|lens| lens.command.is_none()));
+    let run_lenses: Vec<_> = lenses
+        .into_iter()
+        .filter(|lens| {
+            let data = lens.data.as_ref().unwrap();
+            data["kind"] == "runTest" && data["debug"] == false
+        })
+        .collect();
+    assert_eq!(run_lenses.len(), 1);
+    let range = Range {
+        start: Position { line: 4, character: 3 },
+        end: Position { line: 4, character: 11 },
+    };
+    assert_eq!(run_lenses[0].range, range);
+
+    let lens = rls.request::<CodeLensResolve>(2, run_lenses[0].clone());
+    let expected = Command {
+        command: "rls.runTest".to_string(),
+        title: "Run test".to_string(),
+        arguments: Some(vec![
+            json!({
                 "args": [ "test", "--", "--nocapture", "test_foo" ],
                 "binary": "cargo",
                 "env": { "RUST_BACKTRACE": "short" }
-            })]),
-        }),
-        data: None,
-        range: Range {
-            start: Position { line: 4, character: 3 },
-            end: Position { line: 4, character: 11 },
-        },
+            }),
+            json!(false),
+        ]),
     };
-
-    assert_eq!(lens, Some(vec![expected]));
+    assert_eq!(lens.range, range);
+    assert_eq!(lens.command, Some(expected));
 }
 
 #[test]
@@ -1444,6 +1650,46 @@ fn client_hover() {
     assert_eq!(result.contents, HoverContents::Array(contents));
 }
 
+#[test]
+fn client_hover_actions() {
+    let p = ProjectBuilder::try_from_fixture(fixtures_dir().join("common")).unwrap().build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    let opts = json!({"settings": {"rust": { "all_targets": false, "hover_actions": true } } });
+    rls.request::<Initialize>(0, initialize_params_with_opts(root_path, opts));
+
+    rls.wait_for_indexing();
+
+    let params = TextDocumentPositionParams {
+        position: Position { line: 12, character: 27 },
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(p.root().join("src/main.rs")).unwrap(),
+        },
+    };
+    rls.send(json!({
+        "jsonrpc": "2.0",
+        "id": 11,
+        "method": HoverRequest::METHOD,
+        "params": params,
+    }));
+    let response = rls.wait_for_message(|msg| msg["id"] == 11);
+
+    let result = &response["result"];
+    assert!(result["contents"].as_array().map_or(false, |contents| !contents.is_empty()));
+    let commands: Vec<_> = result["data"]["commands"]
+        .as_array()
+        .expect("hover response without commands")
+        .iter()
+        .map(|command| command["command"].as_str().unwrap())
+        .collect();
+    assert_eq!(commands, ["textDocument/definition", "textDocument/references"]);
+    assert_eq!(
+        result["data"]["commands"][0]["arguments"][0],
+        serde_json::to_value(&params).unwrap()
+    );
+}
+
 /// Test hover continues to work after the source has moved line
 #[ignore] // FIXME(#1265): Spurious failure - sometimes we lose the semantic information from Rust - why?
 #[test]
@@ -1553,6 +1799,120 @@ fn client_workspace_symbol() {
     }
 }
 
+#[test]
+fn client_workspace_symbol_across_members() {
+    let p = project("workspace_symbol_members")
+        .file(
+            "Cargo.toml",
+            r#"
+                [workspace]
+                members = [
+                "first",
+                "second",
+                ]
+            "#,
+        )
+        .file("first/Cargo.toml", &basic_lib_manifest("first"))
+        .file("first/src/lib.rs", "pub fn first_helper() {}\n")
+        .file("second/Cargo.toml", &basic_lib_manifest("second"))
+        .file(
+            "second/src/lib.rs",
+            "pub fn make_gizmo() {}\n\
+             pub struct GizmoFactory;\n\
+             pub struct Gizmo;\n",
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    rls.request::<Initialize>(0, initialize_params(root_path));
+
+    rls.wait_for_indexing();
+
+    let symbols = rls
+        .request::<WorkspaceSymbol>(42, WorkspaceSymbolParams { query: "gizmo".to_owned() })
+        .unwrap();
+
+    // Exact matches first, then prefix and substring matches.
+    let names: Vec<_> = symbols.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, ["Gizmo", "GizmoFactory", "make_gizmo"]);
+    let uri = Url::from_file_path(p.root().join("second/src/lib.rs")).unwrap();
+    assert!(symbols.iter().all(|s| s.location.uri == uri));
+}
+
+#[test]
+fn client_analyze_all_targets() {
+    let p = project("analyze_all_targets")
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "analyze_all_targets"
+                version = "0.1.0"
+                authors = ["Example <rls@example.com>"]
+            "#,
+        )
+        .file(
+            "src/lib.rs",
+            r#"
+                pub fn answer() -> u32 { 42 }
+
+                #[cfg(test)]
+                mod tests {
+                    #[test]
+                    fn unit_test() { assert_eq!(super::answer(), 42); }
+                }
+            "#,
+        )
+        .file(
+            "tests/integration.rs",
+            r#"
+                #[test]
+                fn integration_test() { assert_eq!(analyze_all_targets::answer(), 42); }
+            "#,
+        )
+        .file(
+            "benches/bench.rs",
+            r#"
+                #[test]
+                fn bench_test() { assert_eq!(analyze_all_targets::answer(), 42); }
+            "#,
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    // Overrides both `all_targets` and the target selection.
+    let opts = json!({"settings": {"rust":
+        { "analyze_all_targets": true, "all_targets": false, "build_tests": true } } });
+    rls.request::<Initialize>(0, initialize_params_with_opts(root_path, opts));
+
+    rls.wait_for_indexing();
+
+    for (id, (name, file)) in [
+        ("unit_test", "src/lib.rs"),
+        ("integration_test", "tests/integration.rs"),
+        ("bench_test", "benches/bench.rs"),
+    ]
+    .iter()
+    .enumerate()
+    {
+        let symbols = rls
+            .request::<WorkspaceSymbol>(
+                42 + id as u64,
+                WorkspaceSymbolParams { query: name.to_string() },
+            )
+            .unwrap();
+        let uri = Url::from_file_path(p.root().join(file)).unwrap();
+        assert!(
+            symbols.iter().any(|s| s.name == *name && s.location.uri == uri),
+            "`{}` wasn't analyzed: {:?}",
+            name,
+            symbols
+        );
+    }
+}
+
 #[test]
 fn client_workspace_symbol_duplicates() {
     let p = ProjectBuilder::try_from_fixture(fixtures_dir().join("workspace_symbol_duplicates"))
@@ -1716,6 +2076,52 @@ fn client_highlight() {
     }
 }
 
+#[test]
+fn client_highlight_kinds() {
+    let p = project("highlight_kinds")
+        .file("Cargo.toml", &basic_bin_manifest("highlight_kinds"))
+        .file(
+            "src/main.rs",
+            "fn main() {\n\
+             \x20   let total = 40;\n\
+             \x20   println!(\"{}\", total + total);\n\
+             }\n",
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    rls.request::<Initialize>(0, initialize_params(root_path));
+
+    rls.wait_for_indexing();
+
+    let mut result = rls
+        .request::<DocumentHighlightRequest>(
+            100,
+            TextDocumentPositionParams {
+                position: Position::new(2, 20),
+                text_document: TextDocumentIdentifier {
+                    uri: Url::from_file_path(p.root().join("src/main.rs")).unwrap(),
+                },
+            },
+        )
+        .unwrap();
+    result.sort_by_key(|highlight| (highlight.range.start.line, highlight.range.start.character));
+
+    let highlight = |line, start, kind| DocumentHighlight {
+        range: Range { start: Position::new(line, start), end: Position::new(line, start + 5) },
+        kind: Some(kind),
+    };
+    assert_eq!(
+        result,
+        vec![
+            highlight(1, 8, DocumentHighlightKind::Write),
+            highlight(2, 19, DocumentHighlightKind::Read),
+            highlight(2, 27, DocumentHighlightKind::Read),
+        ]
+    );
+}
+
 #[test]
 fn client_rename() {
     let p = ProjectBuilder::try_from_fixture(fixtures_dir().join("common")).unwrap().build();
@@ -1761,8 +2167,49 @@ fn client_rename() {
 }
 
 #[test]
-fn client_reformat() {
-    let p = ProjectBuilder::try_from_fixture(fixtures_dir().join("reformat")).unwrap().build();
+fn client_prepare_rename() {
+    let p = ProjectBuilder::try_from_fixture(fixtures_dir().join("common")).unwrap().build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    let opts = json!({"settings": {"rust": { "all_targets": false } } });
+    rls.request::<Initialize>(0, initialize_params_with_opts(root_path, opts));
+
+    rls.wait_for_indexing();
+
+    let position = |line, character| TextDocumentPositionParams {
+        position: Position { line, character },
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(p.root().join("src/main.rs")).unwrap(),
+        },
+    };
+
+    let result = rls.request::<PrepareRenameRequest>(42, position(12, 27));
+    assert_eq!(
+        result,
+        Some(PrepareRenameResponse::RangeWithPlaceholder {
+            range: Range {
+                start: Position { line: 12, character: 27 },
+                end: Position { line: 12, character: 32 },
+            },
+            placeholder: "world".to_owned(),
+        })
+    );
+
+    // `u64` is a built-in type.
+    rls.send(json!({
+        "jsonrpc": "2.0",
+        "id": 43,
+        "method": PrepareRenameRequest::METHOD,
+        "params": position(1, 8),
+    }));
+    let msg = rls.wait_for_message(|msg| msg["id"] == 43);
+    assert_eq!(msg["error"]["message"], "Cannot rename: `u64` is a built-in type");
+}
+
+#[test]
+fn client_reformat() {
+    let p = ProjectBuilder::try_from_fixture(fixtures_dir().join("reformat")).unwrap().build();
     let root_path = p.root();
     let mut rls = p.spawn_rls_async();
 
@@ -2107,6 +2554,134 @@ fn client_all_targets() {
     assert!(diag.diagnostics[0].message.contains("unused variable: `unused_var`"));
 }
 
+#[test]
+fn client_build_examples() {
+    let p = project("build_examples")
+        .file("Cargo.toml", &basic_lib_manifest("build_examples"))
+        .file("src/lib.rs", "")
+        .file(
+            "examples/demo.rs",
+            r#"
+                fn main() {
+                    let unused_var = 1;
+                }
+            "#,
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    let opts = json!({"settings": {"rust": { "all_targets": false, "build_examples": true } } });
+    rls.request::<Initialize>(0, initialize_params_with_opts(root_path, opts));
+
+    let diag = rls.future_diagnostics("examples/demo.rs");
+    let diag = rls.block_on(diag).unwrap();
+    assert!(diag.diagnostics[0].message.contains("unused variable: `unused_var`"));
+}
+
+#[test]
+fn client_build_examples_over_all_targets() {
+    let p = project("build_examples_over_all_targets")
+        .file("Cargo.toml", &basic_lib_manifest("build_examples_over_all_targets"))
+        .file("src/lib.rs", "")
+        .file(
+            "examples/demo.rs",
+            r#"
+                fn main() {
+                    let unused_var = 1;
+                }
+            "#,
+        )
+        .file(
+            "tests/unchecked.rs",
+            r#"
+                #[test]
+                fn unchecked() {
+                    let unused_test_var = 1;
+                }
+            "#,
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    // `all_targets` is on by default, but only the examples are checked.
+    let opts = json!({"settings": {"rust": { "build_examples": true } } });
+    rls.request::<Initialize>(0, initialize_params_with_opts(root_path, opts));
+
+    let diag = rls.future_diagnostics("examples/demo.rs");
+    let diag = rls.block_on(diag).unwrap();
+    assert!(diag.diagnostics[0].message.contains("unused variable: `unused_var`"));
+
+    rls.wait_for_indexing();
+    let test_diagnostics = rls.messages().iter().any(|msg| {
+        msg["method"] == PublishDiagnostics::METHOD
+            && msg["params"]["uri"].as_str().unwrap().ends_with("tests/unchecked.rs")
+    });
+    assert!(!test_diagnostics);
+}
+
+/// Whether the toolchain running the tests has `clippy-driver` installed.
+fn clippy_driver_installed() -> bool {
+    let output = std::process::Command::new("rustc").args(&["--print", "sysroot"]).output();
+    let sysroot = match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).trim().to_owned(),
+        Err(_) => return false,
+    };
+    let driver = format!("clippy-driver{}", std::env::consts::EXE_SUFFIX);
+    Path::new(&sysroot).join("bin").join(driver).is_file()
+}
+
+#[test]
+fn client_use_clippy() {
+    if !clippy_driver_installed() {
+        eprintln!("skipping client_use_clippy, `clippy-driver` isn't installed");
+        return;
+    }
+
+    let p = project("use_clippy")
+        .file("Cargo.toml", &basic_lib_manifest("use_clippy"))
+        .file(
+            "src/lib.rs",
+            r#"pub fn copy(x: u32) -> u32 {
+    let x = x.clone();
+    x
+}
+"#,
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    let opts = json!({"settings": {"rust": { "use_clippy": true } } });
+    rls.request::<Initialize>(0, initialize_params_with_opts(root_path, opts));
+
+    let diag = rls.future_diagnostics("src/lib.rs");
+    let diag = rls.block_on(diag).unwrap();
+    let clippy_lint = diag
+        .diagnostics
+        .iter()
+        .find(|d| d.source.as_ref().map_or(false, |s| s == "clippy"))
+        .unwrap_or_else(|| panic!("no Clippy lint in {:?}", diag.diagnostics));
+    assert_eq!(clippy_lint.range.start.line, 1);
+    assert!(clippy_lint.message.contains("clone"), "{}", clippy_lint.message);
+}
+
+#[test]
+fn client_log_build_commands() {
+    let p = ProjectBuilder::try_from_fixture(fixtures_dir().join("bin_lib")).unwrap().build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    let opts = json!({"settings": {"rust": { "log_build_commands": true } } });
+    rls.request::<Initialize>(0, initialize_params_with_opts(root_path, opts));
+
+    rls.wait_for_indexing();
+
+    let log = std::fs::read_to_string(root_path.join("target/rls/build-commands.log")).unwrap();
+    assert!(log.lines().any(|line| line.contains("--crate-name bin_lib")));
+}
+
 /// Handle receiving a notification before the `initialize` request by ignoring and
 /// continuing to run
 #[test]
@@ -2227,3 +2802,497 @@ fn client_parse_error_on_malformed_input() {
     // to provide better fault tolerance.
     cmd.wait().unwrap();
 }
+
+#[test]
+fn client_type_hierarchy() {
+    use rls::lsp_data::{
+        PrepareTypeHierarchy, TypeHierarchyItemParams, TypeHierarchySubtypes,
+        TypeHierarchySupertypes,
+    };
+
+    let p = project("type_hierarchy")
+        .file("Cargo.toml", &basic_bin_manifest("type_hierarchy"))
+        .file(
+            "src/main.rs",
+            "trait Shape {}\n\
+             struct Circle;\n\
+             impl Shape for Circle {}\n\
+             fn main() {}\n",
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    rls.request::<Initialize>(0, initialize_params(root_path));
+
+    rls.wait_for_indexing();
+
+    let uri = Url::from_file_path(p.root().join("src/main.rs")).unwrap();
+    let circle = rls
+        .request::<PrepareTypeHierarchy>(
+            100,
+            TextDocumentPositionParams {
+                position: Position::new(1, 9),
+                text_document: TextDocumentIdentifier { uri: uri.clone() },
+            },
+        )
+        .expect("Circle is a type")
+        .remove(0);
+    assert_eq!(circle.name, "Circle");
+    assert_eq!(circle.kind, SymbolKind::Struct);
+    assert_eq!(circle.uri, uri);
+
+    let supertypes = rls
+        .request::<TypeHierarchySupertypes>(101, TypeHierarchyItemParams { item: circle.clone() })
+        .unwrap();
+    assert_eq!(supertypes.len(), 1);
+    let shape = supertypes[0].clone();
+    assert_eq!(shape.name, "Shape");
+    assert_eq!(shape.kind, SymbolKind::Interface);
+    assert_eq!(shape.selection_range.start, Position::new(0, 6));
+
+    let subtypes =
+        rls.request::<TypeHierarchySubtypes>(102, TypeHierarchyItemParams { item: shape }).unwrap();
+    assert_eq!(subtypes, vec![circle]);
+}
+
+#[test]
+fn client_code_action_resolve() {
+    let p = project("code_action_resolve")
+        .file("Cargo.toml", &basic_bin_manifest("code_action_resolve"))
+        .file(
+            "src/main.rs",
+            "fn main() {\n\
+             \x20   println!(\"{}\", 1 + 2);\n\
+             }\n",
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    let mut params = initialize_params(root_path);
+    params.capabilities.experimental = Some(json!({ "codeActionResolveSupport": true }));
+    rls.request::<Initialize>(0, params);
+
+    rls.wait_for_indexing();
+
+    let uri = Url::from_file_path(p.root().join("src/main.rs")).unwrap();
+    rls.send(json!({
+        "jsonrpc": "2.0",
+        "id": 100,
+        "method": CodeActionRequest::METHOD,
+        "params": {
+            "textDocument": { "uri": uri },
+            "range": {
+                "start": { "line": 1, "character": 4 },
+                "end": { "line": 1, "character": 28 },
+            },
+            "context": { "diagnostics": [] },
+        },
+    }));
+    let response = rls.wait_for_message(|msg| msg["id"] == 100);
+    let actions = response["result"].as_array().unwrap();
+    let action = actions.iter().find(|action| action["title"] == "Extract to function").unwrap();
+    // The edit is only computed once the action is resolved.
+    assert!(action.get("edit").is_none());
+    assert!(action["data"].is_u64());
+
+    rls.send(json!({
+        "jsonrpc": "2.0",
+        "id": 101,
+        "method": "codeAction/resolve",
+        "params": action,
+    }));
+    let response = rls.wait_for_message(|msg| msg["id"] == 101);
+    let resolved = &response["result"];
+    assert_eq!(resolved["title"], "Extract to function");
+    assert_eq!(resolved["data"], action["data"]);
+    let edits = resolved["edit"]["changes"][uri.as_str()].as_array().unwrap();
+    assert!(!edits.is_empty());
+}
+
+#[test]
+fn client_workspace_diagnostic() {
+    use rls::lsp_data::{
+        PreviousResultId, WorkspaceDiagnostic, WorkspaceDiagnosticParams,
+        WorkspaceDocumentDiagnosticReport,
+    };
+
+    let p = project("workspace_diagnostic")
+        .file("Cargo.toml", &basic_bin_manifest("workspace_diagnostic"))
+        .file(
+            "src/main.rs",
+            "fn main() {\n\
+             \x20   let unused = 42;\n\
+             }\n",
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    let opts = json!({"settings": {"rust": { "pull_diagnostics": true } } });
+    rls.request::<Initialize>(0, initialize_params_with_opts(root_path, opts));
+
+    rls.wait_for_indexing();
+
+    let uri = Url::from_file_path(p.root().join("src/main.rs")).unwrap();
+    let report = rls.request::<WorkspaceDiagnostic>(100, WorkspaceDiagnosticParams::default());
+    assert_eq!(report.items.len(), 1);
+    let result_id = match &report.items[0] {
+        WorkspaceDocumentDiagnosticReport::Full { uri: report_uri, result_id, items, .. } => {
+            assert_eq!(*report_uri, uri);
+            assert_eq!(items.len(), 1);
+            assert_eq!(items[0].severity, Some(DiagnosticSeverity::Warning));
+            assert!(items[0].message.contains("unused variable: `unused`"));
+            result_id.clone()
+        }
+        unchanged => panic!("expected a full report, got {:?}", unchanged),
+    };
+
+    let params = WorkspaceDiagnosticParams {
+        previous_result_ids: vec![PreviousResultId { uri: uri.clone(), value: result_id.clone() }],
+    };
+    let report = rls.request::<WorkspaceDiagnostic>(101, params);
+    assert_eq!(
+        report.items,
+        vec![WorkspaceDocumentDiagnosticReport::Unchanged { uri, version: None, result_id }]
+    );
+}
+
+#[test]
+fn client_on_type_formatting() {
+    let p = project("on_type_formatting")
+        .file("Cargo.toml", &basic_bin_manifest("on_type_formatting"))
+        .file(
+            "src/main.rs",
+            "fn main() {\n\
+             let x = 1;\n\
+             \x20   if x > 0 {\n\
+             \x20 println!(\"{}\", x);\n\
+             \x20       }\n\
+             }\n\
+             struct Point {\n\
+             x: i32,\n\
+             }\n",
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    let opts = json!({"settings": {"rust": {"on_type_formatting": true } } });
+    rls.request::<Initialize>(0, initialize_params_with_opts(root_path, opts));
+
+    rls.wait_for_indexing();
+
+    let params = |position, ch: &str| DocumentOnTypeFormattingParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(p.root().join("src/main.rs")).unwrap(),
+        },
+        position,
+        ch: ch.to_owned(),
+        options: FormattingOptions {
+            tab_size: 4,
+            insert_spaces: true,
+            properties: Default::default(),
+        },
+    };
+    let edits = rls.request::<OnTypeFormatting>(100, params(Position::new(4, 9), "}")).unwrap();
+
+    // Only the block closed by the typed `}` is formatted.
+    let newline = if cfg!(windows) { "\r\n" } else { "\n" };
+    assert!(!edits.is_empty());
+    assert!(edits.iter().all(|edit| edit.range.start.line >= 2));
+    let new_text: String = edits.iter().map(|edit| edit.new_text.as_str()).collect();
+    let block = "    if x > 0 {\n        println!(\"{}\", x);\n    }\n".replace("\n", newline);
+    assert!(new_text.contains(&block), "unexpected edits: {:?}", edits);
+
+    // The field of the struct closed by the typed `}` is indented.
+    let edits = rls.request::<OnTypeFormatting>(101, params(Position::new(8, 1), "}")).unwrap();
+    assert_eq!(
+        edits,
+        vec![TextEdit {
+            range: Range { start: Position::new(7, 0), end: Position::new(8, 0) },
+            new_text: format!("    x: i32,{}", newline),
+        }]
+    );
+}
+
+#[test]
+fn client_selection_range() {
+    use rls::lsp_data::{SelectionRangeParams, SelectionRangeRequest};
+
+    let p = project("selection_range")
+        .file("Cargo.toml", &basic_bin_manifest("selection_range"))
+        .file(
+            "src/main.rs",
+            "fn main() {\n\
+             \x20   let mut items = vec![1, 2];\n\
+             \x20   let index = 0;\n\
+             \x20   items.swap(index, 1);\n\
+             }\n",
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    rls.request::<Initialize>(0, initialize_params(root_path));
+
+    rls.wait_for_indexing();
+
+    let ranges = rls.request::<SelectionRangeRequest>(
+        100,
+        SelectionRangeParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(p.root().join("src/main.rs")).unwrap(),
+            },
+            positions: vec![Position::new(3, 17)],
+        },
+    );
+    assert_eq!(ranges.len(), 1);
+
+    let mut ranges: Vec<_> = std::iter::successors(Some(&ranges[0]), |range| {
+        range.parent.as_ref().map(|parent| &**parent)
+    })
+    .map(|range| range.range)
+    .collect();
+    for pair in ranges.windows(2) {
+        assert!(pair[1].start <= pair[0].start && pair[0].end <= pair[1].end);
+        assert_ne!(pair[0], pair[1]);
+    }
+
+    let range = |start: (u64, u64), end: (u64, u64)| Range {
+        start: Position::new(start.0, start.1),
+        end: Position::new(end.0, end.1),
+    };
+    // The argument, the arguments, the call, the statement and the block.
+    let expected = [
+        range((3, 15), (3, 20)),
+        range((3, 15), (3, 23)),
+        range((3, 4), (3, 24)),
+        range((3, 4), (3, 25)),
+        range((0, 10), (4, 1)),
+    ];
+    ranges.retain(|range| expected.contains(range));
+    assert_eq!(ranges, expected);
+}
+
+#[test]
+fn client_inlay_hints() {
+    use rls::lsp_data::{InlayHint, InlayHintKind, InlayHintParams, InlayHintRequest};
+
+    let p = project("inlay_hints")
+        .file("Cargo.toml", &basic_bin_manifest("inlay_hints"))
+        .file(
+            "src/main.rs",
+            "fn add(left: u32, right: u32) -> u32 {\n\
+             \x20   left + right\n\
+             }\n\
+             \n\
+             fn main() {\n\
+             \x20   let x = 1;\n\
+             \x20   let sum: u32 = add(1, 2);\n\
+             \x20   println!(\"{} {}\", x, sum);\n\
+             }\n",
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    rls.request::<Initialize>(0, initialize_params(root_path));
+
+    rls.wait_for_indexing();
+
+    let hints = rls.request::<InlayHintRequest>(
+        100,
+        InlayHintParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::from_file_path(p.root().join("src/main.rs")).unwrap(),
+            },
+            range: Range { start: Position::new(4, 0), end: Position::new(9, 0) },
+        },
+    );
+
+    let hint = |line, character, label: &str, kind| InlayHint {
+        position: Position::new(line, character),
+        label: label.to_owned(),
+        kind: Some(kind),
+    };
+    // `sum` has an explicit type.
+    assert_eq!(
+        hints,
+        vec![
+            hint(5, 9, ": i32", InlayHintKind::TYPE),
+            hint(6, 23, "left:", InlayHintKind::PARAMETER),
+            hint(6, 26, "right:", InlayHintKind::PARAMETER),
+        ]
+    );
+}
+
+#[test]
+fn client_signature_help() {
+    use rls::lsp_data::request::SignatureHelpRequest;
+    use rls::lsp_data::{ParameterLabel, TextDocumentPositionParams};
+
+    let p = project("signature_help")
+        .file("Cargo.toml", &basic_bin_manifest("signature_help"))
+        .file(
+            "src/main.rs",
+            "/// Adds two numbers.\n\
+             fn add(left: u32, right: u32) -> u32 {\n\
+             \x20   left + right\n\
+             }\n\
+             \n\
+             fn main() {\n\
+             \x20   let sum = add(add(1, 2), 3);\n\
+             \x20   println!(\"{}\", sum);\n\
+             }\n",
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    rls.request::<Initialize>(0, initialize_params(root_path));
+
+    rls.wait_for_indexing();
+
+    // The cursor is on `3`, after the nested call.
+    let help = rls
+        .request::<SignatureHelpRequest>(
+            100,
+            TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier {
+                    uri: Url::from_file_path(p.root().join("src/main.rs")).unwrap(),
+                },
+                position: Position::new(6, 29),
+            },
+        )
+        .expect("no signature help");
+
+    assert_eq!(help.active_parameter, Some(1));
+    let signature = &help.signatures[0];
+    let params: Vec<_> = signature
+        .parameters
+        .as_ref()
+        .unwrap()
+        .iter()
+        .map(|param| match param.label {
+            ParameterLabel::LabelOffsets([start, end]) => {
+                &signature.label[start as usize..end as usize]
+            }
+            ParameterLabel::Simple(ref label) => label.as_str(),
+        })
+        .collect();
+    assert_eq!(params, vec!["left: u32", "right: u32"]);
+}
+
+#[test]
+fn client_call_hierarchy() {
+    use rls::lsp_data::{
+        CallHierarchyIncomingCalls, CallHierarchyItemParams, CallHierarchyOutgoingCalls,
+        CallHierarchyPrepare,
+    };
+
+    let p = project("call_hierarchy")
+        .file("Cargo.toml", &basic_bin_manifest("call_hierarchy"))
+        .file(
+            "src/main.rs",
+            "fn main() {\n\
+             \x20   first();\n\
+             }\n\
+             \n\
+             fn first() {\n\
+             \x20   second();\n\
+             }\n\
+             \n\
+             fn second() {}\n",
+        )
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    rls.request::<Initialize>(0, initialize_params(root_path));
+
+    rls.wait_for_indexing();
+
+    let range = |start: (u64, u64), end: (u64, u64)| Range {
+        start: Position::new(start.0, start.1),
+        end: Position::new(end.0, end.1),
+    };
+
+    let items = rls
+        .request::<CallHierarchyPrepare>(
+            100,
+            TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier {
+                    uri: Url::from_file_path(p.root().join("src/main.rs")).unwrap(),
+                },
+                position: Position::new(4, 4),
+            },
+        )
+        .unwrap();
+    assert_eq!(items.len(), 1);
+    let item = items[0].clone();
+    assert_eq!(item.name, "first");
+    assert_eq!(item.range, range((4, 3), (6, 1)));
+    assert_eq!(item.selection_range, range((4, 3), (4, 8)));
+
+    let incoming = rls
+        .request::<CallHierarchyIncomingCalls>(101, CallHierarchyItemParams { item: item.clone() })
+        .unwrap();
+    assert_eq!(incoming.len(), 1);
+    assert_eq!(incoming[0].from.name, "main");
+    assert_eq!(incoming[0].from.range, range((0, 3), (2, 1)));
+    assert_eq!(incoming[0].from.selection_range, range((0, 3), (0, 7)));
+    assert_eq!(incoming[0].from_ranges, vec![range((1, 4), (1, 9))]);
+
+    let outgoing =
+        rls.request::<CallHierarchyOutgoingCalls>(102, CallHierarchyItemParams { item }).unwrap();
+    assert_eq!(outgoing.len(), 1);
+    assert_eq!(outgoing[0].to.name, "second");
+    assert_eq!(outgoing[0].to.range, range((8, 3), (8, 14)));
+    assert_eq!(outgoing[0].to.selection_range, range((8, 3), (8, 9)));
+    assert_eq!(outgoing[0].from_ranges, vec![range((5, 4), (5, 10))]);
+}
+
+#[test]
+fn client_rls_commands() {
+    let p = project("rls_commands")
+        .file("Cargo.toml", &basic_bin_manifest("rls_commands"))
+        .file("src/main.rs", "fn main() {\n    let unused = 1;\n}\n")
+        .build();
+    let root_path = p.root();
+    let mut rls = p.spawn_rls_async();
+
+    rls.request::<Initialize>(0, initialize_params(root_path));
+
+    let diagnostics = rls.wait_for_diagnostics();
+    assert_eq!(diagnostics.diagnostics.len(), 1);
+    rls.wait_for_indexing();
+
+    let command = |name: &str| ExecuteCommandParams { command: name.to_owned(), arguments: vec![] };
+
+    let stats = rls.request::<ExecuteCommand>(100, command("rls.showAnalysisStats")).unwrap();
+    for key in &["crates", "defs", "refs"] {
+        assert!(stats[key].as_u64().unwrap() > 0, "no {} in {}", key, stats);
+    }
+
+    rls.request::<ExecuteCommand>(101, command("rls.clearDiagnostics"));
+    let cleared = rls
+        .messages()
+        .iter()
+        .rfind(|msg| msg["method"] == PublishDiagnostics::METHOD)
+        .map(|msg| PublishDiagnosticsParams::deserialize(&msg["params"]).unwrap())
+        .unwrap();
+    assert_eq!(cleared.uri, diagnostics.uri);
+    assert_eq!(cleared.diagnostics, vec![]);
+
+    // Rebuilding publishes the diagnostics again.
+    rls.request::<ExecuteCommand>(102, command("rls.rebuildProject"));
+    assert_eq!(rls.wait_for_diagnostics().diagnostics.len(), 1);
+    rls.wait_for_indexing();
+
+    // As does reloading the config, without the warnings this time.
+    std::fs::write(p.root().join("rls.toml"), "show_warnings = false\n").unwrap();
+    rls.request::<ExecuteCommand>(103, command("rls.reloadConfig"));
+    assert_eq!(rls.wait_for_diagnostics().diagnostics, vec![]);
+}
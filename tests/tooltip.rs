@@ -182,6 +182,13 @@ impl TooltipTestHarness {
         let client_caps = ClientCapabilities {
             code_completion_has_snippet_support: true,
             related_information_support: true,
+            code_action_resolve_support: false,
+            inline_value_support: false,
+            semantic_tokens_refresh_support: false,
+            work_done_progress_support: false,
+            inlay_hint_dynamic_registration: false,
+            call_hierarchy_dynamic_registration: false,
+            document_link_dynamic_registration: false,
         };
 
         let _working_dir = tempfile::tempdir().expect("Couldn't create tempdir");